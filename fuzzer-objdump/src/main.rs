@@ -6,15 +6,21 @@ use std::fmt;
 use std::fs;
 use std::io;
 use std::io::Write;
+use std::mem;
 use std::path::Path;
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::{Duration, Instant};
 
-use riscv_emu::elf::{self, Elf};
-use riscv_emu::emulator::{Emulator, RegAlias, VmExit};
+use riscv_emu::cmplog::{self, CmpLog};
+use riscv_emu::elf::{self, Elf, SymbolTable};
+use riscv_emu::emulator::{Emulator, HookCallback, Reg, RegAlias, VmExit};
 use riscv_emu::jit::JitCache;
-use riscv_emu::mmu::{self, Mmu, Perm, VirtAddr, PERM_READ, PERM_WRITE};
+use riscv_emu::mmu::{
+    self, Access, AllocConfig, AllocFailPolicy, AllocSite, LeakKind, Mmu,
+    Perm, Pod, Region, RegionKind, VirtAddr, PERM_EXEC, PERM_READ, PERM_WRITE,
+};
+use riscv_emu::snapshot;
 
 /// If `true`, print debug messages.
 const DEBUG: bool = false;
@@ -39,17 +45,89 @@ const JIT_CACHE_SIZE: usize = 32 * 1024 * 1024;
 /// value must be bigger than 1024.
 const STACK_SIZE: usize = 1024 * 1024;
 
+/// Amount of memory below the stack registered as a guard region.
+const STACK_GUARD_SIZE: usize = 64 * 1024;
+
+/// Maximum number of instructions executed per fuzz case. If this budget is
+/// exhausted, the fuzz case is considered a timeout.
+const INST_BUDGET: u64 = 100_000_000;
+
 /// If `true`, allocate memory with WRITE|RAW permissions, so unitialized
 /// memory accesses are detected. Otherwise, allocate memory with WRITE|READ
 /// permissions.
 const CHECK_RAW: bool = false;
 
+/// If `true`, track uninitialized memory bit by bit, reporting undefined
+/// values only when they affect a branch, an address or a syscall argument.
+/// Code is always executed using emulation.
+const CHECK_UNINIT: bool = false;
+
+/// If `true`, the memory returned by the allocation hooks is uninitialized
+/// until written.
+const MALLOC_RAW: bool = CHECK_RAW || CHECK_UNINIT;
+
+/// If `true`, the allocations leaked when the program exits are reported as
+/// crashes. Pointers in the registers, the globals and the stack are followed
+/// to tell leaked allocations apart from reachable ones.
+const CHECK_LEAKS: bool = false;
+
+/// Policy deciding which allocations made through the hooks return NULL, to
+/// exercise the handling of allocation failures. The failed allocations are
+/// listed in the crash reports.
+const ALLOC_FAIL_POLICY: Option<AllocFailPolicy> = None;
+
+/// If `true`, the allocations that fail are decided by the fuzz input instead
+/// of `ALLOC_FAIL_POLICY`: the last `ALLOC_FAIL_BITS_LEN` bytes of the input
+/// are used as an `AllocFailPolicy::Bits` policy. This way the failures are
+/// mutated along with the input and reproduced from the crash files.
+const ALLOC_FAIL_FROM_INPUT: bool = false;
+
+/// Number of bytes of the input deciding which allocations fail.
+const ALLOC_FAIL_BITS_LEN: usize = 8;
+
+/// Alignment of the memory returned by the allocation hooks.
+const MALLOC_ALIGN: usize = 16;
+
+/// Maximum number of freed bytes kept in quarantine before being reused.
+const HEAP_QUARANTINE_SIZE: usize = 1024 * 1024;
+
+/// Size of the redzones placed before and after every allocation.
+const HEAP_REDZONE_SIZE: usize = 64;
+
+/// Maximum number of return addresses recorded for every allocation and free.
+const ALLOC_STACK_DEPTH: usize = 8;
+
 /// If `true`, mutate inputs.
 const MUTATE: bool = true;
 
 /// If `true`, execute the target program using JIT compilation.
 const USE_JIT: bool = true;
 
+/// If set, the lifted blocks and the JIT statistics are dumped into this
+/// directory.
+const JIT_DUMP_PATH: Option<&str> = None;
+
+/// If `true`, every input increasing coverage is run again with comparison
+/// logging enabled. The inputs obtained by patching it with the logged
+/// operands are fuzzed before picking new inputs from the corpus.
+const USE_CMPLOG: bool = true;
+
+/// Maximum number of runs used to colorize an input before a comparison
+/// logging run. Bigger values randomize more bytes of the input, which
+/// discards more of the operands that do not come from it.
+const MAX_COLORIZATION_RUNS: usize = 64;
+
+/// Maximum number of inputs obtained from comparison logging runs that are
+/// pending to be fuzzed. New inputs are discarded while the queue is full.
+const MAX_CMPLOG_QUEUE_LEN: usize = 4096;
+
+/// Maximum length of the path names passed to syscalls, including the NUL
+/// terminator.
+const PATH_MAX: usize = 4096;
+
+/// Target program.
+const PROGRAM_PATH: &str = "test-targets/binutils/objdump-2.35-riscv";
+
 /// Inputs directory.
 const INPUTS_PATH: &str = "test-targets/inputs";
 
@@ -59,6 +137,11 @@ const CRASHES_PATH: &str = "test-targets/crashes";
 /// Log filename.
 const LOG_FILENAME: &str = "test-targets/fuzzer-objdump.log";
 
+/// Snapshot filename. If the file exists, the snapshot is loaded from it.
+/// Otherwise, the snapshot is taken and saved to it. It must be removed when
+/// the target program changes.
+const SNAPSHOT_PATH: &str = "test-targets/fuzzer-objdump.snapshot";
+
 /// Fuzzer's exit reason.
 #[derive(Debug)]
 enum FuzzExit {
@@ -130,6 +213,16 @@ enum FaultType {
 
     /// Invalid free, due to double free or corrupted heap.
     Free,
+
+    /// Access to the guard region below the stack.
+    StackOverflow,
+
+    /// Allocation not freed when the program exits.
+    Leak,
+
+    /// Any other error, like an unsupported instruction or an Mmu error not
+    /// caused by a memory access.
+    Other,
 }
 
 impl fmt::Display for FaultType {
@@ -141,6 +234,19 @@ impl fmt::Display for FaultType {
             FaultType::Uninit => write!(f, "uninit"),
             FaultType::Bounds => write!(f, "bounds"),
             FaultType::Free => write!(f, "free"),
+            FaultType::StackOverflow => write!(f, "stack-overflow"),
+            FaultType::Leak => write!(f, "leak"),
+            FaultType::Other => write!(f, "other"),
+        }
+    }
+}
+
+impl From<Access> for FaultType {
+    fn from(access: Access) -> FaultType {
+        match access {
+            Access::Read => FaultType::Read,
+            Access::Write => FaultType::Write,
+            Access::Exec => FaultType::Exec,
         }
     }
 }
@@ -148,32 +254,41 @@ impl fmt::Display for FaultType {
 /// Address range classification.
 #[derive(Copy, Clone, PartialEq, Eq, Hash)]
 enum AddressType {
-    /// Address within the range [0, 32KiB).
+    /// Address within a memory region registered in the Mmu.
+    Region(RegionKind),
+
+    /// Address within the range [0, 32KiB), outside any region.
     Null,
 
-    /// Address within the range [-32KiB, 0).
+    /// Address within the range [-32KiB, 0), outside any region.
     Negative,
 
     /// Other addresses.
-    Normal,
+    Unmapped,
 }
 
 impl fmt::Display for AddressType {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
+            AddressType::Region(kind) => write!(f, "{}", kind),
             AddressType::Null => write!(f, "null"),
             AddressType::Negative => write!(f, "negative"),
-            AddressType::Normal => write!(f, "normal"),
+            AddressType::Unmapped => write!(f, "unmapped"),
         }
     }
 }
 
-impl From<VirtAddr> for AddressType {
-    fn from(addr: VirtAddr) -> AddressType {
+impl AddressType {
+    /// Classifies `addr` according to the memory regions of `mmu`.
+    fn new(mmu: &Mmu, addr: VirtAddr) -> AddressType {
+        if let Some(region) = mmu.region(addr) {
+            return AddressType::Region(region.kind);
+        }
+
         match *addr as isize {
             0..=32767 => AddressType::Null,
             -32768..=-1 => AddressType::Negative,
-            _ => AddressType::Normal,
+            _ => AddressType::Unmapped,
         }
     }
 }
@@ -221,6 +336,9 @@ struct Stats {
     /// Total number of CPU cycles spent mutating the input.
     mutation_cycles: u64,
 
+    /// Total number of CPU cycles spent in comparison logging runs.
+    cmplog_cycles: u64,
+
     /// Total number of crashes.
     crashes: u64,
 
@@ -243,6 +361,34 @@ struct InputFile {
     cursor: usize,
 }
 
+/// File status returned by the stat syscalls, as defined by Linux on
+/// RISC-V 64.
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+struct Stat {
+    st_dev: u64,
+    st_ino: u64,
+    st_mode: u32,
+    st_nlink: u32,
+    st_uid: u32,
+    st_gid: u32,
+    st_rdev: u64,
+    pad1: u64,
+    st_size: i64,
+    st_blksize: i32,
+    pad2: i32,
+    st_blocks: i64,
+    st_atime: i64,
+    st_atime_nsec: u64,
+    st_mtime: i64,
+    st_mtime_nsec: u64,
+    st_ctime: i64,
+    st_ctime_nsec: u64,
+    unused: [u32; 2],
+}
+
+unsafe impl Pod for Stat {}
+
 /// Profiling information for one fuzz case.
 #[derive(Default)]
 struct Profile {
@@ -283,6 +429,18 @@ struct Fuzzer {
 
     /// Random number generator.
     rng: xorshift::Rng,
+
+    /// Inputs obtained from comparison logging runs, pending to be fuzzed.
+    cmplog_queue: Vec<Vec<u8>>,
+
+    /// Hooks of the comparison functions. They are only set in
+    /// `emu_cmplog`, so they do not force the lifted code to exit in the
+    /// rest of the runs.
+    cmplog_hooks: Vec<(VirtAddr, HookCallback)>,
+
+    /// Emulator used in comparison logging runs. It is reset to `emu_init`
+    /// before every run, as `emu` is.
+    emu_cmplog: Emulator,
 }
 
 impl Fuzzer {
@@ -293,8 +451,10 @@ impl Fuzzer {
         corpus: Arc<Mutex<HashSet<Vec<u8>>>>,
         unique_crashes: Arc<Mutex<HashSet<UniqueCrash>>>,
         stats: Arc<Mutex<Stats>>,
+        cmplog_hooks: Vec<(VirtAddr, HookCallback)>,
     ) -> Fuzzer {
         let emu = emu_init.fork();
+        let emu_cmplog = fork_cmplog(&emu_init, &cmplog_hooks);
 
         Fuzzer {
             emu_init,
@@ -309,6 +469,9 @@ impl Fuzzer {
                 cursor: 0,
             },
             rng: xorshift::Rng::new(0x5273e95b7c721b5a),
+            cmplog_queue: Vec::new(),
+            cmplog_hooks,
+            emu_cmplog,
         }
     }
 
@@ -333,18 +496,25 @@ impl Fuzzer {
             input_file_is_open: self.input_file_is_open,
             input_file: self.input_file.clone(),
             rng: xorshift::Rng::new(0x5273e95b7c721b5a ^ rdtsc()),
+            cmplog_queue: Vec::new(),
+            cmplog_hooks: self.cmplog_hooks.clone(),
+            emu_cmplog: fork_cmplog(&self.emu, &self.cmplog_hooks),
         }
     }
 
-    /// Take a snapshot at the specified address.
+    /// Take a snapshot at the specified address. The instruction budget of
+    /// the fuzz cases starts counting from this point.
     fn run_until(&mut self, addr: VirtAddr) -> Result<(), FuzzExit> {
         // Input file size must be bigger than 0 to pass objdump checks.
         self.input_file.contents = vec![0; 16];
 
         loop {
-            let run_result = self.emu.run_emu_until(addr);
+            let run_result = self.emu.run_until(addr);
             match run_result {
-                Err(VmExit::UserBreakpoint) => return Ok(()),
+                Err(VmExit::UserBreakpoint) => {
+                    self.emu.set_inst_budget(INST_BUDGET);
+                    return Ok(());
+                }
                 Err(VmExit::Ecall) => {
                     if let Err(err) = self.syscall_dispatcher() {
                         break Err(err);
@@ -407,6 +577,15 @@ impl Fuzzer {
                     let mut corpus = self.corpus.lock().unwrap();
                     corpus.insert(self.input_file.contents.clone());
                 }
+
+                drop(coverage);
+
+                // Look for input-to-state correspondences in the new input.
+                if USE_CMPLOG && new_coverage {
+                    let cmplog_start = rdtsc();
+                    self.run_cmplog();
+                    local_stats.cmplog_cycles += rdtsc() - cmplog_start;
+                }
             }
 
             // Update global stats.
@@ -418,6 +597,7 @@ impl Fuzzer {
             stats.vm_cycles += local_stats.vm_cycles;
             stats.syscall_cycles += local_stats.syscall_cycles;
             stats.mutation_cycles += local_stats.mutation_cycles;
+            stats.cmplog_cycles += local_stats.cmplog_cycles;
             stats.crashes += local_stats.crashes;
             stats.timeouts += local_stats.timeouts;
 
@@ -425,8 +605,14 @@ impl Fuzzer {
         }
     }
 
-    /// Pick an input from the corpus and mutate it.
+    /// Pick an input from the corpus and mutate it. Inputs obtained from
+    /// comparison logging runs take precedence and are not mutated.
     fn set_and_mutate_input(&mut self) {
+        if let Some(contents) = self.cmplog_queue.pop() {
+            self.input_file.contents = contents;
+            return;
+        }
+
         // Get input from corpus.
         let mut contents = {
             let corpus = self.corpus.lock().unwrap();
@@ -446,8 +632,104 @@ impl Fuzzer {
         self.input_file.contents = contents;
     }
 
+    /// Runs the current input with comparison logging enabled and queues the
+    /// inputs obtained by patching it with the logged operands. The input is
+    /// colorized first, so only the operands coming from it are patched.
+    fn run_cmplog(&mut self) {
+        let contents = self.input_file.contents.clone();
+
+        mem::swap(&mut self.emu, &mut self.emu_cmplog);
+
+        let (pcs, cmplog) = self.trace_input(&contents);
+        let (colorized, colorized_cmplog) =
+            self.colorize(&contents, &pcs, &cmplog);
+
+        mem::swap(&mut self.emu, &mut self.emu_cmplog);
+
+        let patches = cmplog.patch_input_colorized(
+            &contents,
+            &colorized_cmplog,
+            &colorized,
+        );
+        let free = MAX_CMPLOG_QUEUE_LEN - self.cmplog_queue.len();
+        self.cmplog_queue.extend(patches.into_iter().take(free));
+
+        self.input_file.contents = contents;
+    }
+
+    /// Runs `contents` with comparison logging enabled. It returns the
+    /// executed instructions, which identify the execution path, and the
+    /// logged comparisons.
+    fn trace_input(&mut self, contents: &[u8]) -> (HashSet<VirtAddr>, CmpLog) {
+        self.reset();
+        self.input_file.contents = contents.to_vec();
+
+        let mut profile = Profile::default();
+        let fcexit = self.run_fc(&mut profile);
+
+        if DEBUG {
+            eprintln!("Cmplog exit: {}", fcexit);
+        }
+
+        let pcs = self.emu.coverage().pcs.clone();
+        let cmplog = self.emu.cmplog().cloned().unwrap_or_default();
+
+        (pcs, cmplog)
+    }
+
+    /// Randomizes as many bytes of `contents` as possible, while the
+    /// execution path `pcs` does not change. Ranges of bytes are randomized
+    /// at once, and split in halves if the path changes, using at most
+    /// `MAX_COLORIZATION_RUNS` runs. It returns the colorized input and the
+    /// comparisons logged running it. `cmplog` must contain the comparisons
+    /// logged running `contents`.
+    fn colorize(
+        &mut self,
+        contents: &[u8],
+        pcs: &HashSet<VirtAddr>,
+        cmplog: &CmpLog,
+    ) -> (Vec<u8>, CmpLog) {
+        let mut colorized = contents.to_vec();
+        let mut colorized_cmplog = cmplog.clone();
+
+        let mut ranges = vec![(0, contents.len())];
+        let mut runs = 0;
+
+        while let Some((start, end)) = ranges.pop() {
+            if runs >= MAX_COLORIZATION_RUNS {
+                break;
+            }
+            runs += 1;
+
+            let mut candidate = colorized.clone();
+            for byte in &mut candidate[start..end] {
+                *byte = self.rng.rand() as u8;
+            }
+
+            let (candidate_pcs, candidate_cmplog) =
+                self.trace_input(&candidate);
+            if candidate_pcs == *pcs {
+                colorized = candidate;
+                colorized_cmplog = candidate_cmplog;
+            } else if end - start > 1 {
+                let mid = start + (end - start) / 2;
+                ranges.push((mid, end));
+                ranges.push((start, mid));
+            }
+        }
+
+        (colorized, colorized_cmplog)
+    }
+
     /// Runs a single fuzz_case.
     fn run_fc(&mut self, profile: &mut Profile) -> FuzzExit {
+        if ALLOC_FAIL_FROM_INPUT {
+            let contents = &self.input_file.contents;
+            let start = contents.len().saturating_sub(ALLOC_FAIL_BITS_LEN);
+            let policy = AllocFailPolicy::Bits(contents[start..].to_vec());
+            self.emu.mmu_mut().set_alloc_fail_policy(Some(policy));
+        }
+
         loop {
             let vm_start = rdtsc();
             let run_result = self.emu.run();
@@ -472,6 +754,29 @@ impl Fuzzer {
     }
     /// Handle the results obtained by the fuzz case.
     fn handle_fcexit(&mut self, fcexit: FuzzExit, stats: &mut Stats) {
+        // Explain heap errors while the memory state is still available.
+        let heap_report = match &fcexit {
+            FuzzExit::VmExit(VmExit::MemoryFault { error, .. })
+            | FuzzExit::VmExit(VmExit::MmuError(error)) => error
+                .addr()
+                .and_then(|addr| self.emu.mmu().heap_report(addr)),
+            _ => None,
+        };
+
+        // Explain where uninitialized values come from.
+        let origin = match &fcexit {
+            FuzzExit::VmExit(VmExit::UninitValue { origin, .. }) => {
+                origin.clone()
+            }
+            _ => None,
+        };
+
+        // Look for leaks while the memory state is still available.
+        let leaks = match fcexit {
+            FuzzExit::ProgramExit(_) if CHECK_LEAKS => self.emu.leaks(),
+            _ => Vec::new(),
+        };
+
         let pc = self.emu.reg(RegAlias::Pc).unwrap();
         let pc = VirtAddr(pc as usize);
 
@@ -479,9 +784,29 @@ impl Fuzzer {
             eprintln!("Fuzz exit: {}", fcexit);
         }
 
+        let address_type = |addr| AddressType::new(self.emu.mmu(), addr);
+
         let unique_crash = match fcexit {
             FuzzExit::ProgramExit(_) => {
-                return;
+                // Leaks are deduplicated by the caller of the allocation
+                // function.
+                let leak = leaks
+                    .iter()
+                    .find(|leak| leak.kind == LeakKind::Direct)
+                    .or_else(|| leaks.first());
+                match leak {
+                    Some(leak) => {
+                        let site = &leak.site;
+                        let pc =
+                            site.stack.first().copied().unwrap_or(site.pc);
+                        UniqueCrash(
+                            pc,
+                            FaultType::Leak,
+                            address_type(leak.addr),
+                        )
+                    }
+                    None => return,
+                }
             }
             FuzzExit::VmExit(vmexit) => match vmexit {
                 VmExit::Timeout => {
@@ -489,34 +814,86 @@ impl Fuzzer {
                     return;
                 }
                 VmExit::AddressMisaligned => {
-                    UniqueCrash(pc, FaultType::Exec, AddressType::from(pc))
+                    UniqueCrash(pc, FaultType::Exec, address_type(pc))
+                }
+                VmExit::InvalidInstruction
+                | VmExit::UnimplementedInstruction => {
+                    UniqueCrash(pc, FaultType::Exec, address_type(pc))
                 }
-                VmExit::InvalidInstruction => {
-                    UniqueCrash(pc, FaultType::Exec, AddressType::from(pc))
+                VmExit::PageFault { pc, addr, cause } => {
+                    let fault_type = match cause {
+                        12 => FaultType::Exec,
+                        13 => FaultType::Read,
+                        _ => FaultType::Write,
+                    };
+                    UniqueCrash(pc, fault_type, address_type(addr))
+                }
+                VmExit::MemoryFault {
+                    pc,
+                    access,
+                    ref error,
+                } => {
+                    let (fault_type, addr) = match *error {
+                        mmu::Error::UninitFault { addr, .. } => {
+                            (FaultType::Uninit, addr)
+                        }
+                        mmu::Error::InvalidAddress { addr, .. }
+                        | mmu::Error::AddressIntegerOverflow {
+                            addr, ..
+                        } => (FaultType::Bounds, addr),
+                        mmu::Error::StackOverflow { addr, .. } => {
+                            (FaultType::StackOverflow, addr)
+                        }
+                        mmu::Error::ReadFault { addr, .. }
+                        | mmu::Error::WriteFault { addr, .. }
+                        | mmu::Error::ExecFault { addr, .. }
+                        | mmu::Error::UnkFault { addr, .. } => {
+                            (FaultType::from(access), addr)
+                        }
+                        _ => (FaultType::Other, error.addr().unwrap_or(pc)),
+                    };
+                    UniqueCrash(pc, fault_type, address_type(addr))
                 }
                 VmExit::MmuError(mmu::Error::ExecFault { addr, .. }) => {
-                    UniqueCrash(pc, FaultType::Exec, AddressType::from(addr))
+                    UniqueCrash(pc, FaultType::Exec, address_type(addr))
                 }
                 VmExit::MmuError(mmu::Error::ReadFault { addr, .. }) => {
-                    UniqueCrash(pc, FaultType::Read, AddressType::from(addr))
+                    UniqueCrash(pc, FaultType::Read, address_type(addr))
                 }
                 VmExit::MmuError(mmu::Error::WriteFault { addr, .. }) => {
-                    UniqueCrash(pc, FaultType::Write, AddressType::from(addr))
+                    UniqueCrash(pc, FaultType::Write, address_type(addr))
                 }
                 VmExit::MmuError(mmu::Error::UninitFault { addr, .. }) => {
-                    UniqueCrash(pc, FaultType::Uninit, AddressType::from(addr))
+                    UniqueCrash(pc, FaultType::Uninit, address_type(addr))
                 }
                 VmExit::MmuError(mmu::Error::InvalidAddress {
                     addr, ..
-                }) => {
-                    UniqueCrash(pc, FaultType::Bounds, AddressType::from(addr))
-                }
+                })
+                | VmExit::MmuError(mmu::Error::StackCollision {
+                    addr, ..
+                }) => UniqueCrash(pc, FaultType::Bounds, address_type(addr)),
+                VmExit::MmuError(mmu::Error::StackOverflow {
+                    addr, ..
+                }) => UniqueCrash(
+                    pc,
+                    FaultType::StackOverflow,
+                    address_type(addr),
+                ),
                 VmExit::MmuError(mmu::Error::InvalidFree { addr }) => {
-                    UniqueCrash(pc, FaultType::Free, AddressType::from(addr))
+                    UniqueCrash(pc, FaultType::Free, address_type(addr))
+                }
+                VmExit::UninitValue { ref origin, .. } => {
+                    // Classify the memory the undefined value comes from.
+                    let addr = origin.as_ref().map_or(pc, |o| o.addr);
+                    UniqueCrash(pc, FaultType::Uninit, address_type(addr))
+                }
+                VmExit::MmuError(ref error) => {
+                    let addr = error.addr().unwrap_or(pc);
+                    UniqueCrash(pc, FaultType::Other, address_type(addr))
                 }
-                _ => panic!("Unexpected VmExit error: {}", vmexit),
+                _ => UniqueCrash(pc, FaultType::Other, address_type(pc)),
             },
-            _ => panic!("Unexpected FuzzExit error: {}", fcexit),
+            _ => UniqueCrash(pc, FaultType::Other, address_type(pc)),
         };
 
         stats.crashes += 1;
@@ -535,6 +912,29 @@ impl Fuzzer {
             fs::write(crash_path, &self.input_file.contents)
                 .expect("could not create crash file");
 
+            // The report explains heap errors, uninitialized values and
+            // leaks, and describes the memory layout.
+            let mut report = String::new();
+            if let Some(heap_report) = heap_report {
+                report.push_str(&format!("{}\n\n", heap_report));
+            }
+            if let Some(origin) = origin {
+                report.push_str(&format!("{}\n\n", origin));
+            }
+            for leak in &leaks {
+                report.push_str(&format!("{}\n\n", leak));
+            }
+            for failed in self.emu.mmu().failed_allocs() {
+                report.push_str(&format!("{}\n\n", failed));
+            }
+            report.push_str("Memory map:\n");
+            report.push_str(&self.emu.mmu().maps());
+
+            let report_path = Path::new(CRASHES_PATH)
+                .join(format!("{}.txt", unique_crash.filename()));
+            fs::write(report_path, report)
+                .expect("could not create crash report file");
+
             let mut corpus = self.corpus.lock().unwrap();
             corpus.insert(self.input_file.contents.clone());
         }
@@ -546,6 +946,20 @@ impl Fuzzer {
         let syscall_number = self.emu.reg(RegAlias::A7)?;
         let pc = self.emu.reg(RegAlias::Pc)?;
 
+        // Check that the syscall number and the used arguments, stored
+        // from a0 onwards, are defined.
+        let num_args = match syscall_number {
+            57 | 93 | 214 | 1024 => 1,
+            80 | 215 | 1038 => 2,
+            62..=64 | 226 => 3,
+            222 => 6,
+            _ => 0,
+        };
+        self.emu.check_defined(RegAlias::A7)?;
+        for arg in 0..num_args {
+            self.emu.check_defined(Reg(RegAlias::A0 as u32 + arg))?;
+        }
+
         match syscall_number {
             57 => self.syscall_close()?,
             62 => self.syscall_lseek()?,
@@ -554,6 +968,9 @@ impl Fuzzer {
             80 => self.syscall_fstat()?,
             93 => self.syscall_exit()?,
             214 => self.syscall_brk()?,
+            215 => self.syscall_munmap()?,
+            222 => self.syscall_mmap()?,
+            226 => self.syscall_mprotect()?,
             1024 => self.syscall_open()?,
             1038 => self.syscall_stat()?,
             _ => unimplemented!("[{:#010x}] unknown syscall", pc),
@@ -677,6 +1094,10 @@ impl Fuzzer {
             .mmu_mut()
             .write(buf, &self.input_file.contents[cursor..cursor + count])?;
 
+        // Label the read bytes with their offsets, so mutations can target
+        // the input bytes reaching interesting code.
+        self.emu.mmu_mut().taint_input(buf, count, cursor)?;
+
         self.input_file.cursor += count as usize;
         self.emu.set_reg(RegAlias::A0, count as u64)?;
 
@@ -724,28 +1145,15 @@ impl Fuzzer {
             eprintln!("fstat: fd={}", fd);
         }
 
-        // Set st_mode
-        let st_mode_addr = statbuf.checked_add(16).ok_or(
-            mmu::Error::AddressIntegerOverflow {
-                addr: VirtAddr(statbuf as usize),
-                size: 16,
-            },
-        )?;
-        let st_mode_addr = VirtAddr(st_mode_addr as usize);
-        self.emu.mmu_mut().write_int::<u32>(st_mode_addr, 0x8000)?;
-
-        // Set st_size
-        let st_size_addr = statbuf.checked_add(48).ok_or(
-            mmu::Error::AddressIntegerOverflow {
-                addr: VirtAddr(statbuf as usize),
-                size: 48,
-            },
-        )?;
-        let st_size_addr = VirtAddr(st_size_addr as usize);
-        self.emu.mmu_mut().write_int::<u64>(
-            st_size_addr,
-            self.input_file.contents.len() as u64,
-        )?;
+        // Only a regular file with the size of the input is reported.
+        let stat = Stat {
+            st_mode: 0x8000,
+            st_size: self.input_file.contents.len() as i64,
+            ..Stat::default()
+        };
+        self.emu
+            .mmu_mut()
+            .write_struct(VirtAddr(statbuf as usize), &stat)?;
 
         self.emu.set_reg(RegAlias::A0, 0)?;
 
@@ -775,13 +1183,108 @@ impl Fuzzer {
         panic!("brk should not be called: addr={:#010x}", addr);
     }
 
+    /// munmap syscall handle.
+    fn syscall_munmap(&mut self) -> Result<(), FuzzExit> {
+        let addr = self.emu.reg(RegAlias::A0)?;
+        let len = self.emu.reg(RegAlias::A1)?;
+
+        if DEBUG {
+            eprintln!("munmap: addr={:#010x} len={:#x}", addr, len);
+        }
+
+        let result = self.emu.munmap(VirtAddr(addr as usize), len as usize);
+        let ret = if result.is_ok() { 0 } else { !0 };
+        self.emu.set_reg(RegAlias::A0, ret)?;
+
+        Ok(())
+    }
+
+    /// mmap syscall handle.
+    fn syscall_mmap(&mut self) -> Result<(), FuzzExit> {
+        const MAP_FIXED: u64 = 0x10;
+        const MAP_ANONYMOUS: u64 = 0x20;
+
+        let addr = self.emu.reg(RegAlias::A0)?;
+        let len = self.emu.reg(RegAlias::A1)?;
+        let prot = self.emu.reg(RegAlias::A2)?;
+        let flags = self.emu.reg(RegAlias::A3)?;
+        let fd = self.emu.reg(RegAlias::A4)?;
+        let offset = self.emu.reg(RegAlias::A5)?;
+
+        if DEBUG {
+            eprintln!(
+                "mmap: addr={:#010x} len={:#x} prot={:#x} flags={:#x} fd={}",
+                addr, len, prot, flags, fd
+            );
+        }
+
+        let addr = if flags & MAP_FIXED != 0 {
+            Some(VirtAddr(addr as usize))
+        } else {
+            None
+        };
+
+        // Besides anonymous memory, only the input file can be mapped.
+        let (name, contents): (&str, &[u8]) = if flags & MAP_ANONYMOUS != 0 {
+            ("[anon]", &[])
+        } else if fd == 1337 && self.input_file_is_open {
+            let contents = &self.input_file.contents;
+            ("input", contents.get(offset as usize..).unwrap_or(&[]))
+        } else {
+            self.emu.set_reg(RegAlias::A0, !0)?;
+            return Ok(());
+        };
+
+        let result = self.emu.mmap(
+            addr,
+            len as usize,
+            prot_to_perm(prot),
+            name,
+            contents,
+        );
+        let ret = match result {
+            Ok(addr) => *addr as u64,
+            Err(_) => !0,
+        };
+        self.emu.set_reg(RegAlias::A0, ret)?;
+
+        Ok(())
+    }
+
+    /// mprotect syscall handle.
+    fn syscall_mprotect(&mut self) -> Result<(), FuzzExit> {
+        let addr = self.emu.reg(RegAlias::A0)?;
+        let len = self.emu.reg(RegAlias::A1)?;
+        let prot = self.emu.reg(RegAlias::A2)?;
+
+        if DEBUG {
+            eprintln!(
+                "mprotect: addr={:#010x} len={:#x} prot={:#x}",
+                addr, len, prot
+            );
+        }
+
+        let result = self.emu.mprotect(
+            VirtAddr(addr as usize),
+            len as usize,
+            prot_to_perm(prot),
+        );
+        let ret = if result.is_ok() { 0 } else { !0 };
+        self.emu.set_reg(RegAlias::A0, ret)?;
+
+        Ok(())
+    }
+
     /// open syscall handle.
     fn syscall_open(&mut self) -> Result<(), FuzzExit> {
         // We only care about the path name, so we don't even consider the
         // other arguments.
         let path_name = self.emu.reg(RegAlias::A0)?;
 
-        let path_name = self.get_cstr(VirtAddr(path_name as usize))?;
+        let path_name = self
+            .emu
+            .mmu()
+            .read_cstr(VirtAddr(path_name as usize), PATH_MAX)?;
         let path_name = String::from_utf8_lossy(&path_name);
 
         if DEBUG {
@@ -809,59 +1312,64 @@ impl Fuzzer {
         let path_name = self.emu.reg(RegAlias::A0)?;
         let statbuf = self.emu.reg(RegAlias::A1)?;
 
-        let path_name = self.get_cstr(VirtAddr(path_name as usize))?;
+        let path_name = self
+            .emu
+            .mmu()
+            .read_cstr(VirtAddr(path_name as usize), PATH_MAX)?;
         let path_name = String::from_utf8_lossy(&path_name);
 
         if DEBUG {
             eprintln!("stat: path_name={}", path_name);
         }
 
-        // Set st_mode
-        let st_mode_addr = statbuf.checked_add(16).ok_or(
-            mmu::Error::AddressIntegerOverflow {
-                addr: VirtAddr(statbuf as usize),
-                size: 16,
-            },
-        )?;
-        let st_mode_addr = VirtAddr(st_mode_addr as usize);
-        self.emu.mmu_mut().write_int::<u32>(st_mode_addr, 0x8000)?;
-
-        // Set st_size
-        let st_size_addr = statbuf.checked_add(48).ok_or(
-            mmu::Error::AddressIntegerOverflow {
-                addr: VirtAddr(statbuf as usize),
-                size: 48,
-            },
-        )?;
-        let st_size_addr = VirtAddr(st_size_addr as usize);
-        self.emu.mmu_mut().write_int::<u64>(
-            st_size_addr,
-            self.input_file.contents.len() as u64,
-        )?;
+        // Only a regular file with the size of the input is reported.
+        let stat = Stat {
+            st_mode: 0x8000,
+            st_size: self.input_file.contents.len() as i64,
+            ..Stat::default()
+        };
+        self.emu
+            .mmu_mut()
+            .write_struct(VirtAddr(statbuf as usize), &stat)?;
 
         self.emu.set_reg(RegAlias::A0, 0)?;
 
         Ok(())
     }
+}
 
-    /// Reads a C string (NULL terminated) from memory at `addr`.
-    fn get_cstr(&self, addr: VirtAddr) -> Result<Vec<u8>, FuzzExit> {
-        let mut result = Vec::new();
-        let mut curaddr = addr;
-        loop {
-            let ch = self.emu.mmu().read_int::<u8>(curaddr)?;
-            if ch == 0 {
-                break Ok(result);
-            }
-            result.push(ch);
-            *curaddr = curaddr.checked_add(1).ok_or(
-                mmu::Error::AddressIntegerOverflow {
-                    addr: curaddr,
-                    size: 1,
-                },
-            )?;
-        }
+/// Returns a fork of `emu` with comparison logging enabled and the hooks
+/// `hooks` set.
+fn fork_cmplog(
+    emu: &Emulator,
+    hooks: &[(VirtAddr, HookCallback)],
+) -> Emulator {
+    let mut emu = emu.fork();
+    for (addr, cb) in hooks {
+        emu.hook(*addr, *cb);
+    }
+    emu.set_cmplog(true);
+    emu
+}
+
+/// Returns the memory permissions equivalent to the mmap protection flags
+/// `prot`.
+fn prot_to_perm(prot: u64) -> Perm {
+    const PROT_READ: u64 = 1;
+    const PROT_WRITE: u64 = 2;
+    const PROT_EXEC: u64 = 4;
+
+    let mut perms = 0;
+    if prot & PROT_READ != 0 {
+        perms |= PERM_READ;
     }
+    if prot & PROT_WRITE != 0 {
+        perms |= PERM_WRITE;
+    }
+    if prot & PROT_EXEC != 0 {
+        perms |= PERM_EXEC;
+    }
+    Perm(perms)
 }
 
 /// Loads an ELF program in the emulator. It also points the program
@@ -870,6 +1378,10 @@ fn load_program<P: AsRef<Path>>(
     emu: &mut Emulator,
     program: P,
 ) -> Result<(), FuzzExit> {
+    let name = program
+        .as_ref()
+        .file_name()
+        .map_or(String::new(), |name| name.to_string_lossy().into_owned());
     let contents = fs::read(program)?;
     let elf = Elf::parse(&contents)?;
 
@@ -891,6 +1403,19 @@ fn load_program<P: AsRef<Path>>(
         emu.mmu_mut().poke(mem_start, file_bytes)?;
         emu.mmu_mut().set_perms(mem_start, mem_size, phdr.perms())?;
 
+        let kind = if *phdr.perms() & PERM_EXEC != 0 {
+            RegionKind::Code
+        } else {
+            RegionKind::Global
+        };
+        emu.mmu_mut().add_region(Region {
+            name: name.clone(),
+            kind,
+            addr: mem_start,
+            size: mem_size,
+            perms: phdr.perms(),
+        })?;
+
         // checked_add() is not needed here because integer overflows have
         // been already checked in the previous call to set_perms().
         let mem_end = *mem_start + mem_size;
@@ -912,27 +1437,14 @@ fn load_program<P: AsRef<Path>>(
     Ok(())
 }
 
-/// Set up a stack with a size of `STACK_SIZE` bytes. It also configures
-/// the command line argumets passed to the program.
-///
-/// # Panics
-///
-/// This function will panic if the memory size of the VM is not higher than
-/// `STACK_SIZE`.
+/// Set up a stack with a size of `STACK_SIZE` bytes, guarded by
+/// `STACK_GUARD_SIZE` inaccessible bytes below it. It also configures the
+/// command line argumets passed to the program.
 fn setup_stack(emu: &mut Emulator) -> Result<(), FuzzExit> {
-    let mem_size = emu.mmu().size();
-
-    assert!(mem_size > STACK_SIZE, "the VM memory is not big enough");
+    let stack_top = *emu.mmu_mut().map_stack(STACK_SIZE, STACK_GUARD_SIZE)?;
 
-    let argv_base: usize = mem_size - 512;
-    let stack_init: usize = mem_size - 1024;
-
-    // Set the permissions of the stack to RW.
-    emu.mmu_mut().set_perms(
-        VirtAddr(mem_size - STACK_SIZE),
-        STACK_SIZE,
-        Perm(PERM_READ | PERM_WRITE),
-    )?;
+    let argv_base: usize = stack_top - 512;
+    let stack_init: usize = stack_top - 1024;
 
     // Store program args
     emu.mmu_mut().poke(VirtAddr(argv_base), b"objdump\x00")?;
@@ -975,6 +1487,29 @@ fn populate_corpus<P: AsRef<Path>>(
     Ok(())
 }
 
+/// Returns the allocation site of the hooked allocation function.
+fn alloc_site(emu: &Emulator) -> Result<AllocSite, VmExit> {
+    Ok(AllocSite {
+        pc: VirtAddr(emu.reg(RegAlias::Pc)? as usize),
+        stack: emu.backtrace(ALLOC_STACK_DEPTH),
+    })
+}
+
+/// Allocates `size` bytes for the allocation hooks. It returns `None` if the
+/// allocation is made to fail by `ALLOC_FAIL_POLICY` or the input.
+fn hook_malloc(
+    emu: &mut Emulator,
+    size: usize,
+    raw: bool,
+) -> Result<Option<VirtAddr>, VmExit> {
+    let site = alloc_site(emu)?;
+    match emu.mmu_mut().malloc(size, MALLOC_ALIGN, raw, site) {
+        Ok(addr) => Ok(Some(addr)),
+        Err(mmu::Error::InjectedAllocFailure { .. }) => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
 /// _malloc_r hook.
 fn malloc_r_cb(emu: &mut Emulator) -> Result<(), VmExit> {
     let size = emu.reg(RegAlias::A1)? as usize;
@@ -985,7 +1520,7 @@ fn malloc_r_cb(emu: &mut Emulator) -> Result<(), VmExit> {
     if size == 0 {
         emu.set_reg(RegAlias::A0, 0)?;
     } else {
-        let addr = emu.mmu_mut().malloc(size, CHECK_RAW)?;
+        let addr = hook_malloc(emu, size, MALLOC_RAW)?.unwrap_or(VirtAddr(0));
         if DEBUG {
             println!("malloc: ret={}", addr);
         }
@@ -1007,11 +1542,17 @@ fn calloc_r_cb(emu: &mut Emulator) -> Result<(), VmExit> {
     if nmemb == 0 || size == 0 {
         emu.set_reg(RegAlias::A0, 0)?;
     } else if let Some(total_size) = nmemb.checked_mul(size) {
-        let addr = emu.mmu_mut().malloc(total_size, CHECK_RAW)?;
+        let addr = hook_malloc(emu, total_size, MALLOC_RAW)?;
 
         // Set memory to zero.
-        let zeros = vec![0u8; total_size];
-        emu.mmu_mut().write(addr, &zeros)?;
+        let addr = match addr {
+            Some(addr) => {
+                let zeros = vec![0u8; total_size];
+                emu.mmu_mut().write(addr, &zeros)?;
+                addr
+            }
+            None => VirtAddr(0),
+        };
 
         if DEBUG {
             println!("calloc: ret={}", addr);
@@ -1042,7 +1583,8 @@ fn realloc_r_cb(emu: &mut Emulator) -> Result<(), VmExit> {
         if size == 0 {
             emu.set_reg(RegAlias::A0, 0)?;
         } else {
-            let addr = emu.mmu_mut().malloc(size as usize, CHECK_RAW)?;
+            let addr =
+                hook_malloc(emu, size, MALLOC_RAW)?.unwrap_or(VirtAddr(0));
             if DEBUG {
                 println!("realloc: ret={}", addr);
             }
@@ -1051,7 +1593,8 @@ fn realloc_r_cb(emu: &mut Emulator) -> Result<(), VmExit> {
     } else if size == 0 {
         // Equivalent to free.
         if *ptr != 0 {
-            emu.mmu_mut().free(ptr)?;
+            let site = alloc_site(emu)?;
+            emu.mmu_mut().free(ptr, site)?;
         }
     } else {
         // Get the size of the realloced memory.
@@ -1063,21 +1606,35 @@ fn realloc_r_cb(emu: &mut Emulator) -> Result<(), VmExit> {
         // Calculate the amount of data to copy.
         let copy_size = cmp::min(old_size, size);
 
-        // Allocate new memory and copy old data.
-        let addr = emu.mmu_mut().malloc(size, false)?;
+        // Allocate new memory and copy old data. On failure, the old memory
+        // is left untouched.
+        let addr = match hook_malloc(emu, size, CHECK_UNINIT)? {
+            Some(addr) => addr,
+            None => {
+                emu.set_reg(RegAlias::A0, 0)?;
+                emu.set_reg(RegAlias::Pc, emu.reg(RegAlias::Ra)?)?;
+                return Ok(());
+            }
+        };
         let mut old_data = vec![0u8; copy_size];
         emu.mmu().peek(ptr, &mut old_data)?;
         emu.mmu_mut().poke(addr, &old_data)?;
 
         // Copy old permissions.
-        let old_perms = emu.mmu().perms(ptr, copy_size)?.to_vec();
+        let old_perms = emu.mmu().perms(ptr, copy_size)?;
         for (offset, perms) in old_perms.iter().enumerate() {
             emu.mmu_mut()
                 .set_perms(VirtAddr(*addr + offset), 1, *perms)?;
         }
 
+        // Copy the definedness of the old data, which is only tracked if
+        // `CHECK_UNINIT` is set.
+        let old_definedness = emu.mmu().definedness(ptr, copy_size)?;
+        emu.mmu_mut().set_definedness(addr, &old_definedness)?;
+
         // Free old memory.
-        emu.mmu_mut().free(ptr)?;
+        let site = alloc_site(emu)?;
+        emu.mmu_mut().free(ptr, site)?;
 
         // Return new address.
         if DEBUG {
@@ -1098,7 +1655,8 @@ fn free_r_cb(emu: &mut Emulator) -> Result<(), VmExit> {
     }
 
     if addr != 0 {
-        emu.mmu_mut().free(VirtAddr(addr as usize))?;
+        let site = alloc_site(emu)?;
+        emu.mmu_mut().free(VirtAddr(addr as usize), site)?;
     }
 
     emu.set_reg(RegAlias::Pc, emu.reg(RegAlias::Ra)?)?;
@@ -1106,20 +1664,51 @@ fn free_r_cb(emu: &mut Emulator) -> Result<(), VmExit> {
 }
 
 fn main() {
-    let mmu = Mmu::new(VM_MEM_SIZE);
-    let mut emu_init = Emulator::new(mmu);
+    // Reuse the snapshot saved by a previous run, if any. Snapshots saved
+    // by older versions of the emulator are taken again and overwritten.
+    let snapshot = if Path::new(SNAPSHOT_PATH).exists() {
+        match Emulator::load_file(SNAPSHOT_PATH) {
+            Ok(emu) => Some(emu),
+            Err(snapshot::Error::UnknownFormat)
+            | Err(snapshot::Error::UnsupportedVersion(_))
+            | Err(snapshot::Error::MalformedFile) => {
+                eprintln!("Snapshot is stale, taking a new one");
+                None
+            }
+            Err(err) => panic!("could not load snapshot: {:?}", err),
+        }
+    } else {
+        None
+    };
+    let snapshot_exists = snapshot.is_some();
+
+    let mut emu_init = if let Some(emu) = snapshot {
+        emu
+    } else {
+        let mmu = Mmu::new(VM_MEM_SIZE).with_alloc_config(AllocConfig {
+            quarantine_size: Some(HEAP_QUARANTINE_SIZE),
+            left_redzone: HEAP_REDZONE_SIZE,
+            right_redzone: HEAP_REDZONE_SIZE,
+        });
+        let mut emu = Emulator::new(mmu);
 
-    // Load the program file.
-    load_program(&mut emu_init, "test-targets/binutils/objdump-2.35-riscv")
-        .expect("could not load target program");
+        // Load the program file.
+        load_program(&mut emu, PROGRAM_PATH)
+            .expect("could not load target program");
 
-    // Set up the stack.
-    setup_stack(&mut emu_init).expect("could not set up the stack");
+        // Set up the stack.
+        setup_stack(&mut emu).expect("could not set up the stack");
+
+        emu
+    };
 
     // In JIT mode, create a cache and pass it to the emulator.
     let emu_brk = emu_init.mmu().brk();
     if USE_JIT {
-        let jit_cache = JitCache::new(*emu_brk, JIT_CACHE_SIZE);
+        let mut jit_cache = JitCache::new(*emu_brk, JIT_CACHE_SIZE);
+        if let Some(path) = JIT_DUMP_PATH {
+            jit_cache = jit_cache.with_dump_dir(path);
+        }
         emu_init = emu_init.with_jit(jit_cache);
     }
 
@@ -1129,6 +1718,30 @@ fn main() {
     emu_init.hook(VirtAddr(0x110a30), realloc_r_cb);
     emu_init.hook(VirtAddr(0x10c7a8), free_r_cb);
 
+    // Hooks in comparison functions, so their arguments are logged during
+    // comparison logging runs.
+    let mut cmplog_hooks = Vec::new();
+    if USE_CMPLOG {
+        let symbols = SymbolTable::parse_file(PROGRAM_PATH)
+            .expect("could not parse the symbol table");
+        let hooks: [(&str, HookCallback); 3] = [
+            ("memcmp", cmplog::memcmp_hook),
+            ("strcmp", cmplog::strcmp_hook),
+            ("strncmp", cmplog::strncmp_hook),
+        ];
+        for (name, cb) in hooks.iter() {
+            if let Some(addr) = symbols.addr(name) {
+                cmplog_hooks.push((addr, *cb));
+            }
+        }
+    }
+
+    if CHECK_UNINIT {
+        emu_init.set_uninit_tracking(true);
+    }
+
+    emu_init.mmu_mut().set_alloc_fail_policy(ALLOC_FAIL_POLICY);
+
     // Populate the initial corpus
     let mut corpus = HashSet::new();
     populate_corpus(INPUTS_PATH, &mut corpus)
@@ -1149,15 +1762,22 @@ fn main() {
         Arc::clone(&corpus),
         Arc::clone(&unique_crashes),
         Arc::clone(&stats),
+        cmplog_hooks,
     );
 
     //000000000012af04 <_open>:
     // ...
     // 12af1c:	40000893          	li	a7,1024
     // 12af20:	00000073          	ecall
-    fuzzer
-        .run_until(VirtAddr(0x12af20))
-        .expect("could not take snapshot");
+    if !snapshot_exists {
+        fuzzer
+            .run_until(VirtAddr(0x12af20))
+            .expect("could not take snapshot");
+        fuzzer
+            .emu
+            .save_file(SNAPSHOT_PATH)
+            .expect("could not save snapshot");
+    }
 
     // Get the current time to calculate statistics.
     let start = Instant::now();
@@ -1213,6 +1833,8 @@ fn main() {
             stats.syscall_cycles as f64 / stats.total_cycles as f64;
         let mutation_time =
             stats.mutation_cycles as f64 / stats.total_cycles as f64;
+        let cmplog_time =
+            stats.cmplog_cycles as f64 / stats.total_cycles as f64;
 
         println!(
             "[{elapsed:10.4}] cases {fuzz_cases:10} | \
@@ -1222,7 +1844,8 @@ fn main() {
             Minst/s (last) {last_instps:10.0} | Minst/s {instps:10.1} | \
             coverage {coverage:10} | corpus {corpus:10} | \
             vm {vm_time:6.4} | reset {reset_time:6.4} | \
-            syscall {syscall_time:6.4} | mutation {mutation_time:6.4}",
+            syscall {syscall_time:6.4} | mutation {mutation_time:6.4} | \
+            cmplog {cmplog_time:6.4}",
             elapsed = elapsed,
             fuzz_cases = stats.fuzz_cases,
             unique_crashes = unique_crashes.len(),
@@ -1237,9 +1860,19 @@ fn main() {
             vm_time = vm_time,
             reset_time = reset_time,
             syscall_time = syscall_time,
-            mutation_time = mutation_time
+            mutation_time = mutation_time,
+            cmplog_time = cmplog_time
         );
 
+        // The JIT statistics are only written with the fuzzer statistics, so
+        // lifting does not wait for them.
+        if USE_JIT && JIT_DUMP_PATH.is_some() {
+            fuzzer
+                .emu
+                .dump_jit_stats()
+                .expect("could not dump JIT statistics");
+        }
+
         last_fuzz_cases = stats.fuzz_cases;
         last_total_inst = stats.total_inst;
         last_stats_time = now;
@@ -0,0 +1,566 @@
+//! Comparison logging, aimed to exploit input-to-state correspondence as
+//! described in the RedQueen paper.
+//!
+//! During a comparison logging run, the emulator records the operands of the
+//! executed compare-and-branch instructions, as well as the arguments of hooked
+//! comparison functions like `memcmp` or `strcmp`. If one of the operands is
+//! found in the input, replacing it with the other operand usually allows to
+//! solve magic numbers and checksums directly.
+//!
+//! To tell the operands coming from the input apart from constants that
+//! happen to be in it, the input can be colorized: its bytes are randomized
+//! as long as the execution path does not change. Only the occurrences whose
+//! colorized bytes match the operands logged with the colorized input are
+//! patched.
+//!
+//! References:
+//! - [RedQueen paper][paper]
+//!
+//! [paper]: https://www.ndss-symposium.org/ndss-paper/redqueen-fuzzing-with-input-to-state-correspondence/
+
+use std::collections::{BTreeSet, HashMap};
+
+use crate::emulator::{Emulator, RegAlias, VmExit};
+use crate::mmu::VirtAddr;
+
+/// Maximum number of unique comparisons kept in a `CmpLog`.
+const MAX_CMPS: usize = 4096;
+
+/// Maximum number of bytes logged for `strcmp`-like functions.
+const MAX_STR_LEN: usize = 64;
+
+/// Maximum number of patched inputs returned by `CmpLog::patch_input`.
+const MAX_PATCHES: usize = 1024;
+
+/// Operands of a logged comparison.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Operands {
+    /// Comparison between two integers.
+    Int(u64, u64),
+
+    /// Comparison between two memory buffers.
+    Bytes(Vec<u8>, Vec<u8>),
+}
+
+/// A logged comparison.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Cmp {
+    /// Address of the comparison instruction or of the hooked function.
+    pub pc: VirtAddr,
+
+    /// Operands of the comparison.
+    pub operands: Operands,
+}
+
+/// Set of unique comparisons recorded during a comparison logging run. They
+/// are kept sorted, so the patched inputs are reproducible.
+#[derive(Debug, Clone, Default)]
+pub struct CmpLog {
+    cmps: BTreeSet<Cmp>,
+}
+
+impl CmpLog {
+    /// Returns a new empty comparison log.
+    pub fn new() -> CmpLog {
+        CmpLog::default()
+    }
+
+    /// Records a comparison between two integers at the address `pc`.
+    /// Comparisons with equal operands are discarded.
+    pub fn log_int(&mut self, pc: VirtAddr, op1: u64, op2: u64) {
+        if op1 == op2 {
+            return;
+        }
+        self.insert(Cmp {
+            pc,
+            operands: Operands::Int(op1, op2),
+        });
+    }
+
+    /// Records a comparison between two memory buffers at the address `pc`.
+    /// Comparisons with equal operands are discarded.
+    pub fn log_bytes(&mut self, pc: VirtAddr, op1: Vec<u8>, op2: Vec<u8>) {
+        if op1 == op2 {
+            return;
+        }
+        self.insert(Cmp {
+            pc,
+            operands: Operands::Bytes(op1, op2),
+        });
+    }
+
+    /// Inserts a new comparison in the log, unless the log is full.
+    fn insert(&mut self, cmp: Cmp) {
+        if self.cmps.len() < MAX_CMPS {
+            self.cmps.insert(cmp);
+        }
+    }
+
+    /// Returns an iterator over the logged comparisons.
+    pub fn cmps(&self) -> impl Iterator<Item = &Cmp> {
+        self.cmps.iter()
+    }
+
+    /// Returns the number of logged comparisons.
+    pub fn len(&self) -> usize {
+        self.cmps.len()
+    }
+
+    /// Returns `true` if the log does not contain any comparison.
+    pub fn is_empty(&self) -> bool {
+        self.cmps.is_empty()
+    }
+
+    /// Removes all the logged comparisons.
+    pub fn clear(&mut self) {
+        self.cmps.clear();
+    }
+
+    /// Returns the inputs resulting from replacing, in `input`, every
+    /// occurrence of one of the operands of a logged comparison with the
+    /// other operand. At most `MAX_PATCHES` inputs are returned.
+    ///
+    /// Integer operands are searched using every width they fit in, both in
+    /// little-endian and big-endian. The candidates `op±1` are also generated,
+    /// so less-than and greater-than comparisons can be solved too.
+    pub fn patch_input(&self, input: &[u8]) -> Vec<Vec<u8>> {
+        self.patch(input, None)
+    }
+
+    /// Returns the inputs resulting from patching `input` as
+    /// `CmpLog::patch_input` does, but only where the operands come from the
+    /// input. `colorized_input` must be `input` with randomized bytes that
+    /// follows the same execution path, and `colorized` the comparisons
+    /// logged running it.
+    ///
+    /// An occurrence of an operand is only patched if the bytes at the same
+    /// position of `colorized_input` match the same operand of a comparison
+    /// logged at the same address in `colorized`.
+    pub fn patch_input_colorized(
+        &self,
+        input: &[u8],
+        colorized: &CmpLog,
+        colorized_input: &[u8],
+    ) -> Vec<Vec<u8>> {
+        self.patch(input, Some((colorized, colorized_input)))
+    }
+
+    /// Implements `CmpLog::patch_input` and `CmpLog::patch_input_colorized`.
+    fn patch(
+        &self,
+        input: &[u8],
+        colorized: Option<(&CmpLog, &[u8])>,
+    ) -> Vec<Vec<u8>> {
+        let mut patches = BTreeSet::new();
+
+        // Comparisons of the colorized run, indexed by address.
+        let mut colorized_cmps: HashMap<VirtAddr, Vec<&Cmp>> = HashMap::new();
+        if let Some((colorized, _)) = colorized {
+            for cmp in &colorized.cmps {
+                colorized_cmps.entry(cmp.pc).or_default().push(cmp);
+            }
+        }
+
+        for cmp in &self.cmps {
+            let colorized = match colorized {
+                Some((_, colorized_input)) => {
+                    let cmps = match colorized_cmps.get(&cmp.pc) {
+                        Some(cmps) => cmps,
+                        None => continue,
+                    };
+                    cmps.iter()
+                        .map(|c| Some((colorized_input, &c.operands)))
+                        .collect()
+                }
+                None => vec![None],
+            };
+
+            for colorized in colorized {
+                match (&cmp.operands, colorized) {
+                    (Operands::Int(op1, op2), None) => {
+                        patch_int(input, *op1, *op2, None, &mut patches);
+                        patch_int(input, *op2, *op1, None, &mut patches);
+                    }
+                    (
+                        Operands::Int(op1, op2),
+                        Some((col_input, Operands::Int(col1, col2))),
+                    ) => {
+                        let col1 = Some((col_input, *col1));
+                        let col2 = Some((col_input, *col2));
+                        patch_int(input, *op1, *op2, col1, &mut patches);
+                        patch_int(input, *op2, *op1, col2, &mut patches);
+                    }
+                    (Operands::Bytes(op1, op2), None) => {
+                        patch_bytes(input, op1, op2, None, &mut patches);
+                        patch_bytes(input, op2, op1, None, &mut patches);
+                    }
+                    (
+                        Operands::Bytes(op1, op2),
+                        Some((col_input, Operands::Bytes(col1, col2))),
+                    ) => {
+                        let col1 = Some((col_input, col1.as_slice()));
+                        let col2 = Some((col_input, col2.as_slice()));
+                        patch_bytes(input, op1, op2, col1, &mut patches);
+                        patch_bytes(input, op2, op1, col2, &mut patches);
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        // Do not return the original input.
+        patches.remove(input);
+
+        patches.into_iter().collect()
+    }
+}
+
+/// Replaces the occurrences of the integer `pattern` in `input` with
+/// `repl`, using every width `pattern` fits in. The resulting inputs are
+/// added to `patches`.
+///
+/// If `colorized` is not `None`, it contains the colorized input and the
+/// value of `pattern` logged with it. Only the occurrences where the
+/// colorized input contains that value, with the same width and endianness,
+/// are replaced.
+fn patch_int(
+    input: &[u8],
+    pattern: u64,
+    repl: u64,
+    colorized: Option<(&[u8], u64)>,
+    patches: &mut BTreeSet<Vec<u8>>,
+) {
+    for &size in &[1, 2, 4, 8] {
+        let bits = size * 8;
+
+        // Skip the widths where the pattern is truncated. Sign-extended
+        // values are accepted.
+        if size < 8 {
+            let sext = ((pattern << (64 - bits)) as i64 >> (64 - bits)) as u64;
+            if pattern >> bits != 0 && sext != pattern {
+                continue;
+            }
+        }
+
+        let le = &pattern.to_le_bytes()[..size];
+        let be = &pattern.to_be_bytes()[8 - size..];
+
+        let col = colorized.map(|(input, pattern)| {
+            (input, pattern.to_le_bytes(), pattern.to_be_bytes())
+        });
+        let col_le = col.as_ref().map(|(input, le, _)| (*input, &le[..size]));
+        let col_be =
+            col.as_ref().map(|(input, _, be)| (*input, &be[8 - size..]));
+
+        for &delta in &[0i64, 1, -1] {
+            let repl = repl.wrapping_add(delta as u64);
+            let repl_le = &repl.to_le_bytes()[..size];
+            let repl_be = &repl.to_be_bytes()[8 - size..];
+
+            patch_bytes(input, le, repl_le, col_le, patches);
+            patch_bytes(input, be, repl_be, col_be, patches);
+        }
+    }
+}
+
+/// Replaces the occurrences of `pattern` in `input` with `repl`. If `repl` is
+/// longer than `pattern`, it is truncated. The resulting inputs are added to
+/// `patches`.
+///
+/// If `colorized` is not `None`, it contains the colorized input and the
+/// value of `pattern` logged with it. Only the occurrences where the
+/// colorized input contains that value are replaced.
+fn patch_bytes(
+    input: &[u8],
+    pattern: &[u8],
+    repl: &[u8],
+    colorized: Option<(&[u8], &[u8])>,
+    patches: &mut BTreeSet<Vec<u8>>,
+) {
+    let len = pattern.len().min(repl.len());
+
+    if len == 0 || len > input.len() || patches.len() >= MAX_PATCHES {
+        return;
+    }
+
+    let pattern = &pattern[..len];
+    let repl = &repl[..len];
+
+    for (i, window) in input.windows(len).enumerate() {
+        if window != pattern {
+            continue;
+        }
+
+        if let Some((col_input, col_pattern)) = colorized {
+            let col_window = col_input.get(i..i + len);
+            if col_pattern.len() < len
+                || col_window != Some(&col_pattern[..len])
+            {
+                continue;
+            }
+        }
+
+        let mut patched = input.to_vec();
+        patched[i..i + len].copy_from_slice(repl);
+        patches.insert(patched);
+
+        if patches.len() >= MAX_PATCHES {
+            return;
+        }
+    }
+}
+
+/// `memcmp` hook. It logs the compared buffers and continues executing the
+/// hooked function.
+pub fn memcmp_hook(emu: &mut Emulator) -> Result<(), VmExit> {
+    if emu.cmplog().is_none() {
+        return Ok(());
+    }
+
+    let s1 = emu.reg(RegAlias::A0)?;
+    let s2 = emu.reg(RegAlias::A1)?;
+    let n = emu.reg(RegAlias::A2)? as usize;
+    let pc = VirtAddr(emu.reg(RegAlias::Pc)? as usize);
+
+    let n = n.min(MAX_STR_LEN);
+
+    // If the memory is not readable, let the hooked function fault.
+    let mut op1 = vec![0; n];
+    let mut op2 = vec![0; n];
+    if emu.mmu().read(VirtAddr(s1 as usize), &mut op1).is_err()
+        || emu.mmu().read(VirtAddr(s2 as usize), &mut op2).is_err()
+    {
+        return Ok(());
+    }
+
+    if let Some(cmplog) = emu.cmplog_mut() {
+        cmplog.log_bytes(pc, op1, op2);
+    }
+
+    Ok(())
+}
+
+/// `strcmp` hook. It logs the compared strings and continues executing the
+/// hooked function.
+pub fn strcmp_hook(emu: &mut Emulator) -> Result<(), VmExit> {
+    log_strs(emu, MAX_STR_LEN)
+}
+
+/// `strncmp` hook. It logs the compared strings and continues executing the
+/// hooked function.
+pub fn strncmp_hook(emu: &mut Emulator) -> Result<(), VmExit> {
+    let n = emu.reg(RegAlias::A2)? as usize;
+
+    log_strs(emu, n.min(MAX_STR_LEN))
+}
+
+/// Logs the strings pointed by the first two arguments of a hooked function,
+/// reading up to `max_len` bytes of each one.
+fn log_strs(emu: &mut Emulator, max_len: usize) -> Result<(), VmExit> {
+    if emu.cmplog().is_none() {
+        return Ok(());
+    }
+
+    let s1 = emu.reg(RegAlias::A0)?;
+    let s2 = emu.reg(RegAlias::A1)?;
+    let pc = VirtAddr(emu.reg(RegAlias::Pc)? as usize);
+
+    // If the memory is not readable, let the hooked function fault.
+    let (op1, op2) = match (
+        read_str(emu, VirtAddr(s1 as usize), max_len),
+        read_str(emu, VirtAddr(s2 as usize), max_len),
+    ) {
+        (Some(op1), Some(op2)) => (op1, op2),
+        _ => return Ok(()),
+    };
+
+    if let Some(cmplog) = emu.cmplog_mut() {
+        cmplog.log_bytes(pc, op1, op2);
+    }
+
+    Ok(())
+}
+
+/// Reads a NULL terminated string of up to `max_len` bytes. The NULL byte is
+/// not included.
+fn read_str(
+    emu: &Emulator,
+    addr: VirtAddr,
+    max_len: usize,
+) -> Option<Vec<u8>> {
+    let mut result = Vec::new();
+
+    for i in 0..max_len {
+        let ch = emu
+            .mmu()
+            .read_int::<u8>(VirtAddr(addr.checked_add(i)?))
+            .ok()?;
+        if ch == 0 {
+            break;
+        }
+        result.push(ch);
+    }
+
+    Some(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::mmu::{Mmu, Perm, PERM_READ};
+
+    #[test]
+    fn cmplog_discard_equal() {
+        let mut cmplog = CmpLog::new();
+
+        cmplog.log_int(VirtAddr(0), 1, 1);
+        cmplog.log_bytes(VirtAddr(4), vec![1, 2], vec![1, 2]);
+
+        assert!(cmplog.is_empty());
+    }
+
+    #[test]
+    fn cmplog_dedup() {
+        let mut cmplog = CmpLog::new();
+
+        cmplog.log_int(VirtAddr(0), 1, 2);
+        cmplog.log_int(VirtAddr(0), 1, 2);
+        cmplog.log_int(VirtAddr(4), 1, 2);
+
+        assert_eq!(cmplog.len(), 2);
+    }
+
+    #[test]
+    fn cmplog_patch_int_le() {
+        let mut cmplog = CmpLog::new();
+        cmplog.log_int(VirtAddr(0), 0x41424344, 0xdeadbeef);
+
+        let patches = cmplog.patch_input(b"xxDCBAxx");
+
+        assert!(patches.contains(&b"xx\xef\xbe\xad\xdexx".to_vec()));
+        assert!(patches.contains(&b"xx\xf0\xbe\xad\xdexx".to_vec()));
+        assert!(patches.contains(&b"xx\xee\xbe\xad\xdexx".to_vec()));
+    }
+
+    #[test]
+    fn cmplog_patch_int_be() {
+        let mut cmplog = CmpLog::new();
+        cmplog.log_int(VirtAddr(0), 0x4142, 0x1337);
+
+        let patches = cmplog.patch_input(b"xxABxx");
+
+        assert!(patches.contains(&b"xx\x13\x37xx".to_vec()));
+    }
+
+    #[test]
+    fn cmplog_patch_int_sign_extended() {
+        let mut cmplog = CmpLog::new();
+        cmplog.log_int(VirtAddr(0), 0x10, -2i64 as u64);
+
+        let patches = cmplog.patch_input(b"\x10");
+
+        assert!(patches.contains(&b"\xfe".to_vec()));
+    }
+
+    #[test]
+    fn cmplog_patch_bytes() {
+        let mut cmplog = CmpLog::new();
+        cmplog.log_bytes(VirtAddr(0), b"ELF".to_vec(), b"elf".to_vec());
+
+        let patches = cmplog.patch_input(b"elf-ELF");
+
+        assert_eq!(patches.len(), 2);
+        assert!(patches.contains(&b"ELF-ELF".to_vec()));
+        assert!(patches.contains(&b"elf-elf".to_vec()));
+    }
+
+    #[test]
+    fn cmplog_patch_colorized() {
+        // The first "ABCD" comes from the input, so it changes with the
+        // colorized input. The second one is a constant that happens to be
+        // in the input, so it is ignored.
+        let mut cmplog = CmpLog::new();
+        cmplog.log_int(VirtAddr(0), 0x41424344, 0xdeadbeef);
+        let mut colorized = CmpLog::new();
+        colorized.log_int(VirtAddr(0), 0x31323334, 0xdeadbeef);
+
+        let patches = cmplog.patch_input_colorized(
+            b"DCBA-DCBA",
+            &colorized,
+            b"4321-DCBA",
+        );
+
+        assert!(patches.contains(&b"\xef\xbe\xad\xde-DCBA".to_vec()));
+        assert!(!patches.iter().any(|p| p.ends_with(b"\xef\xbe\xad\xde")));
+    }
+
+    #[test]
+    fn cmplog_patch_colorized_other_pc() {
+        let mut cmplog = CmpLog::new();
+        cmplog.log_bytes(VirtAddr(0), b"ELF".to_vec(), b"elf".to_vec());
+        let mut colorized = CmpLog::new();
+        colorized.log_bytes(VirtAddr(4), b"xyz".to_vec(), b"elf".to_vec());
+
+        let patches = cmplog.patch_input_colorized(b"ELF", &colorized, b"xyz");
+
+        assert!(patches.is_empty());
+    }
+
+    #[test]
+    fn cmplog_patch_reproducible() {
+        // The patches kept when there are too many do not depend on the order
+        // of the comparisons.
+        let pattern = 0x4142434445464748;
+        let mut cmplog = CmpLog::new();
+        let mut reversed = CmpLog::new();
+        for i in 0..2 * MAX_PATCHES as u64 {
+            cmplog.log_int(VirtAddr(0), pattern, i << 8);
+            reversed.log_int(
+                VirtAddr(0),
+                pattern,
+                (2 * MAX_PATCHES as u64 - 1 - i) << 8,
+            );
+        }
+
+        let patches = cmplog.patch_input(b"HGFEDCBA");
+
+        assert_eq!(patches.len(), MAX_PATCHES);
+        assert_eq!(patches, reversed.patch_input(b"HGFEDCBA"));
+    }
+
+    #[test]
+    fn cmplog_patch_not_found() {
+        let mut cmplog = CmpLog::new();
+        cmplog.log_int(VirtAddr(0), 0x41424344, 0xdeadbeef);
+
+        assert!(cmplog.patch_input(b"xxxx").is_empty());
+    }
+
+    #[test]
+    fn cmplog_strncmp_hook() {
+        let mut mmu = Mmu::new(0x1000);
+        mmu.set_perms(VirtAddr(0), 0x100, Perm(PERM_READ)).unwrap();
+        mmu.poke(VirtAddr(0x10), b"MAGIC\0").unwrap();
+        mmu.poke(VirtAddr(0x20), b"magic\0").unwrap();
+
+        let mut emu = Emulator::new(mmu);
+        emu.set_reg(RegAlias::A0, 0x10).unwrap();
+        emu.set_reg(RegAlias::A1, 0x20).unwrap();
+        emu.set_reg(RegAlias::A2, 3).unwrap();
+
+        // Nothing is logged if comparison logging is disabled.
+        strncmp_hook(&mut emu).unwrap();
+        emu.set_cmplog(true);
+        strncmp_hook(&mut emu).unwrap();
+
+        let cmps: Vec<&Cmp> = emu.cmplog().unwrap().cmps().collect();
+        assert_eq!(
+            cmps,
+            vec![&Cmp {
+                pc: VirtAddr(0),
+                operands: Operands::Bytes(b"MAG".to_vec(), b"mag".to_vec()),
+            }]
+        );
+    }
+}
@@ -1,5 +1,7 @@
-//! ELF 64-bit parser able to extract the PT_LOAD program headers of a program.
+//! ELF 64-bit parser able to extract the PT_LOAD program headers and the
+//! function symbols of a program.
 
+use std::collections::HashMap;
 use std::convert::TryInto;
 use std::fmt;
 use std::fs;
@@ -249,6 +251,136 @@ impl Elf {
     }
 }
 
+/// Function symbols of an ELF file.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct SymbolTable {
+    symbols: HashMap<String, VirtAddr>,
+}
+
+impl SymbolTable {
+    /// Returns the address of the function `name`, if it is defined.
+    pub fn addr(&self, name: &str) -> Option<VirtAddr> {
+        self.symbols.get(name).copied()
+    }
+
+    /// Parses the symbol table of an ELF file.
+    pub fn parse_file<P: AsRef<Path>>(path: P) -> Result<SymbolTable, Error> {
+        let contents = fs::read(path)?;
+
+        SymbolTable::parse(&contents)
+    }
+
+    /// Parses the symbol table of a slice of bytes with the contents of an
+    /// ELF file. Stripped files result in an empty symbol table.
+    pub fn parse(contents: &[u8]) -> Result<SymbolTable, Error> {
+        // Check ELF magic.
+        let magic = contents.get(..4).ok_or(Error::MalformedFile)?;
+        if magic != b"\x7fELF" {
+            return Err(Error::UnknownFormat);
+        }
+
+        let e_shoff = read_u64(contents, 0, 40)? as usize;
+        let e_shnum = read_u16(contents, 0, 60)? as usize;
+
+        let mut symbols = HashMap::new();
+
+        for i in 0..e_shnum {
+            let off = i
+                .checked_mul(64)
+                .and_then(|off| off.checked_add(e_shoff))
+                .ok_or(Error::MalformedFile)?;
+
+            // Skip non SHT_SYMTAB sections.
+            if read_u32(contents, off, 4)? != 2 {
+                continue;
+            }
+
+            let sh_offset = read_u64(contents, off, 24)? as usize;
+            let sh_size = read_u64(contents, off, 32)? as usize;
+            let sh_link = read_u32(contents, off, 40)? as usize;
+
+            // The linked section contains the names of the symbols.
+            let strtab_off = sh_link
+                .checked_mul(64)
+                .and_then(|off| off.checked_add(e_shoff))
+                .ok_or(Error::MalformedFile)?;
+            let strtab_offset = read_u64(contents, strtab_off, 24)? as usize;
+            let strtab_size = read_u64(contents, strtab_off, 32)? as usize;
+            let strtab = strtab_offset
+                .checked_add(strtab_size)
+                .and_then(|end| contents.get(strtab_offset..end))
+                .ok_or(Error::MalformedFile)?;
+
+            let sh_end =
+                sh_offset.checked_add(sh_size).ok_or(Error::MalformedFile)?;
+
+            for sym_off in (sh_offset..sh_end).step_by(24) {
+                let st_name = read_u32(contents, sym_off, 0)? as usize;
+                let st_info = read_u8(contents, sym_off, 4)?;
+                let st_shndx = read_u16(contents, sym_off, 6)?;
+                let st_value = read_u64(contents, sym_off, 8)? as usize;
+
+                // Only keep defined STT_FUNC symbols.
+                if st_info & 0xf != 2 || st_shndx == 0 {
+                    continue;
+                }
+
+                let name =
+                    strtab.get(st_name..).ok_or(Error::MalformedFile)?;
+                let len = name
+                    .iter()
+                    .position(|&b| b == 0)
+                    .ok_or(Error::MalformedFile)?;
+                let name = String::from_utf8_lossy(&name[..len]).into_owned();
+
+                // STB_GLOBAL symbols take precedence over local ones.
+                if st_info >> 4 == 1 || !symbols.contains_key(&name) {
+                    symbols.insert(name, VirtAddr(st_value));
+                }
+            }
+        }
+
+        Ok(SymbolTable { symbols })
+    }
+}
+
+/// Returns the `len` bytes at the offset `base + off` of `contents`. Offsets
+/// come from untrusted header fields, so overflows are reported as
+/// [`Error::MalformedFile`].
+fn read_bytes(
+    contents: &[u8],
+    base: usize,
+    off: usize,
+    len: usize,
+) -> Result<&[u8], Error> {
+    let start = base.checked_add(off).ok_or(Error::MalformedFile)?;
+    let end = start.checked_add(len).ok_or(Error::MalformedFile)?;
+    contents.get(start..end).ok_or(Error::MalformedFile)
+}
+
+/// Reads a `u8` at the offset `base + off` of `contents`.
+fn read_u8(contents: &[u8], base: usize, off: usize) -> Result<u8, Error> {
+    Ok(read_bytes(contents, base, off, 1)?[0])
+}
+
+/// Reads a little-endian `u16` at the offset `base + off` of `contents`.
+fn read_u16(contents: &[u8], base: usize, off: usize) -> Result<u16, Error> {
+    let bytes = read_bytes(contents, base, off, 2)?;
+    Ok(u16::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+/// Reads a little-endian `u32` at the offset `base + off` of `contents`.
+fn read_u32(contents: &[u8], base: usize, off: usize) -> Result<u32, Error> {
+    let bytes = read_bytes(contents, base, off, 4)?;
+    Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+/// Reads a little-endian `u64` at the offset `base + off` of `contents`.
+fn read_u64(contents: &[u8], base: usize, off: usize) -> Result<u64, Error> {
+    let bytes = read_bytes(contents, base, off, 8)?;
+    Ok(u64::from_le_bytes(bytes.try_into().unwrap()))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -281,4 +413,42 @@ mod tests {
 
         assert_eq!(Elf::parse_file("testdata/hello").unwrap(), want);
     }
+
+    #[test]
+    fn elf_symbol_table() {
+        let symbols = SymbolTable::parse_file("testdata/hello").unwrap();
+
+        assert_eq!(symbols.addr("main"), Some(VirtAddr(0x10184)));
+        assert_eq!(symbols.addr("strcmp"), Some(VirtAddr(0x1ddfc)));
+        assert_eq!(symbols.addr("_malloc_r"), Some(VirtAddr(0x16a44)));
+        assert_eq!(symbols.addr("memcmp"), None);
+    }
+
+    #[test]
+    fn elf_symbol_table_huge_offsets() {
+        let mut contents = fs::read("testdata/hello").unwrap();
+
+        // A huge e_shoff overflows the section header offsets.
+        let mut huge_shoff = contents.clone();
+        huge_shoff[40..48].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(matches!(
+            SymbolTable::parse(&huge_shoff),
+            Err(Error::MalformedFile)
+        ));
+
+        // A huge sh_link in the SHT_SYMTAB section header points the string
+        // table past the end of the file.
+        let e_shoff = read_u64(&contents, 0, 40).unwrap() as usize;
+        let e_shnum = read_u16(&contents, 0, 60).unwrap() as usize;
+        let symtab_off = (0..e_shnum)
+            .map(|i| e_shoff + i * 64)
+            .find(|&off| read_u32(&contents, off, 4).unwrap() == 2)
+            .unwrap();
+        contents[symtab_off + 40..symtab_off + 44]
+            .copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(
+            SymbolTable::parse(&contents),
+            Err(Error::MalformedFile)
+        ));
+    }
 }
@@ -3,20 +3,55 @@
 
 use std::collections::{HashMap, HashSet};
 use std::fmt;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::mem;
 use std::ops::Deref;
+use std::path::Path;
 use std::sync::{Arc, Mutex};
+use std::time::Instant;
 
+use crate::cmplog::CmpLog;
 use crate::jit::{self, JitCache};
 use crate::mmu::{
-    self, Mmu, Perm, VirtAddr, DIRTY_BLOCK_SIZE, PERM_EXEC, PERM_RAW,
-    PERM_READ, PERM_WRITE,
+    self, Access, Definedness, Leak, Mmu, Origin, OriginId, PagingMode, Perm,
+    Privilege, RegionKind, TlbEntry, VirtAddr, Watchpoint, PAGE_PERMS_OFFSET,
+    PAGE_SIZE, PAGE_STALE_OFFSET, PAGE_SUMMARY_OFFSET, PERM_EXEC, PERM_MMIO,
+    PERM_RAW, PERM_READ, PERM_WATCH, PERM_WRITE, TLB_DIRTY_PTR_OFFSET,
+    TLB_PAGE_NUMBER_OFFSET, TLB_PAGE_PTR_OFFSET, TLB_SIZE,
+    TLB_WRITE_PAGE_NUMBER_OFFSET,
 };
+use crate::snapshot::{self, Reader, Writer};
+use crate::taint::{Taint, TaintLabels};
 
 /// Print debug messages.
 const DEBUG: bool = false;
 
-/// Maximum number of instructions to execute before returning a timeout.
-const TIMEOUT: u64 = 100_000_000;
+/// CSR number of `satp`.
+const CSR_SATP: u32 = 0x180;
+
+/// Use of an undefined value reported by the uninitialized memory tracking.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum UninitUse {
+    /// Condition of a branch.
+    Branch,
+
+    /// Address of a memory access or a jump.
+    Address,
+
+    /// Argument of a syscall.
+    Syscall,
+}
+
+impl fmt::Display for UninitUse {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UninitUse::Branch => write!(f, "branch"),
+            UninitUse::Address => write!(f, "address"),
+            UninitUse::Syscall => write!(f, "syscall"),
+        }
+    }
+}
 
 /// Emulator's exit reason.
 #[derive(Debug)]
@@ -32,6 +67,40 @@ pub enum VmExit {
     UnimplementedInstruction,
     Timeout,
 
+    /// Memory access fault caused by the instruction at `pc`.
+    MemoryFault {
+        pc: VirtAddr,
+        access: Access,
+        error: mmu::Error,
+    },
+
+    /// The instruction at `pc` is about to access memory covered by a
+    /// watchpoint. `addr` and `size` describe the accessed memory range.
+    Watchpoint {
+        pc: VirtAddr,
+        addr: VirtAddr,
+        size: usize,
+        access: Access,
+    },
+
+    /// The instruction at `pc` accessed the virtual address `addr`, which
+    /// has no valid translation. `cause` is the RISC-V exception code:
+    /// instruction (12), load (13) or store (15) page fault.
+    PageFault {
+        pc: VirtAddr,
+        addr: VirtAddr,
+        cause: u64,
+    },
+
+    /// The instruction at `pc` is about to use a value with undefined bits
+    /// in a way that affects the execution. `origin` is the allocation the
+    /// undefined bits come from, if known.
+    UninitValue {
+        pc: VirtAddr,
+        usage: UninitUse,
+        origin: Option<Origin>,
+    },
+
     MmuError(mmu::Error),
     NasmError(nasm::Error),
     JitError(jit::Error),
@@ -52,6 +121,33 @@ impl fmt::Display for VmExit {
                 write!(f, "unimplemented instruction")
             }
             VmExit::Timeout => write!(f, "timeout"),
+            VmExit::MemoryFault { pc, access, error } => {
+                write!(
+                    f,
+                    "memory fault: pc={} access={}: {}",
+                    pc, access, error
+                )
+            }
+            VmExit::Watchpoint {
+                pc,
+                addr,
+                size,
+                access,
+            } => write!(
+                f,
+                "watchpoint: pc={} access={} addr={} size={}",
+                pc, access, addr, size
+            ),
+            VmExit::PageFault { pc, addr, cause } => write!(
+                f,
+                "page fault: pc={} addr={} cause={}",
+                pc, addr, cause
+            ),
+            VmExit::UninitValue { pc, usage, .. } => write!(
+                f,
+                "use of uninitialized value: pc={} use={}",
+                pc, usage
+            ),
             VmExit::MmuError(err) => write!(f, "MMU error: {}", err),
             VmExit::NasmError(err) => write!(f, "Nasm error: {}", err),
             VmExit::JitError(err) => write!(f, "JIT error: {}", err),
@@ -59,6 +155,31 @@ impl fmt::Display for VmExit {
     }
 }
 
+impl VmExit {
+    /// Returns the exit caused by the memory error `error` in an access of
+    /// type `access` of the instruction at `pc`.
+    fn memory_fault(pc: u64, access: Access, error: mmu::Error) -> VmExit {
+        let pc = VirtAddr(pc as usize);
+
+        match error {
+            mmu::Error::Watchpoint { addr, size, access } => {
+                VmExit::Watchpoint {
+                    pc,
+                    addr,
+                    size,
+                    access,
+                }
+            }
+            mmu::Error::PageFault { addr, access } => VmExit::PageFault {
+                pc,
+                addr,
+                cause: access.page_fault_cause(),
+            },
+            error => VmExit::MemoryFault { pc, access, error },
+        }
+    }
+}
+
 impl From<mmu::Error> for VmExit {
     fn from(error: mmu::Error) -> VmExit {
         VmExit::MmuError(error)
@@ -314,7 +435,160 @@ pub struct Coverage {
 }
 
 /// A callback called by a hook.
-type HookCallback = fn(&mut Emulator) -> Result<(), VmExit>;
+pub type HookCallback = fn(&mut Emulator) -> Result<(), VmExit>;
+
+/// A callback called on reads from a memory-mapped I/O region. It receives
+/// the address and the size of the access, and returns the read value.
+type MmioReadCallback =
+    fn(&mut Emulator, VirtAddr, usize) -> Result<u64, VmExit>;
+
+/// A callback called on writes to a memory-mapped I/O region. It receives
+/// the address and the size of the access, and the written value.
+type MmioWriteCallback =
+    fn(&mut Emulator, VirtAddr, usize, u64) -> Result<(), VmExit>;
+
+/// Memory-mapped I/O region.
+#[derive(Clone, Copy)]
+struct MmioRegion {
+    addr: VirtAddr,
+    size: usize,
+    read: MmioReadCallback,
+    write: MmioWriteCallback,
+}
+
+/// Returns the mask of the `size` least significant bytes of a `u64`.
+fn size_mask(size: usize) -> u64 {
+    u64::MAX >> (64 - size * 8)
+}
+
+/// Taint tracking state of the emulator.
+#[derive(Clone)]
+struct TaintState {
+    /// Taint labels of the registers.
+    regs: [Taint; 33],
+
+    /// Unions of labels created during execution.
+    labels: TaintLabels,
+
+    /// Taint label of the address of the last memory access.
+    mem_addr: Taint,
+
+    /// Taint labels of the operands of the executed branches, indexed by
+    /// the address of the branch instruction.
+    branches: HashMap<VirtAddr, Taint>,
+}
+
+impl TaintState {
+    /// Returns a new taint tracking state with every register untainted.
+    fn new() -> TaintState {
+        TaintState {
+            regs: [Taint::UNTAINTED; 33],
+            labels: TaintLabels::new(),
+            mem_addr: Taint::UNTAINTED,
+            branches: HashMap::new(),
+        }
+    }
+}
+
+/// Bit-precise definedness of the registers.
+#[derive(Clone)]
+struct UninitRegs {
+    /// Undefined bits of the registers.
+    undef: [u64; 33],
+
+    /// Origin of the undefined bits of the registers.
+    origins: [OriginId; 33],
+}
+
+impl UninitRegs {
+    /// Returns the definedness of the registers with every register fully
+    /// defined.
+    fn new() -> UninitRegs {
+        UninitRegs {
+            undef: [0; 33],
+            origins: [OriginId::UNKNOWN; 33],
+        }
+    }
+}
+
+/// Definedness updated by an instruction, applied once it has been executed
+/// successfully.
+enum UninitEffect {
+    /// Nothing is updated.
+    None,
+
+    /// The undefined bits of a register and their origin.
+    Reg(Reg, u64, OriginId),
+
+    /// The undefined bits of a memory range, given its address and size, and
+    /// their origin.
+    Mem(VirtAddr, usize, u64, OriginId),
+}
+
+/// Returns the undefined bits of the result of the ALU operation `funct3`
+/// on the operands `a` and `b`, whose undefined bits are `sa` and `sb`.
+/// `alt` selects SUB and SRA, and `word` the 32-bit variants. Like in
+/// MemorySanitizer, the propagation is approximated, so the undefined bits of
+/// the result may be more than the exact ones.
+fn alu_undef(
+    funct3: u32,
+    alt: bool,
+    word: bool,
+    (a, sa): (u64, u64),
+    (b, sb): (u64, u64),
+) -> u64 {
+    let shamt_mask = if word { 0b1_1111 } else { 0b11_1111 };
+
+    let undef = match funct3 {
+        0b000 => {
+            // ADD and SUB. Carries propagate the undefined bits upwards.
+            let s = sa | sb;
+            if s == 0 {
+                0
+            } else {
+                !((s & s.wrapping_neg()) - 1)
+            }
+        }
+        0b001 | 0b101 if sb & shamt_mask != 0 => u64::MAX,
+        0b001 => sa << (b & shamt_mask),
+        0b101 => {
+            let shamt = b & shamt_mask;
+            match (alt, word) {
+                (false, false) => sa >> shamt,
+                (true, false) => ((sa as i64) >> shamt) as u64,
+                (false, true) => ((sa as u32) >> shamt) as u64,
+                (true, true) => ((sa as i32) >> shamt) as u64,
+            }
+        }
+        // SLT and SLTU.
+        0b010 | 0b011 => (sa | sb != 0) as u64,
+        // XOR.
+        0b100 => sa | sb,
+        // OR. Defined ones make the result bits defined.
+        0b110 => (sa & sb) | (!a & sb) | (sa & !b),
+        // AND. Defined zeros make the result bits defined.
+        _ => (sa & sb) | (a & sb) | (sa & b),
+    };
+
+    if word {
+        undef as i32 as u64
+    } else {
+        undef
+    }
+}
+
+/// Taint labels updated by an instruction, applied once it has been
+/// executed successfully.
+enum TaintEffect {
+    /// Nothing is updated.
+    None,
+
+    /// The label of a register.
+    Reg(Reg, Taint),
+
+    /// The labels of a memory range, given its address and size.
+    Mem(VirtAddr, usize, Taint),
+}
 
 /// RISC-V emulator.
 pub struct Emulator {
@@ -335,8 +609,44 @@ pub struct Emulator {
     /// instruction at the specific virtual address is executed.
     hooks: HashMap<VirtAddr, HookCallback>,
 
+    /// Memory-mapped I/O regions. Loads and stores within them are
+    /// dispatched to their callbacks.
+    mmio: Vec<MmioRegion>,
+
+    /// User defined breakpoints. Execution stops with
+    /// `VmExit::UserBreakpoint` just before the instruction at any of these
+    /// virtual addresses is executed.
+    breakpoints: HashSet<VirtAddr>,
+
     /// Coverage information.
     coverage: Coverage,
+
+    /// Comparison log. If `Some`, the operands of the executed comparisons
+    /// are recorded.
+    cmplog: Option<CmpLog>,
+
+    /// Taint tracking state. If `Some`, the taint labels of the registers
+    /// and memory are propagated by every executed instruction.
+    taint: Option<TaintState>,
+
+    /// Definedness of the registers. If `Some`, the undefined bits of the
+    /// registers and memory are propagated by every executed instruction.
+    uninit: Option<UninitRegs>,
+
+    /// Number of executed instructions at which execution stops with
+    /// `VmExit::Timeout`.
+    inst_limit: u64,
+
+    /// PC and number of executed instructions when execution stopped with
+    /// `VmExit::Watchpoint`. When execution is resumed, the instruction at
+    /// this point ignores the watchpoints.
+    watchpoint_resume: Option<(u64, u64)>,
+}
+
+impl Drop for Emulator {
+    fn drop(&mut self) {
+        self.unregister_jit_traps();
+    }
 }
 
 impl fmt::Display for Emulator {
@@ -369,7 +679,14 @@ impl Emulator {
             mmu,
             jit_cache: None,
             hooks: HashMap::new(),
+            mmio: Vec::new(),
+            breakpoints: HashSet::new(),
             coverage: Coverage::default(),
+            cmplog: None,
+            taint: None,
+            uninit: None,
+            inst_limit: u64::MAX,
+            watchpoint_resume: None,
         }
     }
 
@@ -388,6 +705,287 @@ impl Emulator {
         &self.coverage
     }
 
+    /// Sets the maximum number of instructions that can be executed, starting
+    /// from now, before returning `VmExit::Timeout`. By default, there is no
+    /// limit.
+    ///
+    /// After a timeout, the emulator is left in a state where execution can be
+    /// resumed by setting a new budget and calling `run` again. When using JIT
+    /// compilation, the budget is checked at the beginning of every block, so
+    /// the number of executed instructions may exceed it by the length of the
+    /// last block.
+    pub fn set_inst_budget(&mut self, budget: u64) {
+        self.inst_limit = self.coverage.inst_execed.saturating_add(budget);
+    }
+
+    /// Returns the number of instructions that can still be executed before
+    /// returning `VmExit::Timeout`.
+    pub fn inst_budget(&self) -> u64 {
+        self.inst_limit.saturating_sub(self.coverage.inst_execed)
+    }
+
+    /// Enables or disables comparison logging. While it is enabled, code is
+    /// always executed using emulation, given that lifted code does not log
+    /// comparisons.
+    pub fn set_cmplog(&mut self, enabled: bool) {
+        if !enabled {
+            self.cmplog = None;
+        } else if self.cmplog.is_none() {
+            self.cmplog = Some(CmpLog::new());
+        }
+    }
+
+    /// Returns the comparison log. If comparison logging is not enabled,
+    /// `None` is returned.
+    pub fn cmplog(&self) -> Option<&CmpLog> {
+        self.cmplog.as_ref()
+    }
+
+    /// Returns a mutable reference to the comparison log. If comparison
+    /// logging is not enabled, `None` is returned.
+    pub fn cmplog_mut(&mut self) -> Option<&mut CmpLog> {
+        self.cmplog.as_mut()
+    }
+
+    /// Enables or disables taint tracking, both in the emulator and its Mmu.
+    /// While it is enabled, code is always executed using emulation, given
+    /// that lifted code does not propagate taint labels.
+    ///
+    /// Input bytes are labeled with `Mmu::taint_input`. Disabling taint
+    /// tracking discards all the labels.
+    pub fn set_taint_tracking(&mut self, enabled: bool) {
+        if !enabled {
+            self.taint = None;
+        } else if self.taint.is_none() {
+            self.taint = Some(TaintState::new());
+        }
+        self.mmu.set_taint_tracking(enabled);
+    }
+
+    /// Returns `true` if taint tracking is enabled.
+    pub fn taint_tracking(&self) -> bool {
+        self.taint.is_some()
+    }
+
+    /// Returns the taint label of the register `reg`. It is untainted if
+    /// taint tracking is disabled.
+    pub fn reg_taint<R: Into<Reg>>(&self, reg: R) -> Result<Taint, VmExit> {
+        let reg = *reg.into() as usize;
+
+        if reg >= self.regs.len() {
+            return Err(VmExit::InvalidRegister);
+        }
+
+        Ok(self
+            .taint
+            .as_ref()
+            .map_or(Taint::UNTAINTED, |t| t.regs[reg]))
+    }
+
+    /// Sets the taint label of the register `reg` to `taint`. It does
+    /// nothing if taint tracking is disabled.
+    pub fn set_reg_taint<R: Into<Reg>>(
+        &mut self,
+        reg: R,
+        taint: Taint,
+    ) -> Result<(), VmExit> {
+        let reg = *reg.into() as usize;
+
+        if reg >= self.regs.len() {
+            return Err(VmExit::InvalidRegister);
+        }
+
+        // The zero register is always untainted.
+        if let Some(state) = &mut self.taint {
+            if reg != RegAlias::Zero as usize {
+                state.regs[reg] = taint;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the taint label of the address of the last executed memory
+    /// access, including the one that caused a memory fault. It is
+    /// untainted if taint tracking is disabled.
+    pub fn mem_addr_taint(&self) -> Taint {
+        self.taint.as_ref().map_or(Taint::UNTAINTED, |t| t.mem_addr)
+    }
+
+    /// Returns the taint labels of the operands of the executed branches,
+    /// indexed by the address of the branch instruction. Only branches with
+    /// tainted operands are included. If taint tracking is disabled, `None`
+    /// is returned.
+    pub fn tainted_branches(&self) -> Option<&HashMap<VirtAddr, Taint>> {
+        self.taint.as_ref().map(|t| &t.branches)
+    }
+
+    /// Returns the sorted offsets of the input bytes the values labeled with
+    /// `taint` depend on.
+    pub fn taint_offsets(&self, taint: Taint) -> Vec<usize> {
+        match &self.taint {
+            Some(state) => state.labels.offsets(taint),
+            None => Vec::new(),
+        }
+    }
+
+    /// Enables or disables bit-precise uninitialized memory tracking, both in
+    /// the emulator and its Mmu. While it is enabled, code is always executed
+    /// using emulation, given that lifted code does not propagate the
+    /// undefined bits.
+    ///
+    /// Execution stops with `VmExit::UninitValue` when undefined bits affect
+    /// a branch or the address of a memory access or a jump. Syscall handlers
+    /// can check their arguments with `Emulator::check_defined`.
+    pub fn set_uninit_tracking(&mut self, enabled: bool) {
+        if !enabled {
+            self.uninit = None;
+        } else if self.uninit.is_none() {
+            self.uninit = Some(UninitRegs::new());
+        }
+        self.mmu.set_uninit_tracking(enabled);
+    }
+
+    /// Returns `true` if bit-precise uninitialized memory tracking is
+    /// enabled.
+    pub fn uninit_tracking(&self) -> bool {
+        self.uninit.is_some()
+    }
+
+    /// Returns the undefined bits of the register `reg`. They are all
+    /// defined if uninitialized memory tracking is disabled.
+    pub fn reg_undef<R: Into<Reg>>(&self, reg: R) -> Result<u64, VmExit> {
+        let reg = *reg.into() as usize;
+
+        if reg >= self.regs.len() {
+            return Err(VmExit::InvalidRegister);
+        }
+
+        Ok(self.uninit.as_ref().map_or(0, |u| u.undef[reg]))
+    }
+
+    /// Returns `VmExit::UninitValue` if the register `reg` has undefined
+    /// bits. It is aimed to be used by syscall handlers to check their
+    /// arguments.
+    pub fn check_defined<R: Into<Reg>>(&self, reg: R) -> Result<(), VmExit> {
+        let reg = *reg.into() as usize;
+
+        if reg >= self.regs.len() {
+            return Err(VmExit::InvalidRegister);
+        }
+
+        match &self.uninit {
+            Some(uninit) if uninit.undef[reg] != 0 => {
+                let pc = self.regs[RegAlias::Pc as usize];
+                Err(self.uninit_value(
+                    pc,
+                    UninitUse::Syscall,
+                    uninit.origins[reg],
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns the exit caused by the use `usage` of an undefined value
+    /// originated by `origin` in the instruction at `pc`.
+    fn uninit_value(
+        &self,
+        pc: u64,
+        usage: UninitUse,
+        origin: OriginId,
+    ) -> VmExit {
+        VmExit::UninitValue {
+            pc: VirtAddr(pc as usize),
+            usage,
+            origin: self.mmu.origin(origin).cloned(),
+        }
+    }
+
+    /// Writes a snapshot of the emulator to `w`. It contains the registers,
+    /// the coverage information, the instruction budget and the state of the
+    /// Mmu.
+    ///
+    /// Hooks, breakpoints, memory-mapped I/O regions, the JIT cache, the
+    /// comparison log and the taint labels are not included, so they must be
+    /// set up again after loading the snapshot.
+    pub fn save<W: Write>(&self, w: W) -> Result<(), snapshot::Error> {
+        let mut w = Writer::new(w)?;
+
+        for reg in self.regs.iter() {
+            w.write_u64(*reg)?;
+        }
+
+        w.write_u64(self.inst_limit)?;
+        w.write_u64(self.coverage.inst_execed)?;
+
+        // PCs are sorted, so equal emulators produce equal snapshots.
+        let mut pcs: Vec<usize> =
+            self.coverage.pcs.iter().map(|pc| **pc).collect();
+        pcs.sort_unstable();
+        w.write_usize(pcs.len())?;
+        for pc in pcs {
+            w.write_usize(pc)?;
+        }
+
+        self.mmu.write_snapshot(&mut w)?;
+
+        w.finish()?;
+
+        Ok(())
+    }
+
+    /// Writes a snapshot of the emulator to the file `path`. See
+    /// `Emulator::save`.
+    pub fn save_file<P: AsRef<Path>>(
+        &self,
+        path: P,
+    ) -> Result<(), snapshot::Error> {
+        let file = fs::File::create(path)?;
+
+        self.save(io::BufWriter::new(file))
+    }
+
+    /// Returns a new emulator with the state read from the snapshot `r`.
+    pub fn load<R: Read>(r: R) -> Result<Emulator, snapshot::Error> {
+        let mut r = Reader::new(r)?;
+
+        let mut regs = [0u64; 33];
+        for reg in regs.iter_mut() {
+            *reg = r.read_u64()?;
+        }
+
+        let inst_limit = r.read_u64()?;
+
+        let mut coverage = Coverage {
+            inst_execed: r.read_u64()?,
+            pcs: HashSet::new(),
+        };
+        for _ in 0..r.read_usize()? {
+            coverage.pcs.insert(VirtAddr(r.read_usize()?));
+        }
+
+        let mmu = Mmu::read_snapshot(&mut r)?;
+
+        r.finish()?;
+
+        let mut emu = Emulator::new(mmu);
+        emu.regs = regs;
+        emu.coverage = coverage;
+        emu.inst_limit = inst_limit;
+
+        Ok(emu)
+    }
+
+    /// Returns a new emulator with the state read from the snapshot file
+    /// `path`. See `Emulator::load`.
+    pub fn load_file<P: AsRef<Path>>(
+        path: P,
+    ) -> Result<Emulator, snapshot::Error> {
+        let file = fs::File::open(path)?;
+
+        Emulator::load(io::BufReader::new(file))
+    }
+
     /// Returns a copy of the Emulator, including its internal state.
     pub fn fork(&self) -> Emulator {
         let jit_cache = if let Some(cache) = &self.jit_cache {
@@ -396,22 +994,85 @@ impl Emulator {
             None
         };
 
-        Emulator {
+        let emu = Emulator {
             regs: self.regs,
             mmu: self.mmu.fork(),
             jit_cache,
             hooks: self.hooks.clone(),
+            mmio: self.mmio.clone(),
+            breakpoints: self.breakpoints.clone(),
             coverage: self.coverage.clone(),
-        }
+            cmplog: self.cmplog.clone(),
+            taint: self.taint.clone(),
+            uninit: self.uninit.clone(),
+            inst_limit: self.inst_limit,
+            watchpoint_resume: self.watchpoint_resume,
+        };
+        emu.register_jit_traps();
+
+        emu
     }
 
     /// Resets the internal state of the emulator to the given state `other`.
+    /// If comparison logging is enabled, the comparison log is cleared. If
+    /// taint tracking is enabled, the tainted branches are cleared.
     pub fn reset(&mut self, other: &Emulator) {
+        // The exec watchpoints are restored along with the Mmu, so they must
+        // be registered again in the JIT cache if they change.
+        let watchpoints_changed =
+            self.mmu.watchpoints() != other.mmu.watchpoints();
+        if watchpoints_changed {
+            self.unregister_jit_exec_watchpoints();
+        }
+
+        // The lifted blocks of the mappings that changed may not match the
+        // restored memory anymore.
+        let changed_mappings = self.changed_mappings(other);
+
         self.regs = other.regs;
         self.mmu.reset(&other.mmu);
+
+        if watchpoints_changed {
+            self.register_jit_exec_watchpoints();
+        }
+        for (addr, size) in changed_mappings {
+            self.invalidate_jit_range(addr, size);
+        }
         self.coverage.inst_execed = other.coverage.inst_execed;
         self.coverage.pcs.clear();
         self.coverage.pcs.extend(other.coverage.pcs.iter());
+        self.inst_limit = other.inst_limit;
+        self.watchpoint_resume = other.watchpoint_resume;
+
+        // The regions are restored along with the permissions, which contain
+        // `PERM_MMIO`.
+        self.mmio.clone_from(&other.mmio);
+
+        if let Some(cmplog) = &mut self.cmplog {
+            cmplog.clear();
+        }
+
+        if let Some(taint) = &mut self.taint {
+            match &other.taint {
+                Some(other_taint) => {
+                    taint.regs = other_taint.regs;
+                    taint.labels.clone_from(&other_taint.labels);
+                }
+                None => {
+                    taint.regs = [Taint::UNTAINTED; 33];
+                    taint.labels.clear();
+                }
+            }
+            taint.mem_addr = Taint::UNTAINTED;
+            taint.branches.clear();
+        }
+
+        if let Some(uninit) = &mut self.uninit {
+            match &other.uninit {
+                Some(other_uninit) => uninit.clone_from(other_uninit),
+                None => *uninit = UninitRegs::new(),
+            }
+        }
     }
 
     /// Enable JIT compilation. `cache` is the JIT cache used to store the
@@ -419,11 +1080,31 @@ impl Emulator {
     pub fn with_jit(mut self, cache: JitCache) -> Emulator {
         let cache = Arc::new(Mutex::new(cache));
 
+        self.unregister_jit_traps();
         self.jit_cache = Some(cache);
+        self.register_jit_traps();
         self
     }
 
-    /// Sets the value of the register `reg` to `val`.
+    /// Returns the statistics of the JIT cache, or `None` if JIT compilation
+    /// is not enabled.
+    pub fn jit_stats(&self) -> Option<jit::Stats> {
+        self.jit_cache
+            .as_ref()
+            .map(|jit_cache| jit_cache.lock().unwrap().stats())
+    }
+
+    /// Writes the statistics of the JIT cache into its dump directory. See
+    /// `JitCache::dump_stats`.
+    pub fn dump_jit_stats(&self) -> Result<(), jit::Error> {
+        match &self.jit_cache {
+            Some(jit_cache) => jit_cache.lock().unwrap().dump_stats(),
+            None => Ok(()),
+        }
+    }
+
+    /// Sets the value of the register `reg` to `val`. The register becomes
+    /// untainted and fully defined.
     pub fn set_reg<R: Into<Reg>>(
         &mut self,
         reg: R,
@@ -439,6 +1120,13 @@ impl Emulator {
         if reg != RegAlias::Zero as usize {
             self.regs[reg] = val;
         }
+
+        if let Some(taint) = &mut self.taint {
+            taint.regs[reg] = Taint::UNTAINTED;
+        }
+        if let Some(uninit) = &mut self.uninit {
+            uninit.undef[reg] = 0;
+        }
         Ok(())
     }
 
@@ -458,77 +1146,973 @@ impl Emulator {
         }
     }
 
+    /// Returns up to `max_depth` return addresses of the guest call stack,
+    /// innermost first. It is aimed to be used at the entry of a function,
+    /// e.g. from a hook, before its prologue runs. The outer frames are
+    /// walked using the frame pointer, so the call stack is truncated at the
+    /// first function not keeping it.
+    pub fn backtrace(&self, max_depth: usize) -> Vec<VirtAddr> {
+        let mut stack = Vec::new();
+
+        let mut ra = self.regs[RegAlias::Ra as usize];
+        let mut fp = self.regs[RegAlias::S0 as usize];
+
+        while stack.len() < max_depth && ra != 0 {
+            stack.push(VirtAddr(ra as usize));
+
+            // The frame record is stored just below the frame pointer: the
+            // return address at `fp - 8` and the previous frame pointer at
+            // `fp - 16`.
+            let record = (fp as usize).checked_sub(16).and_then(|addr| {
+                let addr = self
+                    .mmu
+                    .peek_translation(VirtAddr(addr), Access::Read)
+                    .ok()?;
+                let prev_fp = self.mmu.read_int::<u64>(addr).ok()?;
+                let ra = self.mmu.read_int::<u64>(VirtAddr(*addr + 8)).ok()?;
+                Some((prev_fp, ra))
+            });
+
+            match record {
+                // Frames of the callers are at higher addresses.
+                Some((prev_fp, prev_ra)) if prev_fp > fp => {
+                    fp = prev_fp;
+                    ra = prev_ra;
+                }
+                _ => break,
+            }
+        }
+
+        stack
+    }
+
+    /// Returns the heap allocations leaked by the guest, sorted by address.
+    /// The allocations reachable from the registers, the globals or the
+    /// stack are not reported. See `Mmu::leaks`.
+    pub fn leaks(&self) -> Vec<Leak> {
+        let roots = &self.regs[1..RegAlias::Pc as usize];
+        self.mmu.leaks(Some(roots))
+    }
+
     /// Hooks the virtual address `addr`. `cb` is the callback called just
     /// before the instruction at `addr` is executed.
     pub fn hook(&mut self, addr: VirtAddr, cb: HookCallback) {
         self.hooks.insert(addr, cb);
     }
 
-    /// Run until vm exit or error.
-    pub fn run(&mut self) -> Result<(), VmExit> {
-        if self.jit_cache.is_some() {
-            self.run_jit()
-        } else {
-            self.run_emu()
-        }
+    /// Maps the memory range (`addr`..`addr` + `size`) to I/O. Loads and
+    /// stores within the range call `read` and `write`, respectively, instead
+    /// of accessing memory. Accesses crossing the boundaries of the range
+    /// fail.
+    ///
+    /// The emulated code never accesses the memory backing the range, so the
+    /// callbacks can keep the state of the device there using `Mmu::peek` and
+    /// `Mmu::poke`. This way, it is forked and reset along with the rest of
+    /// the memory.
+    pub fn add_mmio(
+        &mut self,
+        addr: VirtAddr,
+        size: usize,
+        read: MmioReadCallback,
+        write: MmioWriteCallback,
+    ) -> Result<(), VmExit> {
+        self.mmu.map_mmio(addr, size)?;
+        self.mmio.push(MmioRegion {
+            addr,
+            size,
+            read,
+            write,
+        });
+
+        // The range is not executable anymore.
+        self.invalidate_jit_range(addr, size);
+
+        Ok(())
     }
 
-    /// Run code using pure emulation.
-    pub fn run_emu(&mut self) -> Result<(), VmExit> {
-        self.run_emu_internal(None)
+    /// Unmaps the memory-mapped I/O region starting at `addr`.
+    pub fn remove_mmio(&mut self, addr: VirtAddr) -> Result<(), VmExit> {
+        if let Some(i) = self.mmio.iter().position(|r| r.addr == addr) {
+            let region = self.mmio.remove(i);
+            self.mmu.unmap_mmio(region.addr, region.size)?;
+        }
+
+        Ok(())
     }
 
-    /// Run code using pure emulation until reaching address `until`.
-    pub fn run_emu_until(&mut self, until: VirtAddr) -> Result<(), VmExit> {
-        self.run_emu_internal(Some(until))
+    /// Maps memory as `Mmu::mmap` does, invalidating the lifted blocks of the
+    /// mappings it replaces.
+    pub fn mmap(
+        &mut self,
+        addr: Option<VirtAddr>,
+        size: usize,
+        perms: Perm,
+        name: &str,
+        contents: &[u8],
+    ) -> Result<VirtAddr, VmExit> {
+        let addr = self.mmu.mmap(addr, size, perms, name, contents)?;
+        self.invalidate_jit_pages(addr, size);
+
+        Ok(addr)
     }
 
-    /// Run code using pure emulation. If `until` is not `None`, stop at the
-    /// specified address.
-    fn run_emu_internal(
+    /// Unmaps memory as `Mmu::munmap` does, invalidating the lifted blocks in
+    /// the range.
+    pub fn munmap(
         &mut self,
-        until: Option<VirtAddr>,
+        addr: VirtAddr,
+        size: usize,
     ) -> Result<(), VmExit> {
-        loop {
-            let pc = self.reg(RegAlias::Pc)?;
+        self.mmu.munmap(addr, size)?;
+        self.invalidate_jit_pages(addr, size);
 
-            if let Some(until) = until {
-                if pc as usize == *until {
-                    return Err(VmExit::UserBreakpoint);
-                }
-            }
+        Ok(())
+    }
 
-            if let Some(hook_callback) = self.hooks.get(&VirtAddr(pc as usize))
-            {
-                hook_callback(self)?;
+    /// Sets the permissions of memory as `Mmu::mprotect` does, invalidating
+    /// the lifted blocks in the range, as it may not be executable anymore.
+    pub fn mprotect(
+        &mut self,
+        addr: VirtAddr,
+        size: usize,
+        perms: Perm,
+    ) -> Result<(), VmExit> {
+        self.mmu.mprotect(addr, size, perms)?;
+        self.invalidate_jit_pages(addr, size);
 
-                // If the hook has changed the PC, continue execution in that
-                // position. Otherwise, just continue executing the hooked
-                // instruction.
-                if self.reg(RegAlias::Pc)? != pc {
-                    continue;
-                }
-            }
+        Ok(())
+    }
 
-            if pc & 3 != 0 {
-                return Err(VmExit::AddressMisaligned);
-            }
+    /// Returns the memory-mapped I/O region containing the memory range
+    /// (`addr`..`addr` + `size`).
+    fn mmio_region(&self, addr: VirtAddr, size: usize) -> Option<MmioRegion> {
+        self.mmio
+            .iter()
+            .find(|r| {
+                let off = (*addr).wrapping_sub(*r.addr);
+                *addr >= *r.addr && off < r.size && size <= r.size - off
+            })
+            .copied()
+    }
 
-            let inst = self.mmu.read_int_with_perms::<u32>(
-                VirtAddr(pc as usize),
-                Perm(PERM_EXEC),
-            )?;
+    /// Dispatches the read of `size` bytes at `addr`, performed by the
+    /// instruction at `pc`, to the memory-mapped I/O region containing it.
+    fn mmio_read(
+        &mut self,
+        pc: u64,
+        addr: VirtAddr,
+        size: usize,
+    ) -> Result<u64, VmExit> {
+        let region = self.mmio_region(addr, size).ok_or_else(|| {
+            VmExit::memory_fault(
+                pc,
+                Access::Read,
+                mmu::Error::Mmio { addr, size },
+            )
+        })?;
+
+        let value = (region.read)(self, addr, size)?;
+
+        Ok(value & size_mask(size))
+    }
 
-            if self.coverage.inst_execed > TIMEOUT {
-                return Err(VmExit::Timeout);
-            }
+    /// Dispatches the write of the `size` least significant bytes of `value`
+    /// at `addr`, performed by the instruction at `pc`, to the memory-mapped
+    /// I/O region containing it.
+    fn mmio_write(
+        &mut self,
+        pc: u64,
+        addr: VirtAddr,
+        size: usize,
+        value: u64,
+    ) -> Result<(), VmExit> {
+        let region = self.mmio_region(addr, size).ok_or_else(|| {
+            VmExit::memory_fault(
+                pc,
+                Access::Write,
+                mmu::Error::Mmio { addr, size },
+            )
+        })?;
+
+        (region.write)(self, addr, size, value & size_mask(size))
+    }
 
-            self.emulate_instruction(pc, inst)?;
+    /// Sets a breakpoint at the virtual address `addr`. Execution will stop
+    /// with `VmExit::UserBreakpoint` just before the instruction at `addr` is
+    /// executed. When execution is resumed, the instruction at the breakpoint
+    /// is executed normally.
+    pub fn add_breakpoint(&mut self, addr: VirtAddr) {
+        if self.breakpoints.insert(addr) {
+            if let Some(cache) = &self.jit_cache {
+                cache.lock().unwrap().add_breakpoint(addr);
+            }
+        }
+    }
 
-            // Update coverage.
-            self.coverage.inst_execed += 1;
-            self.coverage.pcs.insert(VirtAddr(pc as usize));
+    /// Removes the breakpoint at the virtual address `addr`.
+    pub fn remove_breakpoint(&mut self, addr: VirtAddr) {
+        if self.breakpoints.remove(&addr) {
+            if let Some(cache) = &self.jit_cache {
+                cache.lock().unwrap().remove_breakpoint(addr);
+            }
+        }
+    }
+
+    /// Returns the set of breakpoints.
+    pub fn breakpoints(&self) -> &HashSet<VirtAddr> {
+        &self.breakpoints
+    }
+
+    /// Sets a watchpoint on the memory range (`addr`..`addr` + `size`).
+    /// Execution will stop with `VmExit::Watchpoint` just before an
+    /// instruction accesses the range with the access type `access`. When
+    /// execution is resumed, the access is performed normally.
+    ///
+    /// Accesses performed by hooks and syscall handlers are not watched if
+    /// they do not check permissions, like `Mmu::peek` and `Mmu::poke`.
+    pub fn add_watchpoint(
+        &mut self,
+        addr: VirtAddr,
+        size: usize,
+        access: Access,
+    ) -> Result<(), VmExit> {
+        let watchpoint = Watchpoint { addr, size, access };
+        let exists = self.mmu.watchpoints().contains(&watchpoint);
+
+        self.mmu.add_watchpoint(addr, size, access)?;
+
+        // Lifted code only checks exec watchpoints when it is lifted.
+        if access == Access::Exec && !exists {
+            if let Some(cache) = &self.jit_cache {
+                cache.lock().unwrap().add_exec_watchpoint(addr, size);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Removes the watchpoint on the memory range (`addr`..`addr` + `size`)
+    /// for the accesses of type `access`.
+    pub fn remove_watchpoint(
+        &mut self,
+        addr: VirtAddr,
+        size: usize,
+        access: Access,
+    ) {
+        let watchpoint = Watchpoint { addr, size, access };
+        let exists = self.mmu.watchpoints().contains(&watchpoint);
+
+        self.mmu.remove_watchpoint(addr, size, access);
+
+        if access == Access::Exec && exists {
+            if let Some(cache) = &self.jit_cache {
+                cache.lock().unwrap().remove_exec_watchpoint(addr, size);
+            }
+        }
+    }
+
+    /// Registers the breakpoints and the exec watchpoints of the emulator in
+    /// the JIT cache, which is shared with other emulators, so the lifted
+    /// code stops at them no matter which emulator lifts it.
+    fn register_jit_traps(&self) {
+        if let Some(cache) = &self.jit_cache {
+            let mut cache = cache.lock().unwrap();
+            for addr in &self.breakpoints {
+                cache.add_breakpoint(*addr);
+            }
+        }
+        self.register_jit_exec_watchpoints();
+    }
+
+    /// Unregisters the breakpoints and the exec watchpoints registered with
+    /// `register_jit_traps`.
+    fn unregister_jit_traps(&self) {
+        if let Some(cache) = &self.jit_cache {
+            // Do not panic again if a thread sharing the cache panicked.
+            if let Ok(mut cache) = cache.lock() {
+                for addr in &self.breakpoints {
+                    cache.remove_breakpoint(*addr);
+                }
+            }
+        }
+        self.unregister_jit_exec_watchpoints();
+    }
+
+    /// Registers the exec watchpoints of the Mmu in the JIT cache.
+    fn register_jit_exec_watchpoints(&self) {
+        if let Some(cache) = &self.jit_cache {
+            let mut cache = cache.lock().unwrap();
+            for wp in self.mmu.watchpoints() {
+                if wp.access == Access::Exec {
+                    cache.add_exec_watchpoint(wp.addr, wp.size);
+                }
+            }
+        }
+    }
+
+    /// Unregisters the exec watchpoints registered with
+    /// `register_jit_exec_watchpoints`.
+    fn unregister_jit_exec_watchpoints(&self) {
+        if let Some(cache) = &self.jit_cache {
+            if let Ok(mut cache) = cache.lock() {
+                for wp in self.mmu.watchpoints() {
+                    if wp.access == Access::Exec {
+                        cache.remove_exec_watchpoint(wp.addr, wp.size);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Invalidates the lifted blocks overlapping the memory range (`addr`..
+    /// `addr` + `size`), so they are lifted again the next time they are
+    /// executed.
+    fn invalidate_jit_range(&mut self, addr: VirtAddr, size: usize) {
+        if let Some(cache) = &self.jit_cache {
+            cache.lock().unwrap().invalidate_range(addr, size);
+        }
+    }
+
+    /// Returns the memory ranges modified since the last reset that belong to
+    /// mappings of the emulator or `other`. Mapping, unmapping and protecting
+    /// memory dirties it, so these ranges cover every mapping whose contents
+    /// or permissions differ from `other`. It is empty if JIT compilation is
+    /// not enabled.
+    fn changed_mappings(&self, other: &Emulator) -> Vec<(VirtAddr, usize)> {
+        if self.jit_cache.is_none() {
+            return Vec::new();
+        }
+
+        // Mappings are page-aligned, so every dirty block is either inside a
+        // mapping or outside all of them.
+        let is_mapping = |mmu: &Mmu, addr| match mmu.region(addr) {
+            Some(region) => region.kind == RegionKind::Mmap,
+            None => false,
+        };
+        let block_size = self.mmu.dirty_config().block_size;
+
+        self.mmu
+            .dirty_blocks()
+            .into_iter()
+            .filter(|&addr| {
+                is_mapping(&self.mmu, addr) || is_mapping(&other.mmu, addr)
+            })
+            .map(|addr| (addr, block_size))
+            .collect()
+    }
+
+    /// Invalidates the lifted blocks overlapping the memory range (`addr`..
+    /// `addr` + `size`), with the size rounded up to a multiple of the page
+    /// size, as the mappings are.
+    fn invalidate_jit_pages(&mut self, addr: VirtAddr, size: usize) {
+        let size = size.saturating_add(PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE;
+        self.invalidate_jit_range(addr, size);
+    }
+
+    /// Returns `true` if the instruction at `pc` is the one that stopped
+    /// execution with `VmExit::Watchpoint` and it has not been executed yet.
+    fn watchpoint_resuming(&self, pc: u64) -> bool {
+        self.watchpoint_resume == Some((pc, self.coverage.inst_execed))
+    }
+
+    /// Returns `true` if execution must stop at the breakpoint at `pc`. A
+    /// breakpoint is ignored if it is where the current run started and no
+    /// instruction has been executed since then, so execution can be resumed
+    /// after hitting it.
+    fn breakpoint_hit(&self, pc: u64, start: (u64, u64)) -> bool {
+        self.breakpoints.contains(&VirtAddr(pc as usize))
+            && (pc, self.coverage.inst_execed) != start
+    }
+
+    /// Returns `true` if code is executed using JIT compilation. Comparison
+    /// logging, taint tracking, uninitialized memory tracking and paging
+    /// require emulation.
+    ///
+    /// The JIT cache is indexed by the guest address of the code, which is
+    /// only stable without paging. Lifted code runs until the guest enables
+    /// paging and then execution continues in emulation, so both modes share
+    /// the same translation. Enabling paging thus silently turns off JIT
+    /// compilation, even if a JIT cache was set with `with_jit`, until the
+    /// guest disables it again.
+    fn jit_enabled(&self) -> bool {
+        self.jit_cache.is_some()
+            && self.cmplog.is_none()
+            && self.taint.is_none()
+            && self.uninit.is_none()
+            && self.mmu.paging_mode() == PagingMode::Bare
+    }
+
+    /// Run until vm exit or error.
+    pub fn run(&mut self) -> Result<(), VmExit> {
+        if self.jit_enabled() {
+            self.run_jit()
+        } else {
+            self.run_emu()
+        }
+    }
+
+    /// Run until reaching address `until`, vm exit or error. If the PC is
+    /// already `until`, it returns immediately.
+    pub fn run_until(&mut self, until: VirtAddr) -> Result<(), VmExit> {
+        if !self.jit_enabled() {
+            return self.run_emu_until(until);
+        }
+
+        if self.reg(RegAlias::Pc)? as usize == *until {
+            return Err(VmExit::UserBreakpoint);
+        }
+
+        // Use a temporary breakpoint, unless there is already one.
+        if self.breakpoints.contains(&until) {
+            return self.run_jit();
+        }
+
+        self.add_breakpoint(until);
+        let result = self.run_jit();
+        self.remove_breakpoint(until);
+
+        result
+    }
+
+    /// Run code using pure emulation.
+    pub fn run_emu(&mut self) -> Result<(), VmExit> {
+        self.run_emu_internal(None)
+    }
+
+    /// Run code using pure emulation until reaching address `until`.
+    pub fn run_emu_until(&mut self, until: VirtAddr) -> Result<(), VmExit> {
+        self.run_emu_internal(Some(until))
+    }
+
+    /// Run code using pure emulation. If `until` is not `None`, stop at the
+    /// specified address.
+    fn run_emu_internal(
+        &mut self,
+        until: Option<VirtAddr>,
+    ) -> Result<(), VmExit> {
+        let start = (self.reg(RegAlias::Pc)?, self.coverage.inst_execed);
+
+        loop {
+            let pc = self.reg(RegAlias::Pc)?;
+
+            if let Some(until) = until {
+                if pc as usize == *until {
+                    return Err(VmExit::UserBreakpoint);
+                }
+            }
+
+            // Check the instruction budget before running the hooks, so they
+            // are not called twice if execution is resumed after a timeout.
+            if self.coverage.inst_execed >= self.inst_limit {
+                return Err(VmExit::Timeout);
+            }
+
+            if self.breakpoint_hit(pc, start) {
+                return Err(VmExit::UserBreakpoint);
+            }
+
+            self.step()?;
+        }
+    }
+
+    /// Executes a single instruction using emulation, even if JIT compilation
+    /// is enabled. If there is a hook at the current PC, it is called before.
+    /// If the hook changes the PC, no instruction is executed.
+    ///
+    /// Breakpoints and the instruction budget are not taken into account.
+    pub fn step(&mut self) -> Result<(), VmExit> {
+        let pc = self.reg(RegAlias::Pc)?;
+
+        // If the instruction hit a watchpoint, the hook was already called.
+        let hook = if self.watchpoint_resuming(pc) {
+            None
+        } else {
+            self.hooks.get(&VirtAddr(pc as usize))
+        };
+
+        if let Some(hook_callback) = hook {
+            hook_callback(self)?;
+
+            // If the hook has changed the PC, continue execution in that
+            // position. Otherwise, just continue executing the hooked
+            // instruction.
+            if self.reg(RegAlias::Pc)? != pc {
+                return Ok(());
+            }
+        }
+
+        self.execute_instruction(pc)
+    }
+
+    /// Fetches and emulates the instruction at `pc`, updating the coverage
+    /// information. Hooks are not called.
+    ///
+    /// If the instruction hits a watchpoint, it is not executed. When it is
+    /// executed again, without executing other instructions before, the
+    /// watchpoints are ignored.
+    fn execute_instruction(&mut self, pc: u64) -> Result<(), VmExit> {
+        let resuming = self.watchpoint_resuming(pc);
+        self.watchpoint_resume = None;
+
+        if resuming {
+            self.mmu.set_watchpoints_enabled(false);
+        }
+        let result = self.fetch_and_emulate(pc);
+        if resuming {
+            self.mmu.set_watchpoints_enabled(true);
+        }
+
+        if let Err(VmExit::Watchpoint { .. }) = result {
+            self.watchpoint_resume = Some((pc, self.coverage.inst_execed));
+        }
+
+        result
+    }
+
+    /// Fetches and emulates the instruction at `pc`, updating the coverage
+    /// information.
+    fn fetch_and_emulate(&mut self, pc: u64) -> Result<(), VmExit> {
+        if pc & 3 != 0 {
+            return Err(VmExit::AddressMisaligned);
+        }
+
+        let addr =
+            self.translate(pc, VirtAddr(pc as usize), 4, Access::Exec)?;
+        let inst = self
+            .mmu
+            .read_int_with_perms::<u32>(addr, Perm(PERM_EXEC))
+            .map_err(|error| VmExit::memory_fault(pc, Access::Exec, error))?;
+
+        let effect = self.taint_effect(pc, inst);
+        let uninit_effect = self.uninit_effect(pc, inst)?;
+
+        self.emulate_instruction(pc, inst)?;
+
+        self.apply_taint_effect(effect)?;
+        self.apply_uninit_effect(uninit_effect)?;
+
+        // Update coverage.
+        self.coverage.inst_execed += 1;
+        self.coverage.pcs.insert(VirtAddr(pc as usize));
+
+        Ok(())
+    }
+
+    /// Translates the virtual address `addr` of an access of `size` bytes and
+    /// type `access` made by the instruction at `pc`. Accesses crossing into
+    /// a page that is not physically contiguous raise an address-misaligned
+    /// exception, which RISC-V permits for misaligned accesses.
+    fn translate(
+        &mut self,
+        pc: u64,
+        addr: VirtAddr,
+        size: usize,
+        access: Access,
+    ) -> Result<VirtAddr, VmExit> {
+        let paddr = self
+            .mmu
+            .translate(addr, access)
+            .map_err(|error| VmExit::memory_fault(pc, access, error))?;
+
+        let last = VirtAddr(addr.wrapping_add(size - 1));
+        if *last / PAGE_SIZE != *addr / PAGE_SIZE {
+            let plast = self
+                .mmu
+                .translate(last, access)
+                .map_err(|error| VmExit::memory_fault(pc, access, error))?;
+            if *plast != paddr.wrapping_add(size - 1) {
+                return Err(VmExit::AddressMisaligned);
+            }
+        }
+
+        Ok(paddr)
+    }
+
+    /// Computes how the instruction `inst` at `pc` propagates the taint
+    /// labels, before it is executed. Branches and memory accesses are
+    /// recorded right away.
+    fn taint_effect(&mut self, pc: u64, inst: u32) -> TaintEffect {
+        let state = match &mut self.taint {
+            Some(state) => state,
+            None => return TaintEffect::None,
+        };
+
+        let opcode = inst & 0b111_1111;
+
+        match opcode {
+            0b1100011 => {
+                // BRANCH
+                let dec = Btype::from(inst);
+
+                let taint = state.labels.union(
+                    state.regs[*dec.rs1 as usize],
+                    state.regs[*dec.rs2 as usize],
+                );
+                if taint.is_tainted() {
+                    let branch = state
+                        .branches
+                        .entry(VirtAddr(pc as usize))
+                        .or_default();
+                    *branch = state.labels.union(*branch, taint);
+                }
+
+                TaintEffect::None
+            }
+            0b0000011 => {
+                // LOAD
+                let dec = Itype::from(inst);
+
+                let size = 1 << (dec.funct3 & 0b11);
+                let vaddr =
+                    self.regs[*dec.rs1 as usize].wrapping_add(dec.imm as u64);
+                state.mem_addr = state.regs[*dec.rs1 as usize];
+
+                // Invalid accesses fail when executed.
+                let mmu = &self.mmu;
+                let labels = mmu
+                    .peek_translation(VirtAddr(vaddr as usize), Access::Read)
+                    .and_then(|addr| mmu.taint(addr, size))
+                    .unwrap_or_default();
+                let taint =
+                    labels.into_iter().fold(Taint::UNTAINTED, |acc, t| {
+                        state.labels.union(acc, t)
+                    });
+
+                TaintEffect::Reg(dec.rd, taint)
+            }
+            0b0100011 => {
+                // STORE
+                let dec = Stype::from(inst);
+
+                let size = 1 << (dec.funct3 & 0b11);
+                let vaddr =
+                    self.regs[*dec.rs1 as usize].wrapping_add(dec.imm as u64);
+                state.mem_addr = state.regs[*dec.rs1 as usize];
+
+                // Invalid accesses fail when executed, so the effect is not
+                // applied.
+                let addr = self
+                    .mmu
+                    .peek_translation(VirtAddr(vaddr as usize), Access::Write)
+                    .unwrap_or(VirtAddr(vaddr as usize));
+
+                TaintEffect::Mem(addr, size, state.regs[*dec.rs2 as usize])
+            }
+            0b0010011 | 0b0011011 => {
+                // OP-IMM and OP-IMM-32
+                let dec = Itype::from(inst);
+
+                TaintEffect::Reg(dec.rd, state.regs[*dec.rs1 as usize])
+            }
+            0b0110011 | 0b0111011 => {
+                // OP and OP-32
+                let dec = Rtype::from(inst);
+
+                // XOR and SUB of a register with itself are used to clear
+                // it.
+                let clear = dec.rs1 == dec.rs2
+                    && ((dec.funct3 == 0b100 && dec.funct7 == 0)
+                        || (dec.funct3 == 0b000 && dec.funct7 == 0b0100000));
+
+                let taint = if clear {
+                    Taint::UNTAINTED
+                } else {
+                    state.labels.union(
+                        state.regs[*dec.rs1 as usize],
+                        state.regs[*dec.rs2 as usize],
+                    )
+                };
+
+                TaintEffect::Reg(dec.rd, taint)
+            }
+            // Other instructions only write values that do not depend on the
+            // registers, which are untainted by `set_reg`.
+            _ => TaintEffect::None,
+        }
+    }
+
+    /// Applies the taint labels computed by `taint_effect` to the registers
+    /// and memory.
+    fn apply_taint_effect(
+        &mut self,
+        effect: TaintEffect,
+    ) -> Result<(), VmExit> {
+        match effect {
+            TaintEffect::None => {}
+            TaintEffect::Reg(reg, taint) => self.set_reg_taint(reg, taint)?,
+            TaintEffect::Mem(addr, size, taint) => {
+                self.mmu.set_taint(addr, &vec![taint; size])?;
+            }
         }
+
+        Ok(())
+    }
+
+    /// Computes how the instruction `inst` at `pc` propagates the undefined
+    /// bits, before it is executed. It fails if the instruction uses
+    /// undefined bits in a way that affects the execution.
+    fn uninit_effect(
+        &self,
+        pc: u64,
+        inst: u32,
+    ) -> Result<UninitEffect, VmExit> {
+        let uninit = match &self.uninit {
+            Some(uninit) => uninit,
+            None => return Ok(UninitEffect::None),
+        };
+
+        // The zero register is never written, so it is always 0 and fully
+        // defined.
+        let val = |reg: Reg| self.regs[*reg as usize];
+        let undef = |reg: Reg| uninit.undef[*reg as usize];
+        let origin = |reg: Reg| uninit.origins[*reg as usize];
+
+        let opcode = inst & 0b111_1111;
+
+        match opcode {
+            0b1100111 => {
+                // JALR
+                let dec = Itype::from(inst);
+
+                if undef(dec.rs1) != 0 {
+                    return Err(self.uninit_value(
+                        pc,
+                        UninitUse::Address,
+                        origin(dec.rs1),
+                    ));
+                }
+
+                Ok(UninitEffect::None)
+            }
+            0b1100011 => {
+                // BRANCH
+                let dec = Btype::from(inst);
+
+                let s = undef(dec.rs1) | undef(dec.rs2);
+                if s == 0 {
+                    return Ok(UninitEffect::None);
+                }
+
+                // BEQ and BNE do not depend on the undefined bits if the
+                // defined ones are already different.
+                let decided = (dec.funct3 == 0b000 || dec.funct3 == 0b001)
+                    && (val(dec.rs1) ^ val(dec.rs2)) & !s != 0;
+                if decided {
+                    return Ok(UninitEffect::None);
+                }
+
+                let reg = if undef(dec.rs1) != 0 {
+                    dec.rs1
+                } else {
+                    dec.rs2
+                };
+                Err(self.uninit_value(pc, UninitUse::Branch, origin(reg)))
+            }
+            0b0000011 => {
+                // LOAD
+                let dec = Itype::from(inst);
+
+                if undef(dec.rs1) != 0 {
+                    return Err(self.uninit_value(
+                        pc,
+                        UninitUse::Address,
+                        origin(dec.rs1),
+                    ));
+                }
+
+                let size = 1 << (dec.funct3 & 0b11);
+                let vaddr = val(dec.rs1).wrapping_add(dec.imm as u64);
+
+                // Invalid accesses fail when executed.
+                let bytes = self
+                    .mmu
+                    .peek_translation(VirtAddr(vaddr as usize), Access::Read)
+                    .and_then(|addr| self.mmu.definedness(addr, size))
+                    .unwrap_or_default();
+
+                let mut load_undef = bytes
+                    .iter()
+                    .rev()
+                    .fold(0, |acc, d| (acc << 8) | d.undef as u64);
+                let load_origin = bytes
+                    .iter()
+                    .find(|d| d.undef != 0)
+                    .map_or(OriginId::UNKNOWN, |d| d.origin);
+
+                // Signed loads extend the undefined sign bit.
+                let signed = dec.funct3 & 0b100 == 0;
+                if signed && size < 8 && load_undef >> (size * 8 - 1) != 0 {
+                    load_undef |= !size_mask(size);
+                }
+
+                Ok(UninitEffect::Reg(dec.rd, load_undef, load_origin))
+            }
+            0b0100011 => {
+                // STORE
+                let dec = Stype::from(inst);
+
+                if undef(dec.rs1) != 0 {
+                    return Err(self.uninit_value(
+                        pc,
+                        UninitUse::Address,
+                        origin(dec.rs1),
+                    ));
+                }
+
+                let size = 1 << (dec.funct3 & 0b11);
+                let vaddr = val(dec.rs1).wrapping_add(dec.imm as u64);
+
+                // Invalid accesses fail when executed, so the effect is not
+                // applied.
+                let addr = self
+                    .mmu
+                    .peek_translation(VirtAddr(vaddr as usize), Access::Write)
+                    .unwrap_or(VirtAddr(vaddr as usize));
+
+                Ok(UninitEffect::Mem(
+                    addr,
+                    size,
+                    undef(dec.rs2) & size_mask(size),
+                    origin(dec.rs2),
+                ))
+            }
+            0b0010011 | 0b0011011 => {
+                // OP-IMM and OP-IMM-32
+                let dec = Itype::from(inst);
+
+                let alt = dec.funct3 == 0b101 && inst >> 30 & 1 != 0;
+                let word = opcode == 0b0011011;
+                let rd_undef = alu_undef(
+                    dec.funct3,
+                    alt,
+                    word,
+                    (val(dec.rs1), undef(dec.rs1)),
+                    (dec.imm as u64, 0),
+                );
+
+                Ok(UninitEffect::Reg(dec.rd, rd_undef, origin(dec.rs1)))
+            }
+            0b0110011 | 0b0111011 => {
+                // OP and OP-32
+                let dec = Rtype::from(inst);
+
+                // XOR and SUB of a register with itself are used to clear
+                // it.
+                let clear = dec.rs1 == dec.rs2
+                    && ((dec.funct3 == 0b100 && dec.funct7 == 0)
+                        || (dec.funct3 == 0b000 && dec.funct7 == 0b0100000));
+                if clear {
+                    return Ok(UninitEffect::Reg(
+                        dec.rd,
+                        0,
+                        OriginId::UNKNOWN,
+                    ));
+                }
+
+                let alt = dec.funct7 == 0b0100000;
+                let word = opcode == 0b0111011;
+                let rd_undef = alu_undef(
+                    dec.funct3,
+                    alt,
+                    word,
+                    (val(dec.rs1), undef(dec.rs1)),
+                    (val(dec.rs2), undef(dec.rs2)),
+                );
+                let reg = if undef(dec.rs1) != 0 {
+                    dec.rs1
+                } else {
+                    dec.rs2
+                };
+
+                Ok(UninitEffect::Reg(dec.rd, rd_undef, origin(reg)))
+            }
+            // Other instructions only write values that do not depend on the
+            // registers, which are defined by `set_reg`.
+            _ => Ok(UninitEffect::None),
+        }
+    }
+
+    /// Applies the undefined bits computed by `uninit_effect` to the
+    /// registers and memory.
+    fn apply_uninit_effect(
+        &mut self,
+        effect: UninitEffect,
+    ) -> Result<(), VmExit> {
+        match effect {
+            UninitEffect::None => {}
+            UninitEffect::Reg(reg, undef, origin) => {
+                // The zero register is always fully defined.
+                let uninit = self.uninit.as_mut().unwrap();
+                if *reg != RegAlias::Zero as u32 {
+                    uninit.undef[*reg as usize] = undef;
+                    uninit.origins[*reg as usize] = if undef != 0 {
+                        origin
+                    } else {
+                        OriginId::UNKNOWN
+                    };
+                }
+            }
+            UninitEffect::Mem(addr, size, undef, origin) => {
+                let bytes: Vec<Definedness> = (0..size)
+                    .map(|i| match (undef >> (i * 8)) as u8 {
+                        0 => Definedness::default(),
+                        undef => Definedness { undef, origin },
+                    })
+                    .collect();
+
+                self.mmu.set_definedness(addr, &bytes)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Records the operands of a comparison executed at `pc` if comparison
+    /// logging is enabled.
+    fn log_cmp(&mut self, pc: u64, op1: u64, op2: u64) {
+        if let Some(cmplog) = &mut self.cmplog {
+            cmplog.log_int(VirtAddr(pc as usize), op1, op2);
+        }
+    }
+
+    /// Emulates the CSR instruction `dec`. Only `satp` is supported, other
+    /// CSRs are invalid instructions. Writes with an unsupported paging mode
+    /// are ignored, as in hardware.
+    fn emulate_csr(&mut self, dec: Itype) -> Result<(), VmExit> {
+        let csr = dec.imm as u32 & 0xfff;
+
+        if csr != CSR_SATP {
+            return Err(VmExit::InvalidInstruction);
+        }
+        if self.mmu.privilege() == Privilege::User {
+            return Err(VmExit::InvalidInstruction);
+        }
+
+        // The immediate forms encode the operand in the rs1 field.
+        let src = if dec.funct3 & 0b100 != 0 {
+            *dec.rs1 as u64
+        } else {
+            self.reg(dec.rs1)?
+        };
+
+        let old = self.mmu.satp();
+        let new = match dec.funct3 & 0b11 {
+            // CSRRW and CSRRWI
+            0b01 => Some(src),
+            // CSRRS and CSRRSI
+            0b10 if *dec.rs1 != 0 => Some(old | src),
+            // CSRRC and CSRRCI
+            0b11 if *dec.rs1 != 0 => Some(old & !src),
+            0b10 | 0b11 => None,
+            _ => return Err(VmExit::InvalidInstruction),
+        };
+
+        if let Some(new) = new {
+            let _ = self.mmu.set_satp(new);
+        }
+        self.set_reg(dec.rd, old)?;
+
+        Ok(())
     }
 
     /// Emulates a single instruction, updating the internal state of the
@@ -595,6 +2179,8 @@ impl Emulator {
                 let rs1 = self.reg(dec.rs1)?;
                 let rs2 = self.reg(dec.rs2)?;
 
+                self.log_cmp(pc, rs1, rs2);
+
                 match dec.funct3 {
                     0b000 => {
                         // BEQ
@@ -666,46 +2252,55 @@ impl Emulator {
                 let offset = dec.imm as u64;
                 let vaddr = rs1.wrapping_add(offset);
 
-                let vaddr = VirtAddr(vaddr as usize);
+                let size = 1 << (dec.funct3 & 0b11);
+                let vaddr = self.translate(
+                    pc,
+                    VirtAddr(vaddr as usize),
+                    size,
+                    Access::Read,
+                )?;
+
+                let value = match dec.funct3 {
+                    // LB
+                    0b000 => self.mmu.read_int::<i8>(vaddr).map(|v| v as u64),
+                    // LH
+                    0b001 => self.mmu.read_int::<i16>(vaddr).map(|v| v as u64),
+                    // LW
+                    0b010 => self.mmu.read_int::<i32>(vaddr).map(|v| v as u64),
+                    // LBU
+                    0b100 => self.mmu.read_int::<u8>(vaddr).map(|v| v as u64),
+                    // LHU
+                    0b101 => self.mmu.read_int::<u16>(vaddr).map(|v| v as u64),
+                    // LWU
+                    0b110 => self.mmu.read_int::<u32>(vaddr).map(|v| v as u64),
+                    // LD
+                    0b011 => self.mmu.read_int::<u64>(vaddr),
+                    _ => return Err(VmExit::InvalidInstruction),
+                };
 
-                match dec.funct3 {
-                    0b000 => {
-                        // LB
-                        let value = self.mmu.read_int::<i8>(vaddr)?;
-                        self.set_reg(dec.rd, value as u64)?;
-                    }
-                    0b001 => {
-                        // LH
-                        let value = self.mmu.read_int::<i16>(vaddr)?;
-                        self.set_reg(dec.rd, value as u64)?;
-                    }
-                    0b010 => {
-                        // LW
-                        let value = self.mmu.read_int::<i32>(vaddr)?;
-                        self.set_reg(dec.rd, value as u64)?;
-                    }
-                    0b100 => {
-                        // LBU
-                        let value = self.mmu.read_int::<u8>(vaddr)?;
-                        self.set_reg(dec.rd, value as u64)?;
-                    }
-                    0b101 => {
-                        // LHU
-                        let value = self.mmu.read_int::<u16>(vaddr)?;
-                        self.set_reg(dec.rd, value as u64)?;
-                    }
-                    0b110 => {
-                        // LWU
-                        let value = self.mmu.read_int::<u32>(vaddr)?;
-                        self.set_reg(dec.rd, value as u64)?;
+                let value = match value {
+                    Ok(value) => value,
+                    Err(mmu::Error::Mmio { addr, size }) => {
+                        let value = self.mmio_read(pc, addr, size)?;
+
+                        // Sign-extend LB, LH and LW.
+                        match dec.funct3 {
+                            0b000 => value as i8 as u64,
+                            0b001 => value as i16 as u64,
+                            0b010 => value as i32 as u64,
+                            _ => value,
+                        }
                     }
-                    0b011 => {
-                        // LD
-                        let value = self.mmu.read_int::<u64>(vaddr)?;
-                        self.set_reg(dec.rd, value)?;
+                    Err(error) => {
+                        return Err(VmExit::memory_fault(
+                            pc,
+                            Access::Read,
+                            error,
+                        ))
                     }
-                    _ => return Err(VmExit::InvalidInstruction),
-                }
+                };
+
+                self.set_reg(dec.rd, value)?;
             }
             0b0100011 => {
                 let dec = Stype::from(inst);
@@ -713,28 +2308,40 @@ impl Emulator {
                 let rs1 = self.reg(dec.rs1)?;
                 let rs2 = self.reg(dec.rs2)?;
                 let offset = dec.imm as u64;
-                let vaddr = rs1.wrapping_add(offset);
-
-                let vaddr = VirtAddr(vaddr as usize);
-
-                match dec.funct3 {
-                    0b000 => {
-                        // SB
-                        self.mmu.write_int::<u8>(vaddr, rs2 as u8)?;
-                    }
-                    0b001 => {
-                        // SH
-                        self.mmu.write_int::<u16>(vaddr, rs2 as u16)?;
-                    }
-                    0b010 => {
-                        // SW
-                        self.mmu.write_int::<u32>(vaddr, rs2 as u32)?;
+                let vaddr = rs1.wrapping_add(offset);
+
+                let size = 1 << (dec.funct3 & 0b11);
+                let vaddr = self.translate(
+                    pc,
+                    VirtAddr(vaddr as usize),
+                    size,
+                    Access::Write,
+                )?;
+
+                let result = match dec.funct3 {
+                    // SB
+                    0b000 => self.mmu.write_int::<u8>(vaddr, rs2 as u8),
+                    // SH
+                    0b001 => self.mmu.write_int::<u16>(vaddr, rs2 as u16),
+                    // SW
+                    0b010 => self.mmu.write_int::<u32>(vaddr, rs2 as u32),
+                    // SD
+                    0b011 => self.mmu.write_int::<u64>(vaddr, rs2),
+                    _ => return Err(VmExit::InvalidInstruction),
+                };
+
+                match result {
+                    Ok(()) => {}
+                    Err(mmu::Error::Mmio { addr, size }) => {
+                        self.mmio_write(pc, addr, size, rs2)?
                     }
-                    0b011 => {
-                        // SD
-                        self.mmu.write_int::<u64>(vaddr, rs2)?;
+                    Err(error) => {
+                        return Err(VmExit::memory_fault(
+                            pc,
+                            Access::Write,
+                            error,
+                        ))
                     }
-                    _ => return Err(VmExit::InvalidInstruction),
                 }
             }
             0b0010011 => {
@@ -750,6 +2357,8 @@ impl Emulator {
                     }
                     0b010 => {
                         // SLTI
+                        self.log_cmp(pc, rs1, imm);
+
                         if (rs1 as i64) < (imm as i64) {
                             self.set_reg(dec.rd, 1)?;
                         } else {
@@ -758,6 +2367,8 @@ impl Emulator {
                     }
                     0b011 => {
                         // SLTIU
+                        self.log_cmp(pc, rs1, imm);
+
                         if rs1 < imm {
                             self.set_reg(dec.rd, 1)?;
                         } else {
@@ -839,6 +2450,8 @@ impl Emulator {
                         match dec.funct7 {
                             0b0000000 => {
                                 // SLT
+                                self.log_cmp(pc, rs1, rs2);
+
                                 if (rs1 as i64) < (rs2 as i64) {
                                     self.set_reg(dec.rd, 1)?;
                                 } else {
@@ -852,6 +2465,8 @@ impl Emulator {
                         match dec.funct7 {
                             0b0000000 => {
                                 // SLTU
+                                self.log_cmp(pc, rs1, rs2);
+
                                 if rs1 < rs2 {
                                     self.set_reg(dec.rd, 1)?;
                                 } else {
@@ -924,8 +2539,25 @@ impl Emulator {
                     } else {
                         return Err(VmExit::InvalidInstruction);
                     }
+                } else if dec.funct3 == 0 {
+                    let rtype = Rtype::from(inst);
+
+                    if *rtype.rd != 0 || rtype.funct7 != 0b0001001 {
+                        return Err(VmExit::InvalidInstruction);
+                    }
+
+                    // SFENCE.VMA
+                    if self.mmu.privilege() == Privilege::User {
+                        return Err(VmExit::InvalidInstruction);
+                    }
+
+                    let addr = match *rtype.rs1 {
+                        0 => None,
+                        _ => Some(VirtAddr(self.reg(rtype.rs1)? as usize)),
+                    };
+                    self.mmu.sfence_vma(addr);
                 } else {
-                    return Err(VmExit::InvalidInstruction);
+                    self.emulate_csr(dec)?;
                 }
             }
             0b0011011 => {
@@ -1045,62 +2677,108 @@ impl Emulator {
     /// # Calling convention
     ///
     /// Input:
+    /// - `rdi`: Number of executed instructions at which execution stops with
+    ///   a timeout.
     /// - `r8`: Number of executed instructions.
     /// - `r9`: JIT cache lookup table.
     /// - `r10`: Emulator registers.
-    /// - `r11`: MMU memory.
+    /// - `r11`: MMU TLB.
     /// - `r12`: MMU dirty blocks.
-    /// - `r13`: MMU dirty bitmap.
     /// - `r14`: MMU dirty length.
-    /// - `r15`: MMU memory permissions.
     ///
     /// Output:
     /// - `rax`: JIT exit reason.
     ///   - `rax=0`: JIT cache lookup error.
     ///   - `rax=1`: ECALL exception.
     ///   - `rax=2`: EBREAK exception.
-    ///   - `rax=3`: Read fault, `rcx`: Memory address, `rdx`: Size.
-    ///   - `rax=4`: Write fault, `rcx`: Memory address, `rdx`: Size.
-    ///   - `rax=5`: Uninit fault, `rcx`: Memory address, `rdx`: Size.
+    ///   - `rax=3`: Memory access fault or TLB miss. `rcx`: Memory address.
+    ///     The instruction must be emulated to obtain the precise error.
     ///   - `rax=6`: Timeout.
     ///   - `rax=7`: Hook. `rcx`: reentry address.
+    ///   - `rax=8`: Breakpoint. `rcx`: reentry address.
     /// - `rbx`: Next PC. In the case of an exception (EBREAK, ECALL or
-    ///   read/write fault) or a hook, it's the address of the instruction
-    ///   causing the exception.
+    ///   memory access fault), a hook or a breakpoint, it's the address of the
+    ///   instruction causing the exception.
     /// - `rcx`: Extra information.
-    /// - `rdx`: Extra information.
     /// - `r8`: Updated number of executed instructions.
     /// - `r14`: Updated Mmu dirty len.
     pub fn run_jit(&mut self) -> Result<(), VmExit> {
         let mut pc = self.reg(RegAlias::Pc)?;
-        let mut hook_reentry = None;
+        let mut reentry = None;
+
+        let start = (pc, self.coverage.inst_execed);
 
         loop {
-            let block_ptr = if let Some(ptr) = hook_reentry.take() {
+            let block_ptr = if let Some(ptr) = reentry.take() {
                 ptr
             } else {
+                // The instruction that hit a watchpoint is emulated, so it
+                // ignores the watchpoints.
+                if self.watchpoint_resuming(pc) {
+                    self.execute_instruction(pc)?;
+                    pc = self.reg(RegAlias::Pc)?;
+                    continue;
+                }
+
                 if pc & 3 != 0 {
                     return Err(VmExit::AddressMisaligned);
                 }
 
-                let (lookup_table_len, block_lookup) = {
+                // The generation is read along with the lookup, so the
+                // breakpoints and exec watchpoints registered while lifting
+                // are not missed.
+                let (lookup_table_len, block_lookup, generation) = {
                     let jit_cache =
                         self.jit_cache.as_ref().unwrap().lock().unwrap();
 
                     (
                         jit_cache.lookup_table_len(),
                         jit_cache.lookup(VirtAddr(pc as usize)),
+                        jit_cache.generation(),
                     )
                 };
 
                 if let Some(ptr) = block_lookup {
                     ptr
                 } else {
-                    let block = self.lift_block(pc, lookup_table_len)?;
-
-                    let mut jit_cache =
-                        self.jit_cache.as_ref().unwrap().lock().unwrap();
-                    jit_cache.insert(VirtAddr(pc as usize), block)?
+                    let lift_start = Instant::now();
+                    let (code, block, len) =
+                        self.lift_block(pc, lookup_table_len)?;
+                    let lift_time = lift_start.elapsed();
+
+                    // The block is only copied if it has to be dumped, which
+                    // is done after releasing the cache lock.
+                    let (ptr, dump) = {
+                        let mut jit_cache =
+                            self.jit_cache.as_ref().unwrap().lock().unwrap();
+                        jit_cache.add_lift_time(lift_time);
+                        let dump = jit_cache
+                            .dump_dir()
+                            .map(|dir| (dir.to_path_buf(), block.clone()));
+                        let ptr = jit_cache.insert_if_current(
+                            VirtAddr(pc as usize),
+                            len,
+                            block,
+                            generation,
+                        )?;
+                        (ptr, dump)
+                    };
+                    let ptr = match ptr {
+                        Some(ptr) => ptr,
+                        // Lift the block again with the new breakpoints and
+                        // exec watchpoints.
+                        None => continue,
+                    };
+                    if let Some((dir, block)) = dump {
+                        jit::dump_block(
+                            &dir,
+                            VirtAddr(pc as usize),
+                            len,
+                            &code,
+                            &block,
+                        )?;
+                    }
+                    ptr
                 }
             };
 
@@ -1111,34 +2789,33 @@ impl Emulator {
             };
 
             let regs_ptr = self.regs.as_ptr();
-            let memory_ptr = self.mmu.memory_ptr();
+            let tlb_ptr = self.mmu.tlb_ptr();
             let dirty_ptr = self.mmu.dirty_ptr();
-            let dirty_bitmap_ptr = self.mmu.dirty_bitmap_ptr();
             let mut dirty_len = self.mmu.dirty_len();
-            let perms_ptr = self.mmu.perms_ptr();
 
             let mut inst_execed = self.coverage.inst_execed;
+            let inst_limit = self.inst_limit;
 
             let jit_exit: u64;
             let next_pc: u64;
             let rcx: u64;
-            let rdx: u64;
 
             unsafe {
                 asm!("call {block_ptr}",
                      block_ptr = in(reg) block_ptr,
+                     in("rdi") inst_limit,
                      inout("r8") inst_execed,
                      in("r9") lookup_table_ptr,
                      in("r10") regs_ptr,
-                     in("r11") memory_ptr,
+                     in("r11") tlb_ptr,
                      in("r12") dirty_ptr,
-                     in("r13") dirty_bitmap_ptr,
+                     out("r13") _,
                      inout("r14") dirty_len,
-                     in("r15") perms_ptr,
+                     out("r15") _,
                      out("rax") jit_exit,
                      out("rbx") next_pc,
                      out("rcx") rcx,
-                     out("rdx") rdx,
+                     out("rdx") _,
                 );
             }
 
@@ -1174,22 +2851,20 @@ impl Emulator {
                     return Err(VmExit::Ebreak);
                 }
                 3 => {
-                    return Err(VmExit::MmuError(mmu::Error::ReadFault {
-                        addr: VirtAddr(rcx as usize),
-                        size: rdx as usize,
-                    }));
-                }
-                4 => {
-                    return Err(VmExit::MmuError(mmu::Error::WriteFault {
-                        addr: VirtAddr(rcx as usize),
-                        size: rdx as usize,
-                    }));
-                }
-                5 => {
-                    return Err(VmExit::MmuError(mmu::Error::UninitFault {
-                        addr: VirtAddr(rcx as usize),
-                        size: rdx as usize,
-                    }));
+                    // The lifted code only knows that the access is not
+                    // valid or the page is not in the TLB, so the faulting
+                    // instruction is emulated to report the same error as in
+                    // emulation mode.
+                    self.mmu.fill_tlb(VirtAddr(rcx as usize));
+                    self.execute_instruction(next_pc)?;
+
+                    // The instruction may have enabled paging.
+                    if !self.jit_enabled() {
+                        return self.run_emu();
+                    }
+
+                    pc = self.reg(RegAlias::Pc)?;
+                    continue;
                 }
                 6 => return Err(VmExit::Timeout),
                 7 => {
@@ -1205,7 +2880,7 @@ impl Emulator {
                         if hook_pc != next_pc {
                             pc = hook_pc;
                         } else {
-                            hook_reentry = Some(rcx as *const u8);
+                            reentry = Some(rcx as *const u8);
                         }
                         continue;
                     } else {
@@ -1215,17 +2890,27 @@ impl Emulator {
                         );
                     }
                 }
+                8 => {
+                    // The JIT cache is shared among emulators, so the
+                    // breakpoint could belong to a different one.
+                    if self.breakpoint_hit(next_pc, start) {
+                        return Err(VmExit::UserBreakpoint);
+                    }
+                    reentry = Some(rcx as *const u8);
+                    continue;
+                }
                 _ => unimplemented!("unknown jit_exit value"),
             }
         }
     }
 
-    /// Lifts a basic block.
+    /// Lifts a basic block. It returns the generated assembly, the compiled
+    /// code and the length in bytes of the lifted guest code.
     fn lift_block(
         &mut self,
         pc: u64,
         lookup_table_len: usize,
-    ) -> Result<Vec<u8>, VmExit> {
+    ) -> Result<(String, Vec<u8>, usize), VmExit> {
         let mut block_code = String::new();
         let mut cur_pc = pc;
 
@@ -1234,53 +2919,124 @@ impl Emulator {
         }
 
         loop {
-            let inst = self.mmu.read_int_with_perms::<u32>(
-                VirtAddr(cur_pc as usize),
-                Perm(PERM_EXEC),
-            )?;
-
-            // Update coverage.
-            self.coverage.pcs.insert(VirtAddr(cur_pc as usize));
-
-            match self.lift_instruction(cur_pc, inst, lookup_table_len) {
-                Ok((inst_code, end)) => {
+            let lifted = self
+                .mmu
+                .read_int_with_perms::<u32>(
+                    VirtAddr(cur_pc as usize),
+                    Perm(PERM_EXEC),
+                )
+                .map_err(|error| {
+                    VmExit::memory_fault(cur_pc, Access::Exec, error)
+                })
+                .and_then(|inst| {
+                    self.lift_instruction(cur_pc, inst, lookup_table_len)
+                });
+
+            // The exec watchpoints of every emulator sharing the cache are
+            // taken into account, so the block can be reused by all of them.
+            let watched = self
+                .jit_cache
+                .as_ref()
+                .unwrap()
+                .lock()
+                .unwrap()
+                .exec_watched(VirtAddr(cur_pc as usize));
+
+            let (inst_code, end) = match (watched, lifted) {
+                (false, Ok(lifted)) => lifted,
+                (true, _) | (_, Err(VmExit::Watchpoint { .. })) => {
+                    // Instructions covered by exec watchpoints are emulated,
+                    // which reports the watchpoint if it belongs to the
+                    // running emulator.
+                    let inst_code = format!(
+                        "
+                            sub r8, 1
+                            mov rax, 3
+                            mov rbx, {cur_pc}
+                            mov rcx, {cur_pc}
+                            ret
+                        ",
+                        cur_pc = cur_pc,
+                    );
+                    (inst_code, true)
+                }
+                (_, Err(_)) if cur_pc != pc => {
+                    // The previous instructions must be executed before
+                    // raising the exception, so end the block here. The
+                    // exception is raised when the next block is lifted.
                     block_code.push_str(&format!(
                         "
-                            inst_{cur_pc:x}:
+                            xor rax, rax
+                            mov rbx, {cur_pc}
+                            ret
                         ",
                         cur_pc = cur_pc,
                     ));
+                    break;
+                }
+                (_, Err(err)) => return Err(err),
+            };
 
-                    if self.hooks.contains_key(&VirtAddr(cur_pc as usize)) {
-                        block_code.push_str(&format!(
-                            "
-                                mov rax, 7
-                                mov rbx, {cur_pc}
-                                lea rcx, [rel .hook_reentry]
-                                ret
+            // Update coverage.
+            self.coverage.pcs.insert(VirtAddr(cur_pc as usize));
 
-                                .hook_reentry:
-                            ",
-                            cur_pc = cur_pc,
-                        ));
-                    }
+            block_code.push_str(&format!(
+                "
+                    inst_{cur_pc:x}:
+                ",
+                cur_pc = cur_pc,
+            ));
+
+            // The breakpoints of every emulator sharing the cache are
+            // lifted, so the block can be reused by all of them.
+            let breakpoint = self
+                .jit_cache
+                .as_ref()
+                .unwrap()
+                .lock()
+                .unwrap()
+                .has_breakpoint(VirtAddr(cur_pc as usize));
+            if breakpoint {
+                block_code.push_str(&format!(
+                    "
+                        mov rax, 8
+                        mov rbx, {cur_pc}
+                        lea rcx, [rel .breakpoint_reentry]
+                        ret
 
-                    block_code.push_str(&format!(
-                        "
-                            add r8, 1
+                        .breakpoint_reentry:
+                    ",
+                    cur_pc = cur_pc,
+                ));
+            }
 
-                            {inst_code}
-                        ",
-                        inst_code = inst_code
-                    ));
+            if self.hooks.contains_key(&VirtAddr(cur_pc as usize)) {
+                block_code.push_str(&format!(
+                    "
+                        mov rax, 7
+                        mov rbx, {cur_pc}
+                        lea rcx, [rel .hook_reentry]
+                        ret
+
+                        .hook_reentry:
+                    ",
+                    cur_pc = cur_pc,
+                ));
+            }
 
-                    cur_pc = cur_pc.wrapping_add(4);
+            block_code.push_str(&format!(
+                "
+                    add r8, 1
 
-                    if end {
-                        break;
-                    }
-                }
-                Err(err) => return Err(err),
+                    {inst_code}
+                ",
+                inst_code = inst_code
+            ));
+
+            cur_pc = cur_pc.wrapping_add(4);
+
+            if end {
+                break;
             }
         }
 
@@ -1288,10 +3044,9 @@ impl Emulator {
             "
                 BITS 64
 
-                ; Exit with timeout if the number of executed instructions is
-                ; too high.
-                mov rax, {timeout}
-                cmp r8, rax
+                ; Exit with timeout if the number of executed instructions
+                ; reached the limit.
+                cmp r8, rdi
                 jb .notimeout
                 mov rax, 6
                 mov rbx, {pc}
@@ -1302,7 +3057,6 @@ impl Emulator {
             ",
             pc = pc,
             block_code = block_code,
-            timeout = TIMEOUT
         );
 
         let block = match nasm::assemble(&code) {
@@ -1315,7 +3069,7 @@ impl Emulator {
             }
         };
 
-        Ok(block)
+        Ok((code, block, cur_pc.wrapping_sub(pc) as usize))
     }
 
     /// Lifts a single instruction. It returns a String containing the lifted
@@ -1371,7 +3125,8 @@ impl Emulator {
 
         // Returns a `String` containing the asm code to perform a jit cache
         // lookup, jumping to the lifted block if found. Otherwise, it will
-        // exit the JIt with rax=0 and rbx=target.
+        // exit the JIt with rax=0 and rbx=target. Misaligned targets are
+        // never looked up, so the exception is raised outside the JIT.
         //
         // It clobbers the registers `rax` and `rbx` and uses the local label
         // `.lookup_error`.
@@ -1380,6 +3135,8 @@ impl Emulator {
                 format!(
                     "
                         mov rbx, {target}
+                        test rbx, 3
+                        jnz .lookup_error_{target}
                         mov rax, rbx
                         shr rax, 2
                         cmp rax, {lookup_table_len}
@@ -1398,6 +3155,50 @@ impl Emulator {
             };
         }
 
+        // Returns a `String` containing the asm code to translate the virtual
+        // address in `rcx`, which is the address of a memory access of `size`
+        // bytes. If `write` is true, the translation is only valid for pages
+        // which can be written without being copied first. If the page is in
+        // the TLB, `rdx` will contain the host address of the accessed byte
+        // and `r13` will point to the TLB entry. Otherwise, or if the access
+        // crosses a page boundary, it jumps to the local label `.fault`.
+        //
+        // It clobbers the registers `rax`, `rdx` and `r13`.
+        macro_rules! tlb_lookup {
+            ($size:expr, $write:expr) => {
+                format!(
+                    "
+                        mov rax, rcx
+                        shr rax, {page_shift}
+                        mov r13, rax
+                        and r13, {tlb_size} - 1
+                        shl r13, {tlb_entry_shift}
+                        add r13, r11
+                        cmp qword [r13+{tag}], rax
+                        jne .fault
+
+                        mov rdx, rcx
+                        and rdx, {page_size} - 1
+                        cmp rdx, {page_size} - {size}
+                        ja .fault
+                        add rdx, qword [r13+{page_ptr}]
+                    ",
+                    page_shift = PAGE_SIZE.trailing_zeros(),
+                    page_size = PAGE_SIZE,
+                    tlb_size = TLB_SIZE,
+                    tlb_entry_shift =
+                        mem::size_of::<TlbEntry>().trailing_zeros(),
+                    tag = if $write {
+                        TLB_WRITE_PAGE_NUMBER_OFFSET
+                    } else {
+                        TLB_PAGE_NUMBER_OFFSET
+                    },
+                    page_ptr = TLB_PAGE_PTR_OFFSET,
+                    size = $size
+                )
+            };
+        }
+
         match opcode {
             0b0110111 => {
                 // LUI
@@ -1504,7 +3305,8 @@ impl Emulator {
                 let mut raw_mask = 0u64;
                 for i in 0..size {
                     read_mask |= (PERM_READ as u64) << (i * 8);
-                    raw_mask |= (PERM_RAW as u64) << (i * 8);
+                    raw_mask |= ((PERM_RAW | PERM_WATCH | PERM_MMIO) as u64)
+                        << (i * 8);
                 }
 
                 code.push_str(&read_reg!(dec.rs1, "rcx"));
@@ -1512,37 +3314,49 @@ impl Emulator {
                     "
                         add rcx, {offset}
 
-                        ; Check memory boundaries.
-                        cmp rcx, {memory_len} - {size}
-                        ja .read_fault
+                        ; Translate address.
+                        {tlb_lookup}
+
+                        ; Skip the checks if the whole page is readable.
+                        mov rax, qword [r13+{page_ptr}]
+                        test byte [rax+{summary}], {perm_read}
+                        jnz .read
+
+                        ; Byte-level permissions, if the page has them.
+                        mov rax, qword [rax+{perms}]
+                        test rax, rax
+                        jz .fault
+                        mov rdx, rcx
+                        and rdx, {page_size} - 1
+                        add rdx, rax
 
-                        ; Check uninit.
-                        {movzx} {movzx_rax}, {size_mod} [r15+rcx]
+                        ; Check uninit, watchpoints and MMIO.
+                        {movzx} {movzx_rax}, {size_mod} [rdx]
                         mov rbx, {raw_mask}
                         and rax, rbx
-                        jnz .uninit_fault
+                        jnz .fault
 
                         ; Check unreadable.
-                        {movzx} {movzx_rax}, {size_mod} [r15+rcx]
+                        {movzx} {movzx_rax}, {size_mod} [rdx]
                         mov rbx, {read_mask}
                         and rax, rbx
                         cmp rax, rbx
-                        jne .read_fault
+                        jne .fault
+
+                        ; Restore the host address.
+                        mov rdx, rcx
+                        and rdx, {page_size} - 1
+                        add rdx, qword [r13+{page_ptr}]
 
                         ; Read.
-                        {mov} {rax}, {size_mod} [r11+rcx]
+                        .read:
+                        {mov} {rax}, {size_mod} [rdx]
                         jmp .out
 
-                        .uninit_fault:
-                        mov rax, 5
-                        mov rbx, {pc}
-                        mov rdx, {size}
-                        ret
-
-                        .read_fault:
+                        .fault:
+                        sub r8, 1
                         mov rax, 3
                         mov rbx, {pc}
-                        mov rdx, {size}
                         ret
 
                         .out:
@@ -1552,9 +3366,13 @@ impl Emulator {
                     size_mod = size_mod,
                     rax = rax,
                     movzx_rax = movzx_rax,
-                    size = size,
                     offset = offset as i32,
-                    memory_len = self.mmu.memory_len(),
+                    tlb_lookup = tlb_lookup!(size, false),
+                    page_ptr = TLB_PAGE_PTR_OFFSET,
+                    summary = PAGE_SUMMARY_OFFSET,
+                    perm_read = PERM_READ,
+                    perms = PAGE_PERMS_OFFSET,
+                    page_size = PAGE_SIZE,
                     read_mask = read_mask,
                     raw_mask = raw_mask,
                     pc = pc
@@ -1576,19 +3394,83 @@ impl Emulator {
                 };
 
                 let mut write_mask = 0u64;
+                let mut trap_mask = 0u64;
                 let mut raw_mask = 0u64;
                 for i in 0..size {
                     write_mask |= (PERM_WRITE as u64) << (i * 8);
+                    trap_mask |= ((PERM_WATCH | PERM_MMIO) as u64) << (i * 8);
                     raw_mask |= (PERM_RAW as u64) << (i * 8);
                 }
 
-                // Check DIRTY_BLOCK_SIZE fits the requirements.
-                assert_eq!(
-                    DIRTY_BLOCK_SIZE.count_ones(),
-                    1,
-                    "DIRTY_BLOCK_SIZE must be a power of two"
-                );
-                let dirty_bs_shift = DIRTY_BLOCK_SIZE.trailing_zeros();
+                // The dirty block size is a power of two, checked by the
+                // Mmu.
+                let dirty_config = self.mmu.dirty_config();
+                let dirty_bs_shift = dirty_config.block_size.trailing_zeros();
+
+                let mark_dirty = if dirty_config.hierarchical {
+                    format!(
+                        "
+                            ; Record the page in the dirty list if it was
+                            ; clean, and mark the blocks containing the first
+                            ; and the last byte as dirty. Both are in the same
+                            ; page.
+                            mov r13, qword [r13+{dirty_ptr}]
+                            cmp qword [r13], 0
+                            jne .mark_blocks
+                            mov rax, rcx
+                            shr rax, {page_shift}
+                            mov qword [r12+8*r14], rax
+                            add r14, 1
+
+                            .mark_blocks:
+                            mov r15, rcx
+                            shr r15, {dirty_bs_shift}
+                            and r15, {page_dirty_blocks} - 1
+                            bts qword [r13], r15
+                            lea r15, [rcx+{size}-1]
+                            shr r15, {dirty_bs_shift}
+                            and r15, {page_dirty_blocks} - 1
+                            bts qword [r13], r15
+                            jmp .out
+                        ",
+                        dirty_ptr = TLB_DIRTY_PTR_OFFSET,
+                        page_shift = PAGE_SIZE.trailing_zeros(),
+                        dirty_bs_shift = dirty_bs_shift,
+                        page_dirty_blocks = dirty_config.page_blocks(),
+                        size = size,
+                    )
+                } else {
+                    format!(
+                        "
+                            ; Mark the blocks containing the first and the
+                            ; last byte as dirty. Both are in the same page.
+                            mov r13, qword [r13+{dirty_ptr}]
+                            mov rax, rcx
+                            shr rax, {dirty_bs_shift}
+                            mov r15, rax
+                            and r15, {page_dirty_blocks} - 1
+                            bts qword [r13], r15
+                            jc .last_block
+                            mov qword [r12+8*r14], rax
+                            add r14, 1
+
+                            .last_block:
+                            lea rax, [rcx+{size}-1]
+                            shr rax, {dirty_bs_shift}
+                            mov r15, rax
+                            and r15, {page_dirty_blocks} - 1
+                            bts qword [r13], r15
+                            jc .out
+                            mov qword [r12+8*r14], rax
+                            add r14, 1
+                            jmp .out
+                        ",
+                        dirty_ptr = TLB_DIRTY_PTR_OFFSET,
+                        dirty_bs_shift = dirty_bs_shift,
+                        page_dirty_blocks = dirty_config.page_blocks(),
+                        size = size,
+                    )
+                };
 
                 code.push_str(&read_reg!(dec.rs1, "rcx"));
                 code.push_str(&read_reg!(dec.rs2, "rbx"));
@@ -1596,65 +3478,63 @@ impl Emulator {
                     "
                         add rcx, {offset}
 
-                        ; Check memory boundaries.
-                        cmp rcx, {memory_len} - {size}
-                        ja .fault
+                        ; Translate address.
+                        {tlb_lookup}
 
-                        ; Check write.
-                        {movzx} {movzx_rax}, {size_mod} [r15+rcx]
-                        mov rdx, {write_mask}
-                        and rax, rdx
-                        cmp rax, rdx
+                        ; Skip the checks if the whole page is writable.
+                        mov rax, qword [r13+{page_ptr}]
+                        test byte [rax+{summary}], {perm_write}
+                        jnz .write
+
+                        ; Byte-level permissions, if the page has them.
+                        mov rax, qword [rax+{perms}]
+                        test rax, rax
+                        jz .fault
+                        mov rdx, rcx
+                        and rdx, {page_size} - 1
+                        add rdx, rax
+
+                        ; Check write, watchpoints and MMIO.
+                        {movzx} {movzx_rax}, {size_mod} [rdx]
+                        mov r15, {check_mask}
+                        and rax, r15
+                        mov r15, {write_mask}
+                        cmp rax, r15
                         jne .fault
 
                         ; Remove PERM_RAW and add PERM_READ.
-                        {movzx} {movzx_rax}, {size_mod} [r15+rcx]
-                        mov rdx, {raw_mask}
-                        and rdx, rax
-                        xor rax, rdx
-                        shr rdx, 1
-                        or rax, rdx
-                        mov {size_mod} [r15+rcx], {rax}
+                        {movzx} {movzx_rax}, {size_mod} [rdx]
+                        mov r15, {raw_mask}
+                        and r15, rax
+                        xor rax, r15
+                        shr r15, 1
+                        or rax, r15
+                        mov {size_mod} [rdx], {rax}
+
+                        ; The permission counts of the page are out of date
+                        ; if PERM_RAW was removed.
+                        test r15, r15
+                        jz .restore
+                        mov rax, qword [r13+{page_ptr}]
+                        mov byte [rax+{stale}], 1
+
+                        ; Restore the host address.
+                        .restore:
+                        mov rdx, rcx
+                        and rdx, {page_size} - 1
+                        add rdx, qword [r13+{page_ptr}]
 
                         ; Write.
+                        .write:
                         mov rax, rbx
-                        mov {size_mod} [r11+rcx], {rax}
-
-                        ; Be conservative and mark both the starting block and
-                        ; the next one as dirty. Computing if the second block
-                        ; is dirty is more expensive than resetting more
-                        ; memory blocks.
-                        shr rcx, {dirty_bs_shift}
-
-                        bts qword [r13], rcx
-                        jc .next_block
-
-                        ; Mark starting block as dirty.
-                        mov qword [r12+8*r14], rcx
-                        add r14, 1
-
-                        .next_block:
-
-                        ; Mark following block as dirty.
-                        add rcx, 1
-
-                        ; We have to check if the following block is still
-                        ; valid. The first one is already checked by the
-                        ; initial boundary checking. dirty_capacity is the
-                        ; maximum number of dirty blocks.
-                        cmp rcx, {dirty_capacity}
-                        jae .out
+                        mov {size_mod} [rdx], {rax}
 
-                        bts qword [r13], rcx
-                        jc .out
-                        mov qword [r12+8*r14], rcx
-                        add r14, 1
-                        jmp .out
+                        {mark_dirty}
 
                         .fault:
-                        mov rax, 4
+                        sub r8, 1
+                        mov rax, 3
                         mov rbx, {pc}
-                        mov rdx, {size}
                         ret
 
                         .out:
@@ -1663,12 +3543,17 @@ impl Emulator {
                     size_mod = size_mod,
                     rax = rax,
                     movzx_rax = movzx_rax,
-                    size = size,
                     offset = offset as i32,
-                    memory_len = self.mmu.memory_len(),
-                    dirty_bs_shift = dirty_bs_shift,
-                    dirty_capacity = self.mmu.dirty_capacity(),
+                    tlb_lookup = tlb_lookup!(size, true),
+                    page_ptr = TLB_PAGE_PTR_OFFSET,
+                    summary = PAGE_SUMMARY_OFFSET,
+                    perm_write = PERM_WRITE,
+                    perms = PAGE_PERMS_OFFSET,
+                    stale = PAGE_STALE_OFFSET,
+                    page_size = PAGE_SIZE,
+                    mark_dirty = mark_dirty,
                     write_mask = write_mask,
+                    check_mask = write_mask | trap_mask,
                     raw_mask = raw_mask,
                     pc = pc
                 ));
@@ -1983,10 +3868,13 @@ impl Emulator {
                 let dec = Itype::from(inst);
 
                 if *dec.rd == 0 && dec.funct3 == 0 && *dec.rs1 == 0 {
+                    // Exceptions are not counted as executed instructions, as
+                    // in emulation mode.
                     if dec.imm == 0 {
                         // ECALL
                         code.push_str(&format!(
                             "
+                                sub r8, 1
                                 mov rax, 1
                                 mov rbx, {pc:#x}
                                 ret
@@ -1998,6 +3886,7 @@ impl Emulator {
                         // EBREAK
                         code.push_str(&format!(
                             "
+                                sub r8, 1
                                 mov rax, 2
                                 mov rbx, {pc:#x}
                                 ret
@@ -2008,8 +3897,24 @@ impl Emulator {
                     } else {
                         return Err(VmExit::InvalidInstruction);
                     }
-                } else {
+                } else if dec.funct3 != 0 && dec.imm as u32 & 0xfff != CSR_SATP
+                {
+                    // Only the `satp` CSR is supported.
                     return Err(VmExit::InvalidInstruction);
+                } else {
+                    // Accesses to `satp` and SFENCE.VMA are emulated, as they
+                    // may enable paging, which requires emulation.
+                    code.push_str(&format!(
+                        "
+                            sub r8, 1
+                            mov rax, 3
+                            mov rbx, {pc:#x}
+                            mov rcx, {pc:#x}
+                            ret
+                        ",
+                        pc = pc,
+                    ));
+                    return Ok((code, true));
                 }
             }
             0b0011011 => {
@@ -2176,3 +4081,685 @@ impl Emulator {
         Ok((code, false))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mmu::{PTE_R, PTE_V, PTE_W, PTE_X};
+
+    /// `addi a0, a0, 1`
+    const INC_A0: u32 = 0x00150513;
+
+    /// `ebreak`
+    const EBREAK: u32 = 0x00100073;
+
+    /// `addi a1, a1, -1`
+    const DEC_A1: u32 = 0xfff58593;
+
+    /// `bnez a1, -8`
+    const BNEZ_A1_BACK_2: u32 = 0xfe059ce3;
+
+    /// Loop incrementing `a0` and decrementing `a1` until `a1` is zero.
+    const LOOP: [u32; 4] = [INC_A0, DEC_A1, BNEZ_A1_BACK_2, EBREAK];
+
+    /// Returns an emulator using JIT compilation, with `code` loaded at
+    /// `0x1000` and the PC pointing to it.
+    fn jit_emulator(code: &[u32]) -> Emulator {
+        emulator(code).with_jit(JitCache::new(0x10000, 0x10000))
+    }
+
+    /// Returns an emulator using emulation, with `code` loaded at `0x1000`
+    /// and the PC pointing to it.
+    fn emulator(code: &[u32]) -> Emulator {
+        let mut emu = Emulator::new(Mmu::new(0x10000));
+        for (i, inst) in code.iter().enumerate() {
+            emu.mmu_mut()
+                .poke_int::<u32>(VirtAddr(0x1000 + 4 * i), *inst)
+                .unwrap();
+        }
+        emu.mmu_mut()
+            .set_perms(VirtAddr(0x1000), 4 * code.len(), Perm(PERM_EXEC))
+            .unwrap();
+        emu.set_reg(RegAlias::Pc, 0x1000).unwrap();
+        emu
+    }
+
+    #[test]
+    fn emulator_fork_breakpoints() {
+        let emu = jit_emulator(&[INC_A0, INC_A0, INC_A0, EBREAK]);
+
+        let mut with_bp = emu.fork();
+        let mut without_bp = emu.fork();
+        with_bp.add_breakpoint(VirtAddr(0x1008));
+
+        // The block is lifted by the fork without the breakpoint.
+        match without_bp.run() {
+            Err(VmExit::Ebreak) => {}
+            res => panic!("Wrong result {:?}", res),
+        }
+        assert_eq!(without_bp.reg(RegAlias::A0).unwrap(), 3);
+
+        match with_bp.run() {
+            Err(VmExit::UserBreakpoint) => {}
+            res => panic!("Wrong result {:?}", res),
+        }
+        assert_eq!(with_bp.reg(RegAlias::A0).unwrap(), 2);
+        assert_eq!(with_bp.reg(RegAlias::Pc).unwrap(), 0x1008);
+
+        // Forks inherit the breakpoints, and dropping an emulator removes
+        // them from the cache.
+        let fork = with_bp.fork();
+        drop(with_bp);
+        let cache = Arc::clone(emu.jit_cache.as_ref().unwrap());
+        assert!(cache.lock().unwrap().has_breakpoint(VirtAddr(0x1008)));
+        drop(fork);
+        assert!(!cache.lock().unwrap().has_breakpoint(VirtAddr(0x1008)));
+    }
+
+    #[test]
+    fn emulator_fork_exec_watchpoints() {
+        let emu = jit_emulator(&[INC_A0, INC_A0, INC_A0, EBREAK]);
+
+        let mut with_wp = emu.fork();
+        let mut without_wp = emu.fork();
+        with_wp
+            .add_watchpoint(VirtAddr(0x1008), 4, Access::Exec)
+            .unwrap();
+
+        // The block is lifted by the fork without the watchpoint.
+        match without_wp.run() {
+            Err(VmExit::Ebreak) => {}
+            res => panic!("Wrong result {:?}", res),
+        }
+        assert_eq!(without_wp.reg(RegAlias::A0).unwrap(), 3);
+
+        match with_wp.run() {
+            Err(VmExit::Watchpoint { .. }) => {}
+            res => panic!("Wrong result {:?}", res),
+        }
+        assert_eq!(with_wp.reg(RegAlias::A0).unwrap(), 2);
+        assert_eq!(with_wp.reg(RegAlias::Pc).unwrap(), 0x1008);
+
+        // Resetting to a state without watchpoints removes them from the
+        // cache.
+        with_wp.reset(&emu);
+        let cache = emu.jit_cache.as_ref().unwrap().lock().unwrap();
+        assert!(!cache.exec_watched(VirtAddr(0x1008)));
+    }
+
+    #[test]
+    fn emulator_mmio_second_region() {
+        // lui a1, 0x3; ld a0, 8(a1); ebreak
+        let mut emu = jit_emulator(&[0x000035b7, 0x0085b503, EBREAK]);
+
+        fn read(
+            _emu: &mut Emulator,
+            addr: VirtAddr,
+            _size: usize,
+        ) -> Result<u64, VmExit> {
+            Ok(*addr as u64)
+        }
+        fn write(
+            _emu: &mut Emulator,
+            _addr: VirtAddr,
+            _size: usize,
+            _value: u64,
+        ) -> Result<(), VmExit> {
+            Ok(())
+        }
+        emu.add_mmio(VirtAddr(0x2000), 0x10, read, write).unwrap();
+        emu.add_mmio(VirtAddr(0x3000), 0x10, read, write).unwrap();
+
+        match emu.run() {
+            Err(VmExit::Ebreak) => {}
+            res => panic!("Wrong result {:?}", res),
+        }
+        assert_eq!(emu.reg(RegAlias::A0).unwrap(), 0x3008);
+    }
+
+    #[test]
+    fn emulator_csr() {
+        // csrr a0, fcsr
+        let mut emu = jit_emulator(&[0x00302573, EBREAK]);
+        match emu.run() {
+            Err(VmExit::InvalidInstruction) => {}
+            res => panic!("Wrong result {:?}", res),
+        }
+
+        // csrw satp, zero
+        let mut emu = jit_emulator(&[0x18001073, EBREAK]);
+
+        // Guests run in user mode by default, without access to `satp`.
+        let mut user = emu.fork();
+        match user.run() {
+            Err(VmExit::InvalidInstruction) => {}
+            res => panic!("Wrong result {:?}", res),
+        }
+
+        emu.mmu_mut().set_privilege(Privilege::Supervisor);
+        match emu.run() {
+            Err(VmExit::Ebreak) => {}
+            res => panic!("Wrong result {:?}", res),
+        }
+        assert_eq!(emu.mmu().paging_mode(), PagingMode::Bare);
+    }
+
+    #[test]
+    fn emulator_mappings_invalidate_jit() {
+        let mut emu = Emulator::new(Mmu::new(0x10000))
+            .with_jit(JitCache::new(0x10000, 0x10000));
+        let code = |insts: &[u32]| -> Vec<u8> {
+            insts
+                .iter()
+                .flat_map(|inst| inst.to_le_bytes().to_vec())
+                .collect()
+        };
+        let exec = Perm(PERM_EXEC);
+
+        emu.mmap(Some(VirtAddr(0x1000)), 1, exec, "a", &code(&[EBREAK]))
+            .unwrap();
+        emu.set_reg(RegAlias::Pc, 0x1000).unwrap();
+        match emu.run() {
+            Err(VmExit::Ebreak) => {}
+            res => panic!("Wrong result {:?}", res),
+        }
+
+        // Replacing the mapping replaces the code.
+        let contents = code(&[INC_A0, EBREAK]);
+        emu.mmap(Some(VirtAddr(0x1000)), 1, exec, "b", &contents)
+            .unwrap();
+        emu.set_reg(RegAlias::Pc, 0x1000).unwrap();
+        match emu.run() {
+            Err(VmExit::Ebreak) => {}
+            res => panic!("Wrong result {:?}", res),
+        }
+        assert_eq!(emu.reg(RegAlias::A0).unwrap(), 1);
+
+        // The code is not executable anymore.
+        emu.mprotect(VirtAddr(0x1000), 1, Perm(PERM_READ)).unwrap();
+        emu.set_reg(RegAlias::Pc, 0x1000).unwrap();
+        match emu.run() {
+            Err(VmExit::MemoryFault {
+                access: Access::Exec,
+                ..
+            }) => {}
+            res => panic!("Wrong result {:?}", res),
+        }
+
+        emu.mprotect(VirtAddr(0x1000), 1, exec).unwrap();
+        match emu.run() {
+            Err(VmExit::Ebreak) => {}
+            res => panic!("Wrong result {:?}", res),
+        }
+
+        emu.munmap(VirtAddr(0x1000), 1).unwrap();
+        emu.set_reg(RegAlias::Pc, 0x1000).unwrap();
+        match emu.run() {
+            Err(VmExit::MemoryFault {
+                access: Access::Exec,
+                ..
+            }) => {}
+            res => panic!("Wrong result {:?}", res),
+        }
+    }
+
+    #[test]
+    fn emulator_reset_invalidates_mappings_jit() {
+        let mut emu = Emulator::new(Mmu::new(0x10000))
+            .with_jit(JitCache::new(0x10000, 0x10000));
+        let code = |insts: &[u32]| -> Vec<u8> {
+            insts
+                .iter()
+                .flat_map(|inst| inst.to_le_bytes().to_vec())
+                .collect()
+        };
+        let exec = Perm(PERM_EXEC);
+
+        emu.mmap(Some(VirtAddr(0x2000)), 1, exec, "a", &code(&[EBREAK]))
+            .unwrap();
+        let snapshot = emu.fork();
+
+        // Code mapped after the snapshot is not executable once reset.
+        emu.mmap(Some(VirtAddr(0x1000)), 1, exec, "b", &code(&[EBREAK]))
+            .unwrap();
+        emu.set_reg(RegAlias::Pc, 0x1000).unwrap();
+        match emu.run() {
+            Err(VmExit::Ebreak) => {}
+            res => panic!("Wrong result {:?}", res),
+        }
+
+        emu.reset(&snapshot);
+        emu.set_reg(RegAlias::Pc, 0x1000).unwrap();
+        match emu.run() {
+            Err(VmExit::MemoryFault {
+                access: Access::Exec,
+                ..
+            }) => {}
+            res => panic!("Wrong result {:?}", res),
+        }
+
+        // Code replaced after the snapshot is restored once reset.
+        let contents = code(&[INC_A0, EBREAK]);
+        emu.mmap(Some(VirtAddr(0x2000)), 1, exec, "c", &contents)
+            .unwrap();
+        emu.set_reg(RegAlias::Pc, 0x2000).unwrap();
+        match emu.run() {
+            Err(VmExit::Ebreak) => {}
+            res => panic!("Wrong result {:?}", res),
+        }
+        assert_eq!(emu.reg(RegAlias::A0).unwrap(), 1);
+
+        emu.reset(&snapshot);
+        emu.set_reg(RegAlias::Pc, 0x2000).unwrap();
+        match emu.run() {
+            Err(VmExit::Ebreak) => {}
+            res => panic!("Wrong result {:?}", res),
+        }
+        assert_eq!(emu.reg(RegAlias::A0).unwrap(), 0);
+    }
+
+    /// Checks that an emulator returned by `new_emu` stops when its
+    /// instruction budget is exhausted, and that resuming execution reaches
+    /// the same state as an uninterrupted run.
+    fn check_inst_budget(new_emu: fn(&[u32]) -> Emulator) {
+        let mut emu = new_emu(&LOOP);
+        emu.set_reg(RegAlias::A1, 100).unwrap();
+
+        let mut uninterrupted = emu.fork();
+        match uninterrupted.run() {
+            Err(VmExit::Ebreak) => {}
+            res => panic!("Wrong result {:?}", res),
+        }
+
+        // The budget belongs to each fork.
+        let mut fork = emu.fork();
+        fork.set_inst_budget(10);
+        assert_eq!(emu.inst_budget(), u64::MAX);
+
+        match fork.run() {
+            Err(VmExit::Timeout) => {}
+            res => panic!("Wrong result {:?}", res),
+        }
+        let inst_execed = fork.coverage().inst_execed;
+        assert!(inst_execed >= 10 && inst_execed < 300);
+        assert_eq!(fork.inst_budget(), 0);
+
+        // Timeouts are reported again until a new budget is set.
+        match fork.run() {
+            Err(VmExit::Timeout) => {}
+            res => panic!("Wrong result {:?}", res),
+        }
+        assert_eq!(fork.coverage().inst_execed, inst_execed);
+
+        fork.set_inst_budget(1000);
+        match fork.run() {
+            Err(VmExit::Ebreak) => {}
+            res => panic!("Wrong result {:?}", res),
+        }
+        assert_eq!(fork.regs, uninterrupted.regs);
+        assert_eq!(
+            fork.coverage().inst_execed,
+            uninterrupted.coverage().inst_execed
+        );
+    }
+
+    #[test]
+    fn emulator_inst_budget() {
+        check_inst_budget(emulator);
+    }
+
+    #[test]
+    fn emulator_inst_budget_jit() {
+        check_inst_budget(jit_emulator);
+    }
+
+    #[test]
+    fn emulator_taint_propagation() {
+        let mut emu = emulator(&[
+            0x0025a503, // lw a0, 2(a1)
+            0x00150613, // addi a2, a0, 1
+            0x00e606b3, // add a3, a2, a4
+            0x00d5b423, // sd a3, 8(a1)
+            0x00069463, // bne a3, zero, 8
+            EBREAK, 0x00a54533, // xor a0, a0, a0
+            EBREAK,
+        ]);
+        emu.set_taint_tracking(true);
+        emu.mmu_mut()
+            .set_perms(VirtAddr(0x2000), 0x10, Perm(PERM_READ | PERM_WRITE))
+            .unwrap();
+        emu.mmu_mut().taint_input(VirtAddr(0x2000), 8, 0).unwrap();
+        emu.set_reg(RegAlias::A1, 0x2000).unwrap();
+
+        match emu.run() {
+            Err(VmExit::Ebreak) => {}
+            res => panic!("Wrong result {:?}", res),
+        }
+        assert_eq!(emu.reg(RegAlias::Pc).unwrap(), 0x101c);
+
+        // Loads, ALU operations, stores and branches propagate the labels
+        // of the loaded bytes.
+        let loaded = vec![2, 3, 4, 5];
+        let a2 = emu.reg_taint(RegAlias::A2).unwrap();
+        let a3 = emu.reg_taint(RegAlias::A3).unwrap();
+        assert_eq!(emu.taint_offsets(a2), loaded);
+        assert_eq!(emu.taint_offsets(a3), loaded);
+        for taint in emu.mmu().taint(VirtAddr(0x2008), 8).unwrap() {
+            assert_eq!(emu.taint_offsets(taint), loaded);
+        }
+        let branch = emu.tainted_branches().unwrap()[&VirtAddr(0x1010)];
+        assert_eq!(emu.taint_offsets(branch), loaded);
+
+        // Untainted operands and cleared registers stay untainted.
+        assert!(!emu.reg_taint(RegAlias::A4).unwrap().is_tainted());
+        assert!(!emu.reg_taint(RegAlias::A0).unwrap().is_tainted());
+        assert_eq!(emu.mem_addr_taint(), Taint::UNTAINTED);
+    }
+
+    /// Returns an emulator with uninitialized memory tracking enabled and
+    /// `a1` pointing to a new allocation of `size` bytes, whose bits are
+    /// undefined.
+    fn uninit_emulator(code: &[u32], size: usize) -> Emulator {
+        let mut emu = emulator(code);
+        emu.set_uninit_tracking(true);
+        emu.mmu_mut().set_brk(VirtAddr(0x4000));
+        let addr = emu
+            .mmu_mut()
+            .malloc(size, 8, true, mmu::AllocSite::default())
+            .unwrap();
+        emu.set_reg(RegAlias::A1, *addr as u64).unwrap();
+        emu
+    }
+
+    #[test]
+    fn emulator_uninit_branch() {
+        let mut emu = uninit_emulator(
+            &[
+                0x00a58023, // sb a0, 0(a1)
+                0x0005c603, // lbu a2, 0(a1)
+                0x0005d683, // lhu a3, 0(a1)
+                0x00060463, // beq a2, zero, 8
+                0x00069463, // bne a3, zero, 8
+                EBREAK, 0x0006c463, // blt a3, zero, 8
+                EBREAK,
+            ],
+            8,
+        );
+        emu.set_reg(RegAlias::A0, 1).unwrap();
+        let addr = VirtAddr(emu.reg(RegAlias::A1).unwrap() as usize);
+
+        // Branches on defined bits, or whose result is decided by the
+        // defined bits, are not reported.
+        let origin = Origin {
+            addr,
+            size: 8,
+            site: mmu::AllocSite::default(),
+        };
+        match emu.run() {
+            Err(VmExit::UninitValue {
+                pc: VirtAddr(0x1018),
+                usage: UninitUse::Branch,
+                origin: Some(ref o),
+            }) if *o == origin => {}
+            res => panic!("Wrong result {:?}", res),
+        }
+        assert_eq!(emu.reg_undef(RegAlias::A2).unwrap(), 0);
+        assert_eq!(emu.reg_undef(RegAlias::A3).unwrap(), 0xff00);
+    }
+
+    #[test]
+    fn emulator_uninit_sign_extension() {
+        let mut emu = emulator(&[
+            0x00059603, // lh a2, 0(a1)
+            0x00259683, // lh a3, 2(a1)
+            0x0005d703, // lhu a4, 0(a1)
+            EBREAK,
+        ]);
+        emu.set_uninit_tracking(true);
+        emu.mmu_mut()
+            .set_perms(VirtAddr(0x2000), 4, Perm(PERM_READ))
+            .unwrap();
+        let undef = |undef| Definedness {
+            undef,
+            origin: OriginId::UNKNOWN,
+        };
+        emu.mmu_mut()
+            .set_definedness(
+                VirtAddr(0x2000),
+                &[undef(0), undef(0x80), undef(0), undef(0x01)],
+            )
+            .unwrap();
+        emu.set_reg(RegAlias::A1, 0x2000).unwrap();
+
+        match emu.run() {
+            Err(VmExit::Ebreak) => {}
+            res => panic!("Wrong result {:?}", res),
+        }
+
+        // Signed loads extend the sign bit only if it is undefined.
+        assert_eq!(emu.reg_undef(RegAlias::A2).unwrap(), !0x7fff);
+        assert_eq!(emu.reg_undef(RegAlias::A3).unwrap(), 0x0100);
+        assert_eq!(emu.reg_undef(RegAlias::A4).unwrap(), 0x8000);
+    }
+
+    #[test]
+    fn emulator_uninit_store_load() {
+        let mut emu = uninit_emulator(
+            &[
+                0x0005b603, // ld a2, 0(a1)
+                0x00c6b023, // sd a2, 0(a3)
+                0x0006b703, // ld a4, 0(a3)
+                0x00070463, // beq a4, zero, 8
+                EBREAK,
+            ],
+            16,
+        );
+        emu.mmu_mut()
+            .set_perms(VirtAddr(0x3000), 8, Perm(PERM_READ | PERM_WRITE))
+            .unwrap();
+        emu.set_reg(RegAlias::A3, 0x3000).unwrap();
+        let addr = VirtAddr(emu.reg(RegAlias::A1).unwrap() as usize);
+
+        // The undefined bits and their origin are kept in memory.
+        match emu.run() {
+            Err(VmExit::UninitValue {
+                pc: VirtAddr(0x100c),
+                usage: UninitUse::Branch,
+                origin:
+                    Some(Origin {
+                        addr: a, size: 16, ..
+                    }),
+            }) if a == addr => {}
+            res => panic!("Wrong result {:?}", res),
+        }
+        assert_eq!(emu.reg_undef(RegAlias::A4).unwrap(), u64::MAX);
+        for d in emu.mmu().definedness(VirtAddr(0x3000), 8).unwrap() {
+            assert_eq!(d.undef, 0xff);
+        }
+    }
+
+    /// Returns an emulator running in supervisor mode with the paging mode
+    /// `mode`, and `code` loaded at the virtual address `0x1000`. The
+    /// virtual pages at `0x2000` and `0x3000` are readable and writable, but
+    /// not physically contiguous.
+    fn paging_emulator(code: &[u32], mode: PagingMode) -> Emulator {
+        let mut emu = Emulator::new(Mmu::new(0x100000));
+        let mmu = emu.mmu_mut();
+        for (i, inst) in code.iter().enumerate() {
+            mmu.poke_int::<u32>(VirtAddr(0x20000 + 4 * i), *inst)
+                .unwrap();
+        }
+        mmu.set_perms(VirtAddr(0x20000), 4 * code.len(), Perm(PERM_EXEC))
+            .unwrap();
+        mmu.set_perms(VirtAddr(0x21000), 0x1000, Perm(PERM_READ | PERM_WRITE))
+            .unwrap();
+        mmu.set_perms(VirtAddr(0x23000), 0x1000, Perm(PERM_READ | PERM_WRITE))
+            .unwrap();
+
+        // Sv39 tables rooted at 0x10000. The Sv39 root table is the second
+        // level of the Sv48 tables, rooted at 0x30000.
+        let pte = |number: u64, flags: u64| number << 10 | flags | PTE_V;
+        mmu.poke_int::<u64>(VirtAddr(0x30000), pte(0x10, 0))
+            .unwrap();
+        mmu.poke_int::<u64>(VirtAddr(0x10000), pte(0x11, 0))
+            .unwrap();
+        mmu.poke_int::<u64>(VirtAddr(0x11000), pte(0x12, 0))
+            .unwrap();
+        mmu.poke_int::<u64>(VirtAddr(0x12008), pte(0x20, PTE_R | PTE_X))
+            .unwrap();
+        mmu.poke_int::<u64>(VirtAddr(0x12010), pte(0x21, PTE_R | PTE_W))
+            .unwrap();
+        mmu.poke_int::<u64>(VirtAddr(0x12018), pte(0x23, PTE_R | PTE_W))
+            .unwrap();
+
+        let satp = match mode {
+            PagingMode::Bare => 0,
+            PagingMode::Sv39 => 8 << 60 | 0x10,
+            PagingMode::Sv48 => 9 << 60 | 0x30,
+        };
+        mmu.set_privilege(Privilege::Supervisor);
+        mmu.set_satp(satp).unwrap();
+        emu.set_reg(RegAlias::Pc, 0x1000).unwrap();
+        emu
+    }
+
+    #[test]
+    fn emulator_page_fault() {
+        for mode in [PagingMode::Sv39, PagingMode::Sv48] {
+            // ld a0, 0(a1); ebreak
+            let mut emu = paging_emulator(&[0x0005b503, EBREAK], mode);
+            emu.set_reg(RegAlias::A1, 0x2008).unwrap();
+            emu.mmu_mut()
+                .poke_int::<u64>(VirtAddr(0x21008), 42)
+                .unwrap();
+
+            let mut fault = emu.fork();
+            match emu.run() {
+                Err(VmExit::Ebreak) => {}
+                res => panic!("Wrong result {:?}", res),
+            }
+            assert_eq!(emu.reg(RegAlias::A0).unwrap(), 42);
+
+            // Load page fault.
+            fault.set_reg(RegAlias::A1, 0x5000).unwrap();
+            match fault.run() {
+                Err(VmExit::PageFault {
+                    pc: VirtAddr(0x1000),
+                    addr: VirtAddr(0x5000),
+                    cause: 13,
+                }) => {}
+                res => panic!("Wrong result {:?}", res),
+            }
+        }
+    }
+
+    #[test]
+    fn emulator_paging_cross_page() {
+        // ld a0, 0(a1); ebreak
+        let mut emu = paging_emulator(&[0x0005b503, EBREAK], PagingMode::Sv39);
+
+        // The access spans two pages that are not physically contiguous.
+        emu.set_reg(RegAlias::A1, 0x2ffc).unwrap();
+        match emu.run() {
+            Err(VmExit::AddressMisaligned) => {}
+            res => panic!("Wrong result {:?}", res),
+        }
+        assert_eq!(emu.reg(RegAlias::Pc).unwrap(), 0x1000);
+    }
+
+    /// `jalr zero, 0(a1)`
+    const JR_A1: u32 = 0x00058067;
+
+    /// `ld a0, 0(a1)`
+    const LD_A0_A1: u32 = 0x0005b503;
+
+    /// `sd a0, 0(a1)`
+    const SD_A0_A1: u32 = 0x00a5b023;
+
+    /// Runs `code` with `a1` set to `a1` using emulation and JIT
+    /// compilation, and checks that both stop with the same memory fault,
+    /// caused by the instruction at `pc`, and with the same registers.
+    fn check_fault_parity(
+        code: &[u32],
+        a1: u64,
+        pc: usize,
+        access: Access,
+        error: mmu::Error,
+    ) {
+        let mut emu = emulator(code);
+        let mut jit_emu = jit_emulator(code);
+        for emu in [&mut emu, &mut jit_emu] {
+            emu.mmu_mut()
+                .set_perms(VirtAddr(0x3000), 0x10, Perm(PERM_READ))
+                .unwrap();
+            emu.set_reg(RegAlias::A1, a1).unwrap();
+        }
+
+        let expected = VmExit::MemoryFault {
+            pc: VirtAddr(pc),
+            access,
+            error,
+        };
+        for res in [emu.run(), jit_emu.run()] {
+            assert_eq!(format!("{:?}", res), format!("Err({:?})", expected));
+        }
+        assert_eq!(jit_emu.regs, emu.regs);
+        assert_eq!(emu.reg(RegAlias::A0).unwrap(), 1);
+    }
+
+    #[test]
+    fn emulator_exec_fault_parity() {
+        check_fault_parity(
+            &[INC_A0, JR_A1, EBREAK],
+            0x2000,
+            0x2000,
+            Access::Exec,
+            mmu::Error::ExecFault {
+                addr: VirtAddr(0x2000),
+                size: 4,
+            },
+        );
+    }
+
+    #[test]
+    fn emulator_invalid_address_parity() {
+        check_fault_parity(
+            &[INC_A0, LD_A0_A1, EBREAK],
+            0x20000,
+            0x1004,
+            Access::Read,
+            mmu::Error::InvalidAddress {
+                addr: VirtAddr(0x20000),
+                size: 8,
+            },
+        );
+    }
+
+    #[test]
+    fn emulator_address_overflow_parity() {
+        check_fault_parity(
+            &[INC_A0, LD_A0_A1, EBREAK],
+            u64::MAX - 3,
+            0x1004,
+            Access::Read,
+            mmu::Error::AddressIntegerOverflow {
+                addr: VirtAddr(usize::MAX - 3),
+                size: 8,
+            },
+        );
+    }
+
+    #[test]
+    fn emulator_perm_fault_parity() {
+        check_fault_parity(
+            &[INC_A0, SD_A0_A1, EBREAK],
+            0x3000,
+            0x1004,
+            Access::Write,
+            mmu::Error::WriteFault {
+                addr: VirtAddr(0x3000),
+                size: 8,
+            },
+        );
+    }
+}
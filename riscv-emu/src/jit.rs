@@ -2,6 +2,10 @@
 
 use std::collections::HashMap;
 use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use crate::mmu::VirtAddr;
 
@@ -10,6 +14,9 @@ use crate::mmu::VirtAddr;
 pub enum Error {
     InvalidAddress,
     OutOfMemory,
+
+    /// IO error when dumping blocks.
+    IoError(io::Error),
 }
 
 impl fmt::Display for Error {
@@ -17,10 +24,47 @@ impl fmt::Display for Error {
         match self {
             Error::InvalidAddress => write!(f, "invalid address"),
             Error::OutOfMemory => write!(f, "out of memory"),
+            Error::IoError(err) => write!(f, "{}", err),
         }
     }
 }
 
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Error {
+        Error::IoError(error)
+    }
+}
+
+/// JIT compilation statistics.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    /// Number of blocks inserted in the cache.
+    pub blocks: u64,
+
+    /// Number of bytes of compiled code stored in the JIT memory.
+    pub bytes: u64,
+
+    /// Number of inserted blocks whose compiled code was already present in
+    /// the JIT memory.
+    pub dedup_hits: u64,
+
+    /// Time spent lifting and assembling blocks.
+    pub lift_time: Duration,
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "blocks={} bytes={} dedup_hits={} lift_time={:.6}s",
+            self.blocks,
+            self.bytes,
+            self.dedup_hits,
+            self.lift_time.as_secs_f64()
+        )
+    }
+}
+
 /// Memory map used to store the compiled code.
 pub struct JitMemory {
     /// Allocated RWX memory map containing the compiled code.
@@ -41,8 +85,34 @@ pub struct JitCache {
     /// yet.
     lookup_table: Vec<usize>,
 
+    /// Mapping between the program address of every block in the lookup
+    /// table and the length in bytes of the program code it covers.
+    blocks: HashMap<VirtAddr, usize>,
+
     /// Memory map containing the compiled code.
     jit_memory: JitMemory,
+
+    /// JIT compilation statistics.
+    stats: Stats,
+
+    /// Directory where the lifted blocks are dumped, if any.
+    dump_dir: Option<PathBuf>,
+
+    /// Program addresses with a breakpoint, and the number of emulators
+    /// sharing the cache that set it. The lifted code exits at these
+    /// addresses, so every emulator can check its own breakpoints.
+    breakpoints: HashMap<VirtAddr, usize>,
+
+    /// Incremented every time the code lifted at some address changes
+    /// because of a breakpoint or an exec watchpoint. Blocks lifted before it changed are not
+    /// inserted. See `insert_if_current`.
+    generation: u64,
+
+    /// Program ranges covered by exec watchpoints, and the number of
+    /// emulators sharing the cache that set them. The instructions in these
+    /// ranges are not lifted, so every emulator can check its own
+    /// watchpoints when emulating them.
+    exec_watchpoints: HashMap<(VirtAddr, usize), usize>,
 }
 
 /// Creates a memory map of size `size` with RWX permissions.
@@ -80,10 +150,53 @@ impl JitCache {
 
         JitCache {
             lookup_table: vec![0; size],
+            blocks: HashMap::new(),
             jit_memory,
+            stats: Stats::default(),
+            dump_dir: None,
+            breakpoints: HashMap::new(),
+            generation: 0,
+            exec_watchpoints: HashMap::new(),
         }
     }
 
+    /// Enables dumping the lifted blocks into the directory `dir`. See
+    /// `dump_block`.
+    pub fn with_dump_dir<P: AsRef<Path>>(mut self, dir: P) -> JitCache {
+        self.dump_dir = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// Returns the JIT compilation statistics.
+    pub fn stats(&self) -> Stats {
+        self.stats
+    }
+
+    /// Adds `lift_time` to the time spent lifting blocks.
+    pub fn add_lift_time(&mut self, lift_time: Duration) {
+        self.stats.lift_time += lift_time;
+    }
+
+    /// Returns the directory where the lifted blocks are dumped, if it was
+    /// set using `with_dump_dir`.
+    pub fn dump_dir(&self) -> Option<&Path> {
+        self.dump_dir.as_deref()
+    }
+
+    /// Writes the JIT statistics into `stats.txt` in the dump directory, if
+    /// it was set using `with_dump_dir`.
+    pub fn dump_stats(&self) -> Result<(), Error> {
+        let dir = match &self.dump_dir {
+            Some(dir) => dir,
+            None => return Ok(()),
+        };
+
+        fs::create_dir_all(dir)?;
+        fs::write(dir.join("stats.txt"), format!("{}\n", self.stats))?;
+
+        Ok(())
+    }
+
     /// Returns the length of the internal lookup table.
     pub fn lookup_table_len(&self) -> usize {
         self.lookup_table.len()
@@ -110,7 +223,8 @@ impl JitCache {
         }
     }
 
-    /// Inserts a new block in the cache. The function returns a pointer to
+    /// Inserts a new block in the cache. `len` is the length in bytes of the
+    /// program code covered by the block. The function returns a pointer to
     /// this new block. If the block was already present, the function returns
     /// a pointer to the already existing one.
     ///
@@ -120,6 +234,7 @@ impl JitCache {
     pub fn insert(
         &mut self,
         addr: VirtAddr,
+        len: usize,
         block: Vec<u8>,
     ) -> Result<*const u8, Error> {
         if *addr & 3 != 0 {
@@ -140,6 +255,10 @@ impl JitCache {
             // If the dedup hash map contains the key, map the address with the
             // already existing block.
             self.lookup_table[idx] = *ptr;
+            self.blocks.insert(addr, len);
+
+            self.stats.blocks += 1;
+            self.stats.dedup_hits += 1;
 
             Ok(*ptr as *const u8)
         } else {
@@ -168,10 +287,161 @@ impl JitCache {
 
             // Update the lookup table.
             self.lookup_table[idx] = ptr as usize;
+            self.blocks.insert(addr, len);
+
+            self.stats.blocks += 1;
+            self.stats.bytes += size as u64;
 
             Ok(ptr)
         }
     }
+
+    /// Returns the current generation of the cache. It must be read, with
+    /// the cache locked, before lifting a block that is inserted with
+    /// `insert_if_current`.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Inserts a new block in the cache as `insert` does, unless the
+    /// generation of the cache is not `generation` anymore. In that case,
+    /// the block may have been lifted without a breakpoint or an exec
+    /// watchpoint registered by other emulator in the meantime, so it is not inserted and `None` is
+    /// returned. It must be lifted again.
+    pub fn insert_if_current(
+        &mut self,
+        addr: VirtAddr,
+        len: usize,
+        block: Vec<u8>,
+        generation: u64,
+    ) -> Result<Option<*const u8>, Error> {
+        if generation != self.generation {
+            return Ok(None);
+        }
+
+        self.insert(addr, len, block).map(Some)
+    }
+
+    /// Registers a breakpoint set by one of the emulators sharing the cache
+    /// at the program address `addr`. The blocks covering it are invalidated
+    /// if no other emulator had set it.
+    pub fn add_breakpoint(&mut self, addr: VirtAddr) {
+        let count = self.breakpoints.entry(addr).or_insert(0);
+        *count += 1;
+        if *count == 1 {
+            self.generation += 1;
+            self.invalidate(addr);
+        }
+    }
+
+    /// Unregisters a breakpoint registered with `add_breakpoint`. The blocks
+    /// covering it are invalidated if no other emulator has set it.
+    pub fn remove_breakpoint(&mut self, addr: VirtAddr) {
+        if let Some(count) = self.breakpoints.get_mut(&addr) {
+            *count -= 1;
+            if *count == 0 {
+                self.breakpoints.remove(&addr);
+                self.generation += 1;
+                self.invalidate(addr);
+            }
+        }
+    }
+
+    /// Returns `true` if any emulator sharing the cache has a breakpoint at
+    /// the program address `addr`.
+    pub fn has_breakpoint(&self, addr: VirtAddr) -> bool {
+        self.breakpoints.contains_key(&addr)
+    }
+
+    /// Registers an exec watchpoint set by one of the emulators sharing the
+    /// cache on the program range (`addr`..`addr` + `size`). The blocks
+    /// overlapping it are invalidated if no other emulator had set it.
+    pub fn add_exec_watchpoint(&mut self, addr: VirtAddr, size: usize) {
+        let count = self.exec_watchpoints.entry((addr, size)).or_insert(0);
+        *count += 1;
+        if *count == 1 {
+            self.generation += 1;
+            self.invalidate_range(addr, size);
+        }
+    }
+
+    /// Unregisters an exec watchpoint registered with `add_exec_watchpoint`.
+    /// The blocks overlapping it are invalidated if no other emulator has set
+    /// it.
+    pub fn remove_exec_watchpoint(&mut self, addr: VirtAddr, size: usize) {
+        if let Some(count) = self.exec_watchpoints.get_mut(&(addr, size)) {
+            *count -= 1;
+            if *count == 0 {
+                self.exec_watchpoints.remove(&(addr, size));
+                self.generation += 1;
+                self.invalidate_range(addr, size);
+            }
+        }
+    }
+
+    /// Returns `true` if any emulator sharing the cache has an exec
+    /// watchpoint covering the instruction at the program address `addr`.
+    pub fn exec_watched(&self, addr: VirtAddr) -> bool {
+        self.exec_watchpoints.keys().any(|(wp_addr, wp_size)| {
+            *addr < wp_addr.saturating_add(*wp_size) && **wp_addr < *addr + 4
+        })
+    }
+
+    /// Removes from the lookup table all the blocks covering the program
+    /// address `addr`, so they are lifted again the next time they are
+    /// executed. The compiled code is not freed.
+    pub fn invalidate(&mut self, addr: VirtAddr) {
+        self.invalidate_range(addr, 1);
+    }
+
+    /// Removes from the lookup table all the blocks overlapping the program
+    /// range (`addr`..`addr` + `size`), so they are lifted again the next
+    /// time they are executed. The compiled code is not freed.
+    pub fn invalidate_range(&mut self, addr: VirtAddr, size: usize) {
+        let lookup_table = &mut self.lookup_table;
+        let end = addr.saturating_add(size);
+
+        self.blocks.retain(|start, len| {
+            if **start >= end || *addr >= **start + *len {
+                return true;
+            }
+            lookup_table[**start / 4] = 0;
+            false
+        });
+    }
+}
+
+/// Writes the block lifted from the program address `addr` into the dump
+/// directory `dir`. `len` is the length in bytes of the program code covered
+/// by the block, `code` is the generated assembly and `block` the compiled
+/// code.
+///
+/// The assembly and the compiled code are written to `<addr>.asm` and
+/// `<addr>.bin` respectively. It does not require the JIT cache, so blocks
+/// can be dumped without holding its lock.
+pub fn dump_block(
+    dir: &Path,
+    addr: VirtAddr,
+    len: usize,
+    code: &str,
+    block: &[u8],
+) -> Result<(), Error> {
+    fs::create_dir_all(dir)?;
+
+    let asm = format!(
+        "; guest: {:#x}-{:#x} ({} instructions)\n\
+         ; host: {} bytes\n\
+         {}\n",
+        *addr,
+        *addr + len,
+        len / 4,
+        block.len(),
+        code
+    );
+    fs::write(dir.join(format!("{:016x}.asm", *addr)), asm)?;
+    fs::write(dir.join(format!("{:016x}.bin", *addr)), block)?;
+
+    Ok(())
 }
 
 #[cfg(test)]
@@ -189,7 +459,7 @@ mod tests {
         let block = nasm::assemble(code).unwrap();
 
         let mut cache = JitCache::new(0x10, 0x1000);
-        let block_ptr = cache.insert(VirtAddr(0), block).unwrap();
+        let block_ptr = cache.insert(VirtAddr(0), 4, block).unwrap();
 
         let result: u64;
 
@@ -208,8 +478,8 @@ mod tests {
     fn jitcache_insert_dedup() {
         let mut cache = JitCache::new(0x10, 0x1000);
 
-        let block_ptr = cache.insert(VirtAddr(0x0), vec![0x90]).unwrap();
-        let block2_ptr = cache.insert(VirtAddr(0x4), vec![0x90]).unwrap();
+        let block_ptr = cache.insert(VirtAddr(0x0), 4, vec![0x90]).unwrap();
+        let block2_ptr = cache.insert(VirtAddr(0x4), 4, vec![0x90]).unwrap();
 
         assert_eq!(block_ptr, block2_ptr);
     }
@@ -218,7 +488,7 @@ mod tests {
     fn jitcache_insert_invalid_address() {
         let mut cache = JitCache::new(0x10, 0x1000);
 
-        match cache.insert(VirtAddr(0x3), vec![0x90]) {
+        match cache.insert(VirtAddr(0x3), 4, vec![0x90]) {
             Err(Error::InvalidAddress) => return,
             Err(_) => panic!("Wrong error"),
             Ok(_) => panic!("The function didn't return an error"),
@@ -229,7 +499,7 @@ mod tests {
     fn jitcache_insert_oom() {
         let mut cache = JitCache::new(0x10, 0x2);
 
-        match cache.insert(VirtAddr(0x0), vec![0x90; 3]) {
+        match cache.insert(VirtAddr(0x0), 4, vec![0x90; 3]) {
             Err(Error::OutOfMemory) => return,
             Err(_) => panic!("Wrong error"),
             Ok(_) => panic!("The function didn't return an error"),
@@ -240,7 +510,7 @@ mod tests {
     fn jitcache_use_all_memory() {
         let mut cache = JitCache::new(0x10, 0x4);
         cache
-            .insert(VirtAddr(0x0), vec![0x00, 0x01, 0x02, 0x03])
+            .insert(VirtAddr(0x0), 4, vec![0x00, 0x01, 0x02, 0x03])
             .unwrap();
     }
 
@@ -248,7 +518,7 @@ mod tests {
     fn jitcache_lookup() {
         let mut cache = JitCache::new(0x10, 0x1000);
 
-        let block_ptr = cache.insert(VirtAddr(0x4), vec![0x90]).unwrap();
+        let block_ptr = cache.insert(VirtAddr(0x4), 4, vec![0x90]).unwrap();
 
         assert_eq!(Some(block_ptr), cache.lookup(VirtAddr(0x4)));
         assert_eq!(None, cache.lookup(VirtAddr(0x0)));
@@ -256,6 +526,179 @@ mod tests {
         assert_eq!(None, cache.lookup(VirtAddr(0x20)));
     }
 
+    #[test]
+    fn jitcache_stats() {
+        let mut cache = JitCache::new(0x10, 0x1000);
+
+        cache.insert(VirtAddr(0x0), 4, vec![0x90, 0x90]).unwrap();
+        cache.insert(VirtAddr(0x4), 4, vec![0x90, 0x90]).unwrap();
+        cache.insert(VirtAddr(0x8), 4, vec![0xc3]).unwrap();
+        cache.insert(VirtAddr(0x8), 4, vec![0xc3]).unwrap();
+        cache.add_lift_time(Duration::from_millis(10));
+
+        let want = Stats {
+            blocks: 3,
+            bytes: 3,
+            dedup_hits: 1,
+            lift_time: Duration::from_millis(10),
+        };
+
+        assert_eq!(cache.stats(), want);
+    }
+
+    #[test]
+    fn jitcache_dump_block() {
+        let dir = std::env::temp_dir()
+            .join(format!("riscv-emu-jit-dump-{}", std::process::id()));
+        let cache = JitCache::new(0x10, 0x1000).with_dump_dir(&dir);
+
+        let dump_dir = cache.dump_dir().unwrap();
+        dump_block(dump_dir, VirtAddr(0x4), 8, "add r8, 1", &[0x90, 0xc3])
+            .unwrap();
+        assert!(!dir.join("stats.txt").exists());
+        cache.dump_stats().unwrap();
+
+        let asm =
+            fs::read_to_string(dir.join("0000000000000004.asm")).unwrap();
+        let bin = fs::read(dir.join("0000000000000004.bin")).unwrap();
+        let stats = fs::read_to_string(dir.join("stats.txt")).unwrap();
+
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(asm.starts_with("; guest: 0x4-0xc (2 instructions)\n"));
+        assert!(asm.contains("add r8, 1"));
+        assert_eq!(bin, vec![0x90, 0xc3]);
+        assert!(stats.starts_with("blocks=0 bytes=0 dedup_hits=0"));
+    }
+
+    #[test]
+    fn jitcache_invalidate() {
+        let mut cache = JitCache::new(0x20, 0x1000);
+
+        cache.insert(VirtAddr(0x0), 8, vec![0x90]).unwrap();
+        cache.insert(VirtAddr(0x4), 4, vec![0x90]).unwrap();
+        cache.insert(VirtAddr(0x8), 8, vec![0x90]).unwrap();
+
+        cache.invalidate(VirtAddr(0x4));
+
+        assert_eq!(None, cache.lookup(VirtAddr(0x0)));
+        assert_eq!(None, cache.lookup(VirtAddr(0x4)));
+        assert!(cache.lookup(VirtAddr(0x8)).is_some());
+    }
+
+    #[test]
+    fn jitcache_invalidate_range() {
+        let mut cache = JitCache::new(0x20, 0x1000);
+
+        cache.insert(VirtAddr(0x0), 8, vec![0x90]).unwrap();
+        cache.insert(VirtAddr(0x8), 4, vec![0x90]).unwrap();
+        cache.insert(VirtAddr(0xc), 8, vec![0x90]).unwrap();
+        cache.insert(VirtAddr(0x14), 4, vec![0x90]).unwrap();
+
+        cache.invalidate_range(VirtAddr(0x6), 8);
+
+        assert_eq!(None, cache.lookup(VirtAddr(0x0)));
+        assert_eq!(None, cache.lookup(VirtAddr(0x8)));
+        assert_eq!(None, cache.lookup(VirtAddr(0xc)));
+        assert!(cache.lookup(VirtAddr(0x14)).is_some());
+    }
+
+    #[test]
+    fn jitcache_invalidate_reinsert() {
+        let mut cache = JitCache::new(0x10, 0x1000);
+
+        cache.insert(VirtAddr(0x4), 4, vec![0x90]).unwrap();
+        cache.invalidate(VirtAddr(0x4));
+        let block_ptr = cache.insert(VirtAddr(0x4), 4, vec![0xc3]).unwrap();
+
+        assert_eq!(Some(block_ptr), cache.lookup(VirtAddr(0x4)));
+    }
+
+    #[test]
+    fn jitcache_breakpoints() {
+        let mut cache = JitCache::new(0x10, 0x1000);
+
+        cache.add_breakpoint(VirtAddr(0x4));
+        cache.add_breakpoint(VirtAddr(0x4));
+        cache.insert(VirtAddr(0x4), 4, vec![0x90]).unwrap();
+
+        // The block is only invalidated when the last emulator removes it.
+        cache.remove_breakpoint(VirtAddr(0x4));
+        assert!(cache.has_breakpoint(VirtAddr(0x4)));
+        assert!(cache.lookup(VirtAddr(0x4)).is_some());
+
+        cache.remove_breakpoint(VirtAddr(0x4));
+        assert!(!cache.has_breakpoint(VirtAddr(0x4)));
+        assert_eq!(None, cache.lookup(VirtAddr(0x4)));
+    }
+
+    #[test]
+    fn jitcache_breakpoint_while_lifting() {
+        let mut cache = JitCache::new(0x10, 0x1000);
+
+        // Other emulator sets a breakpoint while a block is being lifted
+        // without it.
+        let generation = cache.generation();
+        cache.add_breakpoint(VirtAddr(0x4));
+
+        let ptr = cache
+            .insert_if_current(VirtAddr(0x0), 8, vec![0x90], generation)
+            .unwrap();
+        assert_eq!(ptr, None);
+        assert_eq!(cache.lookup(VirtAddr(0x0)), None);
+
+        // Blocks lifted afterwards are inserted.
+        let generation = cache.generation();
+        let ptr = cache
+            .insert_if_current(VirtAddr(0x0), 8, vec![0x90], generation)
+            .unwrap();
+        assert!(ptr.is_some());
+        assert_eq!(cache.lookup(VirtAddr(0x0)), ptr);
+    }
+
+    #[test]
+    fn jitcache_exec_watchpoints() {
+        let mut cache = JitCache::new(0x20, 0x1000);
+
+        cache.insert(VirtAddr(0x0), 8, vec![0x90]).unwrap();
+        cache.insert(VirtAddr(0x10), 4, vec![0x90]).unwrap();
+        cache.add_exec_watchpoint(VirtAddr(0x6), 1);
+
+        assert_eq!(None, cache.lookup(VirtAddr(0x0)));
+        assert!(cache.lookup(VirtAddr(0x10)).is_some());
+        assert!(cache.exec_watched(VirtAddr(0x4)));
+        assert!(!cache.exec_watched(VirtAddr(0x8)));
+
+        cache.remove_exec_watchpoint(VirtAddr(0x6), 1);
+        assert!(!cache.exec_watched(VirtAddr(0x4)));
+    }
+
+    #[test]
+    fn jitcache_exec_watchpoint_while_lifting() {
+        let mut cache = JitCache::new(0x10, 0x1000);
+
+        // Other emulator sets an exec watchpoint while a block is being
+        // lifted without it.
+        let generation = cache.generation();
+        cache.add_exec_watchpoint(VirtAddr(0x4), 4);
+
+        let ptr = cache
+            .insert_if_current(VirtAddr(0x0), 8, vec![0x90], generation)
+            .unwrap();
+        assert_eq!(ptr, None);
+        assert_eq!(cache.lookup(VirtAddr(0x0)), None);
+
+        // Removing it changes the lifted code again.
+        let generation = cache.generation();
+        cache.remove_exec_watchpoint(VirtAddr(0x4), 4);
+
+        let ptr = cache
+            .insert_if_current(VirtAddr(0x0), 4, vec![0x90], generation)
+            .unwrap();
+        assert_eq!(ptr, None);
+        assert_eq!(cache.lookup(VirtAddr(0x0)), None);
+    }
+
     #[test]
     fn jitcache_insert_lookup_exec() {
         let mut cache = JitCache::new(0x10, 0x1000);
@@ -267,7 +710,7 @@ mod tests {
             ret
         "#;
         let block = nasm::assemble(code).unwrap();
-        cache.insert(VirtAddr(0), block).unwrap();
+        cache.insert(VirtAddr(0), 4, block).unwrap();
 
         let code = r#"
             BITS 64
@@ -276,7 +719,7 @@ mod tests {
             ret
         "#;
         let block = nasm::assemble(code).unwrap();
-        cache.insert(VirtAddr(4), block).unwrap();
+        cache.insert(VirtAddr(4), 4, block).unwrap();
 
         let result_1337: u64;
         let result_c4f3: u64;
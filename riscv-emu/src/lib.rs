@@ -3,7 +3,10 @@
 
 #![feature(asm)]
 
+pub mod cmplog;
 pub mod elf;
 pub mod emulator;
 pub mod jit;
 pub mod mmu;
+pub mod snapshot;
+pub mod taint;
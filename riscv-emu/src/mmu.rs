@@ -1,11 +1,20 @@
 //! Emulated MMU with byte-level memory permissions able to detect
 //! uninitialized memory accesses.
 
-use std::collections::HashMap;
+use std::cell::Cell;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
 use std::convert::TryInto;
 use std::fmt;
+use std::io::{Read, Write};
 use std::mem;
 use std::ops::{Deref, DerefMut};
+use std::ptr;
+use std::slice;
+use std::sync::Arc;
+
+use crate::snapshot::{self, Reader, Writer};
+use crate::taint::Taint;
 
 /// Executable memory. Aimed to be used with `Perm`.
 pub const PERM_EXEC: u8 = 1;
@@ -24,14 +33,32 @@ pub const PERM_READ: u8 = 1 << 2;
 /// uninitialized memory.
 pub const PERM_RAW: u8 = 1 << 3;
 
-/// Block size used for resetting and tracking memory which has been modified.
-/// Memory is considered dirty after writing to it and after changing its
-/// permissions.
+/// Watched memory. Aimed to be used internally by the Mmu.
 ///
-/// The block size must be a power of two. This is a requirement imposed by the
-/// JIT compiler.
+/// This flag is set on the memory covered by watchpoints, so the permission
+/// checks catch the accesses to it. Only then the list of watchpoints is
+/// looked up. It is never returned by `Mmu::perms`.
+pub const PERM_WATCH: u8 = 1 << 4;
+
+/// Memory-mapped I/O. Aimed to be used internally by the Mmu.
+///
+/// Accesses checking permissions fail with `Error::Mmio` on memory with this
+/// flag, so they can be dispatched to the device. It is never returned by
+/// `Mmu::perms`.
+pub const PERM_MMIO: u8 = 1 << 5;
+
+/// Permission flags used internally by the Mmu.
+const PERM_INTERNAL: u8 = PERM_WATCH | PERM_MMIO;
+
+/// Default block size used for resetting and tracking memory which has been
+/// modified. Memory is considered dirty after writing to it and after
+/// changing its permissions.
 pub const DIRTY_BLOCK_SIZE: usize = 1024;
 
+/// Minimum dirty block size. Pages can contain at most 64 dirty blocks. This
+/// is a requirement imposed by the dirty bitmap of the pages.
+pub const MIN_DIRTY_BLOCK_SIZE: usize = PAGE_SIZE / 64;
+
 /// If `true`, extra sanity checks are performed. This causes a lost in
 /// performance, so it should be enabled only for debugging purposes.
 const DEBUG_SANITY_CHECKS: bool = false;
@@ -67,6 +94,51 @@ pub enum Error {
 
     /// Invalid free due to double free or heap corruption.
     InvalidFree { addr: VirtAddr },
+
+    /// Invalid allocation alignment. It must be a power of two.
+    InvalidAlignment { align: usize },
+
+    /// Access to memory covered by a watchpoint.
+    Watchpoint {
+        addr: VirtAddr,
+        size: usize,
+        access: Access,
+    },
+
+    /// Access to memory-mapped I/O.
+    Mmio { addr: VirtAddr, size: usize },
+
+    /// The memory is already mapped to I/O.
+    MmioOverlap { addr: VirtAddr, size: usize },
+
+    /// The memory range overlaps a registered region.
+    RegionOverlap { addr: VirtAddr, size: usize },
+
+    /// No NUL terminator was found within the first `size` bytes of the
+    /// string.
+    StringTooLong { addr: VirtAddr, size: usize },
+
+    /// Access to the guard region below the stack.
+    StackOverflow { addr: VirtAddr, size: usize },
+
+    /// The memory range collides with the stack or its guard region.
+    StackCollision { addr: VirtAddr, size: usize },
+
+    /// Allocation made to fail by the fault-injection policy.
+    InjectedAllocFailure { index: usize, size: usize },
+
+    /// The virtual address `addr` has no valid translation for an access of
+    /// type `access`.
+    PageFault { addr: VirtAddr, access: Access },
+
+    /// The MODE field of the `satp` value is not supported.
+    UnsupportedPagingMode { satp: u64 },
+
+    /// The address of a memory mapping is not aligned to the page size.
+    MisalignedMapping { addr: VirtAddr },
+
+    /// The memory range is not covered by registered regions.
+    NotMapped { addr: VirtAddr, size: usize },
 }
 
 impl fmt::Display for Error {
@@ -103,11 +175,437 @@ impl fmt::Display for Error {
             Error::InvalidFree { addr } => {
                 write!(f, "invalid free: addr={}", addr)
             }
+            Error::InvalidAlignment { align } => {
+                write!(f, "invalid alignment: align={:#x}", align)
+            }
+            Error::Watchpoint { addr, size, access } => write!(
+                f,
+                "watchpoint: addr={} size={} access={}",
+                addr, size, access
+            ),
+            Error::Mmio { addr, size } => {
+                write!(f, "MMIO access: addr={} size={}", addr, size)
+            }
+            Error::MmioOverlap { addr, size } => {
+                write!(f, "MMIO overlap: addr={} size={}", addr, size)
+            }
+            Error::RegionOverlap { addr, size } => {
+                write!(f, "region overlap: addr={} size={}", addr, size)
+            }
+            Error::StringTooLong { addr, size } => {
+                write!(f, "string too long: addr={} size={}", addr, size)
+            }
+            Error::StackOverflow { addr, size } => {
+                write!(f, "stack overflow: addr={} size={}", addr, size)
+            }
+            Error::StackCollision { addr, size } => {
+                write!(f, "stack collision: addr={} size={}", addr, size)
+            }
+            Error::InjectedAllocFailure { index, size } => write!(
+                f,
+                "injected allocation failure: index={} size={:#x}",
+                index, size
+            ),
+            Error::PageFault { addr, access } => {
+                write!(f, "page fault: addr={} access={}", addr, access)
+            }
+            Error::UnsupportedPagingMode { satp } => {
+                write!(f, "unsupported paging mode: satp={:#x}", satp)
+            }
+            Error::MisalignedMapping { addr } => {
+                write!(f, "misaligned mapping: addr={}", addr)
+            }
+            Error::NotMapped { addr, size } => {
+                write!(f, "not mapped: addr={} size={}", addr, size)
+            }
+        }
+    }
+}
+
+impl Error {
+    /// Returns the address that caused the error, if any.
+    pub fn addr(&self) -> Option<VirtAddr> {
+        match *self {
+            Error::InvalidAddress { addr, .. }
+            | Error::AddressIntegerOverflow { addr, .. }
+            | Error::ReadFault { addr, .. }
+            | Error::WriteFault { addr, .. }
+            | Error::ExecFault { addr, .. }
+            | Error::UninitFault { addr, .. }
+            | Error::UnkFault { addr, .. }
+            | Error::InvalidFree { addr }
+            | Error::Watchpoint { addr, .. }
+            | Error::Mmio { addr, .. }
+            | Error::MmioOverlap { addr, .. }
+            | Error::RegionOverlap { addr, .. }
+            | Error::StringTooLong { addr, .. }
+            | Error::StackOverflow { addr, .. }
+            | Error::StackCollision { addr, .. }
+            | Error::PageFault { addr, .. }
+            | Error::MisalignedMapping { addr }
+            | Error::NotMapped { addr, .. } => Some(addr),
+            Error::InvalidAlignment { .. }
+            | Error::InjectedAllocFailure { .. }
+            | Error::UnsupportedPagingMode { .. } => None,
+        }
+    }
+}
+
+/// Type of memory access.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Access {
+    /// Memory read.
+    Read,
+
+    /// Memory write.
+    Write,
+
+    /// Instruction fetch.
+    Exec,
+}
+
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Access::Read => write!(f, "read"),
+            Access::Write => write!(f, "write"),
+            Access::Exec => write!(f, "exec"),
+        }
+    }
+}
+
+impl Access {
+    /// Returns the value used to encode the access type in snapshots.
+    fn to_u64(self) -> u64 {
+        match self {
+            Access::Read => 0,
+            Access::Write => 1,
+            Access::Exec => 2,
+        }
+    }
+
+    /// Returns the access type encoded in snapshots as `val`.
+    fn from_u64(val: u64) -> Result<Access, snapshot::Error> {
+        match val {
+            0 => Ok(Access::Read),
+            1 => Ok(Access::Write),
+            2 => Ok(Access::Exec),
+            _ => Err(snapshot::Error::MalformedFile),
+        }
+    }
+
+    /// Returns the RISC-V exception code of a page fault caused by an access
+    /// of this type.
+    pub fn page_fault_cause(self) -> u64 {
+        match self {
+            Access::Exec => 12,
+            Access::Read => 13,
+            Access::Write => 15,
+        }
+    }
+}
+
+/// Valid page table entry. Aimed to be used to build page tables.
+pub const PTE_V: u64 = 1;
+
+/// Readable page. Aimed to be used to build page tables.
+pub const PTE_R: u64 = 1 << 1;
+
+/// Writable page. Aimed to be used to build page tables.
+pub const PTE_W: u64 = 1 << 2;
+
+/// Executable page. Aimed to be used to build page tables.
+pub const PTE_X: u64 = 1 << 3;
+
+/// Page accessible in user mode. Aimed to be used to build page tables.
+pub const PTE_U: u64 = 1 << 4;
+
+/// Global mapping. Aimed to be used to build page tables.
+pub const PTE_G: u64 = 1 << 5;
+
+/// Accessed page. It is set by the Mmu on the first access to the page.
+pub const PTE_A: u64 = 1 << 6;
+
+/// Dirty page. It is set by the Mmu on the first write to the page.
+pub const PTE_D: u64 = 1 << 7;
+
+/// Position of the physical page number within a page table entry.
+const PTE_PPN_SHIFT: u32 = 10;
+
+/// Mask of the physical page numbers in `satp` and in page table entries.
+const PPN_MASK: u64 = (1 << 44) - 1;
+
+/// Position of the MODE field within `satp`.
+const SATP_MODE_SHIFT: u32 = 60;
+
+/// Number of virtual page number bits translated by every page table level.
+const VPN_BITS: usize = 9;
+
+/// Virtual memory translation scheme, selected by the MODE field of `satp`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PagingMode {
+    /// No translation. Virtual addresses are physical addresses.
+    Bare,
+
+    /// Three-level page tables, 39-bit virtual addresses.
+    Sv39,
+
+    /// Four-level page tables, 48-bit virtual addresses.
+    Sv48,
+}
+
+impl PagingMode {
+    /// Returns the paging mode selected by the `satp` value `satp`, if it is
+    /// supported.
+    pub fn from_satp(satp: u64) -> Option<PagingMode> {
+        match satp >> SATP_MODE_SHIFT {
+            0 => Some(PagingMode::Bare),
+            8 => Some(PagingMode::Sv39),
+            9 => Some(PagingMode::Sv48),
+            _ => None,
+        }
+    }
+
+    /// Returns the number of page table levels.
+    fn levels(self) -> usize {
+        match self {
+            PagingMode::Bare => 0,
+            PagingMode::Sv39 => 3,
+            PagingMode::Sv48 => 4,
+        }
+    }
+}
+
+/// Privilege mode of the memory accesses. It decides which pages are
+/// accessible when paging is enabled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Privilege {
+    /// User mode. Only pages with `PTE_U` are accessible.
+    User,
+
+    /// Supervisor mode. Pages with `PTE_U` are only accessible for reading
+    /// and writing if SUM is set, and they are never executable.
+    Supervisor,
+}
+
+impl Privilege {
+    /// Returns the value encoding the privilege mode in snapshots.
+    fn to_u64(self) -> u64 {
+        match self {
+            Privilege::User => 0,
+            Privilege::Supervisor => 1,
+        }
+    }
+
+    /// Returns the privilege mode encoded in snapshots as `val`.
+    fn from_u64(val: u64) -> Result<Privilege, snapshot::Error> {
+        match val {
+            0 => Ok(Privilege::User),
+            1 => Ok(Privilege::Supervisor),
+            _ => Err(snapshot::Error::MalformedFile),
+        }
+    }
+}
+
+/// Cached translation of a virtual page.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Translation {
+    /// Physical page number.
+    number: usize,
+
+    /// Flags of the leaf page table entry when it was cached.
+    flags: u64,
+}
+
+/// Virtual memory state.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Paging {
+    /// Value of the `satp` register.
+    satp: u64,
+
+    /// Privilege mode of the memory accesses.
+    privilege: Privilege,
+
+    /// Permit supervisor accesses to user pages (`sstatus.SUM`).
+    sum: bool,
+
+    /// Make executable pages readable (`sstatus.MXR`).
+    mxr: bool,
+
+    /// Cached translations, indexed by virtual page number. As in hardware,
+    /// they are only flushed by `Mmu::sfence_vma` and `Mmu::set_satp`, so
+    /// page table updates require a fence to take effect.
+    translations: HashMap<usize, Translation>,
+}
+
+impl Default for Paging {
+    fn default() -> Paging {
+        Paging {
+            satp: 0,
+            privilege: Privilege::User,
+            sum: false,
+            mxr: false,
+            translations: HashMap::new(),
+        }
+    }
+}
+
+impl Paging {
+    /// Returns the paging mode selected by `satp`.
+    fn mode(&self) -> PagingMode {
+        // Only supported modes can be set.
+        PagingMode::from_satp(self.satp).unwrap()
+    }
+
+    /// Returns `true` if the page table entry flags `flags` allow an access
+    /// of type `access`.
+    fn allows(&self, flags: u64, access: Access) -> bool {
+        let user_page = flags & PTE_U != 0;
+
+        let privileged = match (self.privilege, access) {
+            (Privilege::User, _) => user_page,
+            (Privilege::Supervisor, Access::Exec) => !user_page,
+            (Privilege::Supervisor, _) => !user_page || self.sum,
+        };
+
+        let perm = match access {
+            Access::Read => {
+                flags & PTE_R != 0 || (self.mxr && flags & PTE_X != 0)
+            }
+            Access::Write => flags & PTE_W != 0,
+            Access::Exec => flags & PTE_X != 0,
+        };
+
+        privileged && perm
+    }
+}
+
+/// Memory watchpoint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Watchpoint {
+    /// Start of the watched memory range.
+    pub addr: VirtAddr,
+
+    /// Size of the watched memory range.
+    pub size: usize,
+
+    /// Type of the watched accesses.
+    pub access: Access,
+}
+
+impl Watchpoint {
+    /// Returns `true` if the memory range (`addr`..`addr` + `size`) overlaps
+    /// the watched range.
+    fn overlaps(&self, addr: VirtAddr, size: usize) -> bool {
+        *addr < *self.addr + self.size && *self.addr < *addr + size
+    }
+}
+
+/// Kind of a memory region.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RegionKind {
+    /// Executable code, e.g. an ELF text segment.
+    Code,
+
+    /// Global variables, e.g. an ELF data segment.
+    Global,
+
+    /// Memory allocated by the heap allocator.
+    Heap,
+
+    /// Stack.
+    Stack,
+
+    /// Inaccessible memory guarding other regions.
+    Guard,
+
+    /// Memory mapped at runtime.
+    Mmap,
+}
+
+impl fmt::Display for RegionKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RegionKind::Code => f.pad("code"),
+            RegionKind::Global => f.pad("global"),
+            RegionKind::Heap => f.pad("heap"),
+            RegionKind::Stack => f.pad("stack"),
+            RegionKind::Guard => f.pad("guard"),
+            RegionKind::Mmap => f.pad("mmap"),
         }
     }
 }
 
+impl RegionKind {
+    /// Returns the value encoding the region kind in snapshots.
+    fn to_u64(self) -> u64 {
+        match self {
+            RegionKind::Code => 0,
+            RegionKind::Global => 1,
+            RegionKind::Heap => 2,
+            RegionKind::Stack => 3,
+            RegionKind::Guard => 4,
+            RegionKind::Mmap => 5,
+        }
+    }
+
+    /// Returns the region kind encoded in snapshots as `val`.
+    fn from_u64(val: u64) -> Result<RegionKind, snapshot::Error> {
+        match val {
+            0 => Ok(RegionKind::Code),
+            1 => Ok(RegionKind::Global),
+            2 => Ok(RegionKind::Heap),
+            3 => Ok(RegionKind::Stack),
+            4 => Ok(RegionKind::Guard),
+            5 => Ok(RegionKind::Mmap),
+            _ => Err(snapshot::Error::MalformedFile),
+        }
+    }
+}
+
+/// Named memory region. Regions only describe the memory layout, they do not
+/// affect memory accesses.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Region {
+    /// Name of the region, e.g. the name of the file it was loaded from.
+    pub name: String,
+
+    /// Kind of the region.
+    pub kind: RegionKind,
+
+    /// Start of the region.
+    pub addr: VirtAddr,
+
+    /// Size of the region.
+    pub size: usize,
+
+    /// Permissions of the region when it was registered.
+    pub perms: Perm,
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:#012x}-{:#012x} {} {:<6} {}",
+            *self.addr,
+            *self.addr + self.size,
+            self.perms,
+            self.kind,
+            self.name
+        )
+    }
+}
+
+impl Region {
+    /// Returns `true` if the region contains `addr`.
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        *self.addr <= *addr && *addr - *self.addr < self.size
+    }
+}
+
 /// Memory permissions.
+#[repr(transparent)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Perm(pub u8);
 
@@ -146,7 +644,9 @@ impl Deref for Perm {
 }
 
 /// Virtual address.
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[derive(
+    Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash,
+)]
 pub struct VirtAddr(pub usize);
 
 impl fmt::Display for VirtAddr {
@@ -169,1095 +669,6101 @@ impl DerefMut for VirtAddr {
     }
 }
 
-/// Emulated memory management unit.
-#[derive(Debug, PartialEq, Eq)]
-pub struct Mmu {
-    /// Memory size.
-    size: usize,
-
-    /// Memory contents.
-    memory: Vec<u8>,
+/// Memory page. Guest memory is allocated on demand in pages of this size.
+pub const PAGE_SIZE: usize = 4096;
 
-    /// Byte-level memory permissions.
-    perms: Vec<Perm>,
+/// Offset of the pointer to the byte-level permissions within a page. The
+/// pointer is null if every byte of the page has the same permissions.
+/// Aimed to be used by the JIT compiler.
+pub const PAGE_PERMS_OFFSET: usize = PAGE_SIZE;
 
-    /// Block indices in `memory` which are dirty.
-    dirty: Vec<usize>,
+/// Offset of the permission summary within a page. Aimed to be used by the
+/// JIT compiler.
+pub const PAGE_SUMMARY_OFFSET: usize = PAGE_SIZE + 8;
 
-    /// Tracks which parts of memory have been dirtied.
-    dirty_bitmap: Vec<u64>,
+/// Offset of the flag set when the byte-level permissions of a page are
+/// modified without updating the permission counts. Aimed to be used by the
+/// JIT compiler.
+pub const PAGE_STALE_OFFSET: usize = PAGE_SIZE + 10;
 
-    /// Program break. Memory is allocated starting at this address.
-    brk: VirtAddr,
+/// Number of entries of the TLB. It must be a power of two.
+pub const TLB_SIZE: usize = 1024;
 
-    /// List of active allocations.
-    active_allocs: HashMap<VirtAddr, usize>,
-}
+/// Offset of the read page number within a TLB entry. Aimed to be used by the
+/// JIT compiler.
+pub const TLB_PAGE_NUMBER_OFFSET: usize = 0;
 
-impl Mmu {
-    /// Returns a new Mmu with a given memory `size`.
-    ///
-    /// # Panics
-    ///
-    /// This function panics if `size` is lower than `DIRTY_BLOCK_SIZE`.
-    pub fn new(size: usize) -> Mmu {
-        assert!(size >= DIRTY_BLOCK_SIZE, "invalid size");
+/// Offset of the write page number within a TLB entry. Aimed to be used by
+/// the JIT compiler.
+pub const TLB_WRITE_PAGE_NUMBER_OFFSET: usize = 8;
 
-        let dirty_size = (size + DIRTY_BLOCK_SIZE - 1) / DIRTY_BLOCK_SIZE;
-        let dirty_bitmap_size = dirty_size + 63 / 64;
+/// Offset of the page pointer within a TLB entry. Aimed to be used by the JIT
+/// compiler.
+pub const TLB_PAGE_PTR_OFFSET: usize = 16;
 
-        Mmu {
-            size,
-            memory: vec![0; size],
-            perms: vec![Perm(0); size],
-            dirty: Vec::with_capacity(dirty_size),
-            dirty_bitmap: vec![0; dirty_bitmap_size],
-            brk: VirtAddr(0),
-            active_allocs: HashMap::new(),
-        }
-    }
+/// Offset of the dirty bitmap pointer within a TLB entry. Aimed to be used by
+/// the JIT compiler.
+pub const TLB_DIRTY_PTR_OFFSET: usize = 24;
 
-    /// Returns the size of the memory.
-    pub fn size(&self) -> usize {
-        self.size
-    }
+/// Contents of an emulated memory page.
+#[repr(C)]
+#[derive(Debug, Clone)]
+struct PageData {
+    /// Memory contents.
+    memory: [u8; PAGE_SIZE],
+
+    /// Byte-level memory permissions. They are only allocated when the
+    /// bytes of the page do not share the same permissions, which is rare
+    /// and halves the memory used by the rest of the pages.
+    perms: Option<Box<[Perm; PAGE_SIZE]>>,
+
+    /// Permissions shared by every byte of the page. It is empty if any
+    /// byte is uninitialized, watched or mapped to I/O, so the accesses it
+    /// allows do not need to check the byte-level permissions. The JIT
+    /// compiler does not update it when initializing memory, so it may be
+    /// empty until the permissions of the page change again.
+    summary: Perm,
+
+    /// Permissions of every byte of the page when `perms` is `None`.
+    uniform: Perm,
+
+    /// If `true`, `counts` are out of date and must be recomputed from the
+    /// byte-level permissions. The JIT compiler sets it when initializing
+    /// memory.
+    stale: bool,
+
+    /// Number of bytes of the page with each permission bit set. They keep
+    /// the summary up to date without scanning the byte-level permissions.
+    counts: [u16; 8],
+}
 
-    /// Returns a copy of the MMU. It marks all memory as clean in the new
-    /// copy.
-    pub fn fork(&self) -> Mmu {
-        Mmu {
-            size: self.size,
-            memory: self.memory.clone(),
-            perms: self.perms.clone(),
-            dirty: Vec::with_capacity(self.dirty.capacity()),
-            dirty_bitmap: vec![0; self.dirty_bitmap.len()],
-            brk: self.brk,
-            active_allocs: self.active_allocs.clone(),
-        }
+impl PageData {
+    /// Returns an iterator over the permissions of the `len` bytes starting
+    /// at `offset`.
+    fn perms(
+        &self,
+        offset: usize,
+        len: usize,
+    ) -> impl Iterator<Item = Perm> + '_ {
+        let uniform = self.uniform;
+        let perms = self.perms.as_ref().map(|perms| &perms[offset..][..len]);
+
+        (0..len).map(move |i| match perms {
+            Some(perms) => perms[i],
+            None => uniform,
+        })
     }
 
-    /// Restores memory to the original state `other`.
-    pub fn reset(&mut self, other: &Mmu) {
-        // Restore memory and set as clean.
-        for &block in &self.dirty {
-            let start = block * DIRTY_BLOCK_SIZE;
-            let end = (block + 1) * DIRTY_BLOCK_SIZE;
+    /// Replaces the permissions of the `len` bytes starting at `offset` with
+    /// the result of calling `f` with the index of each byte within the
+    /// range and its current permissions. `f` must not have side effects,
+    /// because it may be called more than once for the same byte. The
+    /// permission summary is updated accordingly.
+    fn update_perms<F>(&mut self, offset: usize, len: usize, mut f: F)
+    where
+        F: FnMut(usize, Perm) -> Perm,
+    {
+        if self.perms.is_none() {
+            // Keep the page compact if every byte ends up with the same
+            // permissions.
+            let first = f(0, self.uniform);
+            let uniform = (1..len).all(|i| f(i, self.uniform) == first);
+
+            if uniform && first == self.uniform {
+                return;
+            } else if uniform && len == PAGE_SIZE {
+                self.uniform = first;
+                self.count_perms();
+                self.update_summary();
+                return;
+            }
 
-            self.dirty_bitmap[block / 64] = 0;
-            self.memory[start..end].copy_from_slice(&other.memory[start..end]);
-            self.perms[start..end].copy_from_slice(&other.perms[start..end]);
+            self.perms = Some(Box::new([self.uniform; PAGE_SIZE]));
+        } else if self.stale {
+            self.count_perms();
         }
-        self.dirty.clear();
 
-        self.brk = other.brk;
+        let counts = &mut self.counts;
+        let perms = self.perms.as_mut().unwrap();
+        for (i, perm) in perms[offset..][..len].iter_mut().enumerate() {
+            let new = f(i, *perm);
 
-        self.active_allocs.clear();
-        self.active_allocs.extend(other.active_allocs.iter());
+            let mut changed = *new ^ **perm;
+            while changed != 0 {
+                let bit = changed.trailing_zeros() as usize;
+                if *new & (1 << bit) != 0 {
+                    counts[bit] += 1;
+                } else {
+                    counts[bit] -= 1;
+                }
+                changed &= changed - 1;
+            }
 
-        if DEBUG_SANITY_CHECKS {
-            assert_eq!(self.memory, other.memory);
-            assert_eq!(self.perms, other.perms);
-            assert_eq!(self.dirty, Vec::new());
-            assert_eq!(self.dirty_bitmap, vec![0; other.dirty_bitmap.len()]);
-            assert_eq!(self.active_allocs, other.active_allocs);
+            *perm = new;
         }
-    }
 
-    /// Returns the length of the internal memory buffer.
-    pub fn memory_len(&self) -> usize {
-        self.memory.len()
+        self.update_summary();
     }
 
-    /// Returns a raw pointer to the internal memory buffer.
-    pub fn memory_ptr(&self) -> *const u8 {
-        self.memory.as_ptr()
-    }
+    /// Recomputes the permission counts of the page from scratch.
+    fn count_perms(&mut self) {
+        let mut counts = [0; 8];
+        match &self.perms {
+            Some(perms) => {
+                for perm in perms.iter() {
+                    for (bit, count) in counts.iter_mut().enumerate() {
+                        *count += (**perm >> bit & 1) as u16;
+                    }
+                }
+            }
+            None => {
+                for (bit, count) in counts.iter_mut().enumerate() {
+                    *count =
+                        (*self.uniform >> bit & 1) as u16 * PAGE_SIZE as u16;
+                }
+            }
+        }
 
-    /// Returns the capacity of the internal list of dirty blocks.
-    pub fn dirty_capacity(&self) -> usize {
-        self.dirty.capacity()
+        self.counts = counts;
+        self.stale = false;
     }
 
-    /// Returns the length of the internal list of dirty blocks.
-    pub fn dirty_len(&self) -> usize {
-        self.dirty.len()
-    }
+    /// Recomputes the permission summary of the page from the permission
+    /// counts, and frees the byte-level permissions if they are all the
+    /// same. It must be called after changing the byte-level permissions.
+    fn update_summary(&mut self) {
+        let mut and = 0;
+        let mut or = 0;
+        for (bit, &count) in self.counts.iter().enumerate() {
+            if count as usize == PAGE_SIZE {
+                and |= 1 << bit;
+            }
+            if count != 0 {
+                or |= 1 << bit;
+            }
+        }
 
-    /// Sets the length of the internal list of dirty blocks.
-    ///
-    /// # Safety
-    ///
-    /// `new_len` must be less than or equal to the capacity of the dirty list.
-    pub unsafe fn set_dirty_len(&mut self, new_len: usize) {
-        self.dirty.set_len(new_len)
-    }
+        if and == or {
+            self.perms = None;
+            self.uniform = Perm(and);
+        }
 
-    /// Returns a raw pointer to the internal list of dirty blocks.
-    pub fn dirty_ptr(&self) -> *const usize {
-        self.dirty.as_ptr()
+        self.summary = if or & (PERM_RAW | PERM_INTERNAL) != 0 {
+            Perm(0)
+        } else {
+            Perm(and)
+        };
     }
+}
 
-    /// Returns a raw pointer to the internal bitmap of dirty blocks.
-    pub fn dirty_bitmap_ptr(&self) -> *const u64 {
-        self.dirty_bitmap.as_ptr()
+impl PartialEq for PageData {
+    fn eq(&self, other: &PageData) -> bool {
+        self.memory[..] == other.memory[..]
+            && self.summary == other.summary
+            && self.perms(0, PAGE_SIZE).eq(other.perms(0, PAGE_SIZE))
     }
+}
 
-    /// Returns a raw pointer to the internal permissions buffer.
-    pub fn perms_ptr(&self) -> *const Perm {
-        self.perms.as_ptr()
-    }
+impl Eq for PageData {}
 
-    /// Returns the current program break.
-    pub fn brk(&self) -> VirtAddr {
-        self.brk
-    }
+/// Emulated memory page.
+///
+/// The contents of the page are shared between an Mmu and its forks, and
+/// they are copied the first time one of them writes to the page.
+#[derive(Debug, PartialEq, Eq)]
+struct Page {
+    /// Page contents.
+    data: Arc<PageData>,
 
-    /// Sets the program break.
-    pub fn set_brk(&mut self, addr: VirtAddr) {
-        self.brk = addr;
+    /// Tracks which blocks of the page have been dirtied. It is boxed, so
+    /// the JIT compiler can keep a pointer to it.
+    dirty: Box<u64>,
+}
+
+impl Page {
+    /// Returns a new page with zeroed contents and permissions.
+    fn new() -> Page {
+        Page {
+            data: Arc::new(PageData {
+                memory: [0; PAGE_SIZE],
+                perms: None,
+                summary: Perm(0),
+                uniform: Perm(0),
+                stale: false,
+                counts: [0; 8],
+            }),
+            dirty: Box::new(0),
+        }
     }
+}
 
-    /// Set memory permissions in the given range.
-    pub fn set_perms(
-        &mut self,
-        addr: VirtAddr,
-        size: usize,
-        perms: Perm,
-    ) -> Result<(), Error> {
-        let end = addr
-            .checked_add(size)
-            .ok_or(Error::AddressIntegerOverflow { addr, size })?;
+/// Entry of the TLB used by the JIT compiler to translate virtual addresses.
+///
+/// The TLB is direct-mapped: the page number `n` can only be cached in the
+/// entry `n % TLB_SIZE`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TlbEntry {
+    /// Page number of the cached page, used to translate reads.
+    /// `usize::MAX` if the entry is empty.
+    pub page_number: usize,
 
-        self.perms
-            .get_mut(*addr..end)
-            .ok_or(Error::InvalidAddress { addr, size })?
-            .iter_mut()
-            .for_each(|p| *p = perms);
+    /// Page number of the cached page, used to translate writes.
+    /// `usize::MAX` if the entry is empty or the page is shared with other
+    /// Mmus, so it must be copied before writing to it.
+    pub write_page_number: usize,
 
-        self.update_dirty(addr, size);
+    /// Host address of the contents of the cached page.
+    pub page_ptr: usize,
 
-        Ok(())
+    /// Host address of the dirty bitmap of the cached page.
+    pub dirty_ptr: usize,
+}
+
+impl Default for TlbEntry {
+    fn default() -> TlbEntry {
+        TlbEntry {
+            page_number: usize::MAX,
+            write_page_number: usize::MAX,
+            page_ptr: 0,
+            dirty_ptr: 0,
+        }
     }
+}
 
-    /// Returns a slice with the permissions of the memory range
-    /// (`addr`..`addr` + `size`).
-    pub fn perms(
-        &self,
-        addr: VirtAddr,
-        size: usize,
-    ) -> Result<&[Perm], Error> {
-        let end = addr
-            .checked_add(size)
-            .ok_or(Error::AddressIntegerOverflow { addr, size })?;
+/// Alignment of the heap chunks.
+const CHUNK_ALIGN: usize = 16;
+
+/// Maximum number of chunks kept in the quarantine when its size is not
+/// limited. Older chunks are forgotten, but they are never reused.
+const MAX_UNLIMITED_QUARANTINE_CHUNKS: usize = 4096;
+
+/// Configuration of the heap allocator.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AllocConfig {
+    /// Maximum number of bytes kept in the quarantine. Freed chunks stay in
+    /// the quarantine, with no permissions, until they are evicted in FIFO
+    /// order and can be reused. If `None`, freed memory is never reused, and
+    /// only the most recently freed chunks are kept in the quarantine to
+    /// explain use-after-free errors.
+    pub quarantine_size: Option<usize>,
+
+    /// Minimum size of the region with no permissions placed before every
+    /// allocation.
+    pub left_redzone: usize,
+
+    /// Minimum size of the region with no permissions placed after every
+    /// allocation.
+    pub right_redzone: usize,
+}
 
-        self.perms
-            .get(*addr..end)
-            .ok_or(Error::InvalidAddress { addr, size })
+impl Default for AllocConfig {
+    /// Returns a configuration where memory is never reused and a guard
+    /// region of at least 0xff0 bytes follows every allocation.
+    fn default() -> AllocConfig {
+        AllocConfig {
+            quarantine_size: None,
+            left_redzone: 0,
+            right_redzone: 0xff0,
+        }
     }
+}
 
-    /// Given a memory range and the expected permissions, this function will
-    /// return true if every byte in the specified region satisfies those
-    /// permissions. Otherwise, the function will return false.
-    pub fn check_perms(
-        &self,
-        addr: VirtAddr,
-        size: usize,
-        perms: Perm,
-    ) -> Result<(), Error> {
-        let end = addr
-            .checked_add(size)
-            .ok_or(Error::AddressIntegerOverflow { addr, size })?;
+/// Policy deciding which allocations fail, aimed to exercise the handling of
+/// allocation failures. Allocations are numbered from 0 in the order they are
+/// requested.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AllocFailPolicy {
+    /// Only the allocation with the given number fails.
+    Nth(usize),
+
+    /// The allocations of more than the given number of bytes fail.
+    SizeAbove(usize),
+
+    /// The allocation number `n` fails if the bit `n` of the bytes is set,
+    /// starting from the least significant bit of the first byte. The
+    /// allocations beyond the last bit do not fail. Aimed to be driven by
+    /// the fuzz input.
+    Bits(Vec<u8>),
+}
 
-        let range = self
-            .perms
-            .get(*addr..end)
-            .ok_or(Error::InvalidAddress { addr, size })?;
-
-        for p in range.iter() {
-            // At this point, addr + size cannot overflow. Given that i < size,
-            // checked_add is not needed.
-            if (*perms & PERM_READ != 0) && (**p & PERM_RAW != 0) {
-                return Err(Error::UninitFault { addr, size });
-            }
-
-            if **p & *perms != *perms {
-                if *perms & PERM_READ != 0 {
-                    return Err(Error::ReadFault { addr, size });
-                } else if *perms & PERM_WRITE != 0 {
-                    return Err(Error::WriteFault { addr, size });
-                } else if *perms & PERM_EXEC != 0 {
-                    return Err(Error::ExecFault { addr, size });
-                } else {
-                    return Err(Error::UnkFault {
-                        addr,
-                        size,
-                        exp: perms,
-                        cur: *p,
-                    });
-                }
+impl AllocFailPolicy {
+    /// Returns `true` if the allocation number `index` of `size` bytes must
+    /// fail.
+    fn fails(&self, index: usize, size: usize) -> bool {
+        match self {
+            AllocFailPolicy::Nth(n) => index == *n,
+            AllocFailPolicy::SizeAbove(max_size) => size > *max_size,
+            AllocFailPolicy::Bits(bytes) => {
+                bytes.get(index / 8).map(|byte| byte >> (index % 8) & 1)
+                    == Some(1)
             }
         }
-
-        Ok(())
     }
+}
 
-    /// Copy the bytes in `src` to the given memory address. This function will
-    /// fail if the destination memory is not writable.
-    pub fn write(&mut self, addr: VirtAddr, src: &[u8]) -> Result<(), Error> {
-        self.write_with_perms(addr, src, Perm(PERM_WRITE))
-    }
+/// Allocation made to fail by an `AllocFailPolicy`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FailedAlloc {
+    /// Number of the allocation.
+    pub index: usize,
 
-    /// Copy the bytes in `src` to the given memory address. This function will
-    /// fail if the destination memory does not satisfy the expected
-    /// permissions. Memory marked as `PERM_RAW` will be marked as `PERM_READ`
-    /// only if `PERM_WRITE` is within the expected permissions.
-    pub fn write_with_perms(
-        &mut self,
-        addr: VirtAddr,
-        src: &[u8],
-        perms: Perm,
-    ) -> Result<(), Error> {
-        let size = src.len();
+    /// Requested size.
+    pub size: usize,
 
-        // Check if the destination memory range is writable.
-        self.check_perms(addr, size, perms)?;
+    /// Location where the allocation was requested.
+    pub site: AllocSite,
+}
 
-        let end = *addr + size;
+impl fmt::Display for FailedAlloc {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "allocation #{} of {} bytes made to fail at:\n{}",
+            self.index, self.size, self.site
+        )
+    }
+}
 
-        // Update memory contents
-        self.memory
-            .get_mut(*addr..end)
-            .ok_or(Error::InvalidAddress { addr, size })?
-            .copy_from_slice(src);
-
-        // Add PERM_READ and remove PERM_RAW in case of RAW.
-        if *perms & PERM_WRITE != 0 {
-            self.perms
-                .get_mut(*addr..end)
-                .ok_or(Error::InvalidAddress { addr, size })?
-                .iter_mut()
-                .filter(|p| ***p & PERM_RAW != 0)
-                .for_each(|p| *p = Perm((**p | PERM_READ) & !PERM_RAW));
-        }
+/// Guest location where memory was allocated or freed.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct AllocSite {
+    /// Guest PC, usually the address of the allocation function.
+    pub pc: VirtAddr,
 
-        self.update_dirty(addr, size);
+    /// Return addresses of the calling functions, innermost first.
+    pub stack: Vec<VirtAddr>,
+}
 
+impl fmt::Display for AllocSite {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "    #0 {}", self.pc)?;
+        for (i, addr) in self.stack.iter().enumerate() {
+            write!(f, "\n    #{} {}", i + 1, addr)?;
+        }
         Ok(())
     }
+}
 
-    /// Copy the data starting at the specified memory address into `dst`.
-    /// This function will fail if the source memory is not readable.
-    pub fn read(&self, addr: VirtAddr, dst: &mut [u8]) -> Result<(), Error> {
-        self.read_with_perms(addr, dst, Perm(PERM_READ))
+impl AllocSite {
+    /// Writes the allocation site to the snapshot `w`.
+    fn write_snapshot<W: Write>(
+        &self,
+        w: &mut Writer<W>,
+    ) -> Result<(), snapshot::Error> {
+        w.write_usize(*self.pc)?;
+        w.write_usize(self.stack.len())?;
+        for addr in &self.stack {
+            w.write_usize(**addr)?;
+        }
+        Ok(())
     }
 
-    /// Copy the data starting at the specified memory address into `dst`.
-    /// This function will fail if the source memory does not satisfy the
-    /// expected permissions.
-    pub fn read_with_perms(
-        &self,
-        addr: VirtAddr,
-        dst: &mut [u8],
-        perms: Perm,
-    ) -> Result<(), Error> {
-        let size = dst.len();
+    /// Reads an allocation site from the snapshot `r`.
+    fn read_snapshot<R: Read>(
+        r: &mut Reader<R>,
+    ) -> Result<AllocSite, snapshot::Error> {
+        let pc = VirtAddr(r.read_usize()?);
 
-        // Check if the source memory range is readable.
-        self.check_perms(addr, size, perms)?;
+        let mut stack = Vec::new();
+        for _ in 0..r.read_usize()? {
+            stack.push(VirtAddr(r.read_usize()?));
+        }
 
-        let src = self
-            .memory
-            .get(*addr..*addr + size)
-            .ok_or(Error::InvalidAddress { addr, size })?;
+        Ok(AllocSite { pc, stack })
+    }
+}
 
-        dst.copy_from_slice(src);
+/// Heap allocation originating undefined memory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Origin {
+    /// Address of the allocated memory.
+    pub addr: VirtAddr,
 
-        Ok(())
-    }
+    /// Size of the allocated memory.
+    pub size: usize,
 
-    /// Copy the bytes in `src` to the given memory address. This function
-    /// does not check memory permissions.
-    pub fn poke(&mut self, addr: VirtAddr, src: &[u8]) -> Result<(), Error> {
-        self.write_with_perms(addr, src, Perm(0))
-    }
+    /// Allocation site.
+    pub site: AllocSite,
+}
 
-    /// Copy the data starting at the specified memory address into `dst`.
-    /// This function does not check memory permissions.
-    pub fn peek(&self, addr: VirtAddr, dst: &mut [u8]) -> Result<(), Error> {
-        self.read_with_perms(addr, dst, Perm(0))
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "uninitialized value created by an allocation of {} bytes at \
+             {}:\n{}",
+            self.size, self.addr, self.site
+        )
     }
+}
 
-    /// Compute dirty blocks and bitmap. It does not check if the memory range
-    /// is valid.
-    fn update_dirty(&mut self, addr: VirtAddr, size: usize) {
-        let block_start = *addr / DIRTY_BLOCK_SIZE;
-        // Calculate the start of the next block. It takes into account corner
-        // cases like `end` being equal to the start of the next block.
-        let block_end =
-            (*addr + size + (DIRTY_BLOCK_SIZE - 1)) / DIRTY_BLOCK_SIZE;
-
-        for block in block_start..block_end {
-            let idx = block / 64;
-            let bit = block % 64;
+/// Identifier of the origin of undefined memory within a Mmu.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct OriginId(u32);
 
-            if self.dirty_bitmap[idx] & (1 << bit) == 0 {
-                self.dirty_bitmap[idx] |= 1 << bit;
-                self.dirty.push(block);
-            }
-        }
-    }
+impl OriginId {
+    /// Identifier of undefined memory with unknown origin.
+    pub const UNKNOWN: OriginId = OriginId(0);
+}
 
-    /// Write an integer value into a given memory address. This function will
-    /// fail if the destination memory is not writable.
-    pub fn write_int<T: LeBytes>(
-        &mut self,
-        addr: VirtAddr,
-        value: T::Target,
-    ) -> Result<(), Error> {
-        let bytes = T::to_le_bytes(value);
-        let src = &bytes[..mem::size_of::<T::Target>()];
-        self.write(addr, src)?;
-        Ok(())
-    }
+/// Bit-precise definedness of a byte of memory.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Definedness {
+    /// Mask of the undefined bits.
+    pub undef: u8,
 
-    /// Write an integer value into a given memory address. This function will
-    /// fail if the destination memory does not satisfy the expected
-    /// permissions.
-    pub fn write_int_with_perms<T: LeBytes>(
-        &mut self,
-        addr: VirtAddr,
-        value: T::Target,
-        perms: Perm,
-    ) -> Result<(), Error> {
-        let bytes = T::to_le_bytes(value);
-        let src = &bytes[..mem::size_of::<T::Target>()];
-        self.write_with_perms(addr, src, perms)?;
-        Ok(())
-    }
+    /// Origin of the undefined bits.
+    pub origin: OriginId,
+}
 
-    /// Read the data starting at the specified memory address into an integer.
-    /// This function will fail if the source memory is not readable.
-    pub fn read_int<T: LeBytes>(
-        &self,
-        addr: VirtAddr,
-    ) -> Result<T::Target, Error> {
-        let mut bytes = [0u8; 16];
-        let dst = &mut bytes[..mem::size_of::<T::Target>()];
-        self.read(addr, dst)?;
-        Ok(T::from_le_bytes(bytes))
-    }
+/// Description of a memory address relative to the heap allocation it
+/// belongs to, including its redzones. Aimed to explain heap errors.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeapReport {
+    /// Described address.
+    pub addr: VirtAddr,
 
-    /// Copy the data starting at the specified memory address into `dst`.
-    /// This function will fail if the source memory does not satisfy the
-    /// expected permissions.
-    pub fn read_int_with_perms<T: LeBytes>(
-        &self,
-        addr: VirtAddr,
-        perms: Perm,
-    ) -> Result<T::Target, Error> {
-        let mut bytes = [0u8; 16];
-        let dst = &mut bytes[..mem::size_of::<T::Target>()];
-        self.read_with_perms(addr, dst, perms)?;
-        Ok(T::from_le_bytes(bytes))
-    }
+    /// Address of the allocation.
+    pub region: VirtAddr,
 
-    /// Write an integer value into a given memory address. This function does
-    /// not check memory permissions.
-    pub fn poke_int<T: LeBytes>(
-        &mut self,
-        addr: VirtAddr,
-        value: T::Target,
-    ) -> Result<(), Error> {
-        let bytes = T::to_le_bytes(value);
-        let src = &bytes[..mem::size_of::<T::Target>()];
-        self.poke(addr, src)?;
-        Ok(())
-    }
+    /// Size of the allocation.
+    pub size: usize,
 
-    /// Read the data starting at the specified memory address into an integer.
-    /// This function does not check memory permissions.
-    pub fn peek_int<T: LeBytes>(
-        &self,
-        addr: VirtAddr,
-    ) -> Result<T::Target, Error> {
-        let mut bytes = [0u8; 16];
-        let dst = &mut bytes[..mem::size_of::<T::Target>()];
-        self.peek(addr, dst)?;
-        Ok(T::from_le_bytes(bytes))
-    }
+    /// Location where the allocation was made.
+    pub alloc_site: AllocSite,
 
-    /// Strict memory allocator. This function tries to allocate `size` bytes
-    /// and returns the address of the allocated memory. If `raw` is true, it
-    /// is also able to detect accesses to unitialized data.
-    pub fn malloc(
-        &mut self,
-        size: usize,
-        raw: bool,
-    ) -> Result<VirtAddr, Error> {
-        // 16-byte alignment. A guard region is kept between chunks. This
-        // region has 0 permissions, which allows to detect OOB.
-        let aligned_size =
-            size.checked_add(0xfff)
-                .ok_or(Error::AddressIntegerOverflow {
-                    addr: self.brk,
-                    size,
-                })?
-                & !0xf;
+    /// Location where the allocation was freed, if it was.
+    pub free_site: Option<AllocSite>,
+}
 
-        // Make sure the full memory region (allocated bytes + guard region) is
-        // valid and starts with 0 permissions.
-        self.set_perms(self.brk, aligned_size, Perm(0))?;
+impl fmt::Display for HeapReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let end = *self.region + self.size;
 
-        // Set permissions according to the `raw` value. Which enables the
-        // detection of uninit faults.
-        let perms = if raw {
-            Perm(PERM_WRITE | PERM_RAW)
+        let (offset, relation) = if *self.addr < *self.region {
+            (*self.region - *self.addr, "to the left of")
+        } else if *self.addr >= end {
+            (*self.addr - end, "to the right of")
         } else {
-            Perm(PERM_WRITE | PERM_READ)
+            (*self.addr - *self.region, "inside of")
         };
-        self.set_perms(self.brk, size, perms)?;
-
-        // Update the list of active allocations.
-        self.active_allocs.insert(self.brk, size);
 
-        // Update brk and save the previous value, where the allocated memory
-        // starts.
-        let prev_brk = self.brk;
-        *self.brk += aligned_size;
+        writeln!(
+            f,
+            "{} is located {} bytes {} {}-byte region [{}, {:#x})",
+            self.addr, offset, relation, self.size, self.region, end
+        )?;
+        write!(f, "allocated at:\n{}", self.alloc_site)?;
+        if let Some(free_site) = &self.free_site {
+            write!(f, "\nfreed at:\n{}", free_site)?;
+        }
 
-        Ok(prev_brk)
+        Ok(())
     }
+}
 
-    /// Strict memory free.
-    pub fn free(&mut self, addr: VirtAddr) -> Result<(), Error> {
-        if let Some(size) = self.active_allocs.remove(&addr) {
-            // The permissions of the freed memory are set to 0, which allows
-            // to detect UAF.
-            self.set_perms(addr, size, Perm(0))?;
-            Ok(())
-        } else {
-            // If the address is not in the list of active allocations, this is
-            // an invalid free. This can happen due to a double free or a
-            // corrupted heap.
-            Err(Error::InvalidFree { addr })
-        }
-    }
+/// Kind of memory leak.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LeakKind {
+    /// The allocation is not reachable.
+    Direct,
 
-    /// Returns the size of the allocation corresponding to the virtual address
-    /// `addr`.
-    pub fn alloc_size(&self, addr: VirtAddr) -> Option<usize> {
-        if let Some(&size) = self.active_allocs.get(&addr) {
-            Some(size)
-        } else {
-            None
+    /// The allocation is only reachable from other leaked allocations.
+    Indirect,
+}
+
+impl fmt::Display for LeakKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LeakKind::Direct => write!(f, "direct"),
+            LeakKind::Indirect => write!(f, "indirect"),
         }
     }
 }
 
-/// Types implementing this trait can be converted to and from little-endian
-/// bytes.
-pub trait LeBytes {
-    type Target;
+/// Heap allocation which was never freed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Leak {
+    /// Address of the allocation.
+    pub addr: VirtAddr,
 
-    /// Convert an array of bytes into a value of the associated type.
-    fn from_le_bytes(bytes: [u8; 16]) -> Self::Target;
+    /// Size of the allocation.
+    pub size: usize,
 
-    /// Convert a value of the associated type into an array of bytes.
-    fn to_le_bytes(value: Self::Target) -> [u8; 16];
+    /// Location where the allocation was made.
+    pub site: AllocSite,
+
+    /// Kind of leak.
+    pub kind: LeakKind,
 }
 
-macro_rules! impl_le_bytes {
-    ($Ty: ty) => {
-        impl LeBytes for $Ty {
-            type Target = $Ty;
+impl fmt::Display for Leak {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} leak of {} bytes at {} allocated at:\n{}",
+            self.kind, self.size, self.addr, self.site
+        )
+    }
+}
 
-            fn from_le_bytes(bytes: [u8; 16]) -> $Ty {
-                let src = &bytes[..mem::size_of::<$Ty>()];
+/// Memory range whose bytes differ between two Mmus. The bytes are either
+/// memory contents or permissions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RangeDiff {
+    /// Start of the range.
+    pub addr: VirtAddr,
 
-                <$Ty>::from_le_bytes(src.try_into().unwrap())
+    /// Bytes in the base Mmu.
+    pub old: Vec<u8>,
+
+    /// Bytes in the compared Mmu.
+    pub new: Vec<u8>,
+}
+
+impl fmt::Display for RangeDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        const LINE_LEN: usize = 16;
+
+        let hexdump = |bytes: &[u8]| -> String {
+            let hex: Vec<String> =
+                bytes.iter().map(|b| format!("{:02x}", b)).collect();
+            let ascii: String = bytes
+                .iter()
+                .map(|&b| if b.is_ascii_graphic() { b as char } else { '.' })
+                .collect();
+            format!("{:<47}  |{}|", hex.join(" "), ascii)
+        };
+
+        for (i, (old, new)) in self
+            .old
+            .chunks(LINE_LEN)
+            .zip(self.new.chunks(LINE_LEN))
+            .enumerate()
+        {
+            let addr = *self.addr + i * LINE_LEN;
+            writeln!(f, "- {:#012x}  {}", addr, hexdump(old))?;
+            writeln!(f, "+ {:#012x}  {}", addr, hexdump(new))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Allocation that differs between two Mmus.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AllocDiff {
+    /// Address of the allocation.
+    pub addr: VirtAddr,
+
+    /// Size of the allocation in the base Mmu, if it is active there.
+    pub old: Option<usize>,
+
+    /// Size of the allocation in the compared Mmu, if it is active there.
+    pub new: Option<usize>,
+}
+
+impl fmt::Display for AllocDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let size = |size: Option<usize>| match size {
+            Some(size) => format!("{} bytes", size),
+            None => "none".to_string(),
+        };
+
+        write!(
+            f,
+            "allocation {}: {} -> {}",
+            self.addr,
+            size(self.old),
+            size(self.new)
+        )
+    }
+}
+
+/// Differences between two Mmus, as returned by `Mmu::diff`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct MmuDiff {
+    /// Memory ranges whose contents differ, sorted by address.
+    pub contents: Vec<RangeDiff>,
+
+    /// Memory ranges whose permissions differ, sorted by address.
+    pub perms: Vec<RangeDiff>,
+
+    /// Program breaks of the base and the compared Mmu, if they differ.
+    pub brk: Option<(VirtAddr, VirtAddr)>,
+
+    /// Active allocations that differ, sorted by address.
+    pub allocs: Vec<AllocDiff>,
+}
+
+impl MmuDiff {
+    /// Returns `true` if both Mmus are equal.
+    pub fn is_empty(&self) -> bool {
+        self.contents.is_empty()
+            && self.perms.is_empty()
+            && self.brk.is_none()
+            && self.allocs.is_empty()
+    }
+}
+
+impl fmt::Display for MmuDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some((old, new)) = self.brk {
+            writeln!(f, "brk: {} -> {}", old, new)?;
+        }
+        for alloc in &self.allocs {
+            writeln!(f, "{}", alloc)?;
+        }
+        for range in &self.contents {
+            writeln!(
+                f,
+                "contents at {} ({} bytes):",
+                range.addr,
+                range.old.len()
+            )?;
+            write!(f, "{}", range)?;
+        }
+        for range in &self.perms {
+            writeln!(
+                f,
+                "perms at {} ({} bytes):",
+                range.addr,
+                range.old.len()
+            )?;
+            write!(f, "{}", range)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Appends to `ranges` the bytes that differ between `old` and `new`, which
+/// start at `addr`. Differences contiguous to the last range extend it.
+fn diff_bytes(
+    ranges: &mut Vec<RangeDiff>,
+    addr: usize,
+    old: &[u8],
+    new: &[u8],
+) {
+    if old == new {
+        return;
+    }
+
+    for (i, (&o, &n)) in old.iter().zip(new).enumerate() {
+        if o == n {
+            continue;
+        }
+
+        let cur = addr + i;
+        match ranges.last_mut() {
+            Some(range) if *range.addr + range.old.len() == cur => {
+                range.old.push(o);
+                range.new.push(n);
+            }
+            _ => ranges.push(RangeDiff {
+                addr: VirtAddr(cur),
+                old: vec![o],
+                new: vec![n],
+            }),
+        }
+    }
+}
+
+/// Heap allocation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Alloc {
+    /// Requested size.
+    size: usize,
+
+    /// Address of the chunk holding the allocation, including its redzones.
+    chunk_addr: VirtAddr,
+
+    /// Size of the chunk.
+    chunk_size: usize,
+
+    /// Location where the allocation was made.
+    alloc_site: AllocSite,
+
+    /// Location where the allocation was freed, if it was.
+    free_site: Option<AllocSite>,
+}
+
+/// State of the heap allocator.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct Heap {
+    /// Allocator configuration.
+    config: AllocConfig,
+
+    /// Active allocations, indexed by address. It is shared with the heap
+    /// it was reset from until it is modified, so resetting an unmodified
+    /// heap does not copy it.
+    active: Arc<HashMap<VirtAddr, Alloc>>,
+
+    /// Free chunks, indexed by address. The value is the chunk size.
+    free_by_addr: BTreeMap<VirtAddr, usize>,
+
+    /// Free chunks, sorted by size and then by address.
+    free_by_size: BTreeSet<(usize, VirtAddr)>,
+
+    /// Freed allocations waiting to be reused, in FIFO order, indexed by
+    /// address. It is shared like `active`.
+    quarantine: Arc<VecDeque<(VirtAddr, Alloc)>>,
+
+    /// Sum of the sizes of the chunks in the quarantine.
+    quarantine_len: usize,
+
+    /// Policy deciding which allocations fail, if any.
+    fail_policy: Option<AllocFailPolicy>,
+
+    /// Number of allocations requested so far.
+    alloc_count: usize,
+
+    /// Allocations made to fail by the policy, in order.
+    failed: Vec<FailedAlloc>,
+}
+
+impl Alloc {
+    /// Writes the allocation to the snapshot `w`.
+    fn write_snapshot<W: Write>(
+        &self,
+        w: &mut Writer<W>,
+    ) -> Result<(), snapshot::Error> {
+        w.write_usize(self.size)?;
+        w.write_usize(*self.chunk_addr)?;
+        w.write_usize(self.chunk_size)?;
+        self.alloc_site.write_snapshot(w)?;
+        w.write_bool(self.free_site.is_some())?;
+        if let Some(free_site) = &self.free_site {
+            free_site.write_snapshot(w)?;
+        }
+        Ok(())
+    }
+
+    /// Reads an allocation from the snapshot `r`.
+    fn read_snapshot<R: Read>(
+        r: &mut Reader<R>,
+    ) -> Result<Alloc, snapshot::Error> {
+        let size = r.read_usize()?;
+        let chunk_addr = VirtAddr(r.read_usize()?);
+        let chunk_size = r.read_usize()?;
+        let alloc_site = AllocSite::read_snapshot(r)?;
+        let free_site = if r.read_bool()? {
+            Some(AllocSite::read_snapshot(r)?)
+        } else {
+            None
+        };
+
+        Ok(Alloc {
+            size,
+            chunk_addr,
+            chunk_size,
+            alloc_site,
+            free_site,
+        })
+    }
+}
+
+impl Heap {
+    /// Writes the state of the allocator to the snapshot `w`.
+    fn write_snapshot<W: Write>(
+        &self,
+        w: &mut Writer<W>,
+    ) -> Result<(), snapshot::Error> {
+        w.write_bool(self.config.quarantine_size.is_some())?;
+        w.write_usize(self.config.quarantine_size.unwrap_or(0))?;
+        w.write_usize(self.config.left_redzone)?;
+        w.write_usize(self.config.right_redzone)?;
+
+        // Allocations are sorted, so equal heaps produce equal snapshots.
+        let mut active: Vec<_> = self.active.iter().collect();
+        active.sort_unstable_by_key(|(addr, _)| **addr);
+        w.write_usize(active.len())?;
+        for (addr, alloc) in active {
+            w.write_usize(**addr)?;
+            alloc.write_snapshot(w)?;
+        }
+
+        w.write_usize(self.free_by_addr.len())?;
+        for (addr, size) in &self.free_by_addr {
+            w.write_usize(**addr)?;
+            w.write_usize(*size)?;
+        }
+
+        w.write_usize(self.quarantine.len())?;
+        for (addr, alloc) in self.quarantine.iter() {
+            w.write_usize(**addr)?;
+            alloc.write_snapshot(w)?;
+        }
+
+        w.write_usize(self.alloc_count)?;
+        w.write_usize(self.failed.len())?;
+        for failed in &self.failed {
+            w.write_usize(failed.index)?;
+            w.write_usize(failed.size)?;
+            failed.site.write_snapshot(w)?;
+        }
+
+        Ok(())
+    }
+
+    /// Reads the state of an allocator from the snapshot `r`.
+    fn read_snapshot<R: Read>(
+        r: &mut Reader<R>,
+    ) -> Result<Heap, snapshot::Error> {
+        let mut heap = Heap::default();
+
+        let has_quarantine_size = r.read_bool()?;
+        let quarantine_size = r.read_usize()?;
+        heap.config = AllocConfig {
+            quarantine_size: has_quarantine_size.then_some(quarantine_size),
+            left_redzone: r.read_usize()?,
+            right_redzone: r.read_usize()?,
+        };
+
+        for _ in 0..r.read_usize()? {
+            let addr = VirtAddr(r.read_usize()?);
+            let alloc = Alloc::read_snapshot(r)?;
+            Arc::make_mut(&mut heap.active).insert(addr, alloc);
+        }
+
+        for _ in 0..r.read_usize()? {
+            let addr = VirtAddr(r.read_usize()?);
+            let size = r.read_usize()?;
+            heap.free_by_addr.insert(addr, size);
+            heap.free_by_size.insert((size, addr));
+        }
+
+        for _ in 0..r.read_usize()? {
+            let addr = VirtAddr(r.read_usize()?);
+            let alloc = Alloc::read_snapshot(r)?;
+            heap.quarantine_len = heap
+                .quarantine_len
+                .checked_add(alloc.chunk_size)
+                .ok_or(snapshot::Error::MalformedFile)?;
+            Arc::make_mut(&mut heap.quarantine).push_back((addr, alloc));
+        }
+
+        heap.alloc_count = r.read_usize()?;
+        for _ in 0..r.read_usize()? {
+            heap.failed.push(FailedAlloc {
+                index: r.read_usize()?,
+                size: r.read_usize()?,
+                site: AllocSite::read_snapshot(r)?,
+            });
+        }
+
+        Ok(heap)
+    }
+
+    /// Restores the allocator to the original state `other`.
+    fn reset(&mut self, other: &Heap) {
+        self.config.clone_from(&other.config);
+
+        self.active.clone_from(&other.active);
+
+        self.free_by_addr.clone_from(&other.free_by_addr);
+        self.free_by_size.clone_from(&other.free_by_size);
+
+        self.quarantine.clone_from(&other.quarantine);
+        self.quarantine_len = other.quarantine_len;
+
+        // The policy is not part of the state, it is kept across resets.
+        self.alloc_count = other.alloc_count;
+        self.failed.clone_from(&other.failed);
+    }
+
+    /// Removes the smallest free chunk of at least `size` bytes from the
+    /// free list and returns its address and size.
+    fn take_free(&mut self, size: usize) -> Option<(VirtAddr, usize)> {
+        let &(chunk_size, chunk_addr) =
+            self.free_by_size.range((size, VirtAddr(0))..).next()?;
+
+        self.free_by_size.remove(&(chunk_size, chunk_addr));
+        self.free_by_addr.remove(&chunk_addr);
+
+        Some((chunk_addr, chunk_size))
+    }
+
+    /// Adds a chunk to the free list, merging it with the adjacent free
+    /// chunks.
+    fn insert_free(&mut self, mut addr: VirtAddr, mut size: usize) {
+        // Merge with the previous chunk.
+        let prev = self.free_by_addr.range(..addr).next_back();
+        if let Some((&prev_addr, &prev_size)) = prev {
+            if *prev_addr + prev_size == *addr {
+                self.free_by_addr.remove(&prev_addr);
+                self.free_by_size.remove(&(prev_size, prev_addr));
+                addr = prev_addr;
+                size += prev_size;
+            }
+        }
+
+        // Merge with the next chunk.
+        let next = VirtAddr(*addr + size);
+        if let Some(next_size) = self.free_by_addr.remove(&next) {
+            self.free_by_size.remove(&(next_size, next));
+            size += next_size;
+        }
+
+        self.free_by_addr.insert(addr, size);
+        self.free_by_size.insert((size, addr));
+    }
+
+    /// Puts a freed allocation in the quarantine, moving the oldest chunks
+    /// to the free list if the quarantine is full.
+    fn quarantine(&mut self, addr: VirtAddr, alloc: Alloc) {
+        let quarantine = Arc::make_mut(&mut self.quarantine);
+        self.quarantine_len += alloc.chunk_size;
+        quarantine.push_back((addr, alloc));
+
+        let max_len = match self.config.quarantine_size {
+            Some(max_len) => max_len,
+            None => {
+                // Forget the oldest chunk instead of reusing it, so the
+                // quarantine, which is copied when modified after a reset,
+                // stays small.
+                if quarantine.len() > MAX_UNLIMITED_QUARANTINE_CHUNKS {
+                    let (_, alloc) = quarantine.pop_front().unwrap();
+                    self.quarantine_len -= alloc.chunk_size;
+                }
+                return;
+            }
+        };
+
+        while self.quarantine_len > max_len {
+            let (_, alloc) =
+                Arc::make_mut(&mut self.quarantine).pop_front().unwrap();
+            self.quarantine_len -= alloc.chunk_size;
+            self.insert_free(alloc.chunk_addr, alloc.chunk_size);
+        }
+    }
+
+    /// Returns the active or quarantined allocation whose chunk contains
+    /// `addr`, as well as the address of the allocation.
+    fn find(&self, addr: VirtAddr) -> Option<(VirtAddr, &Alloc)> {
+        let quarantined =
+            self.quarantine.iter().map(|(addr, alloc)| (addr, alloc));
+
+        self.active
+            .iter()
+            .chain(quarantined)
+            .find(|(_, alloc)| {
+                *addr >= *alloc.chunk_addr
+                    && *addr - *alloc.chunk_addr < alloc.chunk_size
+            })
+            .map(|(&region, alloc)| (region, alloc))
+    }
+}
+
+/// Configuration of the tracking of the memory modified since the last reset.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DirtyConfig {
+    /// Size in bytes of the dirty blocks. It must be a power of two between
+    /// `MIN_DIRTY_BLOCK_SIZE` and `PAGE_SIZE`. Smaller blocks make resets
+    /// copy less memory, at the cost of tracking more blocks.
+    pub block_size: usize,
+
+    /// If `true`, the dirty list contains the dirty pages instead of the
+    /// dirty blocks, and the dirty blocks of every page are only recorded in
+    /// its bitmap. The dirty list is smaller and pushed to less often, which
+    /// suits large address spaces and writes spread over many blocks.
+    pub hierarchical: bool,
+}
+
+impl Default for DirtyConfig {
+    fn default() -> DirtyConfig {
+        DirtyConfig {
+            block_size: DIRTY_BLOCK_SIZE,
+            hierarchical: false,
+        }
+    }
+}
+
+impl DirtyConfig {
+    /// Returns `true` if the dirty block size is valid.
+    fn is_valid(&self) -> bool {
+        self.block_size.is_power_of_two()
+            && self.block_size >= MIN_DIRTY_BLOCK_SIZE
+            && self.block_size <= PAGE_SIZE
+    }
+
+    /// Returns the number of dirty blocks in a page.
+    pub fn page_blocks(&self) -> usize {
+        PAGE_SIZE / self.block_size
+    }
+
+    /// Returns the maximum number of entries of the dirty list per page.
+    fn page_entries(&self) -> usize {
+        if self.hierarchical {
+            1
+        } else {
+            self.page_blocks()
+        }
+    }
+}
+
+/// Returns `value` rounded up to a multiple of `align`, which must be a power
+/// of two. It returns `None` on overflow.
+fn align_up(value: usize, align: usize) -> Option<usize> {
+    Some(value.checked_add(align - 1)? & !(align - 1))
+}
+
+/// Shadow memory holding a value of type `T` for every byte, indexed by
+/// page number. Pages without an entry hold `T::default()`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+struct Shadow<T> {
+    pages: HashMap<usize, Box<[T]>>,
+}
+
+impl<T: Copy + Default + PartialEq> Shadow<T> {
+    /// Returns the shadow page with page number `number`, allocating it if
+    /// needed.
+    fn page_mut(&mut self, number: usize) -> &mut [T] {
+        self.pages
+            .entry(number)
+            .or_insert_with(|| vec![T::default(); PAGE_SIZE].into())
+    }
+
+    /// Returns the values of the memory range (`addr`..`addr` + `size`). It
+    /// does not check if the memory range is valid.
+    fn get(&self, addr: VirtAddr, size: usize) -> Vec<T> {
+        let mut values = vec![T::default(); size];
+        let mut dst_off = 0;
+
+        for (number, offset, len) in page_chunks(addr, size) {
+            if let Some(page) = self.pages.get(&number) {
+                values[dst_off..dst_off + len]
+                    .copy_from_slice(&page[offset..offset + len]);
+            }
+
+            dst_off += len;
+        }
+
+        values
+    }
+
+    /// Sets the values of the memory starting at `addr` to `src`. It does
+    /// not check if the memory range is valid.
+    fn set(&mut self, addr: VirtAddr, src: &[T]) {
+        let mut src_off = 0;
+
+        for (number, offset, len) in page_chunks(addr, src.len()) {
+            let src = &src[src_off..src_off + len];
+            src_off += len;
+
+            if !self.pages.contains_key(&number)
+                && src.iter().all(|v| *v == T::default())
+            {
+                continue;
+            }
+
+            self.page_mut(number)[offset..offset + len].copy_from_slice(src);
+        }
+    }
+
+    /// Sets the values of the memory range (`addr`..`addr` + `size`) to
+    /// `val`. It does not check if the memory range is valid.
+    fn fill(&mut self, addr: VirtAddr, size: usize, val: T) {
+        for (number, offset, len) in page_chunks(addr, size) {
+            if !self.pages.contains_key(&number) && val == T::default() {
+                continue;
+            }
+
+            self.page_mut(number)[offset..offset + len]
+                .iter_mut()
+                .for_each(|v| *v = val);
+        }
+    }
+
+    /// Restores the values of the dirty blocks `blocks` in the page with
+    /// page number `number` to the values in `other`.
+    fn reset_blocks(
+        &mut self,
+        other: Option<&Shadow<T>>,
+        number: usize,
+        blocks: u64,
+        config: DirtyConfig,
+    ) {
+        let other_page = other.and_then(|other| other.pages.get(&number));
+
+        if other_page.is_none() && !self.pages.contains_key(&number) {
+            return;
+        }
+
+        let page = self.page_mut(number);
+        for bit in (0..config.page_blocks()).filter(|i| blocks >> i & 1 != 0) {
+            let start = bit * config.block_size;
+            let end = start + config.block_size;
+
+            match other_page {
+                Some(other_page) => {
+                    page[start..end].copy_from_slice(&other_page[start..end])
+                }
+                None => {
+                    page[start..end].iter_mut().for_each(|v| *v = T::default())
+                }
             }
+        }
+    }
+}
+
+/// Bit-precise definedness of the memory.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+struct UninitShadow {
+    /// Definedness of every byte.
+    bytes: Shadow<Definedness>,
+
+    /// Allocations originating undefined memory. `OriginId(n)` refers to
+    /// the origin at index `n - 1`.
+    origins: Vec<Origin>,
+}
+
+/// Emulated memory management unit.
+///
+/// The address space is sparse. Pages are allocated when they are written
+/// or their permissions are set for the first time. Unallocated pages are
+/// zeroed and have no permissions.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Mmu {
+    /// Size of the address space.
+    size: usize,
+
+    /// Allocated pages, indexed by page number.
+    pages: HashMap<usize, Page>,
+
+    /// Cache of the translations used by the JIT compiler. Forking revokes
+    /// the write translations, so its entries can be modified through a
+    /// shared reference.
+    tlb: Vec<Cell<TlbEntry>>,
+
+    /// Dirty memory tracking configuration.
+    dirty_config: DirtyConfig,
+
+    /// Block indices in memory which are dirty or, if the dirty tracking is
+    /// hierarchical, page numbers with dirty blocks.
+    dirty: Vec<usize>,
+
+    /// Program break. Memory is allocated starting at this address.
+    brk: VirtAddr,
+
+    /// Heap allocator.
+    heap: Heap,
+
+    /// Active watchpoints.
+    watchpoints: Vec<Watchpoint>,
+
+    /// If `false`, accesses do not stop at the watchpoints.
+    watchpoints_enabled: bool,
+
+    /// Registered memory regions, indexed by start address.
+    regions: BTreeMap<VirtAddr, Region>,
+
+    /// Taint labels of every byte, if taint tracking is enabled.
+    taint: Option<Shadow<Taint>>,
+
+    /// Definedness of every byte, if bit-precise uninitialized memory
+    /// tracking is enabled.
+    uninit: Option<UninitShadow>,
+
+    /// Virtual memory state.
+    paging: Paging,
+}
+
+/// Splits the memory range (`addr`..`addr` + `size`) into chunks that do not
+/// cross page boundaries. It returns an iterator over tuples of the form
+/// (page number, offset within the page, chunk length). It does not check
+/// if the memory range is valid.
+fn page_chunks(
+    addr: VirtAddr,
+    size: usize,
+) -> impl Iterator<Item = (usize, usize, usize)> {
+    let mut cur = *addr;
+    let end = *addr + size;
+
+    std::iter::from_fn(move || {
+        if cur >= end {
+            return None;
+        }
+
+        let offset = cur % PAGE_SIZE;
+        let len = (PAGE_SIZE - offset).min(end - cur);
+        let chunk = (cur / PAGE_SIZE, offset, len);
+
+        cur += len;
+
+        Some(chunk)
+    })
+}
+
+/// Checks that the permissions `cur` of the memory range (`addr`..`addr` +
+/// `size`) satisfy the expected permissions `perms`.
+fn check_perm(
+    addr: VirtAddr,
+    size: usize,
+    perms: Perm,
+    cur: Perm,
+) -> Result<(), Error> {
+    if (*perms & PERM_READ != 0) && (*cur & PERM_RAW != 0) {
+        return Err(Error::UninitFault { addr, size });
+    }
+
+    if *cur & *perms != *perms {
+        if *perms & PERM_READ != 0 {
+            return Err(Error::ReadFault { addr, size });
+        } else if *perms & PERM_WRITE != 0 {
+            return Err(Error::WriteFault { addr, size });
+        } else if *perms & PERM_EXEC != 0 {
+            return Err(Error::ExecFault { addr, size });
+        } else {
+            return Err(Error::UnkFault {
+                addr,
+                size,
+                exp: perms,
+                cur,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+impl Mmu {
+    /// Returns a new Mmu with an address space of `size` bytes. Memory is
+    /// allocated on demand.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `size` is lower than `DIRTY_BLOCK_SIZE`.
+    pub fn new(size: usize) -> Mmu {
+        assert!(size >= DIRTY_BLOCK_SIZE, "invalid size");
+
+        Mmu {
+            size,
+            pages: HashMap::new(),
+            tlb: vec![Cell::new(TlbEntry::default()); TLB_SIZE],
+            dirty_config: DirtyConfig::default(),
+            dirty: Vec::new(),
+            brk: VirtAddr(0),
+            heap: Heap::default(),
+            watchpoints: Vec::new(),
+            watchpoints_enabled: true,
+            regions: BTreeMap::new(),
+            taint: None,
+            uninit: None,
+            paging: Paging::default(),
+        }
+    }
+
+    /// Returns the Mmu using the heap allocator configuration `config`.
+    pub fn with_alloc_config(mut self, config: AllocConfig) -> Mmu {
+        self.heap.config = config;
+        self
+    }
+
+    /// Returns the Mmu using the dirty memory tracking configuration
+    /// `config`. The JIT code depends on it, so emulators sharing a JIT cache
+    /// must use the same configuration.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if the dirty block size is not valid or if there
+    /// is dirty memory.
+    pub fn with_dirty_config(mut self, config: DirtyConfig) -> Mmu {
+        assert!(config.is_valid(), "invalid dirty block size");
+        assert!(self.dirty.is_empty(), "memory is dirty");
+
+        self.dirty_config = config;
+
+        let capacity = self.pages.len() * config.page_entries();
+        if self.dirty.capacity() < capacity {
+            self.dirty.reserve(capacity);
+        }
+
+        self
+    }
+
+    /// Returns the dirty memory tracking configuration.
+    pub fn dirty_config(&self) -> DirtyConfig {
+        self.dirty_config
+    }
+
+    /// Returns the size of the memory.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Returns a copy of the MMU. It marks all memory as clean in the new
+    /// copy.
+    ///
+    /// Pages are shared between both Mmus and copied on the first write, so
+    /// forking is cheap and every fork only pays for the memory it modifies.
+    pub fn fork(&self) -> Mmu {
+        // The pages are not exclusively owned anymore, so they must not be
+        // written directly by the JIT code.
+        for entry in &self.tlb {
+            entry.set(TlbEntry {
+                write_page_number: usize::MAX,
+                ..entry.get()
+            });
+        }
+
+        let pages = self
+            .pages
+            .iter()
+            .map(|(&number, page)| {
+                let page = Page {
+                    data: Arc::clone(&page.data),
+                    dirty: Box::new(0),
+                };
+                (number, page)
+            })
+            .collect();
+
+        Mmu {
+            size: self.size,
+            pages,
+            tlb: vec![Cell::new(TlbEntry::default()); TLB_SIZE],
+            dirty_config: self.dirty_config,
+            dirty: Vec::with_capacity(
+                self.pages.len() * self.dirty_config.page_entries(),
+            ),
+            brk: self.brk,
+            heap: self.heap.clone(),
+            watchpoints: self.watchpoints.clone(),
+            watchpoints_enabled: self.watchpoints_enabled,
+            regions: self.regions.clone(),
+            taint: self.taint.clone(),
+            uninit: self.uninit.clone(),
+            paging: self.paging.clone(),
+        }
+    }
+
+    /// Restores memory to the original state `other`.
+    pub fn reset(&mut self, other: &Mmu) {
+        // Restore memory and set as clean. Pages are not deallocated, so the
+        // TLB is still valid afterwards, except for the pages which are
+        // copied on write.
+        let config = self.dirty_config;
+        let mut dirty = mem::take(&mut self.dirty);
+        for &entry in &dirty {
+            let (number, blocks) = if config.hierarchical {
+                (entry, *self.pages[&entry].dirty)
+            } else {
+                let page_blocks = config.page_blocks();
+                (entry / page_blocks, 1 << (entry % page_blocks))
+            };
+
+            // Shadow memory is not shared, so it is restored even if the
+            // page contents are.
+            if let Some(taint) = &mut self.taint {
+                taint.reset_blocks(
+                    other.taint.as_ref(),
+                    number,
+                    blocks,
+                    config,
+                );
+            }
+            if let Some(uninit) = &mut self.uninit {
+                let other = other.uninit.as_ref().map(|u| &u.bytes);
+                uninit.bytes.reset_blocks(other, number, blocks, config);
+            }
+
+            let page = self.pages.get_mut(&number).unwrap();
+            *page.dirty = 0;
+
+            let other_page = other.pages.get(&number);
+
+            // Pages still shared with `other` are already restored.
+            if let Some(other_page) = other_page {
+                if Arc::ptr_eq(&page.data, &other_page.data) {
+                    continue;
+                }
+            }
+
+            let data = self.page_data_mut(number);
+            let other_data = other_page.map(|p| &*p.data);
+            for bit in
+                (0..config.page_blocks()).filter(|i| blocks >> i & 1 != 0)
+            {
+                let start = bit * config.block_size;
+                let end = start + config.block_size;
+
+                match other_data {
+                    Some(other_data) => data.memory[start..end]
+                        .copy_from_slice(&other_data.memory[start..end]),
+                    None => {
+                        data.memory[start..end].iter_mut().for_each(|b| *b = 0)
+                    }
+                }
+
+                if let (Some(perms), Some(other_perms)) = (
+                    &mut data.perms,
+                    other_data.and_then(|d| d.perms.as_ref()),
+                ) {
+                    perms[start..end]
+                        .copy_from_slice(&other_perms[start..end]);
+                }
+            }
+
+            // The clean blocks already match `other`, so the whole page
+            // does now. The byte-level permissions only need to be copied
+            // if they were not restored block by block above.
+            match other_data {
+                Some(other_data) => {
+                    if data.perms.is_none() || other_data.perms.is_none() {
+                        data.perms.clone_from(&other_data.perms);
+                    }
+                    data.uniform = other_data.uniform;
+                    data.summary = other_data.summary;
+                    data.stale = other_data.stale;
+                    data.counts = other_data.counts;
+                }
+                None => {
+                    data.perms = None;
+                    data.uniform = Perm(0);
+                    data.summary = Perm(0);
+                    data.stale = false;
+                    data.counts = [0; 8];
+                }
+            }
+        }
+        dirty.clear();
+        self.dirty = dirty;
+
+        self.brk = other.brk;
+
+        self.heap.reset(&other.heap);
+
+        // Watchpoints are restored along with the permissions, which contain
+        // `PERM_WATCH`.
+        self.watchpoints.clone_from(&other.watchpoints);
+
+        self.regions.clone_from(&other.regions);
+
+        // The page tables may have been restored, so the cached translations
+        // are discarded.
+        self.paging.clone_from(&other.paging);
+        self.paging.translations.clear();
+
+        if let Some(uninit) = &mut self.uninit {
+            match &other.uninit {
+                Some(other) => uninit.origins.clone_from(&other.origins),
+                None => uninit.origins.clear(),
+            }
+        }
+
+        if DEBUG_SANITY_CHECKS {
+            let diff = self.diff(other);
+            assert!(diff.is_empty(), "memory differs after reset:\n{}", diff);
+
+            for (number, page) in &self.pages {
+                let want = match other.pages.get(number) {
+                    Some(other_page) => Arc::clone(&other_page.data),
+                    None => Page::new().data,
+                };
+                assert_eq!(page.data, want);
+                assert_eq!(*page.dirty, 0);
+            }
+            assert_eq!(self.dirty, Vec::new());
+            assert_eq!(self.heap, other.heap);
+        }
+    }
+
+    /// Returns `true` if the byte at `addr` was modified since the last
+    /// reset.
+    pub fn is_dirty(&self, addr: VirtAddr) -> bool {
+        let block = (*addr % PAGE_SIZE) / self.dirty_config.block_size;
+
+        match self.pages.get(&(*addr / PAGE_SIZE)) {
+            Some(page) => *page.dirty & (1 << block) != 0,
+            None => false,
+        }
+    }
+
+    /// Returns the start addresses of the blocks modified since the last
+    /// reset, in the order they were modified first. In hierarchical mode,
+    /// the blocks of every page are returned together.
+    pub fn dirty_blocks(&self) -> Vec<VirtAddr> {
+        let config = self.dirty_config;
+
+        if !config.hierarchical {
+            return self
+                .dirty
+                .iter()
+                .map(|&block| VirtAddr(block * config.block_size))
+                .collect();
+        }
+
+        let mut result = Vec::new();
+        for &number in &self.dirty {
+            let blocks = *self.pages[&number].dirty;
+            result.extend(
+                (0..config.page_blocks())
+                    .filter(|i| blocks >> i & 1 != 0)
+                    .map(|i| {
+                        VirtAddr(number * PAGE_SIZE + i * config.block_size)
+                    }),
+            );
+        }
+
+        result
+    }
+
+    /// Returns the length of the guest memory, which is the size of the
+    /// address space. See `Mmu::size`.
+    pub fn memory_len(&self) -> usize {
+        self.size
+    }
+
+    /// Returns a raw pointer to the contents of the first page of the guest
+    /// memory, or a null pointer if it is not allocated. Memory is not stored
+    /// in a single buffer, so the other pages are accessed with
+    /// `page_memory_ptr`.
+    pub fn memory_ptr(&self) -> *const u8 {
+        self.page_memory_ptr(VirtAddr(0)).unwrap_or(ptr::null())
+    }
+
+    /// Returns a raw pointer to the contents of the page containing `addr`,
+    /// or `None` if the page is not allocated.
+    ///
+    /// Pages shared with other Mmus are copied when written, so the pointer
+    /// is only valid until the Mmu is modified.
+    pub fn page_memory_ptr(&self, addr: VirtAddr) -> Option<*const u8> {
+        let page = self.pages.get(&(*addr / PAGE_SIZE))?;
+
+        Some(page.data.memory.as_ptr())
+    }
+
+    /// Returns a raw pointer to the byte-level permissions of the first page
+    /// of the guest memory, or a null pointer if it is not allocated or all
+    /// its bytes have the same permissions. See `page_perms_ptr`.
+    pub fn perms_ptr(&self) -> *const Perm {
+        self.page_perms_ptr(VirtAddr(0)).unwrap_or(ptr::null())
+    }
+
+    /// Returns a raw pointer to the byte-level permissions of the page
+    /// containing `addr`. `None` is returned if the page is not allocated or
+    /// all its bytes have the same permissions, which are returned by
+    /// `Mmu::perms`.
+    ///
+    /// The pointer is only valid until the Mmu is modified.
+    pub fn page_perms_ptr(&self, addr: VirtAddr) -> Option<*const Perm> {
+        let page = self.pages.get(&(*addr / PAGE_SIZE))?;
+
+        page.data.perms.as_ref().map(|perms| perms.as_ptr())
+    }
+
+    /// Returns a raw pointer to the bitmap of dirty blocks of the first page
+    /// of the guest memory, or a null pointer if it is not allocated. See
+    /// `page_dirty_bitmap_ptr`.
+    pub fn dirty_bitmap_ptr(&self) -> *const u64 {
+        self.page_dirty_bitmap_ptr(VirtAddr(0))
+            .unwrap_or(ptr::null())
+    }
+
+    /// Returns a raw pointer to the bitmap of dirty blocks of the page
+    /// containing `addr`, or `None` if the page is not allocated. Bit `i` is
+    /// set if the block `i` of the page is dirty.
+    pub fn page_dirty_bitmap_ptr(&self, addr: VirtAddr) -> Option<*const u64> {
+        let page = self.pages.get(&(*addr / PAGE_SIZE))?;
+
+        Some(&*page.dirty as *const u64)
+    }
+
+    /// Returns the capacity of the internal list of dirty blocks. It is
+    /// always enough to hold every entry of the allocated pages.
+    pub fn dirty_capacity(&self) -> usize {
+        self.dirty.capacity()
+    }
+
+    /// Returns the length of the internal list of dirty blocks.
+    pub fn dirty_len(&self) -> usize {
+        self.dirty.len()
+    }
+
+    /// Sets the length of the internal list of dirty blocks.
+    ///
+    /// # Safety
+    ///
+    /// `new_len` must be less than or equal to the capacity of the dirty list.
+    pub unsafe fn set_dirty_len(&mut self, new_len: usize) {
+        self.dirty.set_len(new_len)
+    }
+
+    /// Returns a raw pointer to the internal list of dirty blocks.
+    pub fn dirty_ptr(&self) -> *const usize {
+        self.dirty.as_ptr()
+    }
+
+    /// Returns a raw pointer to the TLB. It contains `TLB_SIZE` entries.
+    pub fn tlb_ptr(&self) -> *const TlbEntry {
+        self.tlb.as_ptr() as *const TlbEntry
+    }
+
+    /// Caches the translation of the page containing `addr` in the TLB. If
+    /// the page is not allocated, the TLB is not modified. Pages shared with
+    /// other Mmus are only cached for reading.
+    pub fn fill_tlb(&mut self, addr: VirtAddr) {
+        let number = *addr / PAGE_SIZE;
+
+        if let Some(page) = self.pages.get_mut(&number) {
+            let write_page_number = match Arc::get_mut(&mut page.data) {
+                Some(_) => number,
+                None => usize::MAX,
+            };
+
+            self.tlb[number % TLB_SIZE].set(TlbEntry {
+                page_number: number,
+                write_page_number,
+                page_ptr: &*page.data as *const PageData as usize,
+                dirty_ptr: &*page.dirty as *const u64 as usize,
+            });
+        }
+    }
+
+    /// Returns the current program break.
+    pub fn brk(&self) -> VirtAddr {
+        self.brk
+    }
+
+    /// Sets the program break.
+    pub fn set_brk(&mut self, addr: VirtAddr) {
+        self.brk = addr;
+    }
+
+    /// Returns the value of the `satp` register.
+    pub fn satp(&self) -> u64 {
+        self.paging.satp
+    }
+
+    /// Sets the value of the `satp` register, which selects the paging mode
+    /// and the root page table. It flushes the cached translations. It fails
+    /// if the paging mode is not supported, leaving `satp` unchanged.
+    pub fn set_satp(&mut self, satp: u64) -> Result<(), Error> {
+        if PagingMode::from_satp(satp).is_none() {
+            return Err(Error::UnsupportedPagingMode { satp });
+        }
+
+        self.paging.satp = satp;
+        self.paging.translations.clear();
+
+        Ok(())
+    }
+
+    /// Returns the paging mode selected by `satp`.
+    pub fn paging_mode(&self) -> PagingMode {
+        self.paging.mode()
+    }
+
+    /// Returns the privilege mode of the memory accesses.
+    pub fn privilege(&self) -> Privilege {
+        self.paging.privilege
+    }
+
+    /// Sets the privilege mode of the memory accesses. By default, accesses
+    /// are made in user mode, like the ones of user-mode programs.
+    pub fn set_privilege(&mut self, privilege: Privilege) {
+        self.paging.privilege = privilege;
+    }
+
+    /// Sets the SUM and MXR bits of `sstatus`. If `sum` is `true`, supervisor
+    /// mode can read and write user pages. If `mxr` is `true`, executable
+    /// pages are also readable.
+    pub fn set_status(&mut self, sum: bool, mxr: bool) {
+        self.paging.sum = sum;
+        self.paging.mxr = mxr;
+    }
+
+    /// Flushes the cached translation of the page containing `addr` or, if
+    /// `addr` is `None`, all of them. It must be called after modifying the
+    /// page tables, as `SFENCE.VMA`.
+    pub fn sfence_vma(&mut self, addr: Option<VirtAddr>) {
+        match addr {
+            Some(addr) => {
+                self.paging.translations.remove(&(*addr / PAGE_SIZE));
+            }
+            None => self.paging.translations.clear(),
+        }
+    }
+
+    /// Translates the virtual address `addr` of an access of type `access`
+    /// into a physical address. If paging is disabled, `addr` is returned.
+    ///
+    /// The page tables are walked on a miss in the translation cache, as
+    /// well as on the first write to a page through a cached translation.
+    /// The accessed and dirty bits of the page table entry are updated as
+    /// in hardware.
+    pub fn translate(
+        &mut self,
+        addr: VirtAddr,
+        access: Access,
+    ) -> Result<VirtAddr, Error> {
+        if self.paging.mode() == PagingMode::Bare {
+            return Ok(addr);
+        }
+
+        let vpn = *addr / PAGE_SIZE;
+        let offset = *addr % PAGE_SIZE;
+
+        if let Some(t) = self.paging.translations.get(&vpn) {
+            let dirty = access != Access::Write || t.flags & PTE_D != 0;
+            if dirty && self.paging.allows(t.flags, access) {
+                return Ok(VirtAddr(t.number * PAGE_SIZE + offset));
+            }
+        }
+
+        let (pte_addr, pte, number) = self.walk(addr, access)?;
+
+        let mut flags = pte | PTE_A;
+        if access == Access::Write {
+            flags |= PTE_D;
+        }
+        if flags != pte {
+            self.poke_int::<u64>(pte_addr, flags)?;
+        }
+
+        self.paging
+            .translations
+            .insert(vpn, Translation { number, flags });
+
+        Ok(VirtAddr(number * PAGE_SIZE + offset))
+    }
+
+    /// Translates the virtual address `addr` of an access of type `access`
+    /// into a physical address, like `translate`, but without updating the
+    /// page tables nor the translation cache.
+    pub fn peek_translation(
+        &self,
+        addr: VirtAddr,
+        access: Access,
+    ) -> Result<VirtAddr, Error> {
+        if self.paging.mode() == PagingMode::Bare {
+            return Ok(addr);
+        }
+
+        let vpn = *addr / PAGE_SIZE;
+        let offset = *addr % PAGE_SIZE;
+
+        let number = match self.paging.translations.get(&vpn) {
+            Some(t) if self.paging.allows(t.flags, access) => t.number,
+            _ => self.walk(addr, access)?.2,
+        };
+
+        Ok(VirtAddr(number * PAGE_SIZE + offset))
+    }
+
+    /// Walks the page tables to translate the virtual address `addr` of an
+    /// access of type `access`. It returns the address of the leaf page
+    /// table entry, its value and the physical page number.
+    fn walk(
+        &self,
+        addr: VirtAddr,
+        access: Access,
+    ) -> Result<(VirtAddr, u64, usize), Error> {
+        let fault = Error::PageFault { addr, access };
+        let levels = self.paging.mode().levels();
+
+        // The unused upper bits must be copies of the highest used bit.
+        let va_bits = PAGE_SIZE.trailing_zeros() as usize + levels * VPN_BITS;
+        let upper = (*addr as i64) >> (va_bits - 1);
+        if upper != 0 && upper != -1 {
+            return Err(fault);
+        }
+
+        let vpn = *addr / PAGE_SIZE;
+        let mut table = (self.paging.satp & PPN_MASK) as usize * PAGE_SIZE;
+
+        for level in (0..levels).rev() {
+            let index = (vpn >> (level * VPN_BITS)) & ((1 << VPN_BITS) - 1);
+            let pte_addr = VirtAddr(table + index * 8);
+            let pte = self.peek_int::<u64>(pte_addr)?;
+
+            if pte & PTE_V == 0 || (pte & PTE_R == 0 && pte & PTE_W != 0) {
+                return Err(fault);
+            }
+
+            let ppn = ((pte >> PTE_PPN_SHIFT) & PPN_MASK) as usize;
+
+            if pte & (PTE_R | PTE_X) == 0 {
+                // Pointer to the next level.
+                table = ppn * PAGE_SIZE;
+                continue;
+            }
+
+            if !self.paging.allows(pte, access) {
+                return Err(fault);
+            }
+
+            // Superpages must be aligned to their size.
+            let low_mask = (1 << (level * VPN_BITS)) - 1;
+            if ppn & low_mask != 0 {
+                return Err(fault);
+            }
+
+            return Ok((pte_addr, pte, ppn | (vpn & low_mask)));
+        }
+
+        Err(fault)
+    }
+
+    /// Checks that the memory range (`addr`..`addr` + `size`) is within the
+    /// address space.
+    fn check_range(&self, addr: VirtAddr, size: usize) -> Result<(), Error> {
+        let end = addr
+            .checked_add(size)
+            .ok_or(Error::AddressIntegerOverflow { addr, size })?;
+
+        if end > self.size {
+            return Err(Error::InvalidAddress { addr, size });
+        }
+
+        Ok(())
+    }
+
+    /// Returns the contents of the page with the page number `number` for
+    /// writing, allocating the page if needed. If the page is shared with
+    /// other Mmus, it is copied first.
+    fn page_data_mut(&mut self, number: usize) -> &mut PageData {
+        if let Entry::Vacant(entry) = self.pages.entry(number) {
+            entry.insert(Page::new());
+
+            // The JIT compiler requires the dirty list to be able to hold
+            // every entry without reallocating.
+            let capacity = self.pages.len() * self.dirty_config.page_entries();
+            if self.dirty.capacity() < capacity {
+                self.dirty.reserve(capacity - self.dirty.len());
+            }
+        }
+
+        let page = self.pages.get_mut(&number).unwrap();
+
+        if Arc::get_mut(&mut page.data).is_none() {
+            // The page is about to be copied, so its cached translation
+            // would point to the shared contents.
+            let entry = &self.tlb[number % TLB_SIZE];
+            if entry.get().page_number == number {
+                entry.set(TlbEntry::default());
+            }
+        }
+
+        Arc::make_mut(&mut page.data)
+    }
+
+    /// Set memory permissions in the given range.
+    pub fn set_perms(
+        &mut self,
+        addr: VirtAddr,
+        size: usize,
+        perms: Perm,
+    ) -> Result<(), Error> {
+        self.check_range(addr, size)?;
+
+        for (number, offset, len) in page_chunks(addr, size) {
+            // Unallocated pages have no permissions already.
+            if *perms == 0 && !self.pages.contains_key(&number) {
+                continue;
+            }
+
+            // Keep the watchpoints and the memory-mapped I/O.
+            let data = self.page_data_mut(number);
+            data.update_perms(offset, len, |_, p| {
+                Perm(*perms | (*p & PERM_INTERNAL))
+            });
+        }
+
+        self.update_dirty(addr, size);
+
+        Ok(())
+    }
+
+    /// Returns the permissions of the memory range (`addr`..`addr` +
+    /// `size`).
+    pub fn perms(
+        &self,
+        addr: VirtAddr,
+        size: usize,
+    ) -> Result<Vec<Perm>, Error> {
+        self.check_range(addr, size)?;
+
+        let mut result = Vec::with_capacity(size);
+
+        for (number, offset, len) in page_chunks(addr, size) {
+            match self.pages.get(&number) {
+                Some(page) => result.extend(
+                    page.data
+                        .perms(offset, len)
+                        .map(|p| Perm(*p & !PERM_INTERNAL)),
+                ),
+                None => result.resize(result.len() + len, Perm(0)),
+            }
+        }
+
+        Ok(result)
+    }
+
+    /// Given a memory range and the expected permissions, this function will
+    /// return true if every byte in the specified region satisfies those
+    /// permissions. Otherwise, the function will return false.
+    pub fn check_perms(
+        &self,
+        addr: VirtAddr,
+        size: usize,
+        perms: Perm,
+    ) -> Result<(), Error> {
+        self.check_range(addr, size)?;
+
+        let mut watched = false;
+        let mut mmio = false;
+
+        for (number, offset, len) in page_chunks(addr, size) {
+            match self.pages.get(&number) {
+                // Fast path: every byte of the page satisfies `perms`.
+                Some(page)
+                    if *page.data.summary != 0
+                        && *page.data.summary & *perms == *perms => {}
+                Some(page) => {
+                    for p in page.data.perms(offset, len) {
+                        // Memory-mapped I/O has no permissions.
+                        if *perms != 0 && *p & PERM_MMIO != 0 {
+                            mmio = true;
+                        } else {
+                            check_perm(addr, size, perms, p)
+                                .map_err(|error| self.stack_fault(error))?;
+                        }
+                        watched |= *p & PERM_WATCH != 0;
+                    }
+                }
+                None => check_perm(addr, size, perms, Perm(0))
+                    .map_err(|error| self.stack_fault(error))?,
+            }
+        }
+
+        if watched && self.watchpoints_enabled {
+            self.check_watchpoints(addr, size, perms)?;
+        }
+
+        if mmio {
+            return Err(Error::Mmio { addr, size });
+        }
+
+        Ok(())
+    }
+
+    /// Checks that the accesses to the memory range (`addr`..`addr` +
+    /// `size`) with the expected permissions `perms` do not hit any
+    /// watchpoint. Accesses that do not check permissions are not watched.
+    fn check_watchpoints(
+        &self,
+        addr: VirtAddr,
+        size: usize,
+        perms: Perm,
+    ) -> Result<(), Error> {
+        let access = if *perms & PERM_READ != 0 {
+            Access::Read
+        } else if *perms & PERM_WRITE != 0 {
+            Access::Write
+        } else if *perms & PERM_EXEC != 0 {
+            Access::Exec
+        } else {
+            return Ok(());
+        };
+
+        let hit = self
+            .watchpoints
+            .iter()
+            .any(|wp| wp.access == access && wp.overlaps(addr, size));
+
+        if hit {
+            return Err(Error::Watchpoint { addr, size, access });
+        }
+
+        Ok(())
+    }
+
+    /// Sets a watchpoint on the memory range (`addr`..`addr` + `size`).
+    /// Accesses of type `access` to the range fail with
+    /// `Error::Watchpoint`, unless they do not check permissions.
+    pub fn add_watchpoint(
+        &mut self,
+        addr: VirtAddr,
+        size: usize,
+        access: Access,
+    ) -> Result<(), Error> {
+        self.check_range(addr, size)?;
+
+        let watchpoint = Watchpoint { addr, size, access };
+        if !self.watchpoints.contains(&watchpoint) {
+            self.watchpoints.push(watchpoint);
+        }
+        self.update_watch_perms(addr, size);
+
+        Ok(())
+    }
+
+    /// Removes the watchpoint on the memory range (`addr`..`addr` + `size`)
+    /// for the accesses of type `access`.
+    pub fn remove_watchpoint(
+        &mut self,
+        addr: VirtAddr,
+        size: usize,
+        access: Access,
+    ) {
+        let watchpoint = Watchpoint { addr, size, access };
+        self.watchpoints.retain(|wp| *wp != watchpoint);
+
+        if size != 0 && self.check_range(addr, size).is_ok() {
+            self.update_watch_perms(addr, size);
+        }
+    }
+
+    /// Returns the active watchpoints.
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    /// Enables or disables the watchpoints. While they are disabled, accesses
+    /// to watched memory do not fail.
+    pub fn set_watchpoints_enabled(&mut self, enabled: bool) {
+        self.watchpoints_enabled = enabled;
+    }
+
+    /// Registers the memory region `region`. Regions cannot overlap.
+    pub fn add_region(&mut self, region: Region) -> Result<(), Error> {
+        let (addr, size) = (region.addr, region.size);
+
+        self.check_range(addr, size)?;
+
+        if self.overlapping_regions(addr, size).next().is_some() {
+            return Err(Error::RegionOverlap { addr, size });
+        }
+
+        self.regions.insert(addr, region);
+
+        Ok(())
+    }
+
+    /// Returns the registered regions overlapping the memory range (`addr`..
+    /// `addr` + `size`). It does not check if the memory range is valid.
+    fn overlapping_regions(
+        &self,
+        addr: VirtAddr,
+        size: usize,
+    ) -> impl Iterator<Item = &Region> {
+        self.regions.values().filter(move |r| {
+            *addr < *r.addr + r.size && *r.addr < *addr + size
+        })
+    }
+
+    /// Unregisters the region starting at `addr`, returning it.
+    pub fn remove_region(&mut self, addr: VirtAddr) -> Option<Region> {
+        self.regions.remove(&addr)
+    }
+
+    /// Returns the region containing `addr`, if any.
+    pub fn region(&self, addr: VirtAddr) -> Option<&Region> {
+        self.regions
+            .range(..=addr)
+            .next_back()
+            .map(|(_, region)| region)
+            .filter(|region| region.contains(addr))
+    }
+
+    /// Returns the registered regions, sorted by address.
+    pub fn regions(&self) -> impl Iterator<Item = &Region> {
+        self.regions.values()
+    }
+
+    /// Returns the registered regions in a format similar to
+    /// `/proc/self/maps`, one region per line.
+    pub fn maps(&self) -> String {
+        self.regions
+            .values()
+            .map(|region| format!("{}\n", region))
+            .collect()
+    }
+
+    /// Extends the heap region ending at `addr` by `size` bytes, registering
+    /// it if there is none. It is called when the heap allocator moves the
+    /// program break, and fails with `Error::RegionOverlap` if the new part
+    /// of the heap overlaps another region.
+    fn grow_heap_region(
+        &mut self,
+        addr: VirtAddr,
+        size: usize,
+    ) -> Result<(), Error> {
+        if self.overlapping_regions(addr, size).next().is_some() {
+            return Err(Error::RegionOverlap { addr, size });
+        }
+
+        let heap = self.regions.values_mut().find(|region| {
+            region.kind == RegionKind::Heap
+                && *region.addr + region.size == *addr
+        });
+
+        match heap {
+            Some(heap) => heap.size += size,
+            None => {
+                self.regions.entry(addr).or_insert_with(|| Region {
+                    name: "[heap]".to_string(),
+                    kind: RegionKind::Heap,
+                    addr,
+                    size,
+                    perms: Perm(PERM_READ | PERM_WRITE),
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Sets up a stack of `size` bytes at the end of the address space and
+    /// returns its top. The stack is readable and writable, and it is
+    /// registered as a region along with the inaccessible guard region of
+    /// `guard_size` bytes below it.
+    ///
+    /// Accesses to the guard region fail with `Error::StackOverflow`, and
+    /// `Mmu::malloc` fails with `Error::StackCollision` instead of growing
+    /// the heap into it.
+    pub fn map_stack(
+        &mut self,
+        size: usize,
+        guard_size: usize,
+    ) -> Result<VirtAddr, Error> {
+        let total_size = size.checked_add(guard_size).ok_or(
+            Error::AddressIntegerOverflow {
+                addr: VirtAddr(self.size),
+                size,
+            },
+        )?;
+        let guard_addr = match self.size.checked_sub(total_size) {
+            Some(addr) => VirtAddr(addr),
+            None => {
+                return Err(Error::InvalidAddress {
+                    addr: VirtAddr(0),
+                    size: total_size,
+                })
+            }
+        };
+        let stack_addr = VirtAddr(*guard_addr + guard_size);
+
+        if *self.brk > *guard_addr {
+            return Err(Error::StackCollision {
+                addr: guard_addr,
+                size: total_size,
+            });
+        }
+        if self
+            .overlapping_regions(guard_addr, total_size)
+            .next()
+            .is_some()
+        {
+            return Err(Error::RegionOverlap {
+                addr: guard_addr,
+                size: total_size,
+            });
+        }
+
+        self.set_perms(guard_addr, guard_size, Perm(0))?;
+        self.set_perms(stack_addr, size, Perm(PERM_READ | PERM_WRITE))?;
+
+        self.regions.insert(
+            guard_addr,
+            Region {
+                name: "[guard]".to_string(),
+                kind: RegionKind::Guard,
+                addr: guard_addr,
+                size: guard_size,
+                perms: Perm(0),
+            },
+        );
+        self.regions.insert(
+            stack_addr,
+            Region {
+                name: "[stack]".to_string(),
+                kind: RegionKind::Stack,
+                addr: stack_addr,
+                size,
+                perms: Perm(PERM_READ | PERM_WRITE),
+            },
+        );
+
+        Ok(VirtAddr(self.size))
+    }
+
+    /// Returns `true` if the memory range (`addr`..`addr` + `size`) overlaps
+    /// a stack or the guard region below it. It does not check if the memory
+    /// range is valid.
+    fn overlaps_stack(&self, addr: VirtAddr, size: usize) -> bool {
+        self.overlapping_regions(addr, size).any(|region| {
+            region.kind == RegionKind::Stack || self.is_stack_guard(region)
+        })
+    }
+
+    /// Returns `true` if `region` is the guard region below a stack.
+    fn is_stack_guard(&self, region: &Region) -> bool {
+        region.kind == RegionKind::Guard
+            && self
+                .regions
+                .get(&VirtAddr(*region.addr + region.size))
+                .map(|next| next.kind)
+                == Some(RegionKind::Stack)
+    }
+
+    /// Turns the permission fault `error` into `Error::StackOverflow` if the
+    /// faulting access hits the guard region below a stack.
+    fn stack_fault(&self, error: Error) -> Error {
+        match error {
+            Error::ReadFault { addr, size }
+            | Error::WriteFault { addr, size }
+            | Error::ExecFault { addr, size }
+            | Error::UninitFault { addr, size }
+            | Error::UnkFault { addr, size, .. }
+                if self
+                    .overlapping_regions(addr, size)
+                    .any(|region| self.is_stack_guard(region)) =>
+            {
+                Error::StackOverflow { addr, size }
+            }
+            error => error,
+        }
+    }
+
+    /// Maps `size` bytes of memory with the permissions `perms`, registering
+    /// the mapping as a region named `name`. It returns the start of the
+    /// mapping. The size is rounded up to a multiple of the page size.
+    ///
+    /// The memory is initialized with `contents`, e.g. the contents of a
+    /// file, and the rest of it is zeroed. Anonymous mappings use empty
+    /// contents and, by convention, the name "[anon]".
+    ///
+    /// If `addr` is `None`, the mapping is placed at the highest free range
+    /// above the program break. Otherwise, it is placed at `addr`, which must
+    /// be page-aligned, replacing the mappings already there. Mappings cannot
+    /// overlap other kinds of regions.
+    pub fn mmap(
+        &mut self,
+        addr: Option<VirtAddr>,
+        size: usize,
+        perms: Perm,
+        name: &str,
+        contents: &[u8],
+    ) -> Result<VirtAddr, Error> {
+        let size = match align_up(size, PAGE_SIZE) {
+            Some(size) if size != 0 => size,
+            _ => {
+                return Err(Error::InvalidAddress {
+                    addr: addr.unwrap_or(VirtAddr(0)),
+                    size,
+                })
+            }
+        };
+
+        let addr = match addr {
+            Some(addr) => {
+                self.munmap(addr, size)?;
+                addr
+            }
+            None => {
+                self.find_free_range(size).ok_or(Error::InvalidAddress {
+                    addr: self.brk,
+                    size,
+                })?
+            }
+        };
+
+        let len = contents.len().min(size);
+        self.poke(addr, &contents[..len])?;
+        self.set_perms(addr, size, perms)?;
+
+        self.regions.insert(
+            addr,
+            Region {
+                name: name.to_string(),
+                kind: RegionKind::Mmap,
+                addr,
+                size,
+                perms,
+            },
+        );
+
+        Ok(addr)
+    }
+
+    /// Unmaps the memory range (`addr`..`addr` + `size`), which must be
+    /// page-aligned. The mappings in the range are zeroed and made
+    /// inaccessible, and they are unregistered, splitting the ones only
+    /// partially covered. The size is rounded up to a multiple of the page
+    /// size. Other kinds of regions cannot be unmapped.
+    pub fn munmap(
+        &mut self,
+        addr: VirtAddr,
+        size: usize,
+    ) -> Result<(), Error> {
+        let size = self.check_mapping_range(addr, size)?;
+        let end = *addr + size;
+
+        if self
+            .overlapping_regions(addr, size)
+            .any(|region| region.kind != RegionKind::Mmap)
+        {
+            return Err(Error::RegionOverlap { addr, size });
+        }
+
+        let unmapped: Vec<Region> =
+            self.overlapping_regions(addr, size).cloned().collect();
+        for region in unmapped {
+            self.regions.remove(&region.addr);
+
+            let region_end = *region.addr + region.size;
+            if *region.addr < *addr {
+                let head = Region {
+                    size: *addr - *region.addr,
+                    ..region.clone()
+                };
+                self.regions.insert(head.addr, head);
+            }
+            if region_end > end {
+                let tail = Region {
+                    addr: VirtAddr(end),
+                    size: region_end - end,
+                    ..region
+                };
+                self.regions.insert(tail.addr, tail);
+            }
+        }
+
+        // Only the allocated pages can have non-zero contents.
+        let zeros = [0u8; PAGE_SIZE];
+        for (number, offset, len) in page_chunks(addr, size) {
+            if self.pages.contains_key(&number) {
+                let chunk_addr = VirtAddr(number * PAGE_SIZE + offset);
+                self.poke(chunk_addr, &zeros[..len])?;
+            }
+        }
+        self.set_perms(addr, size, Perm(0))
+    }
+
+    /// Sets the permissions of the memory range (`addr`..`addr` + `size`),
+    /// which must be page-aligned and covered by registered regions. The
+    /// size is rounded up to a multiple of the page size. The permissions
+    /// the regions were registered with are not modified.
+    pub fn mprotect(
+        &mut self,
+        addr: VirtAddr,
+        size: usize,
+        perms: Perm,
+    ) -> Result<(), Error> {
+        let size = self.check_mapping_range(addr, size)?;
+        let end = *addr + size;
+
+        // Regions are sorted and do not overlap, so they must be contiguous.
+        let mut cur = *addr;
+        for region in self.overlapping_regions(addr, size) {
+            if *region.addr > cur {
+                break;
+            }
+            cur = *region.addr + region.size;
+        }
+        if cur < end {
+            return Err(Error::NotMapped {
+                addr: VirtAddr(cur),
+                size: end - cur,
+            });
+        }
+
+        self.set_perms(addr, size, perms)
+    }
+
+    /// Checks that the memory range (`addr`..`addr` + `size`) is page-aligned
+    /// and within the address space. It returns the size rounded up to a
+    /// multiple of the page size.
+    fn check_mapping_range(
+        &self,
+        addr: VirtAddr,
+        size: usize,
+    ) -> Result<usize, Error> {
+        if *addr & (PAGE_SIZE - 1) != 0 {
+            return Err(Error::MisalignedMapping { addr });
+        }
+
+        let size = align_up(size, PAGE_SIZE)
+            .ok_or(Error::AddressIntegerOverflow { addr, size })?;
+        self.check_range(addr, size)?;
+
+        Ok(size)
+    }
+
+    /// Returns the start of the highest page-aligned free range of `size`
+    /// bytes above the program break, if any. Free ranges do not overlap
+    /// registered regions.
+    fn find_free_range(&self, size: usize) -> Option<VirtAddr> {
+        let floor = align_up(*self.brk, PAGE_SIZE)?;
+        let mut end = self.size & !(PAGE_SIZE - 1);
+
+        for region in self.regions.values().rev() {
+            let region_end = align_up(*region.addr + region.size, PAGE_SIZE)?;
+            let start = region_end.max(floor);
+
+            if end >= start && end - start >= size {
+                return Some(VirtAddr(end - size));
+            }
+
+            end = end.min(*region.addr & !(PAGE_SIZE - 1));
+            if end <= floor {
+                return None;
+            }
+        }
+
+        // The program break may be in the last partial page, above `end`.
+        if end.checked_sub(floor)? >= size {
+            Some(VirtAddr(end - size))
+        } else {
+            None
+        }
+    }
+
+    /// Maps the memory range (`addr`..`addr` + `size`) to I/O. Accesses
+    /// checking permissions fail with `Error::Mmio`, so they can be
+    /// dispatched to a device. Accesses that do not check permissions, like
+    /// `Mmu::peek` and `Mmu::poke`, use the memory backing the range.
+    pub fn map_mmio(
+        &mut self,
+        addr: VirtAddr,
+        size: usize,
+    ) -> Result<(), Error> {
+        self.check_range(addr, size)?;
+
+        for (number, offset, len) in page_chunks(addr, size) {
+            if let Some(page) = self.pages.get(&number) {
+                let mut perms = page.data.perms(offset, len);
+                if perms.any(|p| *p & PERM_MMIO != 0) {
+                    return Err(Error::MmioOverlap { addr, size });
+                }
+            }
+        }
+
+        for (number, offset, len) in page_chunks(addr, size) {
+            let data = self.page_data_mut(number);
+            data.update_perms(offset, len, |_, p| Perm(*p | PERM_MMIO));
+        }
+
+        self.update_dirty(addr, size);
+
+        Ok(())
+    }
+
+    /// Unmaps the memory range (`addr`..`addr` + `size`) from I/O.
+    pub fn unmap_mmio(
+        &mut self,
+        addr: VirtAddr,
+        size: usize,
+    ) -> Result<(), Error> {
+        self.check_range(addr, size)?;
+
+        for (number, offset, len) in page_chunks(addr, size) {
+            // Unallocated pages are not mapped already.
+            if !self.pages.contains_key(&number) {
+                continue;
+            }
+
+            let data = self.page_data_mut(number);
+            data.update_perms(offset, len, |_, p| Perm(*p & !PERM_MMIO));
+        }
+
+        self.update_dirty(addr, size);
+
+        Ok(())
+    }
+
+    /// Sets `PERM_WATCH` in the memory range (`addr`..`addr` + `size`) where
+    /// it is covered by a watchpoint, and clears it elsewhere. It does not
+    /// check if the memory range is valid.
+    fn update_watch_perms(&mut self, addr: VirtAddr, size: usize) {
+        for (number, offset, len) in page_chunks(addr, size) {
+            // Unallocated pages are not watched already.
+            if !self.pages.contains_key(&number) {
+                continue;
+            }
+
+            let data = self.page_data_mut(number);
+            data.update_perms(offset, len, |_, p| Perm(*p & !PERM_WATCH));
+        }
+
+        let end = *addr + size;
+        let ranges: Vec<(usize, usize)> = self
+            .watchpoints
+            .iter()
+            .map(|wp| (*wp.addr.max(addr), (*wp.addr + wp.size).min(end)))
+            .filter(|(start, end)| start < end)
+            .collect();
+
+        for (start, end) in ranges {
+            for (number, offset, len) in
+                page_chunks(VirtAddr(start), end - start)
+            {
+                let data = self.page_data_mut(number);
+                data.update_perms(offset, len, |_, p| Perm(*p | PERM_WATCH));
+            }
+        }
+
+        self.update_dirty(addr, size);
+    }
+
+    /// Enables or disables taint tracking. Disabling it discards all the
+    /// taint labels.
+    pub fn set_taint_tracking(&mut self, enabled: bool) {
+        if enabled && self.taint.is_none() {
+            self.taint = Some(Shadow::default());
+        } else if !enabled {
+            self.taint = None;
+        }
+    }
+
+    /// Returns `true` if taint tracking is enabled.
+    pub fn taint_tracking(&self) -> bool {
+        self.taint.is_some()
+    }
+
+    /// Returns the taint labels of the memory range (`addr`..`addr` +
+    /// `size`). They are all untainted if taint tracking is disabled.
+    pub fn taint(
+        &self,
+        addr: VirtAddr,
+        size: usize,
+    ) -> Result<Vec<Taint>, Error> {
+        self.check_range(addr, size)?;
+
+        match &self.taint {
+            Some(taint) => Ok(taint.get(addr, size)),
+            None => Ok(vec![Taint::UNTAINTED; size]),
+        }
+    }
+
+    /// Sets the taint labels of the memory starting at `addr` to `labels`.
+    /// It does nothing if taint tracking is disabled.
+    pub fn set_taint(
+        &mut self,
+        addr: VirtAddr,
+        labels: &[Taint],
+    ) -> Result<(), Error> {
+        let size = labels.len();
+
+        self.check_range(addr, size)?;
+
+        if let Some(taint) = &mut self.taint {
+            taint.set(addr, labels);
+            self.update_shadow_dirty(addr, size);
+        }
+
+        Ok(())
+    }
+
+    /// Labels the memory range (`addr`..`addr` + `size`) as the bytes of the
+    /// input starting at `offset`. It does nothing if taint tracking is
+    /// disabled.
+    pub fn taint_input(
+        &mut self,
+        addr: VirtAddr,
+        size: usize,
+        offset: usize,
+    ) -> Result<(), Error> {
+        if self.taint.is_none() {
+            return self.check_range(addr, size);
+        }
+
+        let labels: Vec<Taint> =
+            (offset..offset + size).map(Taint::input).collect();
+        self.set_taint(addr, &labels)
+    }
+
+    /// Enables or disables bit-precise uninitialized memory tracking.
+    /// Disabling it discards the definedness of the memory.
+    ///
+    /// While it is enabled, the memory returned by `Mmu::malloc` with `raw`
+    /// set is readable instead of being marked as `PERM_RAW`, and its bits
+    /// are undefined until they are written. Their origin is the allocation.
+    pub fn set_uninit_tracking(&mut self, enabled: bool) {
+        if enabled && self.uninit.is_none() {
+            self.uninit = Some(UninitShadow::default());
+        } else if !enabled {
+            self.uninit = None;
+        }
+    }
+
+    /// Returns `true` if bit-precise uninitialized memory tracking is
+    /// enabled.
+    pub fn uninit_tracking(&self) -> bool {
+        self.uninit.is_some()
+    }
+
+    /// Returns the definedness of the memory range (`addr`..`addr` +
+    /// `size`). It is fully defined if uninitialized memory tracking is
+    /// disabled.
+    pub fn definedness(
+        &self,
+        addr: VirtAddr,
+        size: usize,
+    ) -> Result<Vec<Definedness>, Error> {
+        self.check_range(addr, size)?;
+
+        match &self.uninit {
+            Some(uninit) => Ok(uninit.bytes.get(addr, size)),
+            None => Ok(vec![Definedness::default(); size]),
+        }
+    }
+
+    /// Sets the definedness of the memory starting at `addr` to `bytes`. It
+    /// does nothing if uninitialized memory tracking is disabled.
+    pub fn set_definedness(
+        &mut self,
+        addr: VirtAddr,
+        bytes: &[Definedness],
+    ) -> Result<(), Error> {
+        let size = bytes.len();
+
+        self.check_range(addr, size)?;
+
+        if let Some(uninit) = &mut self.uninit {
+            uninit.bytes.set(addr, bytes);
+            self.update_shadow_dirty(addr, size);
+        }
+
+        Ok(())
+    }
+
+    /// Returns the allocation identified by `id` which originated undefined
+    /// memory.
+    pub fn origin(&self, id: OriginId) -> Option<&Origin> {
+        let uninit = self.uninit.as_ref()?;
+        let index = (id.0 as usize).checked_sub(1)?;
+
+        uninit.origins.get(index)
+    }
+
+    /// Marks the shadow memory range (`addr`..`addr` + `size`) as dirty.
+    /// Shadow memory is restored on reset along with the dirty blocks, so
+    /// the pages are allocated to be tracked. It does not check if the memory
+    /// range is valid.
+    fn update_shadow_dirty(&mut self, addr: VirtAddr, size: usize) {
+        for (number, _, _) in page_chunks(addr, size) {
+            if !self.pages.contains_key(&number) {
+                self.page_data_mut(number);
+            }
+        }
+
+        self.update_dirty(addr, size);
+    }
+
+    /// Copy the bytes in `src` to the given memory address. This function will
+    /// fail if the destination memory is not writable.
+    pub fn write(&mut self, addr: VirtAddr, src: &[u8]) -> Result<(), Error> {
+        self.write_with_perms(addr, src, Perm(PERM_WRITE))
+    }
+
+    /// Copy the bytes in `src` to the given memory address. This function will
+    /// fail if the destination memory does not satisfy the expected
+    /// permissions. Memory marked as `PERM_RAW` will be marked as `PERM_READ`
+    /// only if `PERM_WRITE` is within the expected permissions.
+    pub fn write_with_perms(
+        &mut self,
+        addr: VirtAddr,
+        src: &[u8],
+        perms: Perm,
+    ) -> Result<(), Error> {
+        let size = src.len();
+
+        // Check if the destination memory range is writable.
+        self.check_perms(addr, size, perms)?;
+
+        let mut src_off = 0;
+
+        // The previous contents are overwritten, so the written memory is
+        // untainted and fully defined.
+        if let Some(taint) = &mut self.taint {
+            taint.fill(addr, size, Taint::UNTAINTED);
+        }
+        if let Some(uninit) = &mut self.uninit {
+            uninit.bytes.fill(addr, size, Definedness::default());
+        }
+
+        for (number, offset, len) in page_chunks(addr, size) {
+            let page = self.page_data_mut(number);
+
+            // Update memory contents
+            page.memory[offset..offset + len]
+                .copy_from_slice(&src[src_off..src_off + len]);
+
+            // Add PERM_READ and remove PERM_RAW in case of RAW.
+            if *perms & PERM_WRITE != 0 {
+                let initialized =
+                    page.perms(offset, len).any(|p| *p & PERM_RAW != 0);
+                if initialized {
+                    page.update_perms(offset, len, |_, p| {
+                        if *p & PERM_RAW != 0 {
+                            Perm((*p | PERM_READ) & !PERM_RAW)
+                        } else {
+                            p
+                        }
+                    });
+                }
+            }
+
+            src_off += len;
+        }
+
+        self.update_dirty(addr, size);
+
+        Ok(())
+    }
+
+    /// Copy the data starting at the specified memory address into `dst`.
+    /// This function will fail if the source memory is not readable.
+    pub fn read(&self, addr: VirtAddr, dst: &mut [u8]) -> Result<(), Error> {
+        self.read_with_perms(addr, dst, Perm(PERM_READ))
+    }
+
+    /// Copy the data starting at the specified memory address into `dst`.
+    /// This function will fail if the source memory does not satisfy the
+    /// expected permissions.
+    pub fn read_with_perms(
+        &self,
+        addr: VirtAddr,
+        dst: &mut [u8],
+        perms: Perm,
+    ) -> Result<(), Error> {
+        let size = dst.len();
+
+        // Check if the source memory range is readable.
+        self.check_perms(addr, size, perms)?;
+
+        let mut dst_off = 0;
+
+        for (number, offset, len) in page_chunks(addr, size) {
+            let dst = &mut dst[dst_off..dst_off + len];
+
+            match self.pages.get(&number) {
+                Some(page) => dst
+                    .copy_from_slice(&page.data.memory[offset..offset + len]),
+                None => dst.iter_mut().for_each(|b| *b = 0),
+            }
+
+            dst_off += len;
+        }
+
+        Ok(())
+    }
+
+    /// Copy the bytes in `src` to the given memory address. This function
+    /// does not check memory permissions.
+    pub fn poke(&mut self, addr: VirtAddr, src: &[u8]) -> Result<(), Error> {
+        self.write_with_perms(addr, src, Perm(0))
+    }
+
+    /// Copy the data starting at the specified memory address into `dst`.
+    /// This function does not check memory permissions.
+    pub fn peek(&self, addr: VirtAddr, dst: &mut [u8]) -> Result<(), Error> {
+        self.read_with_perms(addr, dst, Perm(0))
+    }
+
+    /// Compute dirty blocks. It does not check if the memory range is valid.
+    /// Blocks in unallocated pages are ignored.
+    fn update_dirty(&mut self, addr: VirtAddr, size: usize) {
+        if size == 0 {
+            return;
+        }
+
+        let config = self.dirty_config;
+        let page_blocks = config.page_blocks();
+
+        let block_start = *addr / config.block_size;
+        // Calculate the block containing the last byte. Using the last byte
+        // instead of `end` avoids overflows at the top of the address space.
+        let block_end = (*addr + size - 1) / config.block_size;
+
+        for block in block_start..=block_end {
+            let number = block / page_blocks;
+            let bit = block % page_blocks;
+
+            let page = match self.pages.get_mut(&number) {
+                Some(page) => page,
+                None => continue,
+            };
+
+            if *page.dirty & (1 << bit) == 0 {
+                // In hierarchical mode, only the first dirty block of every
+                // page is recorded in the dirty list.
+                if !config.hierarchical {
+                    self.dirty.push(block);
+                } else if *page.dirty == 0 {
+                    self.dirty.push(number);
+                }
+                *page.dirty |= 1 << bit;
+            }
+        }
+    }
+
+    /// Write an integer value into a given memory address. This function will
+    /// fail if the destination memory is not writable.
+    pub fn write_int<T: LeBytes>(
+        &mut self,
+        addr: VirtAddr,
+        value: T::Target,
+    ) -> Result<(), Error> {
+        let bytes = T::to_le_bytes(value);
+        let src = &bytes[..mem::size_of::<T::Target>()];
+        self.write(addr, src)?;
+        Ok(())
+    }
+
+    /// Write an integer value into a given memory address. This function will
+    /// fail if the destination memory does not satisfy the expected
+    /// permissions.
+    pub fn write_int_with_perms<T: LeBytes>(
+        &mut self,
+        addr: VirtAddr,
+        value: T::Target,
+        perms: Perm,
+    ) -> Result<(), Error> {
+        let bytes = T::to_le_bytes(value);
+        let src = &bytes[..mem::size_of::<T::Target>()];
+        self.write_with_perms(addr, src, perms)?;
+        Ok(())
+    }
+
+    /// Read the data starting at the specified memory address into an integer.
+    /// This function will fail if the source memory is not readable.
+    pub fn read_int<T: LeBytes>(
+        &self,
+        addr: VirtAddr,
+    ) -> Result<T::Target, Error> {
+        let mut bytes = [0u8; 16];
+        let dst = &mut bytes[..mem::size_of::<T::Target>()];
+        self.read(addr, dst)?;
+        Ok(T::from_le_bytes(bytes))
+    }
+
+    /// Copy the data starting at the specified memory address into `dst`.
+    /// This function will fail if the source memory does not satisfy the
+    /// expected permissions.
+    pub fn read_int_with_perms<T: LeBytes>(
+        &self,
+        addr: VirtAddr,
+        perms: Perm,
+    ) -> Result<T::Target, Error> {
+        let mut bytes = [0u8; 16];
+        let dst = &mut bytes[..mem::size_of::<T::Target>()];
+        self.read_with_perms(addr, dst, perms)?;
+        Ok(T::from_le_bytes(bytes))
+    }
+
+    /// Write an integer value into a given memory address. This function does
+    /// not check memory permissions.
+    pub fn poke_int<T: LeBytes>(
+        &mut self,
+        addr: VirtAddr,
+        value: T::Target,
+    ) -> Result<(), Error> {
+        let bytes = T::to_le_bytes(value);
+        let src = &bytes[..mem::size_of::<T::Target>()];
+        self.poke(addr, src)?;
+        Ok(())
+    }
+
+    /// Read the data starting at the specified memory address into an integer.
+    /// This function does not check memory permissions.
+    pub fn peek_int<T: LeBytes>(
+        &self,
+        addr: VirtAddr,
+    ) -> Result<T::Target, Error> {
+        let mut bytes = [0u8; 16];
+        let dst = &mut bytes[..mem::size_of::<T::Target>()];
+        self.peek(addr, dst)?;
+        Ok(T::from_le_bytes(bytes))
+    }
+
+    /// Reads a NUL-terminated string starting at `addr`, reading at most
+    /// `max_len` bytes including the terminator. The terminator is not
+    /// returned. This function will fail if the string is not readable or it
+    /// is not terminated within `max_len` bytes.
+    pub fn read_cstr(
+        &self,
+        addr: VirtAddr,
+        max_len: usize,
+    ) -> Result<Vec<u8>, Error> {
+        self.read_cstr_with_perms(addr, max_len, Perm(PERM_READ))
+    }
+
+    /// Reads a NUL-terminated string starting at `addr`, reading at most
+    /// `max_len` bytes including the terminator. The terminator is not
+    /// returned. This function will fail if the string does not satisfy the
+    /// expected permissions or it is not terminated within `max_len` bytes.
+    pub fn read_cstr_with_perms(
+        &self,
+        addr: VirtAddr,
+        max_len: usize,
+        perms: Perm,
+    ) -> Result<Vec<u8>, Error> {
+        let mut result = Vec::new();
+
+        for off in 0..max_len {
+            let cur = addr.checked_add(off).ok_or(
+                Error::AddressIntegerOverflow {
+                    addr,
+                    size: off + 1,
+                },
+            )?;
+
+            // Read byte by byte, so the memory after the terminator is not
+            // accessed.
+            let ch = self.read_int_with_perms::<u8>(VirtAddr(cur), perms)?;
+            if ch == 0 {
+                return Ok(result);
+            }
+            result.push(ch);
+        }
+
+        Err(Error::StringTooLong {
+            addr,
+            size: max_len,
+        })
+    }
+
+    /// Reads a NUL-terminated string starting at `addr`, reading at most
+    /// `max_len` bytes including the terminator. The terminator is not
+    /// returned. This function does not check memory permissions.
+    pub fn peek_cstr(
+        &self,
+        addr: VirtAddr,
+        max_len: usize,
+    ) -> Result<Vec<u8>, Error> {
+        self.read_cstr_with_perms(addr, max_len, Perm(0))
+    }
+
+    /// Writes `s` followed by a NUL terminator at `addr`. This function will
+    /// fail if the destination memory is not writable.
+    pub fn write_cstr(
+        &mut self,
+        addr: VirtAddr,
+        s: &[u8],
+    ) -> Result<(), Error> {
+        self.write_cstr_with_perms(addr, s, Perm(PERM_WRITE))
+    }
+
+    /// Writes `s` followed by a NUL terminator at `addr`. This function will
+    /// fail if the destination memory does not satisfy the expected
+    /// permissions.
+    pub fn write_cstr_with_perms(
+        &mut self,
+        addr: VirtAddr,
+        s: &[u8],
+        perms: Perm,
+    ) -> Result<(), Error> {
+        let mut src = Vec::with_capacity(s.len() + 1);
+        src.extend_from_slice(s);
+        src.push(0);
+
+        self.write_with_perms(addr, &src, perms)
+    }
+
+    /// Writes `s` followed by a NUL terminator at `addr`. This function does
+    /// not check memory permissions.
+    pub fn poke_cstr(
+        &mut self,
+        addr: VirtAddr,
+        s: &[u8],
+    ) -> Result<(), Error> {
+        self.write_cstr_with_perms(addr, s, Perm(0))
+    }
+
+    /// Reads a plain-data value starting at `addr`. This function will fail
+    /// if the source memory is not readable.
+    pub fn read_struct<T: Pod>(&self, addr: VirtAddr) -> Result<T, Error> {
+        self.read_struct_with_perms(addr, Perm(PERM_READ))
+    }
+
+    /// Reads a plain-data value starting at `addr`. This function will fail
+    /// if the source memory does not satisfy the expected permissions.
+    pub fn read_struct_with_perms<T: Pod>(
+        &self,
+        addr: VirtAddr,
+        perms: Perm,
+    ) -> Result<T, Error> {
+        // Any bit pattern is a valid `T`, as required by `Pod`.
+        let mut value: T = unsafe { mem::zeroed() };
+        self.read_slice_into(addr, slice::from_mut(&mut value), perms)?;
+        Ok(value)
+    }
+
+    /// Reads a plain-data value starting at `addr`. This function does not
+    /// check memory permissions.
+    pub fn peek_struct<T: Pod>(&self, addr: VirtAddr) -> Result<T, Error> {
+        self.read_struct_with_perms(addr, Perm(0))
+    }
+
+    /// Writes the plain-data value `value` at `addr`. This function will fail
+    /// if the destination memory is not writable.
+    pub fn write_struct<T: Pod>(
+        &mut self,
+        addr: VirtAddr,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.write_slice_with_perms(
+            addr,
+            slice::from_ref(value),
+            Perm(PERM_WRITE),
+        )
+    }
+
+    /// Writes the plain-data value `value` at `addr`. This function will fail
+    /// if the destination memory does not satisfy the expected permissions.
+    pub fn write_struct_with_perms<T: Pod>(
+        &mut self,
+        addr: VirtAddr,
+        value: &T,
+        perms: Perm,
+    ) -> Result<(), Error> {
+        self.write_slice_with_perms(addr, slice::from_ref(value), perms)
+    }
+
+    /// Writes the plain-data value `value` at `addr`. This function does not
+    /// check memory permissions.
+    pub fn poke_struct<T: Pod>(
+        &mut self,
+        addr: VirtAddr,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.write_slice_with_perms(addr, slice::from_ref(value), Perm(0))
+    }
+
+    /// Reads an array of `count` plain-data values starting at `addr`. This
+    /// function will fail if the source memory is not readable.
+    pub fn read_slice<T: Pod>(
+        &self,
+        addr: VirtAddr,
+        count: usize,
+    ) -> Result<Vec<T>, Error> {
+        self.read_slice_with_perms(addr, count, Perm(PERM_READ))
+    }
+
+    /// Reads an array of `count` plain-data values starting at `addr`. This
+    /// function will fail if the source memory does not satisfy the expected
+    /// permissions.
+    pub fn read_slice_with_perms<T: Pod>(
+        &self,
+        addr: VirtAddr,
+        count: usize,
+        perms: Perm,
+    ) -> Result<Vec<T>, Error> {
+        // The size is checked before allocating the values.
+        let size = slice_size::<T>(addr, count)?;
+        self.check_range(addr, size)?;
+
+        // Any bit pattern is a valid `T`, as required by `Pod`.
+        let mut values = vec![unsafe { mem::zeroed() }; count];
+        self.read_slice_into(addr, &mut values, perms)?;
+        Ok(values)
+    }
+
+    /// Reads an array of `count` plain-data values starting at `addr`. This
+    /// function does not check memory permissions.
+    pub fn peek_slice<T: Pod>(
+        &self,
+        addr: VirtAddr,
+        count: usize,
+    ) -> Result<Vec<T>, Error> {
+        self.read_slice_with_perms(addr, count, Perm(0))
+    }
+
+    /// Writes the plain-data values in `values` at `addr`. This function will
+    /// fail if the destination memory is not writable.
+    pub fn write_slice<T: Pod>(
+        &mut self,
+        addr: VirtAddr,
+        values: &[T],
+    ) -> Result<(), Error> {
+        self.write_slice_with_perms(addr, values, Perm(PERM_WRITE))
+    }
+
+    /// Writes the plain-data values in `values` at `addr`. This function will
+    /// fail if the destination memory does not satisfy the expected
+    /// permissions.
+    pub fn write_slice_with_perms<T: Pod>(
+        &mut self,
+        addr: VirtAddr,
+        values: &[T],
+        perms: Perm,
+    ) -> Result<(), Error> {
+        // `Pod` types have no padding, so every byte is initialized.
+        let src = unsafe {
+            slice::from_raw_parts(
+                values.as_ptr() as *const u8,
+                mem::size_of_val(values),
+            )
+        };
+        self.write_with_perms(addr, src, perms)
+    }
+
+    /// Writes the plain-data values in `values` at `addr`. This function does
+    /// not check memory permissions.
+    pub fn poke_slice<T: Pod>(
+        &mut self,
+        addr: VirtAddr,
+        values: &[T],
+    ) -> Result<(), Error> {
+        self.write_slice_with_perms(addr, values, Perm(0))
+    }
+
+    /// Reads the plain-data values starting at `addr` into `values`.
+    fn read_slice_into<T: Pod>(
+        &self,
+        addr: VirtAddr,
+        values: &mut [T],
+        perms: Perm,
+    ) -> Result<(), Error> {
+        // Any bit pattern is a valid `T`, as required by `Pod`.
+        let dst = unsafe {
+            slice::from_raw_parts_mut(
+                values.as_mut_ptr() as *mut u8,
+                mem::size_of_val(values),
+            )
+        };
+        self.read_with_perms(addr, dst, perms)
+    }
+
+    /// Reads an array of `count` I/O vectors starting at `addr`, as passed to
+    /// `readv` and `writev`. This function will fail if the array is not
+    /// readable.
+    pub fn read_iovecs(
+        &self,
+        addr: VirtAddr,
+        count: usize,
+    ) -> Result<Vec<IoVec>, Error> {
+        self.read_slice(addr, count)
+    }
+
+    /// Reads an array of `count` I/O vectors starting at `addr`, as passed to
+    /// `readv` and `writev`. This function does not check memory
+    /// permissions.
+    pub fn peek_iovecs(
+        &self,
+        addr: VirtAddr,
+        count: usize,
+    ) -> Result<Vec<IoVec>, Error> {
+        self.peek_slice(addr, count)
+    }
+
+    /// Copies `size` bytes from `src` to `dst`. The ranges may overlap. The
+    /// taint labels and the definedness of the bytes are copied along with
+    /// them. This function will fail if the source memory is not readable or
+    /// the destination memory is not writable.
+    pub fn copy(
+        &mut self,
+        dst: VirtAddr,
+        src: VirtAddr,
+        size: usize,
+    ) -> Result<(), Error> {
+        self.copy_with_perms(dst, src, size, Perm(PERM_READ), Perm(PERM_WRITE))
+    }
+
+    /// Copies `size` bytes from `src` to `dst`. The ranges may overlap. The
+    /// taint labels and the definedness of the bytes are copied along with
+    /// them. This function does not check memory permissions.
+    pub fn copy_unchecked(
+        &mut self,
+        dst: VirtAddr,
+        src: VirtAddr,
+        size: usize,
+    ) -> Result<(), Error> {
+        self.copy_with_perms(dst, src, size, Perm(0), Perm(0))
+    }
+
+    /// Copies `size` bytes from `src` to `dst`, checking that the source
+    /// memory satisfies `src_perms` and the destination memory satisfies
+    /// `dst_perms`.
+    fn copy_with_perms(
+        &mut self,
+        dst: VirtAddr,
+        src: VirtAddr,
+        size: usize,
+        src_perms: Perm,
+        dst_perms: Perm,
+    ) -> Result<(), Error> {
+        self.check_range(src, size)?;
+        self.check_range(dst, size)?;
+
+        let mut bytes = vec![0; size];
+        self.read_with_perms(src, &mut bytes, src_perms)?;
+
+        let taint = self.taint.as_ref().map(|taint| taint.get(src, size));
+        let definedness = self
+            .uninit
+            .as_ref()
+            .map(|uninit| uninit.bytes.get(src, size));
+
+        self.write_with_perms(dst, &bytes, dst_perms)?;
+
+        if let Some(labels) = taint {
+            self.set_taint(dst, &labels)?;
+        }
+        if let Some(definedness) = definedness {
+            self.set_definedness(dst, &definedness)?;
+        }
+
+        Ok(())
+    }
+
+    /// Strict memory allocator. This function tries to allocate `size` bytes
+    /// aligned to `align`, which must be a power of two, and returns the
+    /// address of the allocated memory. If `raw` is true, it is also able to
+    /// detect accesses to unitialized data. `site` is recorded to explain
+    /// later heap errors.
+    ///
+    /// Allocations are surrounded by redzones with 0 permissions, which
+    /// allows to detect OOB. Freed memory is reused according to the
+    /// allocator configuration. Allocations chosen by the fault-injection
+    /// policy fail with `Error::InjectedAllocFailure`.
+    pub fn malloc(
+        &mut self,
+        size: usize,
+        align: usize,
+        raw: bool,
+        site: AllocSite,
+    ) -> Result<VirtAddr, Error> {
+        if !align.is_power_of_two() {
+            return Err(Error::InvalidAlignment { align });
+        }
+
+        let index = self.heap.alloc_count;
+        self.heap.alloc_count += 1;
+
+        if let Some(policy) = &self.heap.fail_policy {
+            if policy.fails(index, size) {
+                self.heap.failed.push(FailedAlloc { index, size, site });
+                return Err(Error::InjectedAllocFailure { index, size });
+            }
+        }
+
+        let overflow = Error::AddressIntegerOverflow {
+            addr: self.brk,
+            size,
+        };
+        let left_redzone = self.heap.config.left_redzone;
+        let right_redzone = self.heap.config.right_redzone;
+
+        // Size of the chunk in the worst case, whatever its address is.
+        let max_chunk_size = left_redzone
+            .checked_add(align - 1)
+            .and_then(|s| s.checked_add(size))
+            .and_then(|s| s.checked_add(right_redzone))
+            .and_then(|s| align_up(s, CHUNK_ALIGN))
+            .ok_or(overflow)?;
+
+        // Try to reuse a free chunk. Otherwise, the chunk is placed at brk.
+        let free_chunk = self.heap.take_free(max_chunk_size);
+        let chunk_addr = match free_chunk {
+            Some((chunk_addr, _)) => chunk_addr,
+            None => self.brk,
+        };
+
+        // Compute the layout of the chunk.
+        let layout = chunk_addr
+            .checked_add(left_redzone)
+            .and_then(|addr| align_up(addr, align))
+            .and_then(|addr| {
+                let end = addr
+                    .checked_add(size)?
+                    .checked_add(right_redzone)
+                    .and_then(|end| align_up(end, CHUNK_ALIGN))?;
+                Some((VirtAddr(addr), end - *chunk_addr))
+            });
+        let (addr, chunk_size) = match layout {
+            Some(layout) => layout,
+            None => {
+                return Err(Error::AddressIntegerOverflow {
+                    addr: chunk_addr,
+                    size,
+                })
+            }
+        };
+
+        // The heap must not grow into the stack nor the mappings.
+        if free_chunk.is_none() {
+            self.check_range(chunk_addr, chunk_size)?;
+
+            if self.overlaps_stack(chunk_addr, chunk_size) {
+                return Err(Error::StackCollision {
+                    addr: chunk_addr,
+                    size: chunk_size,
+                });
+            }
+
+            self.grow_heap_region(chunk_addr, chunk_size)?;
+        }
+
+        // Make sure the full chunk (allocated bytes + redzones) is valid and
+        // starts with 0 permissions.
+        self.set_perms(chunk_addr, chunk_size, Perm(0))?;
+
+        // Set permissions according to the `raw` value. Which enables the
+        // detection of uninit faults, unless uninitialized memory is tracked
+        // bit by bit.
+        let perms = if raw && self.uninit.is_none() {
+            Perm(PERM_WRITE | PERM_RAW)
+        } else {
+            Perm(PERM_WRITE | PERM_READ)
+        };
+        self.set_perms(addr, size, perms)?;
+
+        // Return the unused part of the free chunk to the free list or update
+        // brk.
+        match free_chunk {
+            Some((_, free_size)) if free_size > chunk_size => {
+                self.heap.insert_free(
+                    VirtAddr(*chunk_addr + chunk_size),
+                    free_size - chunk_size,
+                );
+            }
+            Some(_) => {}
+            None => *self.brk += chunk_size,
+        }
+
+        // Reused memory keeps the definedness of its previous contents, so
+        // it is always overwritten.
+        if let Some(uninit) = &mut self.uninit {
+            let definedness = if raw {
+                uninit.origins.push(Origin {
+                    addr,
+                    size,
+                    site: site.clone(),
+                });
+                Definedness {
+                    undef: 0xff,
+                    origin: OriginId(uninit.origins.len() as u32),
+                }
+            } else {
+                Definedness::default()
+            };
+            uninit.bytes.fill(addr, size, definedness);
+        }
+
+        // Update the list of active allocations.
+        Arc::make_mut(&mut self.heap.active).insert(
+            addr,
+            Alloc {
+                size,
+                chunk_addr,
+                chunk_size,
+                alloc_site: site,
+                free_site: None,
+            },
+        );
+
+        Ok(addr)
+    }
+
+    /// Strict memory free. `site` is recorded to explain later heap errors.
+    pub fn free(
+        &mut self,
+        addr: VirtAddr,
+        site: AllocSite,
+    ) -> Result<(), Error> {
+        let active = Arc::make_mut(&mut self.heap.active);
+        if let Some(mut alloc) = active.remove(&addr) {
+            // The permissions of the freed memory are set to 0, which allows
+            // to detect UAF while the chunk is in the quarantine.
+            self.set_perms(addr, alloc.size, Perm(0))?;
+            alloc.free_site = Some(site);
+            self.heap.quarantine(addr, alloc);
+            Ok(())
+        } else {
+            // If the address is not in the list of active allocations, this is
+            // an invalid free. This can happen due to a double free or a
+            // corrupted heap.
+            Err(Error::InvalidFree { addr })
+        }
+    }
+
+    /// Sets the policy deciding which allocations fail with
+    /// `Error::InjectedAllocFailure`. If `None`, allocations only fail on
+    /// errors. The failed allocations are recorded, so they can be reproduced.
+    /// The policy is kept when the memory is reset, so a policy set for a
+    /// fuzz case is not overwritten by the one of the original state.
+    pub fn set_alloc_fail_policy(&mut self, policy: Option<AllocFailPolicy>) {
+        self.heap.fail_policy = policy;
+    }
+
+    /// Returns the policy deciding which allocations fail, if any.
+    pub fn alloc_fail_policy(&self) -> Option<&AllocFailPolicy> {
+        self.heap.fail_policy.as_ref()
+    }
+
+    /// Returns the number of allocations requested so far, including the
+    /// failed ones.
+    pub fn alloc_count(&self) -> usize {
+        self.heap.alloc_count
+    }
+
+    /// Returns the allocations made to fail by the fault-injection policy,
+    /// in order.
+    pub fn failed_allocs(&self) -> &[FailedAlloc] {
+        &self.heap.failed
+    }
+
+    /// Returns the size of the allocation corresponding to the virtual address
+    /// `addr`.
+    pub fn alloc_size(&self, addr: VirtAddr) -> Option<usize> {
+        self.heap.active.get(&addr).map(|alloc| alloc.size)
+    }
+
+    /// Describes `addr` relative to the heap allocation it belongs to. It
+    /// returns `None` if `addr` is not within an active allocation or a freed
+    /// allocation in the quarantine, including their redzones.
+    pub fn heap_report(&self, addr: VirtAddr) -> Option<HeapReport> {
+        let (region, alloc) = self.heap.find(addr)?;
+
+        Some(HeapReport {
+            addr,
+            region,
+            size: alloc.size,
+            alloc_site: alloc.alloc_site.clone(),
+            free_site: alloc.free_site.clone(),
+        })
+    }
+
+    /// Returns the active allocations which are leaked, sorted by address.
+    ///
+    /// If `roots` is `None`, every active allocation is reported as a direct
+    /// leak. Otherwise, a conservative reachability scan is done: the
+    /// allocations pointed to by `roots`, usually the values of the
+    /// registers, or by any aligned word in the global and stack regions
+    /// are reachable, as are the allocations pointed to by reachable ones.
+    /// Pointers to any byte of an allocation are considered.
+    pub fn leaks(&self, roots: Option<&[u64]>) -> Vec<Leak> {
+        let mut allocs: Vec<(VirtAddr, &Alloc)> = self
+            .heap
+            .active
+            .iter()
+            .map(|(addr, alloc)| (*addr, alloc))
+            .collect();
+        allocs.sort_unstable_by_key(|(addr, _)| *addr);
+
+        // Returns the index of the allocation containing `value`.
+        let find = |value: u64| {
+            let value = value as usize;
+            let index = match allocs.binary_search_by_key(&value, |a| *a.0) {
+                Ok(index) => index,
+                Err(0) => return None,
+                Err(index) => index - 1,
+            };
+            let (addr, alloc) = allocs[index];
+            if value == *addr || value - *addr < alloc.size {
+                Some(index)
+            } else {
+                None
+            }
+        };
+
+        let mut reachable = vec![false; allocs.len()];
+        let mut indirect = vec![false; allocs.len()];
+
+        if let Some(roots) = roots {
+            let mut pending = Vec::new();
+
+            let mut words = roots.to_vec();
+            for region in self.regions.values().filter(|region| {
+                region.kind == RegionKind::Global
+                    || region.kind == RegionKind::Stack
+            }) {
+                words.extend(self.words(region.addr, region.size));
+            }
+
+            loop {
+                for index in words.drain(..).filter_map(find) {
+                    if !reachable[index] {
+                        reachable[index] = true;
+                        pending.push(index);
+                    }
+                }
+
+                match pending.pop() {
+                    Some(index) => {
+                        let (addr, alloc) = allocs[index];
+                        words.extend(self.words(addr, alloc.size));
+                    }
+                    None => break,
+                }
+            }
+
+            // Leaks pointed to by other leaks are indirect.
+            for (index, (addr, alloc)) in allocs.iter().enumerate() {
+                if reachable[index] {
+                    continue;
+                }
+                for other in self.words(*addr, alloc.size).filter_map(find) {
+                    if other != index {
+                        indirect[other] = true;
+                    }
+                }
+            }
+        }
+
+        allocs
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| !reachable[*index])
+            .map(|(index, (addr, alloc))| Leak {
+                addr: *addr,
+                size: alloc.size,
+                site: alloc.alloc_site.clone(),
+                kind: if indirect[index] {
+                    LeakKind::Indirect
+                } else {
+                    LeakKind::Direct
+                },
+            })
+            .collect()
+    }
+
+    /// Returns the aligned 64-bit words within the memory range (`addr`..
+    /// `addr` + `size`). Unallocated pages are skipped. It does not check
+    /// memory permissions nor if the memory range is valid.
+    fn words(
+        &self,
+        addr: VirtAddr,
+        size: usize,
+    ) -> impl Iterator<Item = u64> + '_ {
+        let start = (*addr + 7) & !7;
+        let end = (*addr + size) & !7;
+
+        (start..end.max(start)).step_by(8).filter_map(move |word| {
+            let page = self.pages.get(&(word / PAGE_SIZE))?;
+            let offset = word % PAGE_SIZE;
+            let bytes = &page.data.memory[offset..offset + 8];
+            Some(u64::from_le_bytes(bytes.try_into().unwrap()))
+        })
+    }
+
+    /// Compares the Mmu with `base`, e.g. its fork parent or the state it was
+    /// reset to. It returns the memory ranges whose contents or permissions
+    /// differ, the program breaks if they differ and the active allocations
+    /// that differ. Pages still shared with `base` are skipped, so comparing
+    /// an Mmu with its fork parent only inspects the modified pages. The
+    /// permission flags used internally by the Mmu are ignored.
+    pub fn diff(&self, base: &Mmu) -> MmuDiff {
+        let mut numbers: Vec<usize> = self
+            .pages
+            .keys()
+            .chain(base.pages.keys())
+            .copied()
+            .collect();
+        numbers.sort_unstable();
+        numbers.dedup();
+
+        let perms = |data: &PageData| -> Vec<u8> {
+            data.perms(0, PAGE_SIZE)
+                .map(|p| *p & !PERM_INTERNAL)
+                .collect()
+        };
+
+        let empty = Page::new();
+        let mut diff = MmuDiff::default();
+        for number in numbers {
+            let old = &base.pages.get(&number).unwrap_or(&empty).data;
+            let new = &self.pages.get(&number).unwrap_or(&empty).data;
+            if Arc::ptr_eq(old, new) {
+                continue;
+            }
+
+            let addr = number * PAGE_SIZE;
+            diff_bytes(&mut diff.contents, addr, &old.memory, &new.memory);
+            diff_bytes(&mut diff.perms, addr, &perms(old), &perms(new));
+        }
+
+        if self.brk != base.brk {
+            diff.brk = Some((base.brk, self.brk));
+        }
+
+        let addrs: BTreeSet<VirtAddr> = self
+            .heap
+            .active
+            .keys()
+            .chain(base.heap.active.keys())
+            .copied()
+            .collect();
+        for addr in addrs {
+            let old = base.heap.active.get(&addr).map(|alloc| alloc.size);
+            let new = self.heap.active.get(&addr).map(|alloc| alloc.size);
+            if old != new {
+                diff.allocs.push(AllocDiff { addr, old, new });
+            }
+        }
+
+        diff
+    }
+
+    /// Writes the state of the Mmu to the snapshot `w`. It contains the
+    /// memory, the permissions, the program break, the heap allocator, the
+    /// watchpoints, the regions and the paging configuration. Dirty memory,
+    /// memory-mapped I/O, taint labels, the definedness of the memory, the
+    /// allocation fault-injection policy and the cached translations are not
+    /// included.
+    pub fn write_snapshot<W: Write>(
+        &self,
+        w: &mut Writer<W>,
+    ) -> Result<(), snapshot::Error> {
+        w.write_usize(self.size)?;
+        w.write_usize(self.dirty_config.block_size)?;
+        w.write_bool(self.dirty_config.hierarchical)?;
+        w.write_usize(*self.brk)?;
+
+        // Pages are sorted, so equal Mmus produce equal snapshots.
+        let mut numbers: Vec<usize> = self.pages.keys().copied().collect();
+        numbers.sort_unstable();
+        w.write_usize(numbers.len())?;
+        for number in numbers {
+            let data = &self.pages[&number].data;
+            let perms: Vec<u8> =
+                data.perms(0, PAGE_SIZE).map(|p| *p & !PERM_MMIO).collect();
+
+            w.write_usize(number)?;
+            w.write_bytes(&data.memory)?;
+            w.write_bytes(&perms)?;
+        }
+
+        self.heap.write_snapshot(w)?;
+
+        w.write_usize(self.watchpoints.len())?;
+        for watchpoint in &self.watchpoints {
+            w.write_usize(*watchpoint.addr)?;
+            w.write_usize(watchpoint.size)?;
+            w.write_u64(watchpoint.access.to_u64())?;
+        }
+        w.write_bool(self.watchpoints_enabled)?;
+
+        w.write_usize(self.regions.len())?;
+        for region in self.regions.values() {
+            w.write_str(&region.name)?;
+            w.write_u64(region.kind.to_u64())?;
+            w.write_usize(*region.addr)?;
+            w.write_usize(region.size)?;
+            w.write_u64(*region.perms as u64)?;
+        }
+
+        w.write_u64(self.paging.satp)?;
+        w.write_u64(self.paging.privilege.to_u64())?;
+        w.write_bool(self.paging.sum)?;
+        w.write_bool(self.paging.mxr)?;
+
+        Ok(())
+    }
+
+    /// Reads the state of a Mmu from the snapshot `r`. The returned Mmu has
+    /// no dirty memory.
+    pub fn read_snapshot<R: Read>(
+        r: &mut Reader<R>,
+    ) -> Result<Mmu, snapshot::Error> {
+        let size = r.read_usize()?;
+        let dirty_config = DirtyConfig {
+            block_size: r.read_usize()?,
+            hierarchical: r.read_bool()?,
+        };
+        if size < DIRTY_BLOCK_SIZE || !dirty_config.is_valid() {
+            return Err(snapshot::Error::MalformedFile);
+        }
+
+        let mut mmu = Mmu::new(size).with_dirty_config(dirty_config);
+        mmu.brk = VirtAddr(r.read_usize()?);
+
+        let mut perms = [0u8; PAGE_SIZE];
+        for _ in 0..r.read_usize()? {
+            let number = r.read_usize()?;
+            if number > (size - 1) / PAGE_SIZE
+                || mmu.pages.contains_key(&number)
+            {
+                return Err(snapshot::Error::MalformedFile);
+            }
+
+            let data = mmu.page_data_mut(number);
+            r.read_bytes(&mut data.memory)?;
+            r.read_bytes(&mut perms)?;
+            data.update_perms(0, PAGE_SIZE, |i, _| Perm(perms[i]));
+        }
+
+        mmu.heap = Heap::read_snapshot(r)?;
+
+        for _ in 0..r.read_usize()? {
+            mmu.watchpoints.push(Watchpoint {
+                addr: VirtAddr(r.read_usize()?),
+                size: r.read_usize()?,
+                access: Access::from_u64(r.read_u64()?)?,
+            });
+        }
+        mmu.watchpoints_enabled = r.read_bool()?;
+
+        for _ in 0..r.read_usize()? {
+            let name = r.read_string()?;
+            let kind = RegionKind::from_u64(r.read_u64()?)?;
+            let addr = VirtAddr(r.read_usize()?);
+            let size = r.read_usize()?;
+            let perms = r.read_u64()?;
+            if perms > u8::MAX as u64 {
+                return Err(snapshot::Error::MalformedFile);
+            }
+
+            let region = Region {
+                name,
+                kind,
+                addr,
+                size,
+                perms: Perm(perms as u8),
+            };
+            mmu.add_region(region)
+                .map_err(|_| snapshot::Error::MalformedFile)?;
+        }
+
+        mmu.set_satp(r.read_u64()?)
+            .map_err(|_| snapshot::Error::MalformedFile)?;
+        mmu.paging.privilege = Privilege::from_u64(r.read_u64()?)?;
+        mmu.paging.sum = r.read_bool()?;
+        mmu.paging.mxr = r.read_bool()?;
+
+        Ok(mmu)
+    }
+}
+
+/// Types implementing this trait can be converted to and from little-endian
+/// bytes.
+pub trait LeBytes {
+    type Target;
+
+    /// Convert an array of bytes into a value of the associated type.
+    fn from_le_bytes(bytes: [u8; 16]) -> Self::Target;
+
+    /// Convert a value of the associated type into an array of bytes.
+    fn to_le_bytes(value: Self::Target) -> [u8; 16];
+}
+
+macro_rules! impl_le_bytes {
+    ($Ty: ty) => {
+        impl LeBytes for $Ty {
+            type Target = $Ty;
+
+            fn from_le_bytes(bytes: [u8; 16]) -> $Ty {
+                let src = &bytes[..mem::size_of::<$Ty>()];
+
+                <$Ty>::from_le_bytes(src.try_into().unwrap())
+            }
+
+            fn to_le_bytes(value: $Ty) -> [u8; 16] {
+                let bytes = value.to_le_bytes();
+
+                let mut result = [0u8; 16];
+                let dst = &mut result[..mem::size_of::<$Ty>()];
+                dst.copy_from_slice(&bytes);
+
+                result
+            }
+        }
+    };
+}
+
+// Implement LeBytes for unsigned integers.
+impl_le_bytes!(u8);
+impl_le_bytes!(u16);
+impl_le_bytes!(u32);
+impl_le_bytes!(u64);
+impl_le_bytes!(u128);
+
+// Implement LeBytes for signed integers.
+impl_le_bytes!(i8);
+impl_le_bytes!(i16);
+impl_le_bytes!(i32);
+impl_le_bytes!(i64);
+impl_le_bytes!(i128);
+
+/// Types implementing this trait are plain data, which can be copied from and
+/// to guest memory as they are. Their layout in memory is the same in the
+/// host and in the little-endian guest.
+///
+/// # Safety
+///
+/// Any bit pattern must be a valid value of the type, and the type must not
+/// contain padding or pointers. Structs must be `#[repr(C)]`.
+pub unsafe trait Pod: Copy {}
+
+// Implement Pod for integers.
+unsafe impl Pod for u8 {}
+unsafe impl Pod for u16 {}
+unsafe impl Pod for u32 {}
+unsafe impl Pod for u64 {}
+unsafe impl Pod for u128 {}
+unsafe impl Pod for i8 {}
+unsafe impl Pod for i16 {}
+unsafe impl Pod for i32 {}
+unsafe impl Pod for i64 {}
+unsafe impl Pod for i128 {}
+
+/// I/O vector, as used by the `readv` and `writev` syscalls.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct IoVec {
+    /// Address of the buffer.
+    pub base: u64,
+
+    /// Size of the buffer.
+    pub len: u64,
+}
+
+unsafe impl Pod for IoVec {}
+
+/// Returns the size in bytes of an array of `count` values of type `T`
+/// starting at `addr`.
+fn slice_size<T>(addr: VirtAddr, count: usize) -> Result<usize, Error> {
+    count
+        .checked_mul(mem::size_of::<T>())
+        .ok_or(Error::AddressIntegerOverflow { addr, size: count })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mmu_new_edge_size_equal() {
+        let mmu = Mmu::new(2 * DIRTY_BLOCK_SIZE);
+        let want = Mmu {
+            size: 2 * DIRTY_BLOCK_SIZE,
+            pages: HashMap::new(),
+            tlb: vec![Cell::new(TlbEntry::default()); TLB_SIZE],
+            dirty_config: DirtyConfig::default(),
+            dirty: vec![],
+            brk: VirtAddr(0),
+            heap: Heap::default(),
+            watchpoints: vec![],
+            watchpoints_enabled: true,
+            regions: BTreeMap::new(),
+            taint: None,
+            uninit: None,
+            paging: Paging::default(),
+        };
+
+        assert_eq!(mmu, want);
+    }
+
+    #[test]
+    fn mmu_new_edge_size_below() {
+        let mmu = Mmu::new(2 * DIRTY_BLOCK_SIZE - 1);
+        let want = Mmu {
+            size: 2 * DIRTY_BLOCK_SIZE - 1,
+            pages: HashMap::new(),
+            tlb: vec![Cell::new(TlbEntry::default()); TLB_SIZE],
+            dirty_config: DirtyConfig::default(),
+            dirty: vec![],
+            brk: VirtAddr(0),
+            heap: Heap::default(),
+            watchpoints: vec![],
+            watchpoints_enabled: true,
+            regions: BTreeMap::new(),
+            taint: None,
+            uninit: None,
+            paging: Paging::default(),
+        };
+
+        assert_eq!(mmu, want);
+    }
+
+    #[test]
+    fn mmu_new_edge_size_above() {
+        let mmu = Mmu::new(2 * DIRTY_BLOCK_SIZE + 1);
+        let want = Mmu {
+            size: 2 * DIRTY_BLOCK_SIZE + 1,
+            pages: HashMap::new(),
+            tlb: vec![Cell::new(TlbEntry::default()); TLB_SIZE],
+            dirty_config: DirtyConfig::default(),
+            dirty: vec![],
+            brk: VirtAddr(0),
+            heap: Heap::default(),
+            watchpoints: vec![],
+            watchpoints_enabled: true,
+            regions: BTreeMap::new(),
+            taint: None,
+            uninit: None,
+            paging: Paging::default(),
+        };
+
+        assert_eq!(mmu, want);
+    }
+
+    #[test]
+    fn mmu_page_layout() {
+        assert_eq!(PAGE_SIZE % DIRTY_BLOCK_SIZE, 0);
+        assert_eq!(DIRTY_BLOCK_SIZE % MIN_DIRTY_BLOCK_SIZE, 0);
+        assert_eq!(TLB_SIZE.count_ones(), 1);
+        assert_eq!(mem::size_of::<TlbEntry>(), 32);
+
+        let page = Page::new();
+        let base = &*page.data as *const PageData as usize;
+
+        assert!(
+            PAGE_SIZE / MIN_DIRTY_BLOCK_SIZE
+                <= mem::size_of_val(&*page.dirty) * 8
+        );
+
+        assert_eq!(page.data.memory.as_ptr() as usize - base, 0);
+        assert_eq!(
+            &page.data.perms as *const _ as usize - base,
+            PAGE_PERMS_OFFSET
+        );
+        assert_eq!(
+            mem::size_of::<Option<Box<[Perm; PAGE_SIZE]>>>(),
+            mem::size_of::<usize>()
+        );
+        assert_eq!(
+            &page.data.summary as *const Perm as usize - base,
+            PAGE_SUMMARY_OFFSET
+        );
+        assert_eq!(
+            &page.data.stale as *const bool as usize - base,
+            PAGE_STALE_OFFSET
+        );
+
+        let entry = TlbEntry::default();
+        let base = &entry as *const TlbEntry as usize;
+
+        assert_eq!(
+            &entry.page_number as *const usize as usize - base,
+            TLB_PAGE_NUMBER_OFFSET
+        );
+        assert_eq!(
+            &entry.write_page_number as *const usize as usize - base,
+            TLB_WRITE_PAGE_NUMBER_OFFSET
+        );
+        assert_eq!(
+            &entry.page_ptr as *const usize as usize - base,
+            TLB_PAGE_PTR_OFFSET
+        );
+        assert_eq!(
+            &entry.dirty_ptr as *const usize as usize - base,
+            TLB_DIRTY_PTR_OFFSET
+        );
+    }
+
+    #[test]
+    fn mmu_sparse() {
+        let mut mmu = Mmu::new(usize::MAX);
+
+        // Last page of the address space.
+        let high = VirtAddr(usize::MAX & !(PAGE_SIZE - 1));
+        mmu.set_perms(VirtAddr(0x400000000), 4, Perm(PERM_READ | PERM_WRITE))
+            .unwrap();
+        mmu.set_perms(high, PAGE_SIZE - 1, Perm(PERM_READ | PERM_WRITE))
+            .unwrap();
+        mmu.write(VirtAddr(0x400000000), &[1, 2, 3, 4]).unwrap();
+        mmu.write(high, &[5, 6, 7, 8]).unwrap();
+
+        let mut got = [0u8; 4];
+        mmu.read(VirtAddr(0x400000000), &mut got).unwrap();
+        assert_eq!(&got, &[1, 2, 3, 4]);
+        mmu.read(high, &mut got).unwrap();
+        assert_eq!(&got, &[5, 6, 7, 8]);
+
+        assert_eq!(mmu.pages.len(), 2);
+    }
+
+    #[test]
+    fn mmu_unallocated_read_fault() {
+        let mmu = Mmu::new(usize::MAX);
+
+        let mut tmp = [0u8; 2];
+        match mmu.read(VirtAddr(0x400000000), &mut tmp) {
+            Err(Error::ReadFault { .. }) => {}
+            Err(err) => panic!("Wrong error {:?}", err),
+            _ => panic!("The function didn't return an error"),
+        }
+
+        mmu.peek(VirtAddr(0x400000000), &mut tmp).unwrap();
+        assert_eq!(&tmp, &[0, 0]);
+        assert!(mmu.pages.is_empty());
+    }
+
+    #[test]
+    fn mmu_cross_page() {
+        let mut mmu = Mmu::new(4 * PAGE_SIZE);
+        mmu.set_perms(
+            VirtAddr(PAGE_SIZE - 2),
+            4,
+            Perm(PERM_READ | PERM_WRITE),
+        )
+        .unwrap();
+        mmu.write(VirtAddr(PAGE_SIZE - 2), &[1, 2, 3, 4]).unwrap();
+
+        let mut got = [0u8; 4];
+        mmu.read(VirtAddr(PAGE_SIZE - 2), &mut got).unwrap();
+        assert_eq!(&got, &[1, 2, 3, 4]);
+
+        match mmu.read(VirtAddr(PAGE_SIZE - 2), &mut [0u8; 5]) {
+            Err(Error::ReadFault { .. }) => {}
+            Err(err) => panic!("Wrong error {:?}", err),
+            _ => panic!("The function didn't return an error"),
+        }
+    }
+
+    #[test]
+    fn mmu_reset_new_page() {
+        let mmu = Mmu::new(1024 * DIRTY_BLOCK_SIZE);
+        let mut mmu_fork = mmu.fork();
+
+        mmu_fork
+            .poke(VirtAddr(PAGE_SIZE + 4), &[1, 2, 3, 4])
+            .unwrap();
+        mmu_fork.fill_tlb(VirtAddr(PAGE_SIZE));
+        let tlb = mmu_fork.tlb.clone();
+
+        mmu_fork.reset(&mmu);
+
+        let mut got = [0u8; 4];
+        mmu_fork.peek(VirtAddr(PAGE_SIZE + 4), &mut got).unwrap();
+        assert_eq!(&got, &[0, 0, 0, 0]);
+        assert_eq!(mmu_fork.tlb, tlb);
+    }
+
+    #[test]
+    fn mmu_fill_tlb() {
+        let mut mmu = Mmu::new(1024 * DIRTY_BLOCK_SIZE);
+
+        mmu.fill_tlb(VirtAddr(PAGE_SIZE));
+        assert_eq!(mmu.tlb[1].get(), TlbEntry::default());
+
+        mmu.poke(VirtAddr(PAGE_SIZE + 4), &[1]).unwrap();
+        mmu.fill_tlb(VirtAddr(PAGE_SIZE + 8));
+
+        let page = mmu.pages.get(&1).unwrap();
+        let want = TlbEntry {
+            page_number: 1,
+            write_page_number: 1,
+            page_ptr: &*page.data as *const PageData as usize,
+            dirty_ptr: &*page.dirty as *const u64 as usize,
+        };
+        assert_eq!(mmu.tlb[1].get(), want);
+    }
+
+    #[test]
+    fn mmu_fork_shares_pages() {
+        let mut mmu = Mmu::new(1024 * DIRTY_BLOCK_SIZE);
+        mmu.poke(VirtAddr(PAGE_SIZE + 4), &[1, 2, 3, 4]).unwrap();
+
+        let mmu_fork = mmu.fork();
+
+        let page = mmu.pages.get(&1).unwrap();
+        let fork_page = mmu_fork.pages.get(&1).unwrap();
+        assert!(Arc::ptr_eq(&page.data, &fork_page.data));
+        assert_eq!(*fork_page.dirty, 0);
+    }
+
+    #[test]
+    fn mmu_fork_copy_on_write() {
+        let mut mmu = Mmu::new(1024 * DIRTY_BLOCK_SIZE);
+        mmu.poke(VirtAddr(PAGE_SIZE + 4), &[1, 2, 3, 4]).unwrap();
+
+        let mut mmu_fork = mmu.fork();
+        mmu_fork.poke(VirtAddr(PAGE_SIZE + 4), &[5, 6]).unwrap();
+
+        let mut got = [0u8; 4];
+        mmu.peek(VirtAddr(PAGE_SIZE + 4), &mut got).unwrap();
+        assert_eq!(&got, &[1, 2, 3, 4]);
+        mmu_fork.peek(VirtAddr(PAGE_SIZE + 4), &mut got).unwrap();
+        assert_eq!(&got, &[5, 6, 3, 4]);
+
+        mmu_fork.reset(&mmu);
+        mmu_fork.peek(VirtAddr(PAGE_SIZE + 4), &mut got).unwrap();
+        assert_eq!(&got, &[1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn mmu_fork_tlb() {
+        let mut mmu = Mmu::new(1024 * DIRTY_BLOCK_SIZE);
+        mmu.poke(VirtAddr(PAGE_SIZE + 4), &[1]).unwrap();
+        mmu.fill_tlb(VirtAddr(PAGE_SIZE));
+        assert_eq!(mmu.tlb[1].get().write_page_number, 1);
+
+        // Shared pages can only be read through the TLB.
+        let mut mmu_fork = mmu.fork();
+        assert_eq!(mmu.tlb[1].get().page_number, 1);
+        assert_eq!(mmu.tlb[1].get().write_page_number, usize::MAX);
+
+        mmu_fork.fill_tlb(VirtAddr(PAGE_SIZE));
+        assert_eq!(mmu_fork.tlb[1].get().page_number, 1);
+        assert_eq!(mmu_fork.tlb[1].get().write_page_number, usize::MAX);
+
+        // Copying the page invalidates its translation.
+        mmu_fork.poke(VirtAddr(PAGE_SIZE + 4), &[2]).unwrap();
+        assert_eq!(mmu_fork.tlb[1].get(), TlbEntry::default());
+
+        mmu_fork.fill_tlb(VirtAddr(PAGE_SIZE));
+        assert_eq!(mmu_fork.tlb[1].get().write_page_number, 1);
+    }
+
+    #[test]
+    fn mmu_raw_pointers() {
+        let mut mmu = Mmu::new(4 * PAGE_SIZE + 1);
+        assert_eq!(mmu.memory_len(), 4 * PAGE_SIZE + 1);
+        assert_eq!(mmu.page_memory_ptr(VirtAddr(PAGE_SIZE)), None);
+        assert_eq!(mmu.page_dirty_bitmap_ptr(VirtAddr(PAGE_SIZE)), None);
+        assert!(mmu.memory_ptr().is_null());
+        assert!(mmu.perms_ptr().is_null());
+        assert!(mmu.dirty_bitmap_ptr().is_null());
+
+        mmu.set_perms(VirtAddr(PAGE_SIZE), 8, Perm(PERM_READ))
+            .unwrap();
+        mmu.poke(VirtAddr(PAGE_SIZE + 4), b"ab").unwrap();
+
+        let memory = mmu.page_memory_ptr(VirtAddr(PAGE_SIZE + 4)).unwrap();
+        let perms = mmu.page_perms_ptr(VirtAddr(PAGE_SIZE + 4)).unwrap();
+        let dirty =
+            mmu.page_dirty_bitmap_ptr(VirtAddr(PAGE_SIZE + 4)).unwrap();
+        unsafe {
+            assert_eq!(*memory.add(4), b'a');
+            assert_eq!(*perms.add(7), Perm(PERM_READ));
+            assert_eq!(*perms.add(8), Perm(0));
+            assert_eq!(*dirty, 1);
+        }
+
+        // Pages whose bytes share the same permissions do not store them
+        // byte by byte.
+        mmu.set_perms(VirtAddr(PAGE_SIZE), PAGE_SIZE, Perm(PERM_READ))
+            .unwrap();
+        assert_eq!(mmu.page_perms_ptr(VirtAddr(PAGE_SIZE)), None);
+
+        // The pointers without an address refer to the first page.
+        mmu.poke(VirtAddr(0), b"c").unwrap();
+        assert_eq!(Some(mmu.memory_ptr()), mmu.page_memory_ptr(VirtAddr(0)));
+        assert_eq!(
+            Some(mmu.dirty_bitmap_ptr()),
+            mmu.page_dirty_bitmap_ptr(VirtAddr(0))
+        );
+        unsafe {
+            assert_eq!(*mmu.memory_ptr(), b'c');
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn mmu_new_small_size() {
+        Mmu::new(DIRTY_BLOCK_SIZE - 1);
+    }
+
+    #[test]
+    fn mmu_check_perms() {
+        let mut mmu = Mmu::new(DIRTY_BLOCK_SIZE);
+        mmu.set_perms(VirtAddr(0), 8, Perm(PERM_WRITE | PERM_READ))
+            .unwrap();
+        mmu.check_perms(VirtAddr(0), 8, Perm(PERM_WRITE | PERM_READ))
+            .unwrap();
+    }
+
+    #[test]
+    #[should_panic]
+    fn mmu_check_perms_subset() {
+        let mut mmu = Mmu::new(DIRTY_BLOCK_SIZE);
+        mmu.set_perms(VirtAddr(0), 8, Perm(PERM_WRITE)).unwrap();
+
+        mmu.check_perms(VirtAddr(0), 8, Perm(PERM_WRITE | PERM_READ))
+            .unwrap();
+    }
+
+    #[test]
+    fn mmu_check_perms_oob() {
+        let mut mmu = Mmu::new(DIRTY_BLOCK_SIZE);
+        match mmu.set_perms(
+            VirtAddr(DIRTY_BLOCK_SIZE + 5),
+            16,
+            Perm(PERM_WRITE),
+        ) {
+            Err(Error::InvalidAddress { .. }) => return,
+            Err(err) => panic!("Wrong error {:?}", err),
+            _ => panic!("The function didn't return an error"),
+        }
+    }
+
+    #[test]
+    fn mmu_check_perms_integer_overflow() {
+        let mut mmu = Mmu::new(DIRTY_BLOCK_SIZE);
+        match mmu.set_perms(VirtAddr(usize::MAX), 1, Perm(PERM_WRITE)) {
+            Err(Error::AddressIntegerOverflow { .. }) => return,
+            Err(err) => panic!("Wrong error {:?}", err),
+            _ => panic!("The function didn't return an error"),
+        }
+    }
+
+    #[test]
+    fn mmu_perms_compact() {
+        let mut mmu = Mmu::new(4 * PAGE_SIZE);
+        mmu.set_perms(VirtAddr(0), 2 * PAGE_SIZE, Perm(PERM_READ))
+            .unwrap();
+        assert!(mmu.pages[&0].data.perms.is_none());
+        assert_eq!(mmu.pages[&0].data.summary, Perm(PERM_READ));
+
+        let orig = mmu.fork();
+
+        mmu.set_perms(VirtAddr(0x10), 4, Perm(PERM_WRITE | PERM_RAW))
+            .unwrap();
+        assert!(mmu.pages[&0].data.perms.is_some());
+        assert_eq!(mmu.pages[&0].data.summary, Perm(0));
+        assert_eq!(
+            mmu.perms(VirtAddr(0xf), 6).unwrap(),
+            &[
+                Perm(PERM_READ),
+                Perm(PERM_WRITE | PERM_RAW),
+                Perm(PERM_WRITE | PERM_RAW),
+                Perm(PERM_WRITE | PERM_RAW),
+                Perm(PERM_WRITE | PERM_RAW),
+                Perm(PERM_READ),
+            ]
+        );
+
+        mmu.set_perms(VirtAddr(0x10), 4, Perm(PERM_READ)).unwrap();
+        assert!(mmu.pages[&0].data.perms.is_none());
+        assert_eq!(mmu.pages[&0].data.summary, Perm(PERM_READ));
+
+        mmu.set_perms(VirtAddr(PAGE_SIZE + 8), 1, Perm(PERM_WRITE))
+            .unwrap();
+        mmu.reset(&orig);
+        assert!(mmu.pages[&1].data.perms.is_none());
+        assert_eq!(
+            mmu.perms(VirtAddr(PAGE_SIZE + 8), 1).unwrap(),
+            &[Perm(PERM_READ)]
+        );
+    }
+
+    #[test]
+    fn mmu_poke_peek() {
+        let mut mmu = Mmu::new(DIRTY_BLOCK_SIZE);
+        mmu.poke(VirtAddr(0), &[1, 2, 3, 4]).unwrap();
+
+        let mut got = [0u8; 4];
+        mmu.peek(VirtAddr(0), &mut got).unwrap();
+
+        assert_eq!(&got, &[1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn mmu_write_read() {
+        let mut mmu = Mmu::new(DIRTY_BLOCK_SIZE);
+
+        mmu.set_perms(VirtAddr(0), 4, Perm(PERM_READ | PERM_WRITE))
+            .unwrap();
+        mmu.write(VirtAddr(0), &[1, 2, 3, 4]).unwrap();
+
+        let mut got = [0u8; 4];
+        mmu.read(VirtAddr(0), &mut got).unwrap();
+
+        assert_eq!(&got, &[1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn mmu_write_fault() {
+        let mut mmu = Mmu::new(DIRTY_BLOCK_SIZE);
+        match mmu.write(VirtAddr(0), &[1, 2, 3, 4]) {
+            Err(Error::WriteFault { .. }) => return,
+            Err(err) => panic!("Wrong error {:?}", err),
+            _ => panic!("The function didn't return an error"),
+        }
+    }
+
+    #[test]
+    fn mmu_read_fault() {
+        let mmu = Mmu::new(DIRTY_BLOCK_SIZE);
+
+        let mut tmp = [0u8; 2];
+        match mmu.read(VirtAddr(0), &mut tmp) {
+            Err(Error::ReadFault { .. }) => return,
+            Err(err) => panic!("Wrong error {:?}", err),
+            _ => panic!("The function didn't return an error"),
+        }
+    }
+
+    #[test]
+    fn mmu_exec_exec_fault() {
+        let mmu = Mmu::new(DIRTY_BLOCK_SIZE);
+
+        let mut tmp = [0u8; 2];
+        match mmu.read_with_perms(VirtAddr(0), &mut tmp, Perm(PERM_EXEC)) {
+            Err(Error::ExecFault { .. }) => return,
+            Err(err) => panic!("Wrong error {:?}", err),
+            _ => panic!("The function didn't return an error"),
+        }
+    }
+
+    #[test]
+    fn mmu_exec_unk_fault() {
+        let mmu = Mmu::new(DIRTY_BLOCK_SIZE);
+
+        let mut tmp = [0u8; 2];
+        match mmu.read_with_perms(VirtAddr(0), &mut tmp, Perm(1 << 7)) {
+            Err(Error::UnkFault { .. }) => return,
+            Err(err) => panic!("Wrong error {:?}", err),
+            _ => panic!("The function didn't return an error"),
+        }
+    }
+
+    #[test]
+    fn mmu_raw_after_write() {
+        let mut mmu = Mmu::new(DIRTY_BLOCK_SIZE);
+        mmu.set_perms(VirtAddr(0), 3, Perm(PERM_WRITE | PERM_RAW))
+            .unwrap();
+        mmu.write(VirtAddr(0), &[1, 2]).unwrap();
+
+        let mut got = [0u8; 4];
+        mmu.peek(VirtAddr(0), &mut got).unwrap();
+
+        assert_eq!(&got, &[1, 2, 0, 0]);
+        assert_eq!(
+            mmu.perms(VirtAddr(0), 4).unwrap(),
+            &[
+                Perm(PERM_WRITE | PERM_READ),
+                Perm(PERM_WRITE | PERM_READ),
+                Perm(PERM_WRITE | PERM_RAW),
+                Perm(0)
+            ]
+        );
+    }
+
+    #[test]
+    fn mmu_raw_ok() {
+        let mut mmu = Mmu::new(DIRTY_BLOCK_SIZE);
+        mmu.set_perms(VirtAddr(0), 2, Perm(PERM_READ | PERM_WRITE))
+            .unwrap();
+        mmu.set_perms(VirtAddr(2), 2, Perm(PERM_WRITE | PERM_RAW))
+            .unwrap();
+        mmu.write(VirtAddr(0), &[1, 2, 3, 4]).unwrap();
+
+        let mut got = [0u8; 4];
+        mmu.read(VirtAddr(0), &mut got).unwrap();
+
+        assert_eq!(&got, &[1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn mmu_raw_uninit() {
+        let mut mmu = Mmu::new(DIRTY_BLOCK_SIZE);
+        mmu.set_perms(VirtAddr(0), 2, Perm(PERM_READ)).unwrap();
+        mmu.set_perms(VirtAddr(2), 2, Perm(PERM_WRITE | PERM_RAW))
+            .unwrap();
+
+        let mut tmp = [0u8; 2];
+        match mmu.read(VirtAddr(1), &mut tmp) {
+            Err(Error::UninitFault { .. }) => return,
+            Err(err) => panic!("Wrong error {:?}", err),
+            _ => panic!("The function didn't return an error"),
+        }
+    }
+
+    #[test]
+    fn mmu_raw_read_fault() {
+        let mut mmu = Mmu::new(DIRTY_BLOCK_SIZE);
+        mmu.set_perms(VirtAddr(0), 2, Perm(PERM_WRITE)).unwrap();
+        mmu.set_perms(VirtAddr(2), 2, Perm(PERM_WRITE | PERM_RAW))
+            .unwrap();
+
+        let mut tmp = [0u8; 2];
+        match mmu.read(VirtAddr(1), &mut tmp) {
+            Err(Error::ReadFault { .. }) => return,
+            Err(err) => panic!("Wrong error {:?}", err),
+            _ => panic!("The function didn't return an error"),
+        }
+    }
+
+    #[test]
+    fn mmu_reset() {
+        let mmu = Mmu::new(1024 * DIRTY_BLOCK_SIZE);
+        let mut mmu_fork = mmu.fork();
+
+        mmu_fork
+            .set_perms(VirtAddr(DIRTY_BLOCK_SIZE + 4), 4, Perm(PERM_WRITE))
+            .unwrap();
+        mmu_fork
+            .write(VirtAddr(DIRTY_BLOCK_SIZE + 4), &[1, 2, 3, 4])
+            .unwrap();
+
+        let mut got = [0u8; 4];
+
+        mmu_fork
+            .peek(VirtAddr(DIRTY_BLOCK_SIZE + 4), &mut got)
+            .unwrap();
+        assert_eq!(&got, &[1, 2, 3, 4]);
+
+        mmu_fork.reset(&mmu);
+
+        mmu_fork
+            .peek(VirtAddr(DIRTY_BLOCK_SIZE + 4), &mut got)
+            .unwrap();
+        assert_eq!(&got, &[0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn mmu_reset_two_blocks() {
+        let mmu = Mmu::new(1024 * DIRTY_BLOCK_SIZE);
+        let mut mmu_fork = mmu.fork();
+
+        mmu_fork
+            .set_perms(VirtAddr(DIRTY_BLOCK_SIZE - 2), 4, Perm(PERM_WRITE))
+            .unwrap();
+        mmu_fork
+            .write(VirtAddr(DIRTY_BLOCK_SIZE - 2), &[1, 2, 3, 4])
+            .unwrap();
+
+        let mut got = [0u8; 4];
+
+        mmu_fork
+            .peek(VirtAddr(DIRTY_BLOCK_SIZE - 2), &mut got)
+            .unwrap();
+        assert_eq!(&got, &[1, 2, 3, 4]);
+
+        mmu_fork.reset(&mmu);
+
+        mmu_fork
+            .peek(VirtAddr(DIRTY_BLOCK_SIZE - 2), &mut got)
+            .unwrap();
+        assert_eq!(&got, &[0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn mmu_reset_one_of_two_blocks() {
+        let mut mmu = Mmu::new(1024 * DIRTY_BLOCK_SIZE);
+
+        mmu.poke(VirtAddr(DIRTY_BLOCK_SIZE - 2), &[1, 2]).unwrap();
+
+        let mut mmu_fork = mmu.fork();
+
+        mmu_fork.poke(VirtAddr(DIRTY_BLOCK_SIZE), &[3, 4]).unwrap();
+
+        let mut got = [0u8; 4];
+        mmu_fork
+            .peek(VirtAddr(DIRTY_BLOCK_SIZE - 2), &mut got)
+            .unwrap();
+        assert_eq!(&got, &[1, 2, 3, 4]);
+
+        mmu_fork.reset(&mmu);
+
+        mmu_fork
+            .peek(VirtAddr(DIRTY_BLOCK_SIZE - 2), &mut got)
+            .unwrap();
+        assert_eq!(&got, &[1, 2, 0, 0]);
+    }
+
+    #[test]
+    fn mmu_reset_all() {
+        let mmu = Mmu::new(1024 * DIRTY_BLOCK_SIZE);
+        let mut mmu_fork = mmu.fork();
+
+        mmu_fork
+            .set_perms(
+                VirtAddr(0),
+                1024 * DIRTY_BLOCK_SIZE,
+                Perm(PERM_WRITE | PERM_RAW),
+            )
+            .unwrap();
+        mmu_fork
+            .write(VirtAddr(DIRTY_BLOCK_SIZE + 4), &[1, 2, 3, 4])
+            .unwrap();
+
+        let mut got = [0u8; 4];
+
+        mmu_fork
+            .read(VirtAddr(DIRTY_BLOCK_SIZE + 4), &mut got)
+            .unwrap();
+        assert_eq!(&got, &[1, 2, 3, 4]);
+
+        mmu_fork.reset(&mmu);
+
+        mmu_fork.peek(VirtAddr(4), &mut got).unwrap();
+        assert_eq!(&got, &[0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn mmu_dirty_block_size() {
+        let config = DirtyConfig {
+            block_size: MIN_DIRTY_BLOCK_SIZE,
+            hierarchical: false,
+        };
+        let mmu = Mmu::new(1024 * PAGE_SIZE).with_dirty_config(config);
+        let mut mmu_fork = mmu.fork();
+
+        mmu_fork.poke(VirtAddr(0x1000 + 62), &[1, 2, 3, 4]).unwrap();
+        mmu_fork.poke(VirtAddr(0x1050), &[5]).unwrap();
+
+        assert_eq!(
+            mmu_fork.dirty_blocks(),
+            vec![VirtAddr(0x1000), VirtAddr(0x1040)]
+        );
+        assert!(mmu_fork.is_dirty(VirtAddr(0x107f)));
+        assert!(!mmu_fork.is_dirty(VirtAddr(0x1080)));
+
+        mmu_fork.reset(&mmu);
+
+        assert!(mmu_fork.dirty_blocks().is_empty());
+        assert_eq!(
+            mmu_fork.peek_int::<u32>(VirtAddr(0x1000 + 62)).unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn mmu_dirty_hierarchical() {
+        let config = DirtyConfig {
+            block_size: 256,
+            hierarchical: true,
+        };
+        let mut mmu = Mmu::new(1024 * PAGE_SIZE).with_dirty_config(config);
+        mmu.poke(VirtAddr(0x2000), &[0xff; 0x1000]).unwrap();
+
+        let mut mmu_fork = mmu.fork();
+
+        mmu_fork.poke(VirtAddr(0x2300), &[1]).unwrap();
+        mmu_fork.poke(VirtAddr(0x3000), &[2]).unwrap();
+        mmu_fork.poke(VirtAddr(0x2100), &[3]).unwrap();
+
+        // Only pages are recorded in the dirty list.
+        assert_eq!(mmu_fork.dirty_len(), 2);
+        assert_eq!(
+            mmu_fork.dirty_blocks(),
+            vec![VirtAddr(0x2100), VirtAddr(0x2300), VirtAddr(0x3000)]
+        );
+
+        mmu_fork.reset(&mmu);
+
+        assert_eq!(mmu_fork.dirty_len(), 0);
+        assert_eq!(mmu_fork.peek_int::<u8>(VirtAddr(0x2300)).unwrap(), 0xff);
+        assert_eq!(mmu_fork.peek_int::<u8>(VirtAddr(0x2100)).unwrap(), 0xff);
+        assert_eq!(mmu_fork.peek_int::<u8>(VirtAddr(0x3000)).unwrap(), 0);
+    }
+
+    #[test]
+    #[should_panic]
+    fn mmu_dirty_invalid_block_size() {
+        let config = DirtyConfig {
+            block_size: 3 * MIN_DIRTY_BLOCK_SIZE,
+            hierarchical: false,
+        };
+        Mmu::new(PAGE_SIZE).with_dirty_config(config);
+    }
 
-            fn to_le_bytes(value: $Ty) -> [u8; 16] {
-                let bytes = value.to_le_bytes();
+    #[test]
+    fn mmu_write_read_int() {
+        let mut mmu_init = Mmu::new(DIRTY_BLOCK_SIZE);
 
-                let mut result = [0u8; 16];
-                let dst = &mut result[..mem::size_of::<$Ty>()];
-                dst.copy_from_slice(&bytes);
+        mmu_init
+            .set_perms(
+                VirtAddr(0),
+                DIRTY_BLOCK_SIZE,
+                Perm(PERM_READ | PERM_WRITE),
+            )
+            .unwrap();
 
-                result
-            }
-        }
-    };
-}
+        let mut mmu = mmu_init.fork();
 
-// Implement LeBytes for unsigned integers.
-impl_le_bytes!(u8);
-impl_le_bytes!(u16);
-impl_le_bytes!(u32);
-impl_le_bytes!(u64);
-impl_le_bytes!(u128);
+        const VAL_U8: u8 = 0x11;
+        const VAL_U16: u16 = 0x1122;
+        const VAL_U32: u32 = 0x11223344;
+        const VAL_U64: u64 = 0x1122334455667788;
+        const VAL_U128: u128 = 0x11223344556677881122334455667788;
 
-// Implement LeBytes for signed integers.
-impl_le_bytes!(i8);
-impl_le_bytes!(i16);
-impl_le_bytes!(i32);
-impl_le_bytes!(i64);
-impl_le_bytes!(i128);
+        // u8
+        mmu.write_int::<u8>(VirtAddr(0), VAL_U8).unwrap();
+        let got = mmu.peek_int::<u128>(VirtAddr(0)).unwrap();
+        assert_eq!(got, VAL_U8 as u128);
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+        // u16
+        mmu.reset(&mmu_init);
+        mmu.write_int::<u16>(VirtAddr(0), VAL_U16).unwrap();
+        let got = mmu.peek_int::<u128>(VirtAddr(0)).unwrap();
+        assert_eq!(got, VAL_U16 as u128);
 
-    #[test]
-    fn mmu_new_edge_size_equal() {
-        let mmu = Mmu::new(2 * DIRTY_BLOCK_SIZE);
-        let want = Mmu {
-            size: 2 * DIRTY_BLOCK_SIZE,
-            memory: vec![0; 2 * DIRTY_BLOCK_SIZE],
-            perms: vec![Perm(0); 2 * DIRTY_BLOCK_SIZE],
-            dirty: vec![],
-            dirty_bitmap: vec![0; 2],
-            brk: VirtAddr(0),
-            active_allocs: HashMap::new(),
-        };
+        // u32
+        mmu.reset(&mmu_init);
+        mmu.write_int::<u32>(VirtAddr(0), VAL_U32).unwrap();
+        let got = mmu.peek_int::<u128>(VirtAddr(0)).unwrap();
+        assert_eq!(got, VAL_U32 as u128);
 
-        assert_eq!(mmu, want);
+        // u64
+        mmu.reset(&mmu_init);
+        mmu.write_int::<u64>(VirtAddr(0), VAL_U64).unwrap();
+        let got = mmu.peek_int::<u128>(VirtAddr(0)).unwrap();
+        assert_eq!(got, VAL_U64 as u128);
+
+        // u128
+        mmu.reset(&mmu_init);
+        mmu.write_int::<u128>(VirtAddr(0), VAL_U128).unwrap();
+        let got = mmu.peek_int::<u128>(VirtAddr(0)).unwrap();
+        assert_eq!(got, VAL_U128 as u128);
     }
 
     #[test]
-    fn mmu_new_edge_size_below() {
-        let mmu = Mmu::new(2 * DIRTY_BLOCK_SIZE - 1);
-        let want = Mmu {
-            size: 2 * DIRTY_BLOCK_SIZE - 1,
-            memory: vec![0; 2 * DIRTY_BLOCK_SIZE - 1],
-            perms: vec![Perm(0); 2 * DIRTY_BLOCK_SIZE - 1],
-            dirty: vec![],
-            dirty_bitmap: vec![0; 2],
-            brk: VirtAddr(0),
-            active_allocs: HashMap::new(),
-        };
+    fn mmu_poke_peek_int() {
+        let mut mmu_init = Mmu::new(DIRTY_BLOCK_SIZE);
 
-        assert_eq!(mmu, want);
+        mmu_init
+            .set_perms(
+                VirtAddr(0),
+                DIRTY_BLOCK_SIZE,
+                Perm(PERM_READ | PERM_WRITE),
+            )
+            .unwrap();
+
+        let mut mmu = mmu_init.fork();
+
+        const VAL_U8: u8 = 0x11;
+        const VAL_U16: u16 = 0x1122;
+        const VAL_U32: u32 = 0x11223344;
+        const VAL_U64: u64 = 0x1122334455667788;
+        const VAL_U128: u128 = 0x11223344556677881122334455667788;
+
+        // u8
+        mmu.poke_int::<u8>(VirtAddr(0), VAL_U8).unwrap();
+        let got = mmu.peek_int::<u128>(VirtAddr(0)).unwrap();
+        assert_eq!(got, VAL_U8 as u128);
+
+        // u16
+        mmu.reset(&mmu_init);
+        mmu.poke_int::<u16>(VirtAddr(0), VAL_U16).unwrap();
+        let got = mmu.peek_int::<u128>(VirtAddr(0)).unwrap();
+        assert_eq!(got, VAL_U16 as u128);
+
+        // u32
+        mmu.reset(&mmu_init);
+        mmu.poke_int::<u32>(VirtAddr(0), VAL_U32).unwrap();
+        let got = mmu.peek_int::<u128>(VirtAddr(0)).unwrap();
+        assert_eq!(got, VAL_U32 as u128);
+
+        // u64
+        mmu.reset(&mmu_init);
+        mmu.poke_int::<u64>(VirtAddr(0), VAL_U64).unwrap();
+        let got = mmu.peek_int::<u128>(VirtAddr(0)).unwrap();
+        assert_eq!(got, VAL_U64 as u128);
+
+        // u128
+        mmu.reset(&mmu_init);
+        mmu.poke_int::<u128>(VirtAddr(0), VAL_U128).unwrap();
+        let got = mmu.peek_int::<u128>(VirtAddr(0)).unwrap();
+        assert_eq!(got, VAL_U128 as u128);
     }
 
     #[test]
-    fn mmu_new_edge_size_above() {
-        let mmu = Mmu::new(2 * DIRTY_BLOCK_SIZE + 1);
-        let want = Mmu {
-            size: 2 * DIRTY_BLOCK_SIZE + 1,
-            memory: vec![0; 2 * DIRTY_BLOCK_SIZE + 1],
-            perms: vec![Perm(0); 2 * DIRTY_BLOCK_SIZE + 1],
-            dirty: vec![],
-            dirty_bitmap: vec![0; 3],
-            brk: VirtAddr(0),
-            active_allocs: HashMap::new(),
-        };
+    fn mmu_cstr() {
+        let mut mmu = Mmu::new(1024 * PAGE_SIZE);
+        mmu.set_perms(VirtAddr(0x1000), 0x10, Perm(PERM_READ | PERM_WRITE))
+            .unwrap();
 
-        assert_eq!(mmu, want);
+        mmu.write_cstr(VirtAddr(0x1008), b"1234567").unwrap();
+        assert_eq!(
+            mmu.read_cstr(VirtAddr(0x1008), 8).unwrap(),
+            b"1234567".to_vec()
+        );
+        match mmu.read_cstr(VirtAddr(0x1008), 7) {
+            Err(Error::StringTooLong {
+                addr: VirtAddr(0x1008),
+                size: 7,
+            }) => {}
+            res => panic!("Wrong result {:?}", res),
+        }
+
+        // The memory after the terminator is not accessed.
+        mmu.write_cstr(VirtAddr(0x100c), b"abc").unwrap();
+        assert_eq!(
+            mmu.read_cstr(VirtAddr(0x100c), 0x100).unwrap(),
+            b"abc".to_vec()
+        );
+
+        match mmu.write_cstr(VirtAddr(0x100d), b"abc") {
+            Err(Error::WriteFault { .. }) => {}
+            res => panic!("Wrong result {:?}", res),
+        }
+        mmu.poke_cstr(VirtAddr(0x100d), b"abc").unwrap();
+        match mmu.read_cstr(VirtAddr(0x100d), 0x100) {
+            Err(Error::ReadFault { .. }) => {}
+            res => panic!("Wrong result {:?}", res),
+        }
+        assert_eq!(
+            mmu.peek_cstr(VirtAddr(0x100d), 0x100).unwrap(),
+            b"abc".to_vec()
+        );
     }
 
     #[test]
-    #[should_panic]
-    fn mmu_new_small_size() {
-        Mmu::new(DIRTY_BLOCK_SIZE - 1);
+    fn mmu_struct() {
+        #[repr(C)]
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        struct Timespec {
+            sec: i64,
+            nsec: u32,
+            flags: u32,
+        }
+
+        unsafe impl Pod for Timespec {}
+
+        let mut mmu = Mmu::new(1024 * PAGE_SIZE);
+        mmu.set_perms(VirtAddr(0x1ff8), 0x20, Perm(PERM_READ | PERM_WRITE))
+            .unwrap();
+
+        let ts = Timespec {
+            sec: -2,
+            nsec: 0x11223344,
+            flags: 1,
+        };
+        mmu.write_struct(VirtAddr(0x1ff8), &ts).unwrap();
+        assert_eq!(mmu.read_int::<i64>(VirtAddr(0x1ff8)).unwrap(), -2);
+        assert_eq!(mmu.read_int::<u32>(VirtAddr(0x2000)).unwrap(), 0x11223344);
+        assert_eq!(mmu.read_struct::<Timespec>(VirtAddr(0x1ff8)).unwrap(), ts);
+
+        match mmu.read_struct::<Timespec>(VirtAddr(0x2010)) {
+            Err(Error::ReadFault { .. }) => {}
+            res => panic!("Wrong result {:?}", res),
+        }
+        mmu.poke_struct(VirtAddr(0x2010), &ts).unwrap();
+        assert_eq!(mmu.peek_struct::<Timespec>(VirtAddr(0x2010)).unwrap(), ts);
+
+        let iov = [
+            IoVec {
+                base: 0x4000,
+                len: 4,
+            },
+            IoVec {
+                base: 0x5000,
+                len: 8,
+            },
+        ];
+        mmu.write_slice(VirtAddr(0x1ff8), &iov).unwrap();
+        assert_eq!(mmu.read_iovecs(VirtAddr(0x1ff8), 2).unwrap(), iov);
+        assert_eq!(
+            mmu.read_slice::<u64>(VirtAddr(0x1ff8), 4).unwrap(),
+            vec![0x4000, 4, 0x5000, 8]
+        );
+        assert!(mmu.read_iovecs(VirtAddr(0x1ff8), 3).is_err());
+        assert_eq!(mmu.peek_iovecs(VirtAddr(0x1ff8), 3).unwrap().len(), 3);
+
+        match mmu.read_slice::<u64>(VirtAddr(0x1ff8), usize::MAX) {
+            Err(Error::AddressIntegerOverflow { .. }) => {}
+            res => panic!("Wrong result {:?}", res),
+        }
     }
 
     #[test]
-    fn mmu_check_perms() {
-        let mut mmu = Mmu::new(DIRTY_BLOCK_SIZE);
-        mmu.set_perms(VirtAddr(0), 8, Perm(PERM_WRITE | PERM_READ))
+    fn mmu_copy() {
+        let mut mmu = Mmu::new(1024 * PAGE_SIZE);
+        mmu.set_perms(VirtAddr(0x1000), 0x10, Perm(PERM_READ | PERM_WRITE))
             .unwrap();
-        mmu.check_perms(VirtAddr(0), 8, Perm(PERM_WRITE | PERM_READ))
+        mmu.set_taint_tracking(true);
+
+        mmu.write(VirtAddr(0x1000), b"abcd").unwrap();
+        mmu.taint_input(VirtAddr(0x1001), 1, 7).unwrap();
+
+        // Overlapping ranges are copied as with memmove.
+        mmu.copy(VirtAddr(0x1002), VirtAddr(0x1000), 4).unwrap();
+        let mut buf = [0; 6];
+        mmu.read(VirtAddr(0x1000), &mut buf).unwrap();
+        assert_eq!(&buf, b"ababcd");
+        assert_eq!(
+            mmu.taint(VirtAddr(0x1001), 3).unwrap(),
+            vec![Taint::input(7), Taint::UNTAINTED, Taint::input(7)]
+        );
+
+        match mmu.copy(VirtAddr(0x2000), VirtAddr(0x1000), 4) {
+            Err(Error::WriteFault { .. }) => {}
+            res => panic!("Wrong result {:?}", res),
+        }
+        match mmu.copy(VirtAddr(0x1000), VirtAddr(0x100e), 4) {
+            Err(Error::ReadFault { .. }) => {}
+            res => panic!("Wrong result {:?}", res),
+        }
+        mmu.copy_unchecked(VirtAddr(0x2000), VirtAddr(0x1000), 4)
             .unwrap();
+        assert_eq!(mmu.peek_int::<u32>(VirtAddr(0x2000)).unwrap(), 0x62616261);
     }
 
     #[test]
-    #[should_panic]
-    fn mmu_check_perms_subset() {
+    fn mmu_malloc_free() {
+        let mut mmu = Mmu::new(1024 * DIRTY_BLOCK_SIZE);
+        mmu.set_brk(VirtAddr(0));
+
+        let ptr1 = mmu.malloc(0x30, 16, false, AllocSite::default()).unwrap();
+        mmu.write(ptr1, &[0x41; 0x30]).unwrap();
+
+        let ptr2 = mmu.malloc(0x30, 16, false, AllocSite::default()).unwrap();
+        mmu.write(ptr2, &[0x41; 0x30]).unwrap();
+
+        mmu.free(ptr1, AllocSite::default()).unwrap();
+        mmu.free(ptr2, AllocSite::default()).unwrap();
+    }
+
+    #[test]
+    fn mmu_malloc_invalid_size() {
         let mut mmu = Mmu::new(DIRTY_BLOCK_SIZE);
-        mmu.set_perms(VirtAddr(0), 8, Perm(PERM_WRITE)).unwrap();
+        mmu.set_brk(VirtAddr(0));
+
+        match mmu.malloc(0x30, 16, false, AllocSite::default()) {
+            Err(Error::InvalidAddress { .. }) => return,
+            Err(err) => panic!("Wrong error {:?}", err),
+            _ => panic!("The function didn't return an error"),
+        }
+    }
+
+    #[test]
+    fn mmu_malloc_oob() {
+        let mut mmu = Mmu::new(1024 * DIRTY_BLOCK_SIZE);
+        mmu.set_brk(VirtAddr(0));
+        let ptr = mmu.malloc(0x30, 16, false, AllocSite::default()).unwrap();
+        match mmu.write(ptr, &[0x41; 0x31]) {
+            Err(Error::WriteFault { .. }) => return,
+            Err(err) => panic!("Wrong error {:?}", err),
+            _ => panic!("The function didn't return an error"),
+        }
+    }
+
+    #[test]
+    fn mmu_malloc_invalid_free() {
+        let mut mmu = Mmu::new(1024 * DIRTY_BLOCK_SIZE);
+        mmu.set_brk(VirtAddr(0));
+        let ptr = mmu.malloc(0x30, 16, false, AllocSite::default()).unwrap();
+        match mmu.free(VirtAddr(*ptr + 1), AllocSite::default()) {
+            Err(Error::InvalidFree { .. }) => return,
+            Err(err) => panic!("Wrong error {:?}", err),
+            _ => panic!("The function didn't return an error"),
+        }
+    }
+
+    #[test]
+    fn mmu_malloc_double_free() {
+        let mut mmu = Mmu::new(1024 * DIRTY_BLOCK_SIZE);
+        mmu.set_brk(VirtAddr(0));
+        let ptr = mmu.malloc(0x30, 16, false, AllocSite::default()).unwrap();
+        mmu.free(ptr, AllocSite::default()).unwrap();
+        match mmu.free(ptr, AllocSite::default()) {
+            Err(Error::InvalidFree { .. }) => return,
+            Err(err) => panic!("Wrong error {:?}", err),
+            _ => panic!("The function didn't return an error"),
+        }
+    }
+
+    #[test]
+    fn mmu_malloc_uaf() {
+        let mut mmu = Mmu::new(1024 * DIRTY_BLOCK_SIZE);
+        mmu.set_brk(VirtAddr(0));
+        let ptr = mmu.malloc(0x30, 16, false, AllocSite::default()).unwrap();
+        mmu.free(ptr, AllocSite::default()).unwrap();
+
+        match mmu.write(ptr, &[0x41; 1]) {
+            Err(Error::WriteFault { .. }) => return,
+            Err(err) => panic!("Wrong error {:?}", err),
+            _ => panic!("The function didn't return an error"),
+        }
+    }
+
+    #[test]
+    fn mmu_malloc_raw() {
+        let mut mmu = Mmu::new(1024 * DIRTY_BLOCK_SIZE);
+        mmu.set_brk(VirtAddr(0));
+
+        let ptr = mmu.malloc(0x30, 16, true, AllocSite::default()).unwrap();
+
+        let want = vec![1, 2, 3, 4, 5];
+        mmu.write(ptr, &want).unwrap();
+
+        let mut got = vec![0; 5];
+        mmu.read(ptr, &mut got).unwrap();
+
+        mmu.free(ptr, AllocSite::default()).unwrap();
 
-        mmu.check_perms(VirtAddr(0), 8, Perm(PERM_WRITE | PERM_READ))
-            .unwrap();
+        assert_eq!(want, got);
     }
 
     #[test]
-    fn mmu_check_perms_oob() {
-        let mut mmu = Mmu::new(DIRTY_BLOCK_SIZE);
-        match mmu.set_perms(
-            VirtAddr(DIRTY_BLOCK_SIZE + 5),
-            16,
-            Perm(PERM_WRITE),
-        ) {
-            Err(Error::InvalidAddress { .. }) => return,
+    fn mmu_malloc_raw_uninit() {
+        let mut mmu = Mmu::new(1024 * DIRTY_BLOCK_SIZE);
+        mmu.set_brk(VirtAddr(0));
+
+        let ptr = mmu.malloc(0x30, 16, true, AllocSite::default()).unwrap();
+
+        let want = vec![1, 2, 3, 4, 5];
+        mmu.write(ptr, &want).unwrap();
+
+        let mut got = vec![0; 6];
+        match mmu.read(ptr, &mut got) {
+            Err(Error::UninitFault { .. }) => return,
             Err(err) => panic!("Wrong error {:?}", err),
             _ => panic!("The function didn't return an error"),
         }
     }
 
     #[test]
-    fn mmu_check_perms_integer_overflow() {
-        let mut mmu = Mmu::new(DIRTY_BLOCK_SIZE);
-        match mmu.set_perms(VirtAddr(usize::MAX), 1, Perm(PERM_WRITE)) {
-            Err(Error::AddressIntegerOverflow { .. }) => return,
+    fn mmu_malloc_align() {
+        let mut mmu = Mmu::new(1024 * DIRTY_BLOCK_SIZE);
+        mmu.set_brk(VirtAddr(0x10));
+
+        let ptr = mmu
+            .malloc(0x30, 0x1000, false, AllocSite::default())
+            .unwrap();
+        assert_eq!(*ptr % 0x1000, 0);
+
+        match mmu.malloc(0x30, 0x18, false, AllocSite::default()) {
+            Err(Error::InvalidAlignment { align: 0x18 }) => return,
             Err(err) => panic!("Wrong error {:?}", err),
             _ => panic!("The function didn't return an error"),
         }
     }
 
     #[test]
-    fn mmu_poke_peek() {
-        let mut mmu = Mmu::new(DIRTY_BLOCK_SIZE);
-        mmu.poke(VirtAddr(0), &[1, 2, 3, 4]).unwrap();
+    fn mmu_malloc_redzones() {
+        let config = AllocConfig {
+            quarantine_size: Some(0),
+            left_redzone: 0x20,
+            right_redzone: 0x20,
+        };
+        let mut mmu =
+            Mmu::new(1024 * DIRTY_BLOCK_SIZE).with_alloc_config(config);
+        mmu.set_brk(VirtAddr(0));
 
-        let mut got = [0u8; 4];
-        mmu.peek(VirtAddr(0), &mut got).unwrap();
+        let ptr1 = mmu.malloc(0x30, 16, false, AllocSite::default()).unwrap();
+        let ptr2 = mmu.malloc(0x30, 16, false, AllocSite::default()).unwrap();
+        assert_eq!(*ptr1, 0x20);
+        assert_eq!(*ptr2, 0x90);
 
-        assert_eq!(&got, &[1, 2, 3, 4]);
+        assert!(mmu.write(VirtAddr(*ptr2 - 1), &[0x41]).is_err());
+        assert!(mmu.write(VirtAddr(*ptr1 + 0x30), &[0x41]).is_err());
     }
 
     #[test]
-    fn mmu_write_read() {
-        let mut mmu = Mmu::new(DIRTY_BLOCK_SIZE);
+    fn mmu_malloc_reuse() {
+        let config = AllocConfig {
+            quarantine_size: Some(0),
+            ..AllocConfig::default()
+        };
+        let mut mmu =
+            Mmu::new(1024 * DIRTY_BLOCK_SIZE).with_alloc_config(config);
+        mmu.set_brk(VirtAddr(0));
 
-        mmu.set_perms(VirtAddr(0), 4, Perm(PERM_READ | PERM_WRITE))
-            .unwrap();
-        mmu.write(VirtAddr(0), &[1, 2, 3, 4]).unwrap();
+        let ptr1 = mmu.malloc(0x30, 16, false, AllocSite::default()).unwrap();
+        let ptr2 = mmu.malloc(0x30, 16, false, AllocSite::default()).unwrap();
+        let brk = mmu.brk();
 
-        let mut got = [0u8; 4];
-        mmu.read(VirtAddr(0), &mut got).unwrap();
+        mmu.free(ptr1, AllocSite::default()).unwrap();
+        assert_eq!(
+            mmu.malloc(0x20, 16, false, AllocSite::default()).unwrap(),
+            ptr1
+        );
 
-        assert_eq!(&got, &[1, 2, 3, 4]);
+        // Adjacent free chunks are merged.
+        mmu.free(ptr1, AllocSite::default()).unwrap();
+        mmu.free(ptr2, AllocSite::default()).unwrap();
+        assert_eq!(
+            mmu.malloc(0x1000, 16, false, AllocSite::default()).unwrap(),
+            ptr1
+        );
+        assert_eq!(mmu.brk(), brk);
     }
 
     #[test]
-    fn mmu_write_fault() {
-        let mut mmu = Mmu::new(DIRTY_BLOCK_SIZE);
-        match mmu.write(VirtAddr(0), &[1, 2, 3, 4]) {
-            Err(Error::WriteFault { .. }) => return,
-            Err(err) => panic!("Wrong error {:?}", err),
-            _ => panic!("The function didn't return an error"),
+    fn mmu_malloc_fail_policy() {
+        let mut mmu = Mmu::new(1024 * PAGE_SIZE);
+        mmu.set_brk(VirtAddr(0x10000));
+
+        let site = AllocSite {
+            pc: VirtAddr(0x4000),
+            stack: vec![],
+        };
+
+        mmu.set_alloc_fail_policy(Some(AllocFailPolicy::Nth(1)));
+        let orig = mmu.fork();
+
+        mmu.malloc(0x10, 16, false, site.clone()).unwrap();
+        match mmu.malloc(0x20, 16, false, site.clone()) {
+            Err(Error::InjectedAllocFailure {
+                index: 1,
+                size: 0x20,
+            }) => {}
+            res => panic!("Wrong result {:?}", res),
         }
-    }
+        mmu.malloc(0x10, 16, false, site.clone()).unwrap();
+        assert_eq!(mmu.alloc_count(), 3);
+        assert_eq!(
+            mmu.failed_allocs(),
+            &[FailedAlloc {
+                index: 1,
+                size: 0x20,
+                site: site.clone(),
+            }]
+        );
 
-    #[test]
-    fn mmu_read_fault() {
-        let mmu = Mmu::new(DIRTY_BLOCK_SIZE);
+        // The decisions are replayed after a reset.
+        mmu.reset(&orig);
+        assert_eq!(mmu.alloc_count(), 0);
+        assert_eq!(mmu.alloc_fail_policy(), Some(&AllocFailPolicy::Nth(1)));
+        assert!(mmu.failed_allocs().is_empty());
+        mmu.malloc(0x10, 16, false, site.clone()).unwrap();
+        assert!(mmu.malloc(0x20, 16, false, site.clone()).is_err());
+
+        mmu.set_alloc_fail_policy(Some(AllocFailPolicy::SizeAbove(0x100)));
+        mmu.malloc(0x100, 16, false, site.clone()).unwrap();
+        assert!(mmu.malloc(0x101, 16, false, site.clone()).is_err());
+
+        // The bits 5 and 8 are set.
+        mmu.set_alloc_fail_policy(Some(AllocFailPolicy::Bits(vec![
+            0x20, 0x01,
+        ])));
+        for index in 4..12 {
+            let res = mmu.malloc(0x10, 16, false, site.clone());
+            assert_eq!(res.is_err(), index == 5 || index == 8);
+        }
+        assert_eq!(
+            mmu.failed_allocs()
+                .iter()
+                .map(|failed| failed.index)
+                .collect::<Vec<_>>(),
+            vec![1, 3, 5, 8]
+        );
 
-        let mut tmp = [0u8; 2];
-        match mmu.read(VirtAddr(0), &mut tmp) {
-            Err(Error::ReadFault { .. }) => return,
-            Err(err) => panic!("Wrong error {:?}", err),
-            _ => panic!("The function didn't return an error"),
+        // The current policy is kept after a reset, even if it differs from
+        // the original one.
+        mmu.reset(&orig);
+        assert_eq!(
+            mmu.alloc_fail_policy(),
+            Some(&AllocFailPolicy::Bits(vec![0x20, 0x01]))
+        );
+        for index in 0..6 {
+            let res = mmu.malloc(0x10, 16, false, site.clone());
+            assert_eq!(res.is_err(), index == 5);
         }
+
+        mmu.set_alloc_fail_policy(None);
+        mmu.reset(&orig);
+        assert_eq!(mmu.alloc_fail_policy(), None);
     }
 
     #[test]
-    fn mmu_exec_exec_fault() {
-        let mmu = Mmu::new(DIRTY_BLOCK_SIZE);
+    fn mmu_malloc_quarantine() {
+        let config = AllocConfig {
+            quarantine_size: Some(0x2000),
+            ..AllocConfig::default()
+        };
+        let mut mmu =
+            Mmu::new(1024 * DIRTY_BLOCK_SIZE).with_alloc_config(config);
+        mmu.set_brk(VirtAddr(0));
 
-        let mut tmp = [0u8; 2];
-        match mmu.read_with_perms(VirtAddr(0), &mut tmp, Perm(PERM_EXEC)) {
-            Err(Error::ExecFault { .. }) => return,
+        let ptr1 = mmu.malloc(0x30, 16, false, AllocSite::default()).unwrap();
+        let ptr2 = mmu.malloc(0x30, 16, false, AllocSite::default()).unwrap();
+        let ptr3 = mmu.malloc(0x30, 16, false, AllocSite::default()).unwrap();
+
+        // The freed chunk is in the quarantine, so UAF is detected.
+        mmu.free(ptr1, AllocSite::default()).unwrap();
+        assert_ne!(
+            mmu.malloc(0x30, 16, false, AllocSite::default()).unwrap(),
+            ptr1
+        );
+        match mmu.write(ptr1, &[0x41; 1]) {
+            Err(Error::WriteFault { .. }) => {}
             Err(err) => panic!("Wrong error {:?}", err),
             _ => panic!("The function didn't return an error"),
         }
+
+        // The oldest chunk is evicted when the quarantine is full.
+        mmu.free(ptr2, AllocSite::default()).unwrap();
+        mmu.free(ptr3, AllocSite::default()).unwrap();
+        assert_eq!(
+            mmu.malloc(0x30, 16, false, AllocSite::default()).unwrap(),
+            ptr1
+        );
     }
 
     #[test]
-    fn mmu_exec_unk_fault() {
-        let mmu = Mmu::new(DIRTY_BLOCK_SIZE);
+    fn mmu_reset_heap_shared() {
+        let mut mmu = Mmu::new(1024 * DIRTY_BLOCK_SIZE);
+        mmu.set_brk(VirtAddr(0));
+        let ptr = mmu.malloc(0x10, 16, false, AllocSite::default()).unwrap();
+        mmu.free(ptr, AllocSite::default()).unwrap();
+        mmu.malloc(0x10, 16, false, AllocSite::default()).unwrap();
+        let snapshot = mmu.fork();
+
+        // Resetting an unmodified heap does not copy the allocations.
+        mmu.reset(&snapshot);
+        assert!(Arc::ptr_eq(&mmu.heap.active, &snapshot.heap.active));
+        assert!(Arc::ptr_eq(&mmu.heap.quarantine, &snapshot.heap.quarantine));
+
+        // They are copied when modified, without changing the snapshot.
+        let ptr = mmu.malloc(0x10, 16, false, AllocSite::default()).unwrap();
+        mmu.free(ptr, AllocSite::default()).unwrap();
+        assert_eq!(mmu.heap.active.len(), 1);
+        assert_eq!(mmu.heap.quarantine.len(), 2);
+        assert_eq!(snapshot.heap.quarantine.len(), 1);
+
+        mmu.reset(&snapshot);
+        assert_eq!(mmu.heap, snapshot.heap);
+    }
 
-        let mut tmp = [0u8; 2];
-        match mmu.read_with_perms(VirtAddr(0), &mut tmp, Perm(1 << 7)) {
-            Err(Error::UnkFault { .. }) => return,
-            Err(err) => panic!("Wrong error {:?}", err),
-            _ => panic!("The function didn't return an error"),
+    #[test]
+    fn mmu_malloc_quarantine_unlimited() {
+        let mut mmu = Mmu::new(1024 * 1024 * 1024);
+        mmu.set_brk(VirtAddr(0));
+
+        let mut ptrs = Vec::new();
+        for _ in 0..MAX_UNLIMITED_QUARANTINE_CHUNKS + 1 {
+            let ptr =
+                mmu.malloc(0x10, 16, false, AllocSite::default()).unwrap();
+            mmu.free(ptr, AllocSite::default()).unwrap();
+            ptrs.push(ptr);
         }
+
+        // The oldest chunk is forgotten, but it is not reused.
+        assert_eq!(mmu.heap.quarantine.len(), MAX_UNLIMITED_QUARANTINE_CHUNKS);
+        assert_eq!(mmu.heap.quarantine[0].0, ptrs[1]);
+        let ptr = mmu.malloc(0x10, 16, false, AllocSite::default()).unwrap();
+        assert!(!ptrs.contains(&ptr));
     }
 
     #[test]
-    fn mmu_raw_after_write() {
-        let mut mmu = Mmu::new(DIRTY_BLOCK_SIZE);
-        mmu.set_perms(VirtAddr(0), 3, Perm(PERM_WRITE | PERM_RAW))
-            .unwrap();
-        mmu.write(VirtAddr(0), &[1, 2]).unwrap();
+    fn mmu_heap_report() {
+        let mut mmu = Mmu::new(1024 * DIRTY_BLOCK_SIZE);
+        mmu.set_brk(VirtAddr(0x1000));
+
+        let alloc_site = AllocSite {
+            pc: VirtAddr(0x10e2d0),
+            stack: vec![VirtAddr(0x10124), VirtAddr(0x10568)],
+        };
+        let free_site = AllocSite {
+            pc: VirtAddr(0x10c7a8),
+            stack: vec![VirtAddr(0x10200)],
+        };
 
-        assert_eq!(&mmu.memory[..4], &[1, 2, 0, 0]);
+        let ptr = mmu.malloc(0x30, 16, false, alloc_site.clone()).unwrap();
+        assert_eq!(mmu.heap_report(VirtAddr(0x0fff)), None);
+
+        let report = mmu.heap_report(VirtAddr(*ptr + 0x34)).unwrap();
+        assert_eq!(report.region, ptr);
+        assert_eq!(report.size, 0x30);
+        assert_eq!(report.alloc_site, alloc_site);
+        assert_eq!(report.free_site, None);
         assert_eq!(
-            &mmu.perms[..4],
-            &[
-                Perm(PERM_WRITE | PERM_READ),
-                Perm(PERM_WRITE | PERM_READ),
-                Perm(PERM_WRITE | PERM_RAW),
-                Perm(0)
-            ]
+            report.to_string(),
+            "0x1034 is located 4 bytes to the right of 48-byte region \
+             [0x1000, 0x1030)\n\
+             allocated at:\n    #0 0x10e2d0\n    #1 0x10124\n    #2 0x10568"
         );
+
+        mmu.free(ptr, free_site.clone()).unwrap();
+
+        let report = mmu.heap_report(VirtAddr(*ptr + 8)).unwrap();
+        assert_eq!(report.free_site, Some(free_site));
+        assert!(report.to_string().starts_with(
+            "0x1008 is located 8 bytes inside of 48-byte region"
+        ));
+        assert!(report
+            .to_string()
+            .ends_with("freed at:\n    #0 0x10c7a8\n    #1 0x10200"));
     }
 
     #[test]
-    fn mmu_raw_ok() {
-        let mut mmu = Mmu::new(DIRTY_BLOCK_SIZE);
-        mmu.set_perms(VirtAddr(0), 2, Perm(PERM_READ | PERM_WRITE))
+    fn mmu_watchpoint() {
+        let mut mmu = Mmu::new(1024 * DIRTY_BLOCK_SIZE);
+        mmu.set_perms(VirtAddr(0x1000), 0x100, Perm(PERM_READ | PERM_WRITE))
             .unwrap();
-        mmu.set_perms(VirtAddr(2), 2, Perm(PERM_WRITE | PERM_RAW))
+        mmu.add_watchpoint(VirtAddr(0x1010), 4, Access::Write)
             .unwrap();
-        mmu.write(VirtAddr(0), &[1, 2, 3, 4]).unwrap();
 
-        let mut got = [0u8; 4];
-        mmu.read(VirtAddr(0), &mut got).unwrap();
+        match mmu.write_int::<u64>(VirtAddr(0x100c), 0) {
+            Err(Error::Watchpoint {
+                addr: VirtAddr(0x100c),
+                size: 8,
+                access: Access::Write,
+            }) => {}
+            res => panic!("Wrong result {:?}", res),
+        }
+        assert_eq!(mmu.read_int::<u64>(VirtAddr(0x100c)).unwrap(), 0);
+        mmu.write_int::<u32>(VirtAddr(0x1014), 0).unwrap();
 
-        assert_eq!(&got, &[1, 2, 3, 4]);
+        // The watchpoint bit is not visible and survives set_perms.
+        mmu.set_perms(VirtAddr(0x1000), 0x100, Perm(PERM_READ | PERM_WRITE))
+            .unwrap();
+        assert_eq!(
+            mmu.perms(VirtAddr(0x1010), 1).unwrap(),
+            vec![Perm(PERM_READ | PERM_WRITE)]
+        );
+
+        // Accesses that do not check permissions are not watched.
+        mmu.poke_int::<u32>(VirtAddr(0x1010), 0x41).unwrap();
+
+        mmu.set_watchpoints_enabled(false);
+        mmu.write_int::<u32>(VirtAddr(0x1010), 0).unwrap();
+        mmu.set_watchpoints_enabled(true);
+
+        mmu.remove_watchpoint(VirtAddr(0x1010), 4, Access::Write);
+        assert!(mmu.watchpoints().is_empty());
+        mmu.write_int::<u32>(VirtAddr(0x1010), 0).unwrap();
+        assert_eq!(
+            *mmu.pages[&1].data.perms(0x10, 1).next().unwrap(),
+            PERM_READ | PERM_WRITE
+        );
     }
 
     #[test]
-    fn mmu_raw_uninit() {
-        let mut mmu = Mmu::new(DIRTY_BLOCK_SIZE);
-        mmu.set_perms(VirtAddr(0), 2, Perm(PERM_READ)).unwrap();
-        mmu.set_perms(VirtAddr(2), 2, Perm(PERM_WRITE | PERM_RAW))
+    fn mmu_watchpoint_overlap() {
+        let mut mmu = Mmu::new(1024 * DIRTY_BLOCK_SIZE);
+        mmu.set_perms(VirtAddr(0x1000), 0x100, Perm(PERM_READ | PERM_EXEC))
+            .unwrap();
+        mmu.add_watchpoint(VirtAddr(0x1000), 0x10, Access::Read)
+            .unwrap();
+        mmu.add_watchpoint(VirtAddr(0x1008), 0x10, Access::Exec)
             .unwrap();
 
-        let mut tmp = [0u8; 2];
-        match mmu.read(VirtAddr(1), &mut tmp) {
-            Err(Error::UninitFault { .. }) => return,
-            Err(err) => panic!("Wrong error {:?}", err),
-            _ => panic!("The function didn't return an error"),
+        assert!(mmu.read_int::<u32>(VirtAddr(0x1004)).is_err());
+        assert!(mmu.read_int::<u32>(VirtAddr(0x1010)).is_ok());
+        match mmu.read_int_with_perms::<u32>(VirtAddr(0x1010), Perm(PERM_EXEC))
+        {
+            Err(Error::Watchpoint {
+                addr: VirtAddr(0x1010),
+                size: 4,
+                access: Access::Exec,
+            }) => {}
+            res => panic!("Wrong result {:?}", res),
         }
+
+        // Removing a watchpoint keeps the overlapping ones.
+        mmu.remove_watchpoint(VirtAddr(0x1000), 0x10, Access::Read);
+        assert!(mmu.read_int::<u32>(VirtAddr(0x1004)).is_ok());
+        assert!(mmu
+            .read_int_with_perms::<u32>(VirtAddr(0x1008), Perm(PERM_EXEC))
+            .is_err());
     }
 
     #[test]
-    fn mmu_raw_read_fault() {
-        let mut mmu = Mmu::new(DIRTY_BLOCK_SIZE);
-        mmu.set_perms(VirtAddr(0), 2, Perm(PERM_WRITE)).unwrap();
-        mmu.set_perms(VirtAddr(2), 2, Perm(PERM_WRITE | PERM_RAW))
+    fn mmu_mmio() {
+        let mut mmu = Mmu::new(1024 * PAGE_SIZE);
+        mmu.set_perms(VirtAddr(0x1000), 0x100, Perm(PERM_READ | PERM_WRITE))
             .unwrap();
+        mmu.map_mmio(VirtAddr(0x1010), 0x10).unwrap();
+
+        match mmu.read_int::<u32>(VirtAddr(0x101c)) {
+            Err(Error::Mmio {
+                addr: VirtAddr(0x101c),
+                size: 4,
+            }) => {}
+            res => panic!("Wrong result {:?}", res),
+        }
+        match mmu.write_int::<u64>(VirtAddr(0x100c), 0) {
+            Err(Error::Mmio { .. }) => {}
+            res => panic!("Wrong result {:?}", res),
+        }
+        match mmu.map_mmio(VirtAddr(0x101f), 2) {
+            Err(Error::MmioOverlap { .. }) => {}
+            res => panic!("Wrong result {:?}", res),
+        }
 
-        let mut tmp = [0u8; 2];
-        match mmu.read(VirtAddr(1), &mut tmp) {
-            Err(Error::ReadFault { .. }) => return,
-            Err(err) => panic!("Wrong error {:?}", err),
-            _ => panic!("The function didn't return an error"),
+        // The backing memory is accessible without checking permissions.
+        mmu.poke_int::<u32>(VirtAddr(0x1010), 0x41).unwrap();
+        assert_eq!(mmu.peek_int::<u32>(VirtAddr(0x1010)).unwrap(), 0x41);
+        assert_eq!(
+            mmu.perms(VirtAddr(0x1010), 1).unwrap(),
+            vec![Perm(PERM_READ | PERM_WRITE)]
+        );
+
+        mmu.unmap_mmio(VirtAddr(0x1010), 0x10).unwrap();
+        assert_eq!(mmu.read_int::<u32>(VirtAddr(0x1010)).unwrap(), 0x41);
+    }
+
+    #[test]
+    fn mmu_perm_summary() {
+        let mut mmu = Mmu::new(1024 * PAGE_SIZE);
+        mmu.set_perms(
+            VirtAddr(0x1000),
+            PAGE_SIZE,
+            Perm(PERM_READ | PERM_WRITE),
+        )
+        .unwrap();
+        assert_eq!(mmu.pages[&1].data.summary, Perm(PERM_READ | PERM_WRITE));
+
+        let orig = mmu.fork();
+
+        // Uninitialized memory disables the fast path until it is written.
+        mmu.set_perms(VirtAddr(0x1ff0), 0x10, Perm(PERM_WRITE | PERM_RAW))
+            .unwrap();
+        assert_eq!(mmu.pages[&1].data.summary, Perm(0));
+        match mmu.read_int::<u32>(VirtAddr(0x1ff0)) {
+            Err(Error::UninitFault { .. }) => {}
+            res => panic!("Wrong result {:?}", res),
         }
+        mmu.write(VirtAddr(0x1ff0), &[0; 0x10]).unwrap();
+        assert_eq!(mmu.pages[&1].data.summary, Perm(PERM_READ | PERM_WRITE));
+
+        // Watched and memory-mapped pages are checked byte by byte.
+        mmu.reset(&orig);
+        assert_eq!(mmu.pages[&1].data.summary, Perm(PERM_READ | PERM_WRITE));
+        mmu.add_watchpoint(VirtAddr(0x1800), 1, Access::Read)
+            .unwrap();
+        assert_eq!(mmu.pages[&1].data.summary, Perm(0));
+        assert!(mmu.read_int::<u8>(VirtAddr(0x1800)).is_err());
+        mmu.remove_watchpoint(VirtAddr(0x1800), 1, Access::Read);
+        mmu.map_mmio(VirtAddr(0x1800), 1).unwrap();
+        assert_eq!(mmu.pages[&1].data.summary, Perm(0));
+        mmu.unmap_mmio(VirtAddr(0x1800), 1).unwrap();
+        assert_eq!(mmu.pages[&1].data.summary, Perm(PERM_READ | PERM_WRITE));
     }
 
     #[test]
-    fn mmu_reset() {
-        let mmu = Mmu::new(1024 * DIRTY_BLOCK_SIZE);
-        let mut mmu_fork = mmu.fork();
+    fn mmu_perm_counts() {
+        let mut mmu = Mmu::new(4 * PAGE_SIZE);
+        mmu.set_perms(
+            VirtAddr(0x1000),
+            PAGE_SIZE,
+            Perm(PERM_WRITE | PERM_RAW),
+        )
+        .unwrap();
+        assert!(mmu.pages[&1].data.perms.is_none());
+
+        // Initializing the page byte by byte makes it uniform again.
+        for off in 0..PAGE_SIZE {
+            mmu.write(VirtAddr(0x1000 + off), &[0x41]).unwrap();
+            let data = &mmu.pages[&1].data;
+            assert_eq!(data.perms.is_none(), off == PAGE_SIZE - 1);
+        }
+        assert_eq!(mmu.pages[&1].data.summary, Perm(PERM_READ | PERM_WRITE));
 
-        mmu_fork
-            .set_perms(VirtAddr(DIRTY_BLOCK_SIZE + 4), 4, Perm(PERM_WRITE))
+        // Permissions modified behind the Mmu's back, as the JIT compiler
+        // does, are counted again the next time they change.
+        mmu.set_perms(VirtAddr(0x1000), 2, Perm(PERM_WRITE | PERM_RAW))
             .unwrap();
-        mmu_fork
-            .write(VirtAddr(DIRTY_BLOCK_SIZE + 4), &[1, 2, 3, 4])
+        let data = mmu.page_data_mut(1);
+        data.perms.as_mut().unwrap()[0] = Perm(PERM_READ | PERM_WRITE);
+        data.stale = true;
+        mmu.write(VirtAddr(0x1001), &[0x41]).unwrap();
+        let data = &mmu.pages[&1].data;
+        assert!(data.perms.is_none());
+        assert!(!data.stale);
+        assert_eq!(data.summary, Perm(PERM_READ | PERM_WRITE));
+    }
+
+    #[test]
+    fn mmu_snapshot() {
+        let mut mmu = Mmu::new(1024 * PAGE_SIZE)
+            .with_alloc_config(AllocConfig {
+                quarantine_size: Some(0x1000),
+                left_redzone: 16,
+                right_redzone: 16,
+            })
+            .with_dirty_config(DirtyConfig {
+                block_size: 256,
+                hierarchical: true,
+            });
+        mmu.set_brk(VirtAddr(0x10000));
+        mmu.set_perms(VirtAddr(0x1ff0), 0x20, Perm(PERM_READ | PERM_RAW))
+            .unwrap();
+        mmu.poke(VirtAddr(0x1ff8), &[1, 2, 3, 4, 5, 6, 7, 8, 9])
+            .unwrap();
+        mmu.add_watchpoint(VirtAddr(0x2000), 4, Access::Read)
             .unwrap();
+        mmu.add_region(Region {
+            name: "[stack]".to_string(),
+            kind: RegionKind::Stack,
+            addr: VirtAddr(0x1000),
+            size: 0x2000,
+            perms: Perm(PERM_READ | PERM_WRITE),
+        })
+        .unwrap();
+
+        let site = AllocSite {
+            pc: VirtAddr(0x1234),
+            stack: vec![VirtAddr(0x5678)],
+        };
+        let ptr = mmu.malloc(0x30, 16, false, site.clone()).unwrap();
+        mmu.malloc(0x40, 16, true, site.clone()).unwrap();
+        mmu.free(ptr, site).unwrap();
 
-        let mut got = [0u8; 4];
+        let mut writer = Writer::new(Vec::new()).unwrap();
+        mmu.write_snapshot(&mut writer).unwrap();
+        let buf = writer.finish().unwrap();
 
-        mmu_fork
-            .peek(VirtAddr(DIRTY_BLOCK_SIZE + 4), &mut got)
+        let mut reader = Reader::new(buf.as_slice()).unwrap();
+        let got = Mmu::read_snapshot(&mut reader).unwrap();
+        reader.finish().unwrap();
+
+        assert_eq!(got, mmu.fork());
+        assert_eq!(got.heap_report(ptr), mmu.heap_report(ptr));
+        assert!(got.read_int::<u8>(VirtAddr(0x2000)).is_err());
+    }
+
+    #[test]
+    fn mmu_taint() {
+        let mut mmu = Mmu::new(1024 * PAGE_SIZE);
+        mmu.set_perms(VirtAddr(0x1000), 0x2000, Perm(PERM_READ | PERM_WRITE))
             .unwrap();
-        assert_eq!(&got, &[1, 2, 3, 4]);
 
-        mmu_fork.reset(&mmu);
+        // Without taint tracking, labels are ignored.
+        mmu.taint_input(VirtAddr(0x1000), 4, 0).unwrap();
+        assert_eq!(
+            mmu.taint(VirtAddr(0x1000), 1).unwrap(),
+            vec![Taint::UNTAINTED]
+        );
 
-        mmu_fork
-            .peek(VirtAddr(DIRTY_BLOCK_SIZE + 4), &mut got)
+        mmu.set_taint_tracking(true);
+        mmu.taint_input(VirtAddr(0x1ffe), 4, 0x10).unwrap();
+        assert_eq!(
+            mmu.taint(VirtAddr(0x1ffd), 6).unwrap(),
+            vec![
+                Taint::UNTAINTED,
+                Taint::input(0x10),
+                Taint::input(0x11),
+                Taint::input(0x12),
+                Taint::input(0x13),
+                Taint::UNTAINTED,
+            ]
+        );
+
+        // Writes overwrite the labels.
+        mmu.write_int::<u16>(VirtAddr(0x1fff), 0).unwrap();
+        assert_eq!(
+            mmu.taint(VirtAddr(0x1ffe), 4).unwrap(),
+            vec![
+                Taint::input(0x10),
+                Taint::UNTAINTED,
+                Taint::UNTAINTED,
+                Taint::input(0x13),
+            ]
+        );
+
+        match mmu.taint(VirtAddr(1024 * PAGE_SIZE - 1), 2) {
+            Err(Error::InvalidAddress { .. }) => {}
+            res => panic!("Wrong result {:?}", res),
+        }
+    }
+
+    #[test]
+    fn mmu_taint_reset() {
+        let mut mmu = Mmu::new(1024 * PAGE_SIZE);
+        mmu.set_perms(VirtAddr(0x1000), 0x1000, Perm(PERM_READ | PERM_WRITE))
             .unwrap();
-        assert_eq!(&got, &[0, 0, 0, 0]);
+        mmu.set_taint_tracking(true);
+        mmu.taint_input(VirtAddr(0x1000), 1, 0).unwrap();
+
+        let mut fork = mmu.fork();
+        fork.write_int::<u8>(VirtAddr(0x1000), 0).unwrap();
+        fork.taint_input(VirtAddr(0x5000), 1, 1).unwrap();
+        assert_eq!(
+            fork.taint(VirtAddr(0x1000), 1).unwrap(),
+            vec![Taint::UNTAINTED]
+        );
+
+        fork.reset(&mmu);
+        assert_eq!(
+            fork.taint(VirtAddr(0x1000), 1).unwrap(),
+            vec![Taint::input(0)]
+        );
+        assert_eq!(
+            fork.taint(VirtAddr(0x5000), 1).unwrap(),
+            vec![Taint::UNTAINTED]
+        );
     }
 
     #[test]
-    fn mmu_reset_two_blocks() {
-        let mmu = Mmu::new(1024 * DIRTY_BLOCK_SIZE);
-        let mut mmu_fork = mmu.fork();
+    fn mmu_regions() {
+        let mut mmu = Mmu::new(1024 * PAGE_SIZE);
+        mmu.set_brk(VirtAddr(0x10000));
+
+        let code = Region {
+            name: "prog".to_string(),
+            kind: RegionKind::Code,
+            addr: VirtAddr(0x1000),
+            size: 0x800,
+            perms: Perm(PERM_READ | PERM_EXEC),
+        };
+        mmu.add_region(code.clone()).unwrap();
+        mmu.add_region(Region {
+            name: "prog".to_string(),
+            kind: RegionKind::Global,
+            addr: VirtAddr(0x1800),
+            size: 0x100,
+            perms: Perm(PERM_READ | PERM_WRITE),
+        })
+        .unwrap();
+
+        match mmu.add_region(Region {
+            addr: VirtAddr(0x18ff),
+            ..code.clone()
+        }) {
+            Err(Error::RegionOverlap { .. }) => {}
+            res => panic!("Wrong result {:?}", res),
+        }
 
-        mmu_fork
-            .set_perms(VirtAddr(DIRTY_BLOCK_SIZE - 2), 4, Perm(PERM_WRITE))
-            .unwrap();
-        mmu_fork
-            .write(VirtAddr(DIRTY_BLOCK_SIZE - 2), &[1, 2, 3, 4])
-            .unwrap();
+        assert_eq!(mmu.region(VirtAddr(0x17ff)), Some(&code));
+        assert_eq!(
+            mmu.region(VirtAddr(0x18ff)).unwrap().kind,
+            RegionKind::Global
+        );
+        assert_eq!(mmu.region(VirtAddr(0x1900)), None);
+        assert_eq!(mmu.region(VirtAddr(0xfff)), None);
 
-        let mut got = [0u8; 4];
+        // The heap region grows along with the program break.
+        mmu.malloc(0x10, 16, false, AllocSite::default()).unwrap();
+        mmu.malloc(0x10, 16, false, AllocSite::default()).unwrap();
+        let heap = mmu.region(VirtAddr(0x10000)).unwrap();
+        assert_eq!(heap.kind, RegionKind::Heap);
+        assert_eq!(*heap.addr + heap.size, *mmu.brk());
 
-        mmu_fork
-            .peek(VirtAddr(DIRTY_BLOCK_SIZE - 2), &mut got)
-            .unwrap();
-        assert_eq!(&got, &[1, 2, 3, 4]);
+        assert_eq!(
+            mmu.maps(),
+            format!(
+                "0x0000001000-0x0000001800 R-X code   prog\n\
+                 0x0000001800-0x0000001900 RW- global prog\n\
+                 0x0000010000-{:#012x} RW- heap   [heap]\n",
+                *mmu.brk()
+            )
+        );
 
-        mmu_fork.reset(&mmu);
+        assert_eq!(mmu.remove_region(VirtAddr(0x1000)), Some(code));
+        assert_eq!(mmu.region(VirtAddr(0x1000)), None);
+
+        // The heap region cannot grow into other regions.
+        let brk = mmu.brk();
+        mmu.add_region(Region {
+            name: "prog".to_string(),
+            kind: RegionKind::Global,
+            addr: VirtAddr(*brk + 0x10),
+            size: 0x100,
+            perms: Perm(PERM_READ | PERM_WRITE),
+        })
+        .unwrap();
+        match mmu.malloc(0x10, 16, false, AllocSite::default()) {
+            Err(Error::RegionOverlap { .. }) => {}
+            res => panic!("Wrong result {:?}", res),
+        }
+        assert_eq!(mmu.brk(), brk);
+    }
 
-        mmu_fork
-            .peek(VirtAddr(DIRTY_BLOCK_SIZE - 2), &mut got)
+    #[test]
+    fn mmu_stack() {
+        let mut mmu = Mmu::new(0x40000);
+        mmu.set_brk(VirtAddr(0x10000));
+
+        match mmu.map_stack(0x10000, 0x20001) {
+            Err(Error::StackCollision { .. }) => {}
+            res => panic!("Wrong result {:?}", res),
+        }
+        match mmu.map_stack(0x10000, 0x40000) {
+            Err(Error::InvalidAddress { .. }) => {}
+            res => panic!("Wrong result {:?}", res),
+        }
+
+        assert_eq!(mmu.map_stack(0x10000, 0x1000).unwrap(), VirtAddr(0x40000));
+        assert_eq!(mmu.region(VirtAddr(0x30000)).unwrap().name, "[stack]");
+        assert_eq!(mmu.region(VirtAddr(0x2f000)).unwrap().name, "[guard]");
+        mmu.write_int::<u64>(VirtAddr(0x30000), 1).unwrap();
+
+        match mmu.write_int::<u64>(VirtAddr(0x2fffc), 0) {
+            Err(Error::StackOverflow {
+                addr: VirtAddr(0x2fffc),
+                size: 8,
+            }) => {}
+            res => panic!("Wrong result {:?}", res),
+        }
+        match mmu.read_int::<u8>(VirtAddr(0x2f000)) {
+            Err(Error::StackOverflow { .. }) => {}
+            res => panic!("Wrong result {:?}", res),
+        }
+
+        // The heap can grow up to the guard region.
+        mmu.malloc(0x1f000 - 0xff0, 16, false, AllocSite::default())
             .unwrap();
-        assert_eq!(&got, &[0, 0, 0, 0]);
+        assert_eq!(mmu.brk(), VirtAddr(0x2f000));
+        match mmu.malloc(1, 16, false, AllocSite::default()) {
+            Err(Error::StackCollision {
+                addr: VirtAddr(0x2f000),
+                ..
+            }) => {}
+            res => panic!("Wrong result {:?}", res),
+        }
+        match mmu.read_int::<u8>(VirtAddr(0x2efff)) {
+            Err(Error::ReadFault { .. }) => {}
+            res => panic!("Wrong result {:?}", res),
+        }
+
+        match mmu.map_stack(0x1000, 0) {
+            Err(Error::RegionOverlap { .. }) => {}
+            res => panic!("Wrong result {:?}", res),
+        }
     }
 
     #[test]
-    fn mmu_reset_one_of_two_blocks() {
-        let mut mmu = Mmu::new(1024 * DIRTY_BLOCK_SIZE);
+    fn mmu_mmap_brk_last_page() {
+        let mut mmu = Mmu::new(4 * PAGE_SIZE + 0x800);
+        mmu.set_brk(VirtAddr(4 * PAGE_SIZE + 0x400));
+        let rw = Perm(PERM_READ | PERM_WRITE);
+
+        match mmu.mmap(None, 0x1000, rw, "[anon]", &[]) {
+            Err(Error::InvalidAddress { .. }) => {}
+            res => panic!("Wrong result {:?}", res),
+        }
+    }
 
-        mmu.poke(VirtAddr(DIRTY_BLOCK_SIZE - 2), &[1, 2]).unwrap();
+    #[test]
+    fn mmu_mmap() {
+        let mut mmu = Mmu::new(0x40000);
+        mmu.set_brk(VirtAddr(0x10000));
+        mmu.map_stack(0x10000, 0x1000).unwrap();
+        let rw = Perm(PERM_READ | PERM_WRITE);
+
+        // Mappings are placed below the stack, top-down.
+        let anon = mmu.mmap(None, 0x1800, rw, "[anon]", &[]).unwrap();
+        assert_eq!(anon, VirtAddr(0x2d000));
+        let file = mmu
+            .mmap(None, 0x1000, Perm(PERM_READ), "file", b"\x7fELF")
+            .unwrap();
+        assert_eq!(file, VirtAddr(0x2c000));
 
-        let mut mmu_fork = mmu.fork();
+        assert_eq!(mmu.read_int::<u64>(VirtAddr(0x2eff8)).unwrap(), 0);
+        assert_eq!(mmu.read_int::<u32>(file).unwrap(), 0x464c457f);
+        match mmu.write_int::<u8>(file, 0) {
+            Err(Error::WriteFault { .. }) => {}
+            res => panic!("Wrong result {:?}", res),
+        }
 
-        mmu_fork.poke(VirtAddr(DIRTY_BLOCK_SIZE), &[3, 4]).unwrap();
+        let region = mmu.region(VirtAddr(0x2e000)).unwrap();
+        assert_eq!(region.kind, RegionKind::Mmap);
+        assert_eq!(region.size, 0x2000);
 
-        let mut got = [0u8; 4];
-        mmu_fork
-            .peek(VirtAddr(DIRTY_BLOCK_SIZE - 2), &mut got)
+        // Fixed mappings replace the overlapped parts of other mappings, but
+        // no other regions.
+        let fixed = mmu
+            .mmap(Some(VirtAddr(0x2c000)), 0x2000, rw, "[anon]", &[])
             .unwrap();
-        assert_eq!(&got, &[1, 2, 3, 4]);
-
-        mmu_fork.reset(&mmu);
+        assert_eq!(fixed, VirtAddr(0x2c000));
+        assert_eq!(mmu.read_int::<u32>(file).unwrap(), 0);
+        assert_eq!(*mmu.region(VirtAddr(0x2e000)).unwrap().addr, 0x2e000);
+        assert_eq!(*mmu.region(VirtAddr(0x2d000)).unwrap().addr, 0x2c000);
+
+        match mmu.mmap(Some(VirtAddr(0x2f000)), 1, rw, "[anon]", &[]) {
+            Err(Error::RegionOverlap { .. }) => {}
+            res => panic!("Wrong result {:?}", res),
+        }
+        match mmu.mmap(Some(VirtAddr(0x2c010)), 1, rw, "[anon]", &[]) {
+            Err(Error::MisalignedMapping { .. }) => {}
+            res => panic!("Wrong result {:?}", res),
+        }
+        match mmu.mmap(None, 0x20000, rw, "[anon]", &[]) {
+            Err(Error::InvalidAddress { .. }) => {}
+            res => panic!("Wrong result {:?}", res),
+        }
 
-        mmu_fork
-            .peek(VirtAddr(DIRTY_BLOCK_SIZE - 2), &mut got)
+        // The heap cannot grow into the mappings.
+        mmu.mmap(Some(VirtAddr(0x11000)), 1, rw, "[anon]", &[])
             .unwrap();
-        assert_eq!(&got, &[1, 2, 0, 0]);
+        match mmu.malloc(0x1000, 16, false, AllocSite::default()) {
+            Err(Error::RegionOverlap { .. }) => {}
+            res => panic!("Wrong result {:?}", res),
+        }
     }
 
     #[test]
-    fn mmu_reset_all() {
-        let mmu = Mmu::new(1024 * DIRTY_BLOCK_SIZE);
-        let mut mmu_fork = mmu.fork();
+    fn mmu_munmap_mprotect() {
+        let mut mmu = Mmu::new(0x40000);
+        mmu.set_brk(VirtAddr(0x10000));
+        let rw = Perm(PERM_READ | PERM_WRITE);
 
-        mmu_fork
-            .set_perms(
-                VirtAddr(0),
-                1024 * DIRTY_BLOCK_SIZE,
-                Perm(PERM_WRITE | PERM_RAW),
-            )
-            .unwrap();
-        mmu_fork
-            .write(VirtAddr(DIRTY_BLOCK_SIZE + 4), &[1, 2, 3, 4])
-            .unwrap();
+        let addr = mmu.mmap(None, 0x3000, rw, "[anon]", &[]).unwrap();
+        assert_eq!(addr, VirtAddr(0x3d000));
+        mmu.write_int::<u32>(VirtAddr(0x3e000), 0xdeadbeef).unwrap();
 
-        let mut got = [0u8; 4];
+        let orig = mmu.fork();
 
-        mmu_fork
-            .read(VirtAddr(DIRTY_BLOCK_SIZE + 4), &mut got)
+        mmu.munmap(VirtAddr(0x3e000), 1).unwrap();
+        match mmu.read_int::<u8>(VirtAddr(0x3e000)) {
+            Err(Error::ReadFault { .. }) => {}
+            res => panic!("Wrong result {:?}", res),
+        }
+        assert_eq!(mmu.peek_int::<u32>(VirtAddr(0x3e000)).unwrap(), 0);
+        assert_eq!(mmu.region(VirtAddr(0x3e000)), None);
+        assert_eq!(mmu.region(VirtAddr(0x3d000)).unwrap().size, 0x1000);
+        assert_eq!(*mmu.region(VirtAddr(0x3f000)).unwrap().addr, 0x3f000);
+
+        mmu.mprotect(VirtAddr(0x3f000), 0x1000, Perm(PERM_READ))
             .unwrap();
-        assert_eq!(&got, &[1, 2, 3, 4]);
+        match mmu.write_int::<u8>(VirtAddr(0x3f000), 0) {
+            Err(Error::WriteFault { .. }) => {}
+            res => panic!("Wrong result {:?}", res),
+        }
+        match mmu.mprotect(VirtAddr(0x3d000), 0x3000, rw) {
+            Err(Error::NotMapped {
+                addr: VirtAddr(0x3e000),
+                size: 0x2000,
+            }) => {}
+            res => panic!("Wrong result {:?}", res),
+        }
 
-        mmu_fork.reset(&mmu);
+        mmu.add_region(Region {
+            name: "prog".to_string(),
+            kind: RegionKind::Code,
+            addr: VirtAddr(0x1000),
+            size: 0x1000,
+            perms: Perm(PERM_EXEC),
+        })
+        .unwrap();
+        match mmu.munmap(VirtAddr(0x1000), 0x1000) {
+            Err(Error::RegionOverlap { .. }) => {}
+            res => panic!("Wrong result {:?}", res),
+        }
 
-        mmu_fork.peek(VirtAddr(4), &mut got).unwrap();
-        assert_eq!(&got, &[0, 0, 0, 0]);
+        // Resetting restores the mappings and their contents.
+        mmu.reset(&orig);
+        assert_eq!(
+            mmu.read_int::<u32>(VirtAddr(0x3e000)).unwrap(),
+            0xdeadbeef
+        );
+        mmu.write_int::<u8>(VirtAddr(0x3f000), 0).unwrap();
+        assert_eq!(mmu.maps(), orig.maps());
     }
 
     #[test]
-    fn mmu_write_read_int() {
-        let mut mmu_init = Mmu::new(DIRTY_BLOCK_SIZE);
-
-        mmu_init
-            .set_perms(
-                VirtAddr(0),
-                DIRTY_BLOCK_SIZE,
-                Perm(PERM_READ | PERM_WRITE),
-            )
+    fn mmu_diff() {
+        let mut mmu = Mmu::new(0x10000);
+        mmu.set_perms(VirtAddr(0x1000), 0x20, Perm(PERM_READ | PERM_WRITE))
             .unwrap();
+        mmu.write(VirtAddr(0x1008), b"hello").unwrap();
+        mmu.set_brk(VirtAddr(0x4000));
 
-        let mut mmu = mmu_init.fork();
-
-        const VAL_U8: u8 = 0x11;
-        const VAL_U16: u16 = 0x1122;
-        const VAL_U32: u32 = 0x11223344;
-        const VAL_U64: u64 = 0x1122334455667788;
-        const VAL_U128: u128 = 0x11223344556677881122334455667788;
-
-        // u8
-        mmu.write_int::<u8>(VirtAddr(0), VAL_U8).unwrap();
-        let got = mmu.peek_int::<u128>(VirtAddr(0)).unwrap();
-        assert_eq!(got, VAL_U8 as u128);
+        let orig = mmu.fork();
+        assert!(mmu.diff(&orig).is_empty());
 
-        // u16
-        mmu.reset(&mmu_init);
-        mmu.write_int::<u16>(VirtAddr(0), VAL_U16).unwrap();
-        let got = mmu.peek_int::<u128>(VirtAddr(0)).unwrap();
-        assert_eq!(got, VAL_U16 as u128);
+        mmu.write_int::<u32>(VirtAddr(0x1010), 0xdeadbeef).unwrap();
+        mmu.set_perms(VirtAddr(0x2000), 1, Perm(PERM_READ)).unwrap();
+        let ptr = mmu.malloc(16, 16, false, AllocSite::default()).unwrap();
 
-        // u32
-        mmu.reset(&mmu_init);
-        mmu.write_int::<u32>(VirtAddr(0), VAL_U32).unwrap();
-        let got = mmu.peek_int::<u128>(VirtAddr(0)).unwrap();
-        assert_eq!(got, VAL_U32 as u128);
+        let diff = mmu.diff(&orig);
+        assert_eq!(
+            diff.contents,
+            vec![RangeDiff {
+                addr: VirtAddr(0x1010),
+                old: vec![0; 4],
+                new: vec![0xef, 0xbe, 0xad, 0xde],
+            }]
+        );
+        assert_eq!(
+            diff.perms[0],
+            RangeDiff {
+                addr: VirtAddr(0x2000),
+                old: vec![0],
+                new: vec![PERM_READ],
+            }
+        );
+        assert_eq!(diff.brk, Some((VirtAddr(0x4000), mmu.brk())));
+        assert_eq!(
+            diff.allocs,
+            vec![AllocDiff {
+                addr: ptr,
+                old: None,
+                new: Some(16),
+            }]
+        );
 
-        // u64
-        mmu.reset(&mmu_init);
-        mmu.write_int::<u64>(VirtAddr(0), VAL_U64).unwrap();
-        let got = mmu.peek_int::<u128>(VirtAddr(0)).unwrap();
-        assert_eq!(got, VAL_U64 as u128);
+        let report = diff.to_string();
+        assert!(report.starts_with("brk: 0x4000 -> "));
+        assert!(report.contains("contents at 0x1010 (4 bytes):\n"));
+        assert!(report.contains("- 0x0000001010  00 00 00 00 "));
+        assert!(report.contains("+ 0x0000001010  ef be ad de "));
+        assert!(report.contains("  |....|\n"));
 
-        // u128
-        mmu.reset(&mmu_init);
-        mmu.write_int::<u128>(VirtAddr(0), VAL_U128).unwrap();
-        let got = mmu.peek_int::<u128>(VirtAddr(0)).unwrap();
-        assert_eq!(got, VAL_U128 as u128);
+        mmu.reset(&orig);
+        assert!(mmu.diff(&orig).is_empty());
     }
 
     #[test]
-    fn mmu_poke_peek_int() {
-        let mut mmu_init = Mmu::new(DIRTY_BLOCK_SIZE);
-
-        mmu_init
-            .set_perms(
-                VirtAddr(0),
-                DIRTY_BLOCK_SIZE,
-                Perm(PERM_READ | PERM_WRITE),
-            )
+    fn mmu_leaks() {
+        let mut mmu = Mmu::new(0x100000);
+        mmu.set_brk(VirtAddr(0x10000));
+        mmu.map_stack(0x1000, 0x1000).unwrap();
+        mmu.add_region(Region {
+            name: "prog".to_string(),
+            kind: RegionKind::Global,
+            addr: VirtAddr(0x1000),
+            size: 0x100,
+            perms: Perm(PERM_READ | PERM_WRITE),
+        })
+        .unwrap();
+
+        let site = AllocSite {
+            pc: VirtAddr(0x4000),
+            stack: vec![VirtAddr(0x4100)],
+        };
+        let malloc =
+            |mmu: &mut Mmu| mmu.malloc(0x20, 16, false, site.clone()).unwrap();
+        let a = malloc(&mut mmu);
+        let b = malloc(&mut mmu);
+        let c = malloc(&mut mmu);
+        let d = malloc(&mut mmu);
+        let e = malloc(&mut mmu);
+        let f = malloc(&mut mmu);
+        mmu.free(f, AllocSite::default()).unwrap();
+
+        // Interior pointers keep allocations reachable.
+        mmu.poke_int::<u64>(VirtAddr(0x1008), *a as u64 + 4)
             .unwrap();
+        mmu.write_int::<u64>(VirtAddr(*a + 0x18), *b as u64)
+            .unwrap();
+        mmu.write_int::<u64>(VirtAddr(*c + 8), *d as u64).unwrap();
 
-        let mut mmu = mmu_init.fork();
-
-        const VAL_U8: u8 = 0x11;
-        const VAL_U16: u16 = 0x1122;
-        const VAL_U32: u32 = 0x11223344;
-        const VAL_U64: u64 = 0x1122334455667788;
-        const VAL_U128: u128 = 0x11223344556677881122334455667788;
-
-        // u8
-        mmu.poke_int::<u8>(VirtAddr(0), VAL_U8).unwrap();
-        let got = mmu.peek_int::<u128>(VirtAddr(0)).unwrap();
-        assert_eq!(got, VAL_U8 as u128);
-
-        // u16
-        mmu.reset(&mmu_init);
-        mmu.poke_int::<u16>(VirtAddr(0), VAL_U16).unwrap();
-        let got = mmu.peek_int::<u128>(VirtAddr(0)).unwrap();
-        assert_eq!(got, VAL_U16 as u128);
-
-        // u32
-        mmu.reset(&mmu_init);
-        mmu.poke_int::<u32>(VirtAddr(0), VAL_U32).unwrap();
-        let got = mmu.peek_int::<u128>(VirtAddr(0)).unwrap();
-        assert_eq!(got, VAL_U32 as u128);
+        let leaks = mmu.leaks(None);
+        assert_eq!(
+            leaks.iter().map(|leak| leak.addr).collect::<Vec<_>>(),
+            vec![a, b, c, d, e]
+        );
+        assert!(leaks.iter().all(|leak| leak.kind == LeakKind::Direct));
 
-        // u64
-        mmu.reset(&mmu_init);
-        mmu.poke_int::<u64>(VirtAddr(0), VAL_U64).unwrap();
-        let got = mmu.peek_int::<u128>(VirtAddr(0)).unwrap();
-        assert_eq!(got, VAL_U64 as u128);
+        let leaks = mmu.leaks(Some(&[0, *e as u64]));
+        assert_eq!(
+            leaks,
+            vec![
+                Leak {
+                    addr: c,
+                    size: 0x20,
+                    site: site.clone(),
+                    kind: LeakKind::Direct,
+                },
+                Leak {
+                    addr: d,
+                    size: 0x20,
+                    site: site.clone(),
+                    kind: LeakKind::Indirect,
+                },
+            ]
+        );
+        assert_eq!(
+            leaks[0].to_string(),
+            format!(
+                "direct leak of 32 bytes at {} allocated at:\n    \
+                 #0 0x4000\n    #1 0x4100",
+                c
+            )
+        );
 
-        // u128
-        mmu.reset(&mmu_init);
-        mmu.poke_int::<u128>(VirtAddr(0), VAL_U128).unwrap();
-        let got = mmu.peek_int::<u128>(VirtAddr(0)).unwrap();
-        assert_eq!(got, VAL_U128 as u128);
+        // Pointers in the stack keep allocations reachable too.
+        mmu.write_int::<u64>(VirtAddr(0xfff00), *c as u64).unwrap();
+        assert!(mmu.leaks(Some(&[*e as u64])).is_empty());
     }
 
     #[test]
-    fn mmu_malloc_free() {
-        let mut mmu = Mmu::new(1024 * DIRTY_BLOCK_SIZE);
-        mmu.set_brk(VirtAddr(0));
+    fn mmu_uninit_tracking() {
+        let mut mmu = Mmu::new(1024 * PAGE_SIZE);
+        mmu.set_brk(VirtAddr(0x10000));
+        mmu.set_uninit_tracking(true);
+
+        let site = AllocSite {
+            pc: VirtAddr(0x1234),
+            stack: vec![],
+        };
+        let ptr = mmu.malloc(4, 16, true, site.clone()).unwrap();
+        let other = mmu.malloc(4, 16, false, site.clone()).unwrap();
+        let orig = mmu.fork();
+
+        // The memory is readable, but its bits are undefined.
+        assert_eq!(mmu.read_int::<u32>(ptr).unwrap(), 0);
+        let undef = mmu.definedness(ptr, 4).unwrap();
+        assert!(undef.iter().all(|d| d.undef == 0xff));
+        assert_eq!(
+            mmu.origin(undef[0].origin),
+            Some(&Origin {
+                addr: ptr,
+                size: 4,
+                site,
+            })
+        );
+        assert_eq!(
+            mmu.definedness(other, 1).unwrap(),
+            vec![Definedness::default()]
+        );
 
-        let ptr1 = mmu.malloc(0x30, false).unwrap();
-        mmu.write(ptr1, &[0x41; 0x30]).unwrap();
+        // Writes define the memory.
+        mmu.write_int::<u8>(VirtAddr(*ptr + 1), 0).unwrap();
+        mmu.set_definedness(
+            VirtAddr(*ptr + 2),
+            &[Definedness {
+                undef: 0x0f,
+                origin: undef[0].origin,
+            }],
+        )
+        .unwrap();
+        let got: Vec<u8> = mmu
+            .definedness(ptr, 4)
+            .unwrap()
+            .iter()
+            .map(|d| d.undef)
+            .collect();
+        assert_eq!(got, vec![0xff, 0, 0x0f, 0xff]);
+
+        mmu.malloc(4, 16, true, AllocSite::default()).unwrap();
+        mmu.reset(&orig);
+        assert_eq!(mmu.definedness(ptr, 4).unwrap(), undef);
+        assert_eq!(mmu.origin(OriginId(2)), None);
+    }
 
-        let ptr2 = mmu.malloc(0x30, false).unwrap();
-        mmu.write(ptr2, &[0x41; 0x30]).unwrap();
+    /// Builds Sv39 page tables rooted at 0x10000 mapping 0x1000 to 0x20000
+    /// and the 2MiB megapage at 0x200000 to 0x400000.
+    fn sv39_mmu() -> Mmu {
+        let mut mmu = Mmu::new(0x100000);
 
-        mmu.free(ptr1).unwrap();
-        mmu.free(ptr2).unwrap();
+        let table = |number: u64| (number << PTE_PPN_SHIFT) | PTE_V;
+        mmu.poke_int::<u64>(VirtAddr(0x10000), table(0x11)).unwrap();
+        mmu.poke_int::<u64>(VirtAddr(0x11000), table(0x12)).unwrap();
+        mmu.poke_int::<u64>(VirtAddr(0x11008), table(0x400) | PTE_R)
+            .unwrap();
+        mmu.poke_int::<u64>(VirtAddr(0x12008), table(0x20) | PTE_R | PTE_W)
+            .unwrap();
+
+        mmu.set_privilege(Privilege::Supervisor);
+        mmu.set_satp(8 << SATP_MODE_SHIFT | 0x10).unwrap();
+        mmu
     }
 
     #[test]
-    fn mmu_malloc_invalid_size() {
-        let mut mmu = Mmu::new(DIRTY_BLOCK_SIZE);
-        mmu.set_brk(VirtAddr(0));
+    fn mmu_translate_sv39() {
+        let mut mmu = sv39_mmu();
+        assert_eq!(mmu.paging_mode(), PagingMode::Sv39);
 
-        match mmu.malloc(0x30, false) {
-            Err(Error::InvalidAddress { .. }) => return,
-            Err(err) => panic!("Wrong error {:?}", err),
-            _ => panic!("The function didn't return an error"),
+        assert_eq!(
+            mmu.translate(VirtAddr(0x1234), Access::Read).unwrap(),
+            VirtAddr(0x20234)
+        );
+        assert_eq!(
+            mmu.translate(VirtAddr(0x201234), Access::Read).unwrap(),
+            VirtAddr(0x401234)
+        );
+        assert_eq!(
+            mmu.peek_translation(VirtAddr(0x1234), Access::Write)
+                .unwrap(),
+            VirtAddr(0x20234)
+        );
+
+        for addr in &[0x5000, 0x3fff_ffff, 1 << 40] {
+            match mmu.translate(VirtAddr(*addr), Access::Read) {
+                Err(Error::PageFault {
+                    addr: fault,
+                    access,
+                }) => {
+                    assert_eq!(*fault, *addr);
+                    assert_eq!(access, Access::Read);
+                }
+                res => panic!("Wrong result {:?}", res),
+            }
         }
-    }
 
-    #[test]
-    fn mmu_malloc_oob() {
-        let mut mmu = Mmu::new(1024 * DIRTY_BLOCK_SIZE);
-        mmu.set_brk(VirtAddr(0));
-        let ptr = mmu.malloc(0x30, false).unwrap();
-        match mmu.write(ptr, &[0x41; 0x31]) {
-            Err(Error::WriteFault { .. }) => return,
-            Err(err) => panic!("Wrong error {:?}", err),
-            _ => panic!("The function didn't return an error"),
+        match mmu.translate(VirtAddr(0x1000), Access::Exec) {
+            Err(Error::PageFault { .. }) => {}
+            res => panic!("Wrong result {:?}", res),
+        }
+        match mmu.translate(VirtAddr(0x200000), Access::Write) {
+            Err(Error::PageFault { .. }) => {}
+            res => panic!("Wrong result {:?}", res),
         }
     }
 
     #[test]
-    fn mmu_malloc_invalid_free() {
-        let mut mmu = Mmu::new(1024 * DIRTY_BLOCK_SIZE);
-        mmu.set_brk(VirtAddr(0));
-        let ptr = mmu.malloc(0x30, false).unwrap();
-        match mmu.free(VirtAddr(*ptr + 1)) {
-            Err(Error::InvalidFree { .. }) => return,
-            Err(err) => panic!("Wrong error {:?}", err),
-            _ => panic!("The function didn't return an error"),
-        }
+    fn mmu_translate_accessed_dirty() {
+        let mut mmu = sv39_mmu();
+        let pte = VirtAddr(0x12008);
+        let flags = |mmu: &Mmu| mmu.peek_int::<u64>(pte).unwrap() & 0xff;
+
+        mmu.peek_translation(VirtAddr(0x1000), Access::Write)
+            .unwrap();
+        assert_eq!(flags(&mmu), PTE_V | PTE_R | PTE_W);
+
+        mmu.translate(VirtAddr(0x1000), Access::Read).unwrap();
+        assert_eq!(flags(&mmu), PTE_V | PTE_R | PTE_W | PTE_A);
+
+        // The first write through a cached translation walks the tables.
+        mmu.translate(VirtAddr(0x1008), Access::Write).unwrap();
+        assert_eq!(flags(&mmu), PTE_V | PTE_R | PTE_W | PTE_A | PTE_D);
     }
 
     #[test]
-    fn mmu_malloc_double_free() {
-        let mut mmu = Mmu::new(1024 * DIRTY_BLOCK_SIZE);
-        mmu.set_brk(VirtAddr(0));
-        let ptr = mmu.malloc(0x30, false).unwrap();
-        mmu.free(ptr).unwrap();
-        match mmu.free(ptr) {
-            Err(Error::InvalidFree { .. }) => return,
-            Err(err) => panic!("Wrong error {:?}", err),
-            _ => panic!("The function didn't return an error"),
+    fn mmu_translate_cache() {
+        let mut mmu = sv39_mmu();
+        mmu.translate(VirtAddr(0x1000), Access::Read).unwrap();
+
+        // Page table updates require a fence.
+        mmu.poke_int::<u64>(VirtAddr(0x12008), 0).unwrap();
+        assert_eq!(
+            mmu.translate(VirtAddr(0x1000), Access::Read).unwrap(),
+            VirtAddr(0x20000)
+        );
+
+        mmu.sfence_vma(Some(VirtAddr(0x1fff)));
+        match mmu.translate(VirtAddr(0x1000), Access::Read) {
+            Err(Error::PageFault { .. }) => {}
+            res => panic!("Wrong result {:?}", res),
         }
-    }
 
-    #[test]
-    fn mmu_malloc_uaf() {
-        let mut mmu = Mmu::new(1024 * DIRTY_BLOCK_SIZE);
-        mmu.set_brk(VirtAddr(0));
-        let ptr = mmu.malloc(0x30, false).unwrap();
-        mmu.free(ptr).unwrap();
+        mmu.set_satp(0).unwrap();
+        assert_eq!(mmu.paging_mode(), PagingMode::Bare);
+        assert_eq!(
+            mmu.translate(VirtAddr(0x1000), Access::Read).unwrap(),
+            VirtAddr(0x1000)
+        );
 
-        match mmu.write(ptr, &[0x41; 1]) {
-            Err(Error::WriteFault { .. }) => return,
-            Err(err) => panic!("Wrong error {:?}", err),
-            _ => panic!("The function didn't return an error"),
+        match mmu.set_satp(1 << SATP_MODE_SHIFT) {
+            Err(Error::UnsupportedPagingMode { .. }) => {}
+            res => panic!("Wrong result {:?}", res),
         }
+        assert_eq!(mmu.satp(), 0);
     }
 
     #[test]
-    fn mmu_malloc_raw() {
-        let mut mmu = Mmu::new(1024 * DIRTY_BLOCK_SIZE);
-        mmu.set_brk(VirtAddr(0));
-
-        let ptr = mmu.malloc(0x30, true).unwrap();
+    fn mmu_translate_privilege() {
+        let mut mmu = sv39_mmu();
 
-        let want = vec![1, 2, 3, 4, 5];
-        mmu.write(ptr, &want).unwrap();
-
-        let mut got = vec![0; 5];
-        mmu.read(ptr, &mut got).unwrap();
-
-        mmu.free(ptr).unwrap();
+        mmu.set_privilege(Privilege::User);
+        match mmu.translate(VirtAddr(0x1000), Access::Read) {
+            Err(Error::PageFault { .. }) => {}
+            res => panic!("Wrong result {:?}", res),
+        }
 
-        assert_eq!(want, got);
+        let pte = (0x20 << PTE_PPN_SHIFT) | PTE_V | PTE_R | PTE_X | PTE_U;
+        mmu.poke_int::<u64>(VirtAddr(0x12008), pte).unwrap();
+        mmu.sfence_vma(None);
+        mmu.translate(VirtAddr(0x1000), Access::Exec).unwrap();
+
+        // Supervisor mode can only read user pages with SUM and never
+        // execute them.
+        mmu.set_privilege(Privilege::Supervisor);
+        match mmu.translate(VirtAddr(0x1000), Access::Read) {
+            Err(Error::PageFault { .. }) => {}
+            res => panic!("Wrong result {:?}", res),
+        }
+        mmu.set_status(true, false);
+        mmu.translate(VirtAddr(0x1000), Access::Read).unwrap();
+        match mmu.translate(VirtAddr(0x1000), Access::Exec) {
+            Err(Error::PageFault { .. }) => {}
+            res => panic!("Wrong result {:?}", res),
+        }
     }
 
     #[test]
-    fn mmu_malloc_raw_uninit() {
-        let mut mmu = Mmu::new(1024 * DIRTY_BLOCK_SIZE);
-        mmu.set_brk(VirtAddr(0));
-
-        let ptr = mmu.malloc(0x30, true).unwrap();
+    fn mmu_translate_sv48() {
+        let mut mmu = sv39_mmu();
 
-        let want = vec![1, 2, 3, 4, 5];
-        mmu.write(ptr, &want).unwrap();
+        // The Sv39 root table is the second level of the Sv48 tables.
+        let table = (0x10 << PTE_PPN_SHIFT) | PTE_V;
+        mmu.poke_int::<u64>(VirtAddr(0x30000), table).unwrap();
+        mmu.set_satp(9 << SATP_MODE_SHIFT | 0x30).unwrap();
+        assert_eq!(mmu.paging_mode(), PagingMode::Sv48);
 
-        let mut got = vec![0; 6];
-        match mmu.read(ptr, &mut got) {
-            Err(Error::UninitFault { .. }) => return,
-            Err(err) => panic!("Wrong error {:?}", err),
-            _ => panic!("The function didn't return an error"),
+        assert_eq!(
+            mmu.translate(VirtAddr(0x1234), Access::Read).unwrap(),
+            VirtAddr(0x20234)
+        );
+        match mmu.translate(VirtAddr(1 << 40), Access::Read) {
+            Err(Error::PageFault { .. }) => {}
+            res => panic!("Wrong result {:?}", res),
         }
+
+        // The translations are flushed on reset.
+        let orig = mmu.fork();
+        mmu.translate(VirtAddr(0x1000), Access::Read).unwrap();
+        mmu.set_satp(0).unwrap();
+        mmu.reset(&orig);
+        assert_eq!(mmu.satp(), orig.satp());
+        assert!(mmu.paging.translations.is_empty());
     }
 }
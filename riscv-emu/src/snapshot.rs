@@ -0,0 +1,261 @@
+//! Versioned binary snapshots of the emulator state.
+//!
+//! A snapshot starts with the magic bytes `RVSNAP\0\0`, followed by the
+//! format version as a little-endian 32-bit integer. Every integer after the
+//! header is encoded as a little-endian 64-bit integer, and every sequence is
+//! prefixed by its number of elements. Snapshots do not depend on the host,
+//! so they can be reused on other machines.
+
+use std::convert::TryInto;
+use std::fmt;
+use std::io::{self, Read, Write};
+
+/// Magic bytes at the beginning of every snapshot.
+const MAGIC: &[u8; 8] = b"RVSNAP\0\0";
+
+/// Version of the snapshot format. It must be increased every time the
+/// format changes.
+pub const VERSION: u32 = 4;
+
+/// Error related to snapshot serialization.
+#[derive(Debug)]
+pub enum Error {
+    /// Unknown file format.
+    UnknownFormat,
+
+    /// The snapshot was created with an unsupported format version.
+    UnsupportedVersion(u32),
+
+    /// Malformed snapshot.
+    MalformedFile,
+
+    /// IO error when reading or writing the snapshot.
+    IoError(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::UnknownFormat => write!(f, "unknown file format"),
+            Error::UnsupportedVersion(version) => {
+                write!(f, "unsupported snapshot version: {}", version)
+            }
+            Error::MalformedFile => write!(f, "malformed file"),
+            Error::IoError(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Error {
+        match error.kind() {
+            io::ErrorKind::UnexpectedEof => Error::MalformedFile,
+            _ => Error::IoError(error),
+        }
+    }
+}
+
+/// Snapshot writer.
+pub struct Writer<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> Writer<W> {
+    /// Returns a new snapshot writer. It writes the snapshot header to
+    /// `inner`.
+    pub fn new(mut inner: W) -> Result<Writer<W>, Error> {
+        inner.write_all(MAGIC)?;
+        inner.write_all(&VERSION.to_le_bytes())?;
+
+        Ok(Writer { inner })
+    }
+
+    /// Flushes the snapshot and returns the underlying writer.
+    pub fn finish(mut self) -> Result<W, Error> {
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+
+    /// Writes a 64-bit integer.
+    pub fn write_u64(&mut self, val: u64) -> Result<(), Error> {
+        self.inner.write_all(&val.to_le_bytes())?;
+        Ok(())
+    }
+
+    /// Writes a `usize` as a 64-bit integer.
+    pub fn write_usize(&mut self, val: usize) -> Result<(), Error> {
+        self.write_u64(val as u64)
+    }
+
+    /// Writes a boolean as a 64-bit integer.
+    pub fn write_bool(&mut self, val: bool) -> Result<(), Error> {
+        self.write_u64(val as u64)
+    }
+
+    /// Writes the bytes in `buf`. Their length is not written.
+    pub fn write_bytes(&mut self, buf: &[u8]) -> Result<(), Error> {
+        self.inner.write_all(buf)?;
+        Ok(())
+    }
+
+    /// Writes a string, prefixed by its length in bytes.
+    pub fn write_str(&mut self, val: &str) -> Result<(), Error> {
+        self.write_usize(val.len())?;
+        self.write_bytes(val.as_bytes())
+    }
+}
+
+/// Snapshot reader.
+pub struct Reader<R: Read> {
+    inner: R,
+}
+
+impl<R: Read> Reader<R> {
+    /// Returns a new snapshot reader. It reads and checks the snapshot header
+    /// from `inner`.
+    pub fn new(mut inner: R) -> Result<Reader<R>, Error> {
+        let mut magic = [0u8; 8];
+        inner.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(Error::UnknownFormat);
+        }
+
+        let mut version = [0u8; 4];
+        inner.read_exact(&mut version)?;
+        let version = u32::from_le_bytes(version);
+        if version != VERSION {
+            return Err(Error::UnsupportedVersion(version));
+        }
+
+        Ok(Reader { inner })
+    }
+
+    /// Checks that the whole snapshot was read and returns the underlying
+    /// reader.
+    pub fn finish(mut self) -> Result<R, Error> {
+        let mut buf = [0u8; 1];
+        if self.inner.read(&mut buf)? != 0 {
+            return Err(Error::MalformedFile);
+        }
+        Ok(self.inner)
+    }
+
+    /// Reads a 64-bit integer.
+    pub fn read_u64(&mut self) -> Result<u64, Error> {
+        let mut buf = [0u8; 8];
+        self.inner.read_exact(&mut buf)?;
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    /// Reads a 64-bit integer as a `usize`. It fails if the value does not
+    /// fit in a `usize`.
+    pub fn read_usize(&mut self) -> Result<usize, Error> {
+        self.read_u64()?
+            .try_into()
+            .map_err(|_| Error::MalformedFile)
+    }
+
+    /// Reads a boolean encoded as a 64-bit integer.
+    pub fn read_bool(&mut self) -> Result<bool, Error> {
+        match self.read_u64()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(Error::MalformedFile),
+        }
+    }
+
+    /// Reads exactly `buf.len()` bytes into `buf`.
+    pub fn read_bytes(&mut self, buf: &mut [u8]) -> Result<(), Error> {
+        self.inner.read_exact(buf)?;
+        Ok(())
+    }
+
+    /// Reads a string prefixed by its length in bytes. It fails if the
+    /// string is not valid UTF-8.
+    pub fn read_string(&mut self) -> Result<String, Error> {
+        let len = self.read_usize()?;
+
+        // The length is not trusted, so the string is not preallocated.
+        let mut buf = Vec::new();
+        (&mut self.inner).take(len as u64).read_to_end(&mut buf)?;
+        if buf.len() != len {
+            return Err(Error::MalformedFile);
+        }
+
+        String::from_utf8(buf).map_err(|_| Error::MalformedFile)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snapshot_roundtrip() {
+        let mut writer = Writer::new(Vec::new()).unwrap();
+        writer.write_u64(0x1122334455667788).unwrap();
+        writer.write_usize(42).unwrap();
+        writer.write_bool(true).unwrap();
+        writer.write_bytes(b"abc").unwrap();
+        writer.write_str("[stack]").unwrap();
+        let buf = writer.finish().unwrap();
+
+        let mut reader = Reader::new(buf.as_slice()).unwrap();
+        assert_eq!(reader.read_u64().unwrap(), 0x1122334455667788);
+        assert_eq!(reader.read_usize().unwrap(), 42);
+        assert!(reader.read_bool().unwrap());
+        let mut got = [0u8; 3];
+        reader.read_bytes(&mut got).unwrap();
+        assert_eq!(&got, b"abc");
+        assert_eq!(reader.read_string().unwrap(), "[stack]");
+        reader.finish().unwrap();
+    }
+
+    #[test]
+    fn snapshot_unknown_format() {
+        match Reader::new(&b"\x7fELF\x02\x01\x01\x00\x00\x00\x00\x00"[..]) {
+            Err(Error::UnknownFormat) => {}
+            Err(err) => panic!("Wrong error {:?}", err),
+            _ => panic!("The function didn't return an error"),
+        }
+    }
+
+    #[test]
+    fn snapshot_unsupported_version() {
+        let mut buf = MAGIC.to_vec();
+        buf.extend_from_slice(&(VERSION + 1).to_le_bytes());
+
+        match Reader::new(buf.as_slice()) {
+            Err(Error::UnsupportedVersion(version)) => {
+                assert_eq!(version, VERSION + 1)
+            }
+            Err(err) => panic!("Wrong error {:?}", err),
+            _ => panic!("The function didn't return an error"),
+        }
+    }
+
+    #[test]
+    fn snapshot_truncated() {
+        let mut writer = Writer::new(Vec::new()).unwrap();
+        writer.write_u64(1).unwrap();
+        let mut buf = writer.finish().unwrap();
+        buf.pop();
+
+        let mut reader = Reader::new(buf.as_slice()).unwrap();
+        match reader.read_u64() {
+            Err(Error::MalformedFile) => {}
+            Err(err) => panic!("Wrong error {:?}", err),
+            _ => panic!("The function didn't return an error"),
+        }
+    }
+
+    #[test]
+    fn snapshot_trailing_data() {
+        let mut writer = Writer::new(Vec::new()).unwrap();
+        writer.write_u64(1).unwrap();
+        let buf = writer.finish().unwrap();
+
+        let reader = Reader::new(buf.as_slice()).unwrap();
+        assert!(reader.finish().is_err());
+    }
+}
@@ -0,0 +1,164 @@
+//! Byte-level taint tracking, aimed to find out which input bytes influence
+//! the values computed by the emulated program.
+//!
+//! Every byte of memory and every register has a taint label. The label of a
+//! byte of the input is its offset within the input. The label of a value
+//! computed from other tainted values is the union of their labels. Unions
+//! are recorded in a `TaintLabels` table, so every label can be resolved to
+//! the set of input offsets it depends on.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+/// Flag of the labels created by `TaintLabels::union`.
+const UNION_FLAG: u32 = 1 << 31;
+
+/// Taint label of a byte of memory or of a register.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Taint(u32);
+
+impl Taint {
+    /// Label of untainted values.
+    pub const UNTAINTED: Taint = Taint(0);
+
+    /// Returns the label of the byte of the input at `offset`.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `offset` is not lower than 2^31 - 1.
+    pub fn input(offset: usize) -> Taint {
+        assert!(offset < (UNION_FLAG - 1) as usize, "invalid input offset");
+        Taint(offset as u32 + 1)
+    }
+
+    /// Returns `true` if the label is not `Taint::UNTAINTED`.
+    pub fn is_tainted(self) -> bool {
+        self != Taint::UNTAINTED
+    }
+}
+
+impl fmt::Display for Taint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0 & UNION_FLAG != 0 {
+            write!(f, "union#{}", self.0 & !UNION_FLAG)
+        } else if self.is_tainted() {
+            write!(f, "input[{:#x}]", self.0 - 1)
+        } else {
+            write!(f, "untainted")
+        }
+    }
+}
+
+/// Table of the labels created by the union of other labels.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct TaintLabels {
+    /// Operands of every union, indexed by label.
+    unions: Vec<(Taint, Taint)>,
+
+    /// Label of every union, indexed by its operands.
+    dedup: HashMap<(Taint, Taint), Taint>,
+}
+
+impl TaintLabels {
+    /// Returns an empty label table.
+    pub fn new() -> TaintLabels {
+        TaintLabels::default()
+    }
+
+    /// Returns the label of the values depending on the values labeled with
+    /// `a` and `b`.
+    pub fn union(&mut self, a: Taint, b: Taint) -> Taint {
+        if !b.is_tainted() || a == b {
+            return a;
+        }
+        if !a.is_tainted() {
+            return b;
+        }
+
+        let key = if a.0 < b.0 { (a, b) } else { (b, a) };
+
+        if let Some(&taint) = self.dedup.get(&key) {
+            return taint;
+        }
+
+        let taint = Taint(self.unions.len() as u32 | UNION_FLAG);
+        self.unions.push(key);
+        self.dedup.insert(key, taint);
+
+        taint
+    }
+
+    /// Returns the sorted offsets of the input bytes the values labeled with
+    /// `taint` depend on.
+    pub fn offsets(&self, taint: Taint) -> Vec<usize> {
+        let mut offsets = Vec::new();
+        let mut visited = HashSet::new();
+        let mut pending = vec![taint];
+
+        while let Some(taint) = pending.pop() {
+            if !taint.is_tainted() || !visited.insert(taint) {
+                continue;
+            }
+
+            if taint.0 & UNION_FLAG != 0 {
+                let (a, b) = self.unions[(taint.0 & !UNION_FLAG) as usize];
+                pending.push(a);
+                pending.push(b);
+            } else {
+                offsets.push(taint.0 as usize - 1);
+            }
+        }
+
+        offsets.sort_unstable();
+        offsets
+    }
+
+    /// Removes all the unions.
+    pub fn clear(&mut self) {
+        self.unions.clear();
+        self.dedup.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn taint_union() {
+        let mut labels = TaintLabels::new();
+
+        let a = Taint::input(1);
+        let b = Taint::input(7);
+
+        assert_eq!(labels.union(a, Taint::UNTAINTED), a);
+        assert_eq!(labels.union(Taint::UNTAINTED, b), b);
+        assert_eq!(labels.union(a, a), a);
+
+        let ab = labels.union(a, b);
+        assert_eq!(labels.union(b, a), ab);
+        assert_eq!(labels.offsets(ab), vec![1, 7]);
+    }
+
+    #[test]
+    fn taint_offsets_nested() {
+        let mut labels = TaintLabels::new();
+
+        let ab = labels.union(Taint::input(3), Taint::input(0));
+        let bc = labels.union(Taint::input(0), Taint::input(5));
+        let abc = labels.union(ab, bc);
+
+        assert_eq!(labels.offsets(abc), vec![0, 3, 5]);
+        assert!(labels.offsets(Taint::UNTAINTED).is_empty());
+    }
+
+    #[test]
+    fn taint_display() {
+        let mut labels = TaintLabels::new();
+        let ab = labels.union(Taint::input(0x10), Taint::input(1));
+
+        assert_eq!(Taint::UNTAINTED.to_string(), "untainted");
+        assert_eq!(Taint::input(0x10).to_string(), "input[0x10]");
+        assert_eq!(ab.to_string(), "union#0");
+    }
+}